@@ -1,115 +1,311 @@
+pub mod protocol;
+pub mod signing;
+pub mod tls;
 
+use anyhow::{Result, bail};
+use bullg_core::ControlPlane;
+use bullg_crypto::BullGCrypto;
+use futures_util::{SinkExt, StreamExt};
+use moka::sync::Cache;
+use reqwest::Client;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant, sleep, sleep_until};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{Connector, connect_async_tls_with_config};
+use tracing::{error, info, warn};
 
-//Commented Version 1.0.0 Code for Sync Client for Version 1.0.1
-// Once Version 1.0.1 is stable, remove the comments and delete Version 1.0.0 code.
-// use anyhow::Result;
-// //use futures_util::{StreamExt, SinkExt};
-// use bullg_core::GatewayState;
-// use moka::sync::Cache;
-// use reqwest::Client;
-// use tokio::time::{sleep, Duration};
-// use tokio_tungstenite::connect_async;
-// use tracing::{error, info};
-// //use core::slice::SlicePattern;
-// //use bullg_crypto::BullGCrypto;
-
-// pub struct SyncClient {
-//     ws_url: String,
-//     https_url: String,
-//     cp_id: String,
-//     client: Client,
-//     token_cache: Cache<&'static str, (String, i64)>,
-// }
-
-// impl SyncClient {
-//     pub fn new(ws_url: String, https_url: String, cp_id: String) -> Self {
-//         Self {
-//             ws_url,
-//             https_url,
-//             cp_id,
-//             client: Client::new(),
-//             token_cache: Cache::new(10),
-//         }
-//     }
-
-//     pub async fn run<F>(&self, on_state: F)
-//     where
-//         F: Fn(GatewayState) + Send + Sync + 'static + Clone,
-//     {
-//         // Prefer websocket; on failure, fallback to polling HTTPS every 5s
-//         loop {
-//             match self.try_ws(on_state.clone()).await {
-//                 Ok(_) => {}
-//                 Err(e) => {
-//                     error!("WS sync failed: {e}. Falling back to HTTPS polling");
-//                     self.poll_https(on_state.clone()).await;
-//                 }
-//             }
-//             sleep(Duration::from_secs(1)).await;
-//         }
-//     }
-
-//     async fn try_ws<F>(&self, on_state: F) -> Result<()>
-//     where
-//         F: Fn(GatewayState) + Send + Sync + 'static + Clone,
-//     {
-//         let (ws, _resp) = connect_async(&self.ws_url).await?;
-//         info!("WS connected to control-plane");
-//         let (_write, mut read) = ws.split();
-//         use futures_util::StreamExt;
-//         while let Some(msg) = read.next().await {
-//             let msg = msg?;
-//             if msg.is_binary() {
-//                 let decrypted = bullg_utils::custom_decrypt(msg.into_data().as_ref())?;
-//                 //let d = BullGCrypto::decode_data(msg.into_data().as_ref(),"test");
-//                 let state: GatewayState = serde_json::from_slice(&decrypted)?;
-//                 on_state(state);
-//             }
-//         }
-//         Ok(())
-//     }
-
-//     async fn poll_https<F>(&self, on_state: F)
-//     where
-//         F: Fn(GatewayState) + Send + Sync + 'static + Clone,
-//     {
-//         loop {
-//             match self.pull_once().await {
-//                 Ok(state) => on_state(state),
-//                 Err(e) => error!("HTTPS pull failed: {e}"),
-//             }
-//             sleep(Duration::from_secs(5)).await;
-//         }
-//     }
-
-//     async fn pull_once(&self) -> Result<GatewayState> {
-//         let token = if let Some((t, _exp)) = self.token_cache.get("token") {
-//             // TODO check exp refresh; simplified here
-//             t
-//         } else {
-//             let t = self
-//                 .client
-//                 .post(format!("{}/token", self.https_url))
-//                 .json(&serde_json::json!({
-//                     "id": self.cp_id,
-//                     "pub": "public-key-here"
-//                 }))
-//                 .send()
-//                 .await?
-//                 .text()
-//                 .await?;
-//             self.token_cache.insert("token", (t.clone(), 0));
-//             t
-//         };
-//         let bytes = self
-//             .client
-//             .get(format!("{}/state", self.https_url))
-//             .bearer_auth(token)
-//             .send()
-//             .await?
-//             .bytes()
-//             .await?;
-//         let decrypted = bullg_utils::custom_decrypt(&bytes)?;
-//         Ok(serde_json::from_slice(&decrypted)?)
-//     }
-// }
+use crate::protocol::{PROTOCOL_VERSION, StateMessage, StateUpdate};
+use crate::signing::Verifier;
+use crate::tls::ControlPlaneTls;
+
+/// Data plane side of the control plane sync: a websocket receiving state
+/// pushes, falling back to HTTPS polling. Both announce `PROTOCOL_VERSION`,
+/// a protocol 2 control plane then sends revisioned snapshots and deltas,
+/// HTTPS pulls also carry the applied revision. A delta not following the
+/// applied revision, or an update failing to apply, is dropped and a
+/// snapshot fetched.
+pub struct SyncClient {
+    ws_url: String,
+    https_url: String,
+    cp_id: String,
+    public_cert: String,
+    poll_interval: Duration,
+    // None never pings
+    ping_interval: Option<Duration>,
+    pong_timeout: Duration,
+    verifier: Verifier,
+    // Map key of `control_plane.encryption_key` the state is encrypted with
+    state_key: String,
+    client: Client,
+    // None without mTLS settings, the default connector is used
+    ws_connector: Option<Connector>,
+    token_cache: Cache<&'static str, (String, i64)>,
+    // Revision of the last applied protocol 2 message, deltas must follow it
+    revision: Mutex<Option<u64>>,
+    // The websocket is open or the last pull succeeded
+    connected: Arc<AtomicBool>,
+}
+
+impl SyncClient {
+    /// Fails when no signing or encryption key is configured, unsigned or
+    /// plaintext state is never applied, or when the mTLS certificate, key or
+    /// CA cannot be loaded
+    pub fn new(cfg: &ControlPlane) -> Result<Self> {
+        if cfg.encryption_key.is_empty() {
+            bail!("control_plane.encryption_key is required to decrypt control plane state");
+        }
+        let tls = ControlPlaneTls::from_config(cfg)?;
+        let (client, ws_connector) = match &tls {
+            Some(tls) => (tls.http_client()?, Some(tls.ws_connector()?)),
+            None => (Client::new(), None),
+        };
+        Ok(Self {
+            ws_url: format!("{}?protocol={PROTOCOL_VERSION}", cfg.get_ws_url()),
+            https_url: cfg.get_https_url(),
+            cp_id: cfg.id.clone(),
+            public_cert: tls.as_ref().and_then(|t| t.cert_pem()).unwrap_or(&cfg.mtls_cert).to_string(),
+            poll_interval: Duration::from_secs(cfg.poll_interval_sec.max(1)),
+            ping_interval: (cfg.ws_ping_interval_sec > 0).then(|| Duration::from_secs(cfg.ws_ping_interval_sec)),
+            pong_timeout: Duration::from_secs(cfg.ws_pong_timeout_sec.max(1)),
+            verifier: Verifier::from_config(cfg)?,
+            state_key: BullGCrypto::new(&cfg.encryption_key, "").map_encryption_key(None, None),
+            client,
+            ws_connector,
+            token_cache: Cache::new(10),
+            revision: Mutex::new(None),
+            connected: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// True while the websocket is open, or the last HTTPS pull succeeded
+    pub fn connection(&self) -> Arc<AtomicBool> {
+        self.connected.clone()
+    }
+
+    /// Sync forever, handing every snapshot and delta to `on_state` in the
+    /// order they must be applied. The next update is only handed over once
+    /// the previous one is applied, an error resyncs with a snapshot.
+    pub async fn run<F, Fut>(&self, on_state: F)
+    where
+        F: Fn(StateUpdate) -> Fut + Send + Sync + 'static + Clone,
+        Fut: Future<Output = Result<()>> + Send,
+    {
+        // Prefer websocket; on failure, fallback to polling HTTPS
+        loop {
+            let ws = self.try_ws(on_state.clone()).await;
+            self.connected.store(false, Ordering::Relaxed);
+            match ws {
+                Ok(_) => {}
+                Err(e) => {
+                    error!("WS sync failed: {e}. Falling back to HTTPS polling");
+                    self.poll_https(on_state.clone()).await;
+                }
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn try_ws<F, Fut>(&self, on_state: F) -> Result<()>
+    where
+        F: Fn(StateUpdate) -> Fut + Send + Sync + 'static + Clone,
+        Fut: Future<Output = Result<()>> + Send,
+    {
+        let (ws, _resp) = connect_async_tls_with_config(&self.ws_url, None, false, self.ws_connector.clone()).await?;
+        info!("WS connected to control-plane");
+        self.connected.store(true, Ordering::Relaxed);
+        let (mut write, mut read) = ws.split();
+        // Idle for a ping interval the connection is pinged, then anything
+        // arriving in the pong timeout shows it is still alive
+        let mut next_ping = self.ping_interval.map(|every| Instant::now() + every);
+        let mut pong_by: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                msg = read.next() => {
+                    let Some(msg) = msg else {
+                        return Ok(());
+                    };
+                    let msg = msg?;
+                    pong_by = None;
+                    next_ping = self.ping_interval.map(|every| Instant::now() + every);
+                    if msg.is_binary() {
+                        match self.decode(msg.into_data().as_ref()) {
+                            // A new connection starts with a snapshot
+                            Ok(msg) => {
+                                if !self.accept(msg, &on_state).await {
+                                    return Ok(());
+                                }
+                            }
+                            Err(e) => error!("WS state ignored: {e}"),
+                        }
+                    }
+                }
+                _ = sleep_until(next_ping.unwrap_or_else(Instant::now)), if next_ping.is_some() && pong_by.is_none() => {
+                    write.send(Message::Ping(Default::default())).await?;
+                    pong_by = Some(Instant::now() + self.pong_timeout);
+                }
+                _ = sleep_until(pong_by.unwrap_or_else(Instant::now)), if pong_by.is_some() => {
+                    bail!("no pong from the control plane in {}s", self.pong_timeout.as_secs());
+                }
+            }
+        }
+    }
+
+    async fn poll_https<F, Fut>(&self, on_state: F)
+    where
+        F: Fn(StateUpdate) -> Fut + Send + Sync + 'static + Clone,
+        Fut: Future<Output = Result<()>> + Send,
+    {
+        loop {
+            let pulled = self.pull_once().await;
+            self.connected.store(pulled.is_ok(), Ordering::Relaxed);
+            match pulled {
+                // Without an applied revision the next pull is a snapshot
+                Ok(msg) => {
+                    self.accept(msg, &on_state).await;
+                }
+                Err(e) => error!("HTTPS pull failed: {e}"),
+            }
+            sleep(self.poll_interval).await;
+        }
+    }
+
+    async fn pull_once(&self) -> Result<StateMessage> {
+        let token = if let Some((t, _exp)) = self.token_cache.get("token") {
+            // TODO check exp refresh; simplified here
+            t
+        } else {
+            let t = self
+                .client
+                .post(format!("{}/token", self.https_url))
+                .json(&serde_json::json!({
+                    "id": self.cp_id,
+                    "pub": self.public_cert
+                }))
+                .send()
+                .await?
+                .text()
+                .await?;
+            self.token_cache.insert("token", (t.clone(), 0));
+            t
+        };
+        // With the applied revision the control plane may answer with a delta
+        let mut query = vec![("protocol", PROTOCOL_VERSION.to_string())];
+        if let Some(revision) = *self.revision.lock().unwrap_or_else(|e| e.into_inner()) {
+            query.push(("revision", revision.to_string()));
+        }
+        let bytes = self
+            .client
+            .get(format!("{}/state", self.https_url))
+            .query(&query)
+            .bearer_auth(token)
+            .send()
+            .await?
+            .bytes()
+            .await?;
+        self.decode(&bytes)
+    }
+
+    /// Decode a state message, decrypting it and verifying its signature
+    /// before it is parsed
+    fn decode(&self, data: &[u8]) -> Result<StateMessage> {
+        let decrypted = bullg_utils::custom_decrypt(data, &self.state_key)?;
+        let payload = self.verifier.verify(&decrypted)?;
+        StateMessage::parse(&payload)
+    }
+
+    /// Apply a message with `on_state`, the revision only advances once it
+    /// is applied. False for a delta that does not follow the applied
+    /// revision or an update that failed, the state then needs a snapshot.
+    async fn accept<F, Fut>(&self, msg: StateMessage, on_state: &F) -> bool
+    where
+        F: Fn(StateUpdate) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let applied = *self.revision.lock().unwrap_or_else(|e| e.into_inner());
+        if let StateUpdate::Delta(_) = msg.update
+            && (applied.is_none() || msg.base != applied)
+        {
+            warn!("control plane state delta does not follow the applied revision, resyncing");
+            self.set_revision(None);
+            return false;
+        }
+        match on_state(msg.update).await {
+            Ok(()) => {
+                self.set_revision(msg.revision);
+                true
+            }
+            Err(e) => {
+                error!("control plane state rejected, resyncing: {e:#}");
+                self.set_revision(None);
+                false
+            }
+        }
+    }
+
+    fn set_revision(&self, revision: Option<u64>) {
+        *self.revision.lock().unwrap_or_else(|e| e.into_inner()) = revision;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use bullg_core::StateDelta;
+
+    fn client() -> SyncClient {
+        let cfg = ControlPlane {
+            signing_alg: "hmac-sha256".into(),
+            signing_key: "c2VjcmV0".into(),
+            encryption_key: "state-key".into(),
+            ..Default::default()
+        };
+        SyncClient::new(&cfg).unwrap()
+    }
+
+    fn snapshot(revision: u64) -> StateMessage {
+        StateMessage { update: StateUpdate::Snapshot(Default::default()), revision: Some(revision), base: None }
+    }
+
+    fn delta(base: u64, revision: u64) -> StateMessage {
+        StateMessage { update: StateUpdate::Delta(StateDelta::default()), revision: Some(revision), base: Some(base) }
+    }
+
+    fn revision(sync: &SyncClient) -> Option<u64> {
+        *sync.revision.lock().unwrap()
+    }
+
+    async fn apply(_: StateUpdate) -> Result<()> {
+        Ok(())
+    }
+
+    async fn reject(_: StateUpdate) -> Result<()> {
+        Err(anyhow!("invalid state"))
+    }
+
+    #[tokio::test]
+    async fn deltas_must_follow_the_applied_revision() {
+        let sync = client();
+        assert!(!sync.accept(delta(0, 1), &apply).await);
+        assert!(sync.accept(snapshot(3), &apply).await);
+        assert!(sync.accept(delta(3, 4), &apply).await);
+        assert_eq!(revision(&sync), Some(4));
+        assert!(!sync.accept(delta(3, 5), &apply).await);
+        assert_eq!(revision(&sync), None);
+    }
+
+    #[tokio::test]
+    async fn failed_updates_resync_instead_of_advancing() {
+        let sync = client();
+        assert!(sync.accept(snapshot(3), &apply).await);
+        assert!(!sync.accept(delta(3, 4), &reject).await);
+        assert_eq!(revision(&sync), None);
+        // The delta after the rejected one does not apply on top of it
+        assert!(!sync.accept(delta(4, 5), &apply).await);
+        assert!(!sync.accept(snapshot(6), &reject).await);
+        assert!(sync.accept(snapshot(6), &apply).await);
+        assert_eq!(revision(&sync), Some(6));
+    }
+}
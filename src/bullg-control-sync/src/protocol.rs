@@ -0,0 +1,61 @@
+use anyhow::{Result, bail};
+use bullg_core::{ServicesTemplate, StateDelta};
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Highest state message protocol this client speaks, announced to the
+/// control plane in the `protocol` query parameter of the sync websocket and
+/// of state pulls. Protocol 1 is a bare services template, a control plane
+/// only sends protocol 2 messages, deltas among them, to peers announcing it.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// State change sent by the control plane
+#[derive(Debug, Clone)]
+pub enum StateUpdate {
+    /// Whole template, sent on connect and to resync a peer
+    Snapshot(ServicesTemplate),
+    Delta(StateDelta),
+}
+
+/// Protocol 2 message: a snapshot at `revision`, or a delta taking the state
+/// from revision `base` to `revision`
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    revision: u64,
+    #[serde(default)]
+    base: Option<u64>,
+    #[serde(default)]
+    snapshot: Option<ServicesTemplate>,
+    #[serde(default)]
+    delta: Option<StateDelta>,
+}
+
+/// Decoded state message, protocol 1 messages have no revisions
+#[derive(Debug, Clone)]
+pub struct StateMessage {
+    pub update: StateUpdate,
+    pub revision: Option<u64>,
+    pub base: Option<u64>,
+}
+
+impl StateMessage {
+    pub fn parse(payload: &[u8]) -> Result<Self> {
+        let value: Value = serde_json::from_slice(payload)?;
+        let Some(protocol) = value.get("protocol") else {
+            let template = serde_json::from_value(value)?;
+            return Ok(Self { update: StateUpdate::Snapshot(template), revision: None, base: None });
+        };
+        // Checked first, a newer message may not parse as an envelope
+        if protocol.as_u64().is_none_or(|v| v > PROTOCOL_VERSION as u64) {
+            bail!("unsupported state protocol {protocol}");
+        }
+        let envelope: Envelope = serde_json::from_value(value)?;
+        let update = match (envelope.snapshot, envelope.delta) {
+            (Some(snapshot), None) => StateUpdate::Snapshot(snapshot),
+            (None, Some(_)) if envelope.base.is_none() => bail!("state delta without a base revision"),
+            (None, Some(delta)) => StateUpdate::Delta(delta),
+            _ => bail!("state message needs exactly one of snapshot and delta"),
+        };
+        Ok(Self { update, revision: Some(envelope.revision), base: envelope.base })
+    }
+}
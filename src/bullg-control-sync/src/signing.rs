@@ -0,0 +1,107 @@
+use anyhow::{Result, anyhow, bail};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use bullg_core::ControlPlane;
+use bullg_crypto::BullGCrypto;
+use serde::{Deserialize, Serialize};
+
+/// Envelope the control plane wraps every state message in.
+/// `signature` is computed over the decoded `payload` bytes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedMessage {
+    /// Base64 encoded state json
+    pub payload: String,
+    /// Base64 encoded signature
+    pub signature: String,
+}
+
+/// Verifies state messages against the configured control plane key
+pub enum Verifier {
+    Ed25519(Vec<u8>),
+    HmacSha256(Vec<u8>),
+}
+
+impl Verifier {
+    pub fn from_config(cfg: &ControlPlane) -> Result<Self> {
+        if cfg.signing_key.is_empty() {
+            bail!("control_plane.signing_key is required to verify control plane state");
+        }
+        let key = STANDARD
+            .decode(cfg.signing_key.trim())
+            .map_err(|e| anyhow!("control_plane.signing_key is not valid base64: {e}"))?;
+        match cfg.signing_alg.to_ascii_lowercase().as_str() {
+            "ed25519" => {
+                if key.len() != 32 {
+                    bail!("control_plane.signing_key must be a 32 byte ed25519 public key");
+                }
+                Ok(Verifier::Ed25519(key))
+            }
+            "hmac-sha256" => Ok(Verifier::HmacSha256(key)),
+            alg => bail!("unsupported control_plane.signing_alg: {}", alg),
+        }
+    }
+
+    /// Payload of a signed message, unsigned or mis-signed messages are rejected
+    pub fn verify(&self, msg: &[u8]) -> Result<Vec<u8>> {
+        let signed: SignedMessage = serde_json::from_slice(msg)
+            .map_err(|e| anyhow!("rejecting unsigned control plane message: {e}"))?;
+        let payload = STANDARD.decode(&signed.payload)?;
+        let signature = STANDARD.decode(&signed.signature)?;
+        let valid = match self {
+            Verifier::Ed25519(key) => BullGCrypto::verify_ed25519(&payload, key, &signature),
+            Verifier::HmacSha256(secret) => {
+                BullGCrypto::verify_hmac_sha256(&payload, secret, &signature)
+            }
+        };
+        if !valid {
+            bail!("rejecting control plane message: invalid signature");
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const STATE: &[u8] = br#"{"services":[]}"#;
+
+    fn signed(payload: &[u8], signature: &[u8]) -> Vec<u8> {
+        let msg = SignedMessage { payload: STANDARD.encode(payload), signature: STANDARD.encode(signature) };
+        serde_json::to_vec(&msg).unwrap()
+    }
+
+    fn verifier(alg: &str, key: &[u8]) -> Verifier {
+        let cfg = ControlPlane { signing_alg: alg.into(), signing_key: STANDARD.encode(key), ..Default::default() };
+        Verifier::from_config(&cfg).unwrap()
+    }
+
+    #[test]
+    fn hmac_signed_state_is_accepted_and_tampered_state_rejected() {
+        let verifier = verifier("hmac-sha256", b"shared secret");
+        let signature = BullGCrypto::sign_hmac_sha256(STATE, b"shared secret");
+        assert_eq!(verifier.verify(&signed(STATE, &signature)).unwrap(), STATE);
+        assert!(verifier.verify(&signed(br#"{"services":[{}]}"#, &signature)).is_err());
+        let other = BullGCrypto::sign_hmac_sha256(STATE, b"other secret");
+        assert!(verifier.verify(&signed(STATE, &other)).is_err());
+    }
+
+    #[test]
+    fn ed25519_signed_state_is_accepted_and_tampered_state_rejected() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let verifier = verifier("ed25519", key.verifying_key().as_bytes());
+        let signature = key.sign(STATE).to_bytes();
+        assert_eq!(verifier.verify(&signed(STATE, &signature)).unwrap(), STATE);
+        assert!(verifier.verify(&signed(br#"{"services":[{}]}"#, &signature)).is_err());
+    }
+
+    #[test]
+    fn unsigned_state_and_bad_keys_are_rejected() {
+        let verifier = verifier("hmac-sha256", b"shared secret");
+        assert!(verifier.verify(STATE).is_err());
+        let no_key = ControlPlane::default();
+        assert!(Verifier::from_config(&no_key).is_err());
+        let short = ControlPlane { signing_key: STANDARD.encode([1; 16]), ..Default::default() };
+        assert!(Verifier::from_config(&short).is_err());
+    }
+}
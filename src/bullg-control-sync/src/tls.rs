@@ -0,0 +1,102 @@
+use anyhow::{Context, Result, anyhow, bail};
+use bullg_core::ControlPlane;
+use reqwest::{Certificate, Client, Identity};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ClientConfig, RootCertStore};
+use std::sync::Arc;
+use tokio_tungstenite::Connector;
+
+/// TLS settings of the control plane connection: the client certificate the
+/// data plane authenticates with and the CA the control plane certificate
+/// must chain to, replacing the public roots. Each field is inline PEM or the
+/// path of a PEM file.
+pub struct ControlPlaneTls {
+    identity: Option<(String, String)>,
+    ca: Option<String>,
+}
+
+impl ControlPlaneTls {
+    /// None when no mTLS field is set, the connection then uses the public
+    /// roots and no client certificate
+    pub fn from_config(cfg: &ControlPlane) -> Result<Option<Self>> {
+        let identity = match (cfg.mtls_cert.is_empty(), cfg.mtls_key.is_empty()) {
+            (true, true) => None,
+            (false, false) => Some((pem(&cfg.mtls_cert, "mtls_cert")?, pem(&cfg.mtls_key, "mtls_key")?)),
+            _ => bail!("control_plane.mtls_cert and control_plane.mtls_key must be set together"),
+        };
+        let ca = (!cfg.mtls_ca.is_empty()).then(|| pem(&cfg.mtls_ca, "mtls_ca")).transpose()?;
+        if identity.is_none() && ca.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self { identity, ca }))
+    }
+
+    /// PEM of the client certificate, sent when asking for a sync token
+    pub fn cert_pem(&self) -> Option<&str> {
+        self.identity.as_ref().map(|(cert, _)| cert.as_str())
+    }
+
+    pub fn http_client(&self) -> Result<Client> {
+        let mut builder = Client::builder().use_rustls_tls();
+        if let Some((cert, key)) = &self.identity {
+            let identity = Identity::from_pem(format!("{cert}\n{key}").as_bytes())
+                .map_err(|e| anyhow!("control_plane.mtls_cert/mtls_key: invalid client identity: {e}"))?;
+            builder = builder.identity(identity);
+        }
+        if let Some(ca) = &self.ca {
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in Certificate::from_pem_bundle(ca.as_bytes())
+                .map_err(|e| anyhow!("control_plane.mtls_ca: invalid certificate: {e}"))?
+            {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        builder.build().context("control_plane mTLS settings rejected by the HTTPS client")
+    }
+
+    pub fn ws_connector(&self) -> Result<Connector> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca {
+            Some(ca) => {
+                for cert in certs(ca, "mtls_ca")? {
+                    roots.add(cert).map_err(|e| anyhow!("control_plane.mtls_ca: {e}"))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots);
+        let config = match &self.identity {
+            Some((cert, key)) => {
+                let key = PrivateKeyDer::from_pem_slice(key.as_bytes())
+                    .map_err(|e| anyhow!("control_plane.mtls_key: no private key: {e}"))?;
+                builder
+                    .with_client_auth_cert(certs(cert, "mtls_cert")?, key)
+                    .map_err(|e| anyhow!("control_plane.mtls_cert/mtls_key: {e}"))?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Connector::Rustls(Arc::new(config)))
+    }
+}
+
+// Inline PEM, or the content of the PEM file it names
+fn pem(value: &str, field: &str) -> Result<String> {
+    if value.trim_start().starts_with("-----BEGIN") {
+        return Ok(value.to_string());
+    }
+    std::fs::read_to_string(value).with_context(|| format!("control_plane.{field}: cannot read {value}"))
+}
+
+fn certs(pem: &str, field: &str) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_slice_iter(pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("control_plane.{field}: invalid certificate: {e}"))?;
+    if certs.is_empty() {
+        bail!("control_plane.{field}: no certificate found");
+    }
+    Ok(certs)
+}
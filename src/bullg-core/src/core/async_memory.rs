@@ -0,0 +1,188 @@
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Memory, Page, Transaction};
+
+/// Async facade over `Memory` for the request path.
+///
+/// LMDB transactions block, so every call on a persistent store runs on the
+/// blocking thread pool. The in-memory store never blocks and is called inline.
+#[derive(Clone)]
+pub struct AsyncMemory {
+    inner: Arc<Memory>,
+}
+
+impl AsyncMemory {
+    pub fn new(inner: Arc<Memory>) -> Self {
+        Self { inner }
+    }
+
+    /// Underlying synchronous store
+    pub fn inner(&self) -> Arc<Memory> {
+        self.inner.clone()
+    }
+
+    /// Run any synchronous `Memory` operation without blocking the runtime
+    pub async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&Memory) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        if !self.inner.is_lmdb() {
+            return f(&self.inner);
+        }
+        let inner = self.inner.clone();
+        tokio::task::spawn_blocking(move || f(&inner)).await?
+    }
+
+    pub async fn add<T: Serialize + Send + 'static>(&self, db: &str, key: &str, value: T) -> Result<()> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.add(&db, &key, &value)).await
+    }
+
+    pub async fn put<T: Serialize + Send + 'static>(&self, db: &str, key: &str, value: T) -> Result<()> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.put(&db, &key, &value)).await
+    }
+
+    pub async fn update<T: Serialize + Send + 'static>(&self, db: &str, key: &str, value: T) -> Result<()> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.update(&db, &key, &value)).await
+    }
+
+    pub async fn get<T: DeserializeOwned + Send + 'static>(&self, db: &str, key: &str) -> Result<Option<T>> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.get(&db, &key)).await
+    }
+
+    pub async fn get_raw(&self, db: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.get_raw(&db, &key)).await
+    }
+
+    pub async fn delete(&self, db: &str, key: &str) -> Result<()> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.delete(&db, &key)).await
+    }
+
+    pub async fn exists(&self, db: &str, key: &str) -> Result<bool> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.exists(&db, &key)).await
+    }
+
+    pub async fn patch(&self, db: &str, key: &str, updates: Vec<(String, Value)>) -> Result<()> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.patch(&db, &key, &updates)).await
+    }
+
+    pub async fn incr(&self, db: &str, key: &str, delta: i64) -> Result<i64> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.incr(&db, &key, delta)).await
+    }
+
+    pub async fn incr_with_ttl(&self, db: &str, key: &str, delta: i64, ttl: Duration) -> Result<i64> {
+        let (db, key) = (db.to_string(), key.to_string());
+        self.run(move |m| m.incr_with_ttl(&db, &key, delta, ttl)).await
+    }
+
+    pub async fn insert_many<T: Serialize + Send + 'static>(&self, db: &str, entries: Vec<(String, T)>) -> Result<()> {
+        let db = db.to_string();
+        self.run(move |m| m.insert_many(&db, entries)).await
+    }
+
+    pub async fn delete_many(&self, db: &str, keys: Vec<String>) -> Result<()> {
+        let db = db.to_string();
+        self.run(move |m| m.delete_many(&db, &keys)).await
+    }
+
+    pub async fn all<T: DeserializeOwned + Send + 'static>(&self, db: &str) -> Result<Vec<T>> {
+        let db = db.to_string();
+        self.run(move |m| m.all(&db)).await
+    }
+
+    /// `Memory::transaction` off the runtime threads
+    pub async fn transaction<R, F>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&mut Transaction<'_>) -> Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        self.run(move |m| m.transaction(f)).await
+    }
+
+    pub async fn scan_prefix<T: DeserializeOwned + Send + 'static>(
+        &self,
+        db: &str,
+        prefix: &str,
+        limit: usize,
+        after: Option<String>,
+    ) -> Result<Page<T>> {
+        let (db, prefix) = (db.to_string(), prefix.to_string());
+        self.run(move |m| m.scan_prefix(&db, &prefix, limit, after.as_deref())).await
+    }
+
+    pub async fn all_map<T: DeserializeOwned + Send + 'static>(&self, db: &str) -> Result<HashMap<String, T>> {
+        let db = db.to_string();
+        self.run(move |m| m.all_map(&db)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+
+    fn lmdb() -> (AsyncMemory, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("bullg-async-{}", uuid::Uuid::new_v4()));
+        (AsyncMemory::new(Arc::new(Memory::open_lmdb(&path).unwrap())), path)
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn slow_store_calls_do_not_block_other_tasks() {
+        let (store, path) = lmdb();
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        });
+
+        let started = Instant::now();
+        store
+            .transaction(|tx| {
+                std::thread::sleep(Duration::from_millis(200));
+                tx.put("db", "key", &1)
+            })
+            .await
+            .unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        // The single runtime thread kept running the ticker meanwhile
+        assert!(ticks.load(Ordering::SeqCst) >= 5, "{} ticks", ticks.load(Ordering::SeqCst));
+        ticker.abort();
+
+        assert_eq!(store.incr("db", "count", 2).await.unwrap(), 2);
+        assert_eq!(store.get::<i64>("db", "key").await.unwrap(), Some(1));
+        store.delete("db", "key").await.unwrap();
+        assert!(!store.exists("db", "key").await.unwrap());
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn the_in_memory_store_answers_inline() {
+        let store = AsyncMemory::new(Arc::new(Memory::memory()));
+        store.put("db", "a", "x").await.unwrap();
+        store.put("db", "b", "y").await.unwrap();
+        let mut all: Vec<String> = store.all("db").await.unwrap();
+        all.sort();
+        assert_eq!(all, ["x", "y"]);
+        assert_eq!(store.run(|m| m.get::<String>("db", "a")).await.unwrap().as_deref(), Some("x"));
+    }
+}
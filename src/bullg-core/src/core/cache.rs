@@ -1,19 +1,73 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
 use std::time::{Duration, Instant};
-use tokio::sync::RwLock;
-use std::sync::Arc;
+use tokio::sync::{OnceCell, RwLock};
+use tokio::task::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// A cached value with timestamp for TTL
 #[derive(Debug, Clone)]
 struct CacheEntry<T> {
     value: T,
     expires_at: Option<Instant>, // None = never expires
+    used: u64,                   // tick of the last insert or get
 }
 
+#[derive(Debug)]
+struct Entries<K, V> {
+    map: HashMap<K, CacheEntry<V>>,
+    // Keys by the tick they were last used at, least recent first
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K, V> Entries<K, V>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone,
+{
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some(entry) = self.map.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+}
+
+/// Counts of a cache since it was created
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Gets of missing or expired keys
+    pub misses: u64,
+    /// Entries dropped to make room
+    pub evictions: u64,
+    /// Entries dropped once expired
+    pub expirations: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+}
+
+/// Values by key, dropped once their TTL passes and, for a cache created
+/// `with_capacity`, least recently used first when an insert finds it full
 #[derive(Debug)]
 pub struct Cache<K, V> {
-    store: RwLock<HashMap<K, CacheEntry<V>>>,
+    store: RwLock<Entries<K, V>>,
     ttl: Option<Duration>, // default TTL for all entries
+    max_entries: Option<usize>,
+    counters: Counters,
+    // Values being computed by `get_or_insert_with`, by key
+    flights: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
 }
 
 impl<K, V> Cache<K, V>
@@ -23,36 +77,111 @@ where
 {
     /// Create new cache with optional TTL
     pub fn new(ttl: Option<Duration>) -> Arc<Self> {
+        Self::build(ttl, None)
+    }
+
+    /// Create new cache with optional TTL holding at most `max_entries`
+    pub fn with_capacity(ttl: Option<Duration>, max_entries: usize) -> Arc<Self> {
+        Self::build(ttl, Some(max_entries))
+    }
+
+    fn build(ttl: Option<Duration>, max_entries: Option<usize>) -> Arc<Self> {
         Arc::new(Self {
-            store: RwLock::new(HashMap::new()),
+            store: RwLock::new(Entries { map: HashMap::new(), order: BTreeMap::new(), tick: 0 }),
             ttl,
+            max_entries,
+            counters: Counters::default(),
+            flights: Mutex::new(HashMap::new()),
         })
     }
 
     /// Insert value into cache
     pub async fn insert(&self, key: K, value: V) {
         let expires_at = self.ttl.map(|t| Instant::now() + t);
-        let entry = CacheEntry { value, expires_at };
+        self.put(key, value, expires_at).await;
+    }
+
+    /// Insert value expiring after `ttl` instead of the default TTL
+    pub async fn insert_for(&self, key: K, value: V, ttl: Duration) {
+        self.put(key, value, Some(Instant::now() + ttl)).await;
+    }
 
+    async fn put(&self, key: K, value: V, expires_at: Option<Instant>) {
         let mut store = self.store.write().await;
-        store.insert(key, entry);
+        store.remove(&key);
+        if let Some(max) = self.max_entries {
+            while store.map.len() >= max {
+                let Some((_, oldest)) = store.order.pop_first() else {
+                    break;
+                };
+                store.map.remove(&oldest);
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+            if max == 0 {
+                return;
+            }
+        }
+        let used = store.next_tick();
+        store.order.insert(used, key.clone());
+        store.map.insert(key, CacheEntry { value, expires_at, used });
     }
 
-    /// Get value if not expired
+    /// Get value if not expired, it becomes the most recently used
     pub async fn get(&self, key: &K) -> Option<V> {
         let mut store = self.store.write().await;
+        let Some(entry) = store.map.get(key) else {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        if entry.expires_at.is_some_and(|at| Instant::now() > at) {
+            store.remove(key);
+            self.counters.expirations.fetch_add(1, Ordering::Relaxed);
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        let used = store.next_tick();
+        let entry = store.map.get_mut(key)?;
+        let last = std::mem::replace(&mut entry.used, used);
+        let value = entry.value.clone();
+        store.order.remove(&last);
+        store.order.insert(used, key.clone());
+        Some(value)
+    }
 
-        if let Some(entry) = store.get(key) {
-            if let Some(expiry) = entry.expires_at {
-                if Instant::now() > expiry {
-                    // Expired, remove entry
-                    store.remove(key);
-                    return None;
+    /// Cached value of `key`, else the value of `init` inserted with the
+    /// default TTL. Concurrent calls for one key share a single `init` and
+    /// its value, should that one be cancelled the next caller runs its own.
+    pub async fn get_or_insert_with<F, Fut>(&self, key: K, init: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        if let Some(value) = self.get(&key).await {
+            return value;
+        }
+        let flight = self.flights().entry(key.clone()).or_default().clone();
+        let value = flight
+            .get_or_init(|| async {
+                // A flight may have ended between the lookup and joining this one
+                if let Some(value) = self.get(&key).await {
+                    return value;
                 }
-            }
-            return Some(entry.value.clone());
+                let value = init().await;
+                self.insert(key.clone(), value.clone()).await;
+                value
+            })
+            .await
+            .clone();
+        let mut flights = self.flights();
+        if flights.get(&key).is_some_and(|f| Arc::ptr_eq(f, &flight)) {
+            flights.remove(&key);
         }
-        None
+        value
+    }
+
+    fn flights(&self) -> std::sync::MutexGuard<'_, HashMap<K, Arc<OnceCell<V>>>> {
+        self.flights.lock().unwrap_or_else(|e| e.into_inner())
     }
 
     /// Remove specific key
@@ -61,9 +190,179 @@ where
         store.remove(key);
     }
 
+    /// Drop the entries that expired, `get` only drops those it reads
+    pub async fn remove_expired(&self) {
+        let now = Instant::now();
+        let mut store = self.store.write().await;
+        let Entries { map, order, .. } = &mut *store;
+        let before = map.len();
+        map.retain(|_, entry| {
+            let live = entry.expires_at.is_none_or(|at| at >= now);
+            if !live {
+                order.remove(&entry.used);
+            }
+            live
+        });
+        self.counters.expirations.fetch_add((before - map.len()) as u64, Ordering::Relaxed);
+    }
+
     /// Clear entire cache
     pub async fn clear(&self) {
         let mut store = self.store.write().await;
-        store.clear();
+        store.map.clear();
+        store.order.clear();
+    }
+
+    /// Entries held, expired ones not dropped yet included
+    pub async fn len(&self) -> usize {
+        self.store.read().await.map.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Most entries held, None when unbounded
+    pub fn capacity(&self) -> Option<usize> {
+        self.max_entries
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let count = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        CacheStats {
+            hits: count(&self.counters.hits),
+            misses: count(&self.counters.misses),
+            evictions: count(&self.counters.evictions),
+            expirations: count(&self.counters.expirations),
+        }
+    }
+}
+
+impl<K, V> Cache<K, V>
+where
+    K: std::cmp::Eq + std::hash::Hash + Clone + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    /// Drop the expired entries every `interval` in a task of the current
+    /// runtime. The task holds no reference to the cache and ends at the
+    /// first tick after the cache is dropped.
+    pub fn start_janitor(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(cache) = cache.upgrade() else {
+                    return;
+                };
+                cache.remove_expired().await;
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn the_least_recently_used_entry_is_evicted_when_full() {
+        let cache = Cache::with_capacity(None, 3);
+        assert_eq!((cache.capacity(), cache.len().await), (Some(3), 0));
+        for (key, value) in [("a", 1), ("b", 2), ("c", 3)] {
+            cache.insert(key, value).await;
+        }
+        assert_eq!(cache.len().await, 3);
+
+        cache.insert("d", 4).await;
+        assert_eq!(cache.len().await, 3);
+        assert_eq!(cache.get(&"a").await, None);
+        // Reading b makes c the oldest
+        assert_eq!(cache.get(&"b").await, Some(2));
+        cache.insert("e", 5).await;
+        assert_eq!(cache.get(&"c").await, None);
+        assert_eq!((cache.get(&"b").await, cache.get(&"d").await, cache.get(&"e").await), (Some(2), Some(4), Some(5)));
+        assert_eq!(cache.stats().evictions, 2);
+    }
+
+    #[tokio::test]
+    async fn replacing_a_key_evicts_nothing() {
+        let cache = Cache::with_capacity(None, 2);
+        cache.insert("a", 1).await;
+        cache.insert("b", 2).await;
+        cache.insert("a", 10).await;
+        assert_eq!(cache.len().await, 2);
+        // The replaced key is the most recent one
+        cache.insert("c", 3).await;
+        assert_eq!((cache.get(&"a").await, cache.get(&"b").await), (Some(10), None));
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[tokio::test]
+    async fn unbounded_and_empty_caches() {
+        let unbounded = Cache::new(None);
+        for i in 0..1000 {
+            unbounded.insert(i, i).await;
+        }
+        assert_eq!((unbounded.capacity(), unbounded.len().await), (None, 1000));
+
+        let empty = Cache::with_capacity(None, 0);
+        empty.insert("a", 1).await;
+        assert!(empty.is_empty().await);
+        assert_eq!(empty.get(&"a").await, None);
+    }
+
+    #[tokio::test]
+    async fn removed_and_expired_entries_free_their_slot() {
+        let cache = Cache::with_capacity(Some(Duration::from_millis(20)), 2);
+        cache.insert("a", 1).await;
+        cache.insert_for("b", 2, Duration::from_secs(60)).await;
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        cache.remove_expired().await;
+        cache.insert("c", 3).await;
+        assert_eq!(cache.get(&"b").await, Some(2));
+        cache.remove(&"b").await;
+        cache.insert("d", 4).await;
+        assert_eq!(cache.len().await, 2);
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 0, evictions: 0, expirations: 1 });
+    }
+
+    #[tokio::test]
+    async fn concurrent_readers_share_one_slow_init() {
+        let cache = Cache::new(None);
+        let calls = Arc::new(AtomicU64::new(0));
+        let readers: Vec<_> = (0..16)
+            .map(|_| {
+                let (cache, calls) = (cache.clone(), calls.clone());
+                tokio::spawn(async move {
+                    cache
+                        .get_or_insert_with("token", || async move {
+                            calls.fetch_add(1, Ordering::SeqCst);
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            "t-1".to_string()
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.await.unwrap(), "t-1");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(cache.flights().is_empty());
+        // Cached from now on
+        let value = cache.get_or_insert_with("token", || async { unreachable!() }).await;
+        assert_eq!(value, "t-1");
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_init_lets_the_next_caller_run_its_own() {
+        let cache = Cache::new(None);
+        let slow = cache.get_or_insert_with("key", || async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            1
+        });
+        assert!(tokio::time::timeout(Duration::from_millis(20), slow).await.is_err());
+        assert_eq!(cache.get_or_insert_with("key", || async { 2 }).await, 2);
+        assert_eq!(cache.get(&"key").await, Some(2));
     }
 }
@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crate::LoadSheddingCfg;
+
+/// Weight of the newest sample in the latency average
+const EWMA_WEIGHT: f64 = 0.1;
+/// The latency average halves for every second without a sample, so a
+/// node that stopped serving slow requests recovers
+const IDLE_HALF_LIFE_MS: f64 = 1000.0;
+
+/// Live load of a gateway node, shared with plugins through `BullGTools`
+pub struct LoadStats {
+    epoch: Instant,
+    connections: AtomicU64,
+    in_flight: AtomicU64,
+    // f64 bits of the latency moving average in microseconds
+    latency_us: AtomicU64,
+    last_sample_ms: AtomicU64,
+}
+
+/// Point in time view of `LoadStats`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Load {
+    pub connections: u64,
+    pub in_flight: u64,
+    pub latency_ms: f64,
+}
+
+impl LoadStats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            epoch: Instant::now(),
+            connections: AtomicU64::new(0),
+            in_flight: AtomicU64::new(0),
+            latency_us: AtomicU64::new(0f64.to_bits()),
+            last_sample_ms: AtomicU64::new(0),
+        })
+    }
+
+    /// Count an open connection until the guard is dropped
+    pub fn connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Count an in flight request until the guard is dropped, its latency is
+    /// sampled then unless the guard was discarded
+    pub fn request(self: &Arc<Self>) -> RequestGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        RequestGuard {
+            stats: self.clone(),
+            start: Instant::now(),
+            sample: true,
+        }
+    }
+
+    pub fn snapshot(&self) -> Load {
+        let avg = f64::from_bits(self.latency_us.load(Ordering::Relaxed));
+        let idle_ms = self.now_ms().saturating_sub(self.last_sample_ms.load(Ordering::Relaxed)) as f64;
+        Load {
+            connections: self.connections.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            latency_ms: avg * 0.5f64.powf(idle_ms / IDLE_HALF_LIFE_MS) / 1000.0,
+        }
+    }
+
+    /// Load relative to the configured thresholds, the highest ratio of
+    /// the checked ones. Computed before a new request is counted, so it
+    /// includes that request. 0 when no threshold is set.
+    pub fn pressure(&self, cfg: &LoadSheddingCfg) -> f64 {
+        let load = self.snapshot();
+        let ratio = |value: f64, max: u64| if max > 0 { value / max as f64 } else { 0.0 };
+        ratio((load.in_flight + 1) as f64, cfg.max_in_flight)
+            .max(ratio(load.connections as f64, cfg.max_connections))
+            .max(ratio(load.latency_ms, cfg.max_latency_ms))
+    }
+
+    /// Whether the node is above any configured threshold
+    pub fn overloaded(&self, cfg: &LoadSheddingCfg) -> bool {
+        self.pressure(cfg) > 1.0
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    fn sample(&self, latency_us: f64) {
+        let _ = self
+            .latency_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let avg = f64::from_bits(bits);
+                let next = if avg == 0.0 {
+                    latency_us
+                } else {
+                    avg + EWMA_WEIGHT * (latency_us - avg)
+                };
+                Some(next.to_bits())
+            });
+        self.last_sample_ms.store(self.now_ms(), Ordering::Relaxed);
+    }
+}
+
+pub struct ConnectionGuard(Arc<LoadStats>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct RequestGuard {
+    stats: Arc<LoadStats>,
+    start: Instant,
+    sample: bool,
+}
+
+impl RequestGuard {
+    /// Leave this request out of the latency average, for answers the
+    /// gateway produced without doing the work
+    pub fn discard(&mut self) {
+        self.sample = false;
+    }
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+        if self.sample {
+            self.stats.sample(self.start.elapsed().as_micros() as f64);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(max_in_flight: u64, max_connections: u64) -> LoadSheddingCfg {
+        LoadSheddingCfg { enabled: true, max_in_flight, max_connections, ..Default::default() }
+    }
+
+    #[test]
+    fn guards_count_while_held() {
+        let stats = LoadStats::new();
+        let conn = stats.connection();
+        let requests: Vec<RequestGuard> = (0..3).map(|_| stats.request()).collect();
+        let load = stats.snapshot();
+        assert_eq!((load.connections, load.in_flight), (1, 3));
+        drop(requests);
+        drop(conn);
+        let load = stats.snapshot();
+        assert_eq!((load.connections, load.in_flight), (0, 0));
+    }
+
+    #[test]
+    fn overload_engages_past_a_threshold_and_disengages_below_it() {
+        let stats = LoadStats::new();
+        let in_flight = cfg(4, 0);
+        let held: Vec<RequestGuard> = (0..3).map(|_| stats.request()).collect();
+        // The next request would be the fourth
+        assert_eq!(stats.pressure(&in_flight), 1.0);
+        assert!(!stats.overloaded(&in_flight));
+        let more = stats.request();
+        assert!(stats.overloaded(&in_flight));
+        drop(more);
+        drop(held);
+        assert!(!stats.overloaded(&in_flight));
+
+        let conns: Vec<ConnectionGuard> = (0..3).map(|_| stats.connection()).collect();
+        assert!(stats.overloaded(&cfg(0, 2)));
+        drop(conns);
+        assert_eq!(stats.pressure(&cfg(0, 2)), 0.0);
+    }
+
+    #[test]
+    fn discarded_requests_are_not_sampled() {
+        let stats = LoadStats::new();
+        let mut shed = stats.request();
+        shed.discard();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        drop(shed);
+        assert_eq!(stats.snapshot().latency_ms, 0.0);
+
+        let served = stats.request();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        drop(served);
+        assert!(stats.snapshot().latency_ms >= 4.0);
+    }
+}
@@ -1,24 +1,54 @@
 use anyhow::Result;
 use dashmap::DashMap;
 use heed::types::Bytes;
-use heed::{Env, EnvOpenOptions};
+use heed::{Env, EnvFlags, EnvOpenOptions, RwTxn};
 use serde::{de::DeserializeOwned, Serialize};
-use std::collections::HashMap;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::Bound;
 use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde_json::Value;
 use regex::Regex;
+use tracing::warn;
+
+/// First byte of a versioned record. MessagePack never uses it, so records
+/// written before versioning still read as schema 0.
+const SCHEMA_TAG: u8 = 0xc1;
+
+/// Layout of the records written now: a tag, this version and the value as
+/// MessagePack with structs keyed by field name, so fields can be added or
+/// dropped. Schema 0 records are untagged with structs as arrays.
+pub const SCHEMA_VERSION: u8 = 1;
+
+/// Rewrites a record of one database that no longer decodes, given its
+/// schema version and its value as JSON
+pub type Migration = Box<dyn Fn(u8, Value) -> Result<Value> + Send + Sync>;
+
+/// Page of a prefix scan, in key order
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<(String, T)>,
+    /// Key to scan after for the next page, None on the last page
+    pub next: Option<String>,
+}
 
 pub struct Memory {
     kind: MemoryKind,
+    migrations: DashMap<String, Migration>,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 enum MemoryKind {
     LMDB {
         env: Env,
         dbs: DashMap<String, heed::Database<Bytes, Bytes>>,
+        read_only: bool,
     },
     Memory {
         map: DashMap<String, Vec<u8>>,
+        // Held by the running transaction
+        tx_lock: Mutex<()>,
     },
 }
 
@@ -36,16 +66,102 @@ impl Memory {
             kind: MemoryKind::LMDB {
                 env,
                 dbs: DashMap::new(),
+                read_only: false,
+            },
+            migrations: DashMap::new(),
+        })
+    }
+
+    /// Open an existing LMDB storage read-only, for replicas sharing the
+    /// files of a writer. Reads work as usual, every write fails.
+    pub fn open_lmdb_ro<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .max_dbs(128)
+                .map_size(1024 * 1024 * 1024 * 128)
+                .flags(EnvFlags::READ_ONLY)
+                .open(path)?
+        };
+        Ok(Self {
+            kind: MemoryKind::LMDB {
+                env,
+                dbs: DashMap::new(),
+                read_only: true,
             },
+            migrations: DashMap::new(),
         })
     }
 
     /// Open in-memory storage
+    #[allow(clippy::self_named_constructors)]
     pub fn memory() -> Self {
         Self {
             kind: MemoryKind::Memory {
                 map: DashMap::new(),
+                tx_lock: Mutex::new(()),
             },
+            migrations: DashMap::new(),
+        }
+    }
+
+    /// Whether the store is LMDB backed, its transactions block the caller
+    pub fn is_lmdb(&self) -> bool {
+        matches!(self.kind, MemoryKind::LMDB { .. })
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.kind, MemoryKind::LMDB { read_only: true, .. })
+    }
+
+    fn writable(&self) -> Result<()> {
+        if self.is_read_only() {
+            anyhow::bail!("read-only store");
+        }
+        Ok(())
+    }
+
+    /// Migrate the records of `db` that no longer decode into the type they
+    /// are read as. A record still failing, or failing without a migration,
+    /// is skipped with a warning.
+    pub fn set_migration(&self, db: &str, migration: impl Fn(u8, Value) -> Result<Value> + Send + Sync + 'static) {
+        self.migrations.insert(db.to_string(), Box::new(migration));
+    }
+
+    fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        let mut bytes = vec![SCHEMA_TAG, SCHEMA_VERSION];
+        rmp_serde::encode::write_named(&mut bytes, value)?;
+        Ok(bytes)
+    }
+
+    fn payload(bytes: &[u8]) -> (u8, &[u8]) {
+        match bytes {
+            [SCHEMA_TAG, version, payload @ ..] => (*version, payload),
+            _ => (0, bytes),
+        }
+    }
+
+    /// Decode a record, through its migration when it no longer fits `T`.
+    /// None, with a warning, when it still does not.
+    fn decode<T: DeserializeOwned>(&self, db: &str, key: &str, bytes: &[u8]) -> Option<T> {
+        let (version, payload) = Self::payload(bytes);
+        let err = match rmp_serde::from_slice(payload) {
+            Ok(value) => return Some(value),
+            Err(e) => anyhow::Error::from(e),
+        };
+        let migrated = self.migrations.get(db).map(|migrate| {
+            let value = rmp_serde::from_slice::<Value>(payload)?;
+            Ok::<_, anyhow::Error>(serde_json::from_value(migrate(version, value)?)?)
+        });
+        match migrated {
+            Some(Ok(value)) => Some(value),
+            Some(Err(e)) => {
+                warn!("skipping record {}/{} (schema {}): migration failed: {e}", db, key, version);
+                None
+            }
+            None => {
+                warn!("skipping record {}/{} (schema {}): {err}", db, key, version);
+                None
+            }
         }
     }
 
@@ -70,6 +186,28 @@ impl Memory {
         }
     }
 
+    /// Database for reading, None when a read-only store does not have it
+    fn read_db(
+        env: &Env,
+        dbs: &DashMap<String, heed::Database<Bytes, Bytes>>,
+        db_name: &str,
+        read_only: bool,
+    ) -> Result<Option<heed::Database<Bytes, Bytes>>> {
+        if !read_only {
+            return Self::get_db(env, dbs, db_name).map(Some);
+        }
+        if let Some(dbi) = dbs.get(db_name) {
+            return Ok(Some(*dbi));
+        }
+        let rtxn = env.read_txn()?;
+        let dbi = env.open_database::<Bytes, Bytes>(&rtxn, Some(db_name))?;
+        rtxn.commit()?;
+        if let Some(dbi) = dbi {
+            dbs.insert(db_name.to_string(), dbi);
+        }
+        Ok(dbi)
+    }
+
     /// Add new record (fails if exists)
     pub fn add<T: Serialize>(&self, db: &str, key: &str, value: &T) -> Result<()> {
         if self.exists(db, key)? {
@@ -80,16 +218,17 @@ impl Memory {
 
     /// Insert or update (upsert)
     pub fn put<T: Serialize>(&self, db: &str, key: &str, value: &T) -> Result<()> {
-        let bytes = rmp_serde::to_vec(value)?;
+        self.writable()?;
+        let bytes = Self::encode(value)?;
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
+            MemoryKind::LMDB { env, dbs, .. } => {
                 let dbi = Self::get_db(env, dbs, db)?;
                 let mut wtxn = env.write_txn()?;
                 dbi.put(&mut wtxn, key.as_bytes(), &bytes)?;
                 wtxn.commit()?;
                 Ok(())
             }
-            MemoryKind::Memory { map } => {
+            MemoryKind::Memory { map, .. } => {
                 map.insert(Self::make_key(db, key), bytes);
                 Ok(())
             }
@@ -104,46 +243,43 @@ impl Memory {
         self.put(db, key, value)
     }
 
-    /// Get by key
+    /// Get by key, None as well when the record does not decode
     pub fn get<T: DeserializeOwned>(&self, db: &str, key: &str) -> Result<Option<T>> {
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
-                let dbi = Self::get_db(env, dbs, db)?;
+            MemoryKind::LMDB { env, dbs, read_only } => {
+                let Some(dbi) = Self::read_db(env, dbs, db, *read_only)? else {
+                    return Ok(None);
+                };
                 let rtxn = env.read_txn()?;
-                if let Some(bytes) = dbi.get(&rtxn, key.as_bytes())? {
-                    Ok(Some(rmp_serde::from_slice(bytes)?))
-                } else {
-                    Ok(None)
-                }
+                Ok(dbi.get(&rtxn, key.as_bytes())?.and_then(|bytes| self.decode(db, key, bytes)))
             }
-            MemoryKind::Memory { map } => {
-                Ok(map
-                    .get(&Self::make_key(db, key))
-                    .map(|v| rmp_serde::from_slice(&v).unwrap()))
+            MemoryKind::Memory { map, .. } => {
+                Ok(map.get(&Self::make_key(db, key)).and_then(|v| self.decode(db, key, &v)))
             }
         }
     }
 
     /// Delete by key
     pub fn delete(&self, db: &str, key: &str) -> Result<()> {
+        self.writable()?;
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
+            MemoryKind::LMDB { env, dbs, .. } => {
                 let dbi = Self::get_db(env, dbs, db)?;
                 let mut wtxn = env.write_txn()?;
                 dbi.delete(&mut wtxn, key.as_bytes())?;
                 wtxn.commit()?;
                 Ok(())
             }
-            MemoryKind::Memory { map } => {
+            MemoryKind::Memory { map, .. } => {
                 map.remove(&Self::make_key(db, key));
                 Ok(())
             }
         }
     }
 
-    /// Check if key exists
+    /// Check if key exists, whether or not its record decodes
     pub fn exists(&self, db: &str, key: &str) -> Result<bool> {
-        Ok(self.get::<serde_json::Value>(db, key)?.is_some())
+        Ok(self.get_raw(db, key)?.is_some())
     }
 
     /// Insert multiple key/value pairs at once
@@ -152,20 +288,21 @@ impl Memory {
         T: Serialize,
         I: IntoIterator<Item = (String, T)>,
     {
+        self.writable()?;
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
+            MemoryKind::LMDB { env, dbs, .. } => {
                 let dbi = Self::get_db(env, dbs, db)?;
                 let mut wtxn = env.write_txn()?;
                 for (key, value) in entries {
-                    let bytes = rmp_serde::to_vec(&value)?;
+                    let bytes = Self::encode(&value)?;
                     dbi.put(&mut wtxn, key.as_bytes(), &bytes)?;
                 }
                 wtxn.commit()?;
                 Ok(())
             }
-            MemoryKind::Memory { map } => {
+            MemoryKind::Memory { map, .. } => {
                 for (key, value) in entries {
-                    let bytes = rmp_serde::to_vec(&value)?;
+                    let bytes = Self::encode(&value)?;
                     map.insert(Self::make_key(db, &key), bytes);
                 }
                 Ok(())
@@ -179,7 +316,7 @@ impl Memory {
         I: IntoIterator<Item = (String, T)>,
         T: Serialize,
     {
-        self.insert_many(db, map_in.into_iter())
+        self.insert_many(db, map_in)
     }
 
     /// Batch insert (optimized)
@@ -201,15 +338,97 @@ impl Memory {
         self.put(db, key, &Value::Object(obj))
     }
 
-    /// Get raw bytes
-    pub fn get_raw(&self, db: &str, key: &str) -> Result<Option<Vec<u8>>> {
+    /// Add `delta` to a counter and return its new value, a missing record
+    /// counts from 0. Concurrent increments of one counter are not lost.
+    pub fn incr(&self, db: &str, key: &str, delta: i64) -> Result<i64> {
+        self.modify(db, key, |count: Option<i64>| count.unwrap_or(0).saturating_add(delta))
+    }
+
+    /// `incr` of a counter that expires `ttl` after its first increment and
+    /// then counts from 0 again, as fixed window rate limits need. The record
+    /// holds the count and its expiry: read it back with a 0 delta.
+    pub fn incr_with_ttl(&self, db: &str, key: &str, delta: i64, ttl: Duration) -> Result<i64> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let (count, _) = self.modify(db, key, |counter: Option<(i64, u64)>| match counter {
+            Some((count, expires_at)) if expires_at > now => (count.saturating_add(delta), expires_at),
+            _ => (delta, now.saturating_add(ttl.as_millis() as u64)),
+        })?;
+        Ok(count)
+    }
+
+    /// Run `f` as one transaction over any number of records and databases,
+    /// committed when it returns Ok and rolled back when it returns Err.
+    ///
+    /// Transactions run one at a time. On LMDB a transaction is serializable:
+    /// it reads its own writes, readers see the store as it was before or
+    /// after it, never in between, and the transaction waits for, and
+    /// blocks, every other write. Write through `tx` only inside `f`, a
+    /// store write there waits for the transaction to end.
+    ///
+    /// The in-memory store buffers the writes and applies them on commit,
+    /// transactions see each other's writes whole, but reads and writes
+    /// outside a transaction are not held back and can see a commit
+    /// half applied.
+    pub fn transaction<R>(&self, f: impl FnOnce(&mut Transaction<'_>) -> Result<R>) -> Result<R> {
+        self.writable()?;
+        let state = match &self.kind {
+            MemoryKind::LMDB { env, dbs, .. } => TxState::LMDB {
+                env,
+                dbs,
+                wtxn: env.write_txn()?,
+                created: HashMap::new(),
+            },
+            MemoryKind::Memory { map, tx_lock } => TxState::Memory {
+                map,
+                _guard: tx_lock.lock().unwrap_or_else(|e| e.into_inner()),
+                writes: HashMap::new(),
+            },
+        };
+        let mut tx = Transaction { memory: self, state };
+        let result = f(&mut tx)?;
+        tx.commit()?;
+        Ok(result)
+    }
+
+    /// Replace a record by `f` of its current value, None when missing or
+    /// not decoding, in one write transaction or under the map entry lock
+    fn modify<T, F>(&self, db: &str, key: &str, f: F) -> Result<T>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce(Option<T>) -> T,
+    {
+        self.writable()?;
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
+            MemoryKind::LMDB { env, dbs, .. } => {
                 let dbi = Self::get_db(env, dbs, db)?;
+                let mut wtxn = env.write_txn()?;
+                let current = dbi.get(&wtxn, key.as_bytes())?.and_then(|bytes| self.decode(db, key, bytes));
+                let value = f(current);
+                dbi.put(&mut wtxn, key.as_bytes(), &Self::encode(&value)?)?;
+                wtxn.commit()?;
+                Ok(value)
+            }
+            MemoryKind::Memory { map, .. } => {
+                let mut entry = map.entry(Self::make_key(db, key)).or_default();
+                let current = if entry.is_empty() { None } else { self.decode(db, key, &entry) };
+                let value = f(current);
+                *entry = Self::encode(&value)?;
+                Ok(value)
+            }
+        }
+    }
+
+    /// Get the MessagePack bytes of a record, without the schema tag
+    pub fn get_raw(&self, db: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        match &self.kind {
+            MemoryKind::LMDB { env, dbs, read_only } => {
+                let Some(dbi) = Self::read_db(env, dbs, db, *read_only)? else {
+                    return Ok(None);
+                };
                 let rtxn = env.read_txn()?;
-                Ok(dbi.get(&rtxn, key.as_bytes())?.map(|b| b.to_vec()))
+                Ok(dbi.get(&rtxn, key.as_bytes())?.map(|b| Self::payload(b).1.to_vec()))
             }
-            MemoryKind::Memory { map } => Ok(map.get(&Self::make_key(db, key)).map(|v| v.clone())),
+            MemoryKind::Memory { map, .. } => Ok(map.get(&Self::make_key(db, key)).map(|v| Self::payload(&v).1.to_vec())),
         }
     }
 
@@ -220,8 +439,9 @@ impl Memory {
 
     /// Bulk delete by keys
     pub fn delete_many(&self, db: &str, keys: &[String]) -> Result<()> {
+        self.writable()?;
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
+            MemoryKind::LMDB { env, dbs, .. } => {
                 let dbi = Self::get_db(env, dbs, db)?;
                 let mut wtxn = env.write_txn()?;
                 for key in keys {
@@ -230,7 +450,7 @@ impl Memory {
                 wtxn.commit()?;
                 Ok(())
             }
-            MemoryKind::Memory { map } => {
+            MemoryKind::Memory { map, .. } => {
                 for key in keys {
                     map.remove(&Self::make_key(db, key));
                 }
@@ -248,24 +468,26 @@ impl Memory {
         Ok(self.all(db)?.into_iter().filter(|x| pred(x)).collect())
     }
 
-    /// Get all records
+    /// Get all records, those that do not decode are skipped
     pub fn all<T: DeserializeOwned>(&self, db: &str) -> Result<Vec<T>> {
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
-                let dbi = Self::get_db(env, dbs, db)?;
+            MemoryKind::LMDB { env, dbs, read_only } => {
+                let Some(dbi) = Self::read_db(env, dbs, db, *read_only)? else {
+                    return Ok(Vec::new());
+                };
                 let rtxn = env.read_txn()?;
                 let mut result = Vec::new();
                 for item in dbi.iter(&rtxn)? {
-                    let (_k, v) = item?;
-                    result.push(rmp_serde::from_slice(v)?);
+                    let (k, v) = item?;
+                    result.extend(self.decode(db, &String::from_utf8_lossy(k), v));
                 }
                 Ok(result)
             }
-            MemoryKind::Memory { map } => {
+            MemoryKind::Memory { map, .. } => {
                 let mut result = Vec::new();
                 for v in map.iter() {
-                    if v.key().starts_with(&format!("{}/", db)) {
-                        result.push(rmp_serde::from_slice(&v.value())?);
+                    if let Some(key) = v.key().strip_prefix(&format!("{}/", db)) {
+                        result.extend(self.decode(db, key, v.value()));
                     }
                 }
                 Ok(result)
@@ -273,6 +495,85 @@ impl Memory {
         }
     }
 
+    /// Records of `db` whose key starts with `prefix`, in key byte order: at
+    /// most `limit` of them (0 for no limit), from the first key after
+    /// `after` when set. Records that do not decode are skipped but still
+    /// count toward the limit. LMDB walks its cursor from the first key of the
+    /// page, the in-memory store makes one pass over its keys keeping only the
+    /// page ones.
+    pub fn scan_prefix<T: DeserializeOwned>(
+        &self,
+        db: &str,
+        prefix: &str,
+        limit: usize,
+        after: Option<&str>,
+    ) -> Result<Page<T>> {
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        // A cursor before the prefix range, or empty, starts at the range
+        let after = after.filter(|after| !after.is_empty() && *after >= prefix);
+        match &self.kind {
+            MemoryKind::LMDB { env, dbs, read_only } => {
+                let Some(dbi) = Self::read_db(env, dbs, db, *read_only)? else {
+                    return Ok(Page { items: Vec::new(), next: None });
+                };
+                let rtxn = env.read_txn()?;
+                let start = match after {
+                    Some(after) => Bound::Excluded(after.as_bytes()),
+                    // LMDB keys are never empty
+                    None if prefix.is_empty() => Bound::Unbounded,
+                    None => Bound::Included(prefix.as_bytes()),
+                };
+                let (mut items, mut scanned, mut last) = (Vec::new(), 0, None);
+                for item in dbi.range(&rtxn, &(start, Bound::Unbounded))? {
+                    let (k, v) = item?;
+                    if !k.starts_with(prefix.as_bytes()) {
+                        break;
+                    }
+                    if scanned == limit {
+                        return Ok(Page { items, next: last });
+                    }
+                    scanned += 1;
+                    let key = String::from_utf8_lossy(k).into_owned();
+                    items.extend(self.decode(db, &key, v).map(|value| (key.clone(), value)));
+                    last = Some(key);
+                }
+                Ok(Page { items, next: None })
+            }
+            MemoryKind::Memory { map, .. } => {
+                let range = Self::make_key(db, prefix);
+                // The smallest keys past the cursor, one more than the page
+                // tells whether a next page exists
+                let mut keys = BinaryHeap::new();
+                for entry in map.iter() {
+                    let Some(key) = entry.key().starts_with(&range).then(|| &entry.key()[db.len() + 1..]) else {
+                        continue;
+                    };
+                    if after.is_some_and(|after| key <= after) {
+                        continue;
+                    }
+                    keys.push(key.to_string());
+                    if keys.len() > limit.saturating_add(1) {
+                        keys.pop();
+                    }
+                }
+                let mut keys = keys.into_sorted_vec();
+                let mut next = None;
+                if keys.len() > limit {
+                    keys.truncate(limit);
+                    next = keys.last().cloned();
+                }
+                let items = keys
+                    .into_iter()
+                    .filter_map(|key| {
+                        let value = self.decode(db, &key, &map.get(&Self::make_key(db, &key))?)?;
+                        Some((key, value))
+                    })
+                    .collect();
+                Ok(Page { items, next })
+            }
+        }
+    }
+
     /// Filter JSON values
     pub fn filter_json<F>(&self, db: &str, mut pred: F) -> Result<Vec<Value>>
     where
@@ -301,24 +602,30 @@ impl Memory {
         Ok(map_out)
     }
 
-    /// Get all as HashMap
+    /// Get all as HashMap, records that do not decode are left out
     pub fn all_map<T: DeserializeOwned>(&self, db: &str) -> Result<HashMap<String, T>> {
         let mut map_out = HashMap::new();
         match &self.kind {
-            MemoryKind::LMDB { env, dbs } => {
-                let dbi = Self::get_db(env, dbs, db)?;
+            MemoryKind::LMDB { env, dbs, read_only } => {
+                let Some(dbi) = Self::read_db(env, dbs, db, *read_only)? else {
+                    return Ok(map_out);
+                };
                 let rtxn = env.read_txn()?;
                 for item in dbi.iter(&rtxn)? {
                     let (k, v) = item?;
                     let key = String::from_utf8_lossy(k).to_string();
-                    map_out.insert(key, rmp_serde::from_slice(v)?);
+                    if let Some(value) = self.decode(db, &key, v) {
+                        map_out.insert(key, value);
+                    }
                 }
             }
-            MemoryKind::Memory { map } => {
+            MemoryKind::Memory { map, .. } => {
                 for v in map.iter() {
                     if v.key().starts_with(&format!("{}/", db)) {
                         let key = v.key().replacen(&format!("{}/", db), "", 1);
-                        map_out.insert(key, rmp_serde::from_slice(&v.value())?);
+                        if let Some(value) = self.decode(db, &key, v.value()) {
+                            map_out.insert(key, value);
+                        }
                     }
                 }
             }
@@ -331,8 +638,7 @@ impl Memory {
         if pattern.contains('*') {
             let regex_str = format!("^{}$", regex::escape(pattern).replace("\\*", ".*"));
             Regex::new(&regex_str).map(|r| r.is_match(candidate)).unwrap_or(false)
-        } else if pattern.starts_with("regex:") {
-            let expr = &pattern["regex:".len()..];
+        } else if let Some(expr) = pattern.strip_prefix("regex:") {
             Regex::new(expr).map(|r| r.is_match(candidate)).unwrap_or(false)
         } else if pattern.contains(candidate) || candidate.contains(pattern) {
             true
@@ -370,12 +676,288 @@ impl Memory {
                 }
             }
             // Longest prefix
-            if let Some(best) = Self::longest_prefix_match(&pattern, all_keys.keys().map(|s| s.as_str())) {
-                if let Some(val) = all_keys.get(&best) {
-                    result.insert(best.clone(), val.clone());
-                }
+            if let Some(best) = Self::longest_prefix_match(&pattern, all_keys.keys().map(|s| s.as_str()))
+                && let Some(val) = all_keys.get(&best)
+            {
+                result.insert(best.clone(), val.clone());
             }
         }
         Ok(result)
     }
-}
\ No newline at end of file
+}
+
+/// Records read and written within `Memory::transaction`
+pub struct Transaction<'a> {
+    memory: &'a Memory,
+    state: TxState<'a>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+enum TxState<'a> {
+    LMDB {
+        env: &'a Env,
+        dbs: &'a DashMap<String, heed::Database<Bytes, Bytes>>,
+        wtxn: RwTxn<'a>,
+        // Databases created in the transaction, known to the store once it
+        // commits
+        created: HashMap<String, heed::Database<Bytes, Bytes>>,
+    },
+    Memory {
+        map: &'a DashMap<String, Vec<u8>>,
+        _guard: MutexGuard<'a, ()>,
+        // Records written, None when deleted
+        writes: HashMap<String, Option<Vec<u8>>>,
+    },
+}
+
+impl Transaction<'_> {
+    /// Get by key, writes of the transaction included
+    pub fn get<T: DeserializeOwned>(&mut self, db: &str, key: &str) -> Result<Option<T>> {
+        let memory = self.memory;
+        self.with_raw(db, key, |bytes| bytes.and_then(|b| memory.decode(db, key, b)))
+    }
+
+    /// Check if key exists, writes of the transaction included
+    pub fn exists(&mut self, db: &str, key: &str) -> Result<bool> {
+        self.with_raw(db, key, |bytes| bytes.is_some())
+    }
+
+    /// Insert or update (upsert)
+    pub fn put<T: Serialize>(&mut self, db: &str, key: &str, value: &T) -> Result<()> {
+        let bytes = Memory::encode(value)?;
+        match &mut self.state {
+            TxState::LMDB { env, dbs, wtxn, created } => {
+                let dbi = tx_db(env, dbs, wtxn, created, db)?;
+                dbi.put(wtxn, key.as_bytes(), &bytes)?;
+            }
+            TxState::Memory { writes, .. } => {
+                writes.insert(Memory::make_key(db, key), Some(bytes));
+            }
+        }
+        Ok(())
+    }
+
+    /// Delete by key
+    pub fn delete(&mut self, db: &str, key: &str) -> Result<()> {
+        match &mut self.state {
+            TxState::LMDB { env, dbs, wtxn, created } => {
+                let dbi = tx_db(env, dbs, wtxn, created, db)?;
+                dbi.delete(wtxn, key.as_bytes())?;
+            }
+            TxState::Memory { writes, .. } => {
+                writes.insert(Memory::make_key(db, key), None);
+            }
+        }
+        Ok(())
+    }
+
+    // `f` of the stored bytes of a record, None when missing
+    fn with_raw<R>(&mut self, db: &str, key: &str, f: impl FnOnce(Option<&[u8]>) -> R) -> Result<R> {
+        match &mut self.state {
+            TxState::LMDB { env, dbs, wtxn, created } => {
+                let dbi = tx_db(env, dbs, wtxn, created, db)?;
+                Ok(f(dbi.get(wtxn, key.as_bytes())?))
+            }
+            TxState::Memory { map, writes, .. } => {
+                let full = Memory::make_key(db, key);
+                Ok(match writes.get(&full) {
+                    Some(bytes) => f(bytes.as_deref()),
+                    None => f(map.get(&full).as_deref().map(Vec::as_slice)),
+                })
+            }
+        }
+    }
+
+    fn commit(self) -> Result<()> {
+        match self.state {
+            TxState::LMDB { dbs, wtxn, created, .. } => {
+                wtxn.commit()?;
+                for (name, dbi) in created {
+                    dbs.insert(name, dbi);
+                }
+            }
+            TxState::Memory { map, writes, .. } => {
+                for (key, bytes) in writes {
+                    match bytes {
+                        Some(bytes) => map.insert(key, bytes),
+                        None => map.remove(&key).map(|(_, bytes)| bytes),
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Database of a write transaction, created in it when missing
+fn tx_db(
+    env: &Env,
+    dbs: &DashMap<String, heed::Database<Bytes, Bytes>>,
+    wtxn: &mut RwTxn,
+    created: &mut HashMap<String, heed::Database<Bytes, Bytes>>,
+    name: &str,
+) -> Result<heed::Database<Bytes, Bytes>> {
+    if let Some(dbi) = dbs.get(name).map(|d| *d).or_else(|| created.get(name).copied()) {
+        return Ok(dbi);
+    }
+    let dbi = env.create_database::<Bytes, Bytes>(wtxn, Some(name))?;
+    created.insert(name.to_string(), dbi);
+    Ok(dbi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::sync::Arc;
+
+    fn lmdb_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("bullg-memory-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn read_only_stores_read_and_refuse_writes() {
+        let path = lmdb_path();
+        {
+            let writer = Memory::open_lmdb(&path).unwrap();
+            writer.put("users", "alice", &"admin").unwrap();
+            writer.put("users", "bob", &"viewer").unwrap();
+        }
+        let replica = Memory::open_lmdb_ro(&path).unwrap();
+        assert!(replica.is_read_only());
+        assert_eq!(replica.get::<String>("users", "alice").unwrap().as_deref(), Some("admin"));
+        let mut all: Vec<String> = replica.all("users").unwrap();
+        all.sort();
+        assert_eq!(all, ["admin", "viewer"]);
+        // A database the writer never created reads as empty
+        assert_eq!(replica.get::<String>("missing", "x").unwrap(), None);
+        assert!(replica.all::<String>("missing").unwrap().is_empty());
+
+        for err in [
+            replica.put("users", "carol", &"admin").unwrap_err(),
+            replica.update("users", "alice", &"viewer").unwrap_err(),
+            replica.delete("users", "alice").unwrap_err(),
+            replica.incr("counters", "hits", 1).unwrap_err(),
+            replica.transaction(|tx| tx.put("users", "dave", &"admin")).unwrap_err(),
+        ] {
+            assert_eq!(err.to_string(), "read-only store");
+        }
+        assert_eq!(replica.get::<String>("users", "alice").unwrap().as_deref(), Some("admin"));
+        drop(replica);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct UserV1 {
+        name: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct UserV2 {
+        name: String,
+        #[serde(default)]
+        role: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Renamed {
+        full_name: String,
+    }
+
+    fn corrupt(store: &Memory, db: &str, key: &str) {
+        let MemoryKind::Memory { map, .. } = &store.kind else { unreachable!() };
+        map.insert(Memory::make_key(db, key), vec![SCHEMA_TAG, SCHEMA_VERSION, 0xc1, 0xff]);
+    }
+
+    #[test]
+    fn records_written_before_a_field_was_added_still_decode() {
+        let path = lmdb_path();
+        for store in [Memory::memory(), Memory::open_lmdb(&path).unwrap()] {
+            store.put("users", "alice", &UserV1 { name: "alice".into() }).unwrap();
+            let user: Option<UserV2> = store.get("users", "alice").unwrap();
+            assert_eq!(user, Some(UserV2 { name: "alice".into(), role: None }));
+            // And the other way round, unknown fields are ignored
+            store.put("users", "bob", &UserV2 { name: "bob".into(), role: Some("admin".into()) }).unwrap();
+            assert_eq!(store.get::<UserV1>("users", "bob").unwrap(), Some(UserV1 { name: "bob".into() }));
+        }
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn corrupt_records_are_skipped_instead_of_failing_the_read() {
+        let store = Memory::memory();
+        store.put("users", "alice", &UserV1 { name: "alice".into() }).unwrap();
+        corrupt(&store, "users", "broken");
+        assert_eq!(store.get::<UserV1>("users", "broken").unwrap(), None);
+        assert_eq!(store.all::<UserV1>("users").unwrap(), [UserV1 { name: "alice".into() }]);
+    }
+
+    #[test]
+    fn records_not_fitting_the_type_go_through_the_migration() {
+        let store = Memory::memory();
+        store.put("users", "alice", &UserV1 { name: "alice".into() }).unwrap();
+        assert_eq!(store.get::<Renamed>("users", "alice").unwrap(), None);
+
+        let versions = Arc::new(Mutex::new(Vec::new()));
+        let seen = versions.clone();
+        store.set_migration("users", move |version, value| {
+            seen.lock().unwrap().push(version);
+            Ok(serde_json::json!({ "full_name": value["name"] }))
+        });
+        assert_eq!(store.get("users", "alice").unwrap(), Some(Renamed { full_name: "alice".into() }));
+        assert_eq!(*versions.lock().unwrap(), [SCHEMA_VERSION]);
+        // A record the migration cannot read is still skipped
+        corrupt(&store, "users", "broken");
+        assert_eq!(store.get::<Renamed>("users", "broken").unwrap(), None);
+    }
+
+    #[test]
+    fn untagged_records_read_as_schema_0() {
+        let store = Memory::memory();
+        let MemoryKind::Memory { map, .. } = &store.kind else { unreachable!() };
+        map.insert(Memory::make_key("users", "old"), rmp_serde::to_vec(&UserV1 { name: "old".into() }).unwrap());
+        assert_eq!(Memory::payload(&map.get("users/old").unwrap()).0, 0);
+        assert_eq!(store.get("users", "old").unwrap(), Some(UserV1 { name: "old".into() }));
+    }
+
+    #[test]
+    fn concurrent_increments_are_not_lost() {
+        let path = lmdb_path();
+        for store in [Memory::memory(), Memory::open_lmdb(&path).unwrap()] {
+            std::thread::scope(|scope| {
+                for _ in 0..16 {
+                    scope.spawn(|| {
+                        for _ in 0..100 {
+                            store.incr("counters", "hits", 1).unwrap();
+                        }
+                    });
+                }
+            });
+            assert_eq!(store.incr("counters", "hits", 0).unwrap(), 1600);
+            assert_eq!(store.get::<i64>("counters", "hits").unwrap(), Some(1600));
+        }
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn counters_go_both_ways_and_saturate() {
+        let store = Memory::memory();
+        assert_eq!(store.incr("counters", "stock", -3).unwrap(), -3);
+        assert_eq!(store.incr("counters", "stock", 5).unwrap(), 2);
+        assert_eq!(store.incr("counters", "big", i64::MAX).unwrap(), i64::MAX);
+        assert_eq!(store.incr("counters", "big", 1).unwrap(), i64::MAX);
+    }
+
+    #[test]
+    fn expiring_counters_restart_after_their_ttl() {
+        let store = Memory::memory();
+        let ttl = Duration::from_millis(100);
+        assert_eq!(store.incr_with_ttl("windows", "alice", 1, ttl).unwrap(), 1);
+        assert_eq!(store.incr_with_ttl("windows", "alice", 2, ttl).unwrap(), 3);
+        assert_eq!(store.incr_with_ttl("windows", "alice", 0, ttl).unwrap(), 3);
+        assert_eq!(store.incr_with_ttl("windows", "bob", 1, ttl).unwrap(), 1);
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(store.incr_with_ttl("windows", "alice", 0, ttl).unwrap(), 0);
+        assert_eq!(store.incr_with_ttl("windows", "alice", 1, ttl).unwrap(), 1);
+    }
+}
@@ -1,7 +1,11 @@
 pub mod memory;
+pub mod async_memory;
 pub mod cache;
+pub mod load;
 pub mod runner;
 
 pub use memory::*;
+pub use async_memory::*;
 pub use cache::*;
+pub use load::*;
 pub use runner::*;
\ No newline at end of file
@@ -1,19 +1,51 @@
 use anyhow::{Result, anyhow};
+#[cfg(any(feature = "runner-python", feature = "runner-rhai"))]
 use dashmap::DashMap;
+#[cfg(any(feature = "runner-python", feature = "runner-rhai"))]
 use fxhash::FxHasher64;
-use serde_json::{Value, json};
-use std::ffi::CString;
-use std::{collections::HashMap, hash::Hasher, sync::Arc, thread, time::Duration};
+use serde_json::Value;
+
+use crate::{BullGTools, RunnerLimitsCfg};
+#[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+use http::Method;
+#[cfg(feature = "runner-rhai")]
+use std::cell::Cell;
+#[cfg(feature = "runner-js")]
+use std::cell::RefCell;
+#[cfg(any(feature = "runner-python", feature = "runner-rhai"))]
+use std::hash::Hasher;
+#[cfg(feature = "runner-js")]
+use std::rc::Rc;
+#[cfg(feature = "runner-js")]
+use std::sync::{Mutex, mpsc};
+#[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+use std::sync::OnceLock;
+#[cfg(any(feature = "runner-js", feature = "runner-python"))]
+use std::thread;
+#[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+use std::time::Instant;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 // JS engine
-use boa_engine::{Context as BoaContext, Source as BoaSource};
+#[cfg(feature = "runner-js")]
+use boa_engine::{
+    Context as BoaContext, JsNativeError, JsString, JsValue, NativeFunction, Script as BoaScript,
+    Source as BoaSource, js_string, object::ObjectInitializer, property::Attribute,
+};
 
 // Python
-use pyo3::{prelude::*, types::PyDict};
+#[cfg(feature = "runner-python")]
+use pyo3::{exceptions::PyRuntimeError, prelude::*, types::PyDict};
 
 // Rhai
+#[cfg(feature = "runner-rhai")]
 use rhai::{AST as RhaiAST, Dynamic as RhaiDynamic, Engine as RhaiEngine, Scope as RhaiScope};
 
+#[cfg(feature = "runner-rhai")]
+thread_local! {
+    static RHAI_DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Lang {
     Python,
@@ -21,6 +53,40 @@ pub enum Lang {
     RustLite,
 }
 
+impl Lang {
+    /// Cargo feature that compiles this language in
+    pub fn feature(&self) -> &'static str {
+        match self {
+            Lang::Python => "runner-python",
+            Lang::JavaScript => "runner-js",
+            Lang::RustLite => "runner-rhai",
+        }
+    }
+
+    /// Whether this build can run the language
+    pub fn enabled(&self) -> bool {
+        match self {
+            Lang::Python => cfg!(feature = "runner-python"),
+            Lang::JavaScript => cfg!(feature = "runner-js"),
+            Lang::RustLite => cfg!(feature = "runner-rhai"),
+        }
+    }
+}
+
+impl std::str::FromStr for Lang {
+    type Err = anyhow::Error;
+
+    /// Language of a plugin handler, as written in the catalog
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "python" | "py" => Ok(Lang::Python),
+            "javascript" | "js" => Ok(Lang::JavaScript),
+            "rhai" | "rustlite" => Ok(Lang::RustLite),
+            _ => Err(anyhow!("unknown script language: {}", s)),
+        }
+    }
+}
+
 pub type Args = HashMap<String, Value>;
 
 #[derive(Debug, Clone)]
@@ -30,6 +96,9 @@ pub struct RunnerLimits {
     pub max_args_bytes: usize,
     pub rhai_max_ops: u64,
     pub rhai_max_call_depth: usize,
+    /// Whether scripts may make HTTP calls through `tools`, off by default so
+    /// untrusted code cannot reach the network
+    pub allow_network: bool,
 }
 
 impl Default for RunnerLimits {
@@ -40,123 +109,305 @@ impl Default for RunnerLimits {
             max_args_bytes: 64 * 1024,
             rhai_max_ops: 200_000,
             rhai_max_call_depth: 64,
+            allow_network: false,
         }
     }
 }
 
+impl RunnerLimits {
+    /// Limits with the configured values applied over these
+    pub fn with_overrides(&self, cfg: &RunnerLimitsCfg) -> Self {
+        Self {
+            max_time: cfg.max_time.unwrap_or(self.max_time),
+            max_code_bytes: cfg.max_code_bytes.unwrap_or(self.max_code_bytes),
+            max_args_bytes: cfg.max_args_bytes.unwrap_or(self.max_args_bytes),
+            rhai_max_ops: cfg.rhai_max_ops.unwrap_or(self.rhai_max_ops),
+            rhai_max_call_depth: cfg.rhai_max_call_depth.unwrap_or(self.rhai_max_call_depth),
+            allow_network: cfg.allow_network.unwrap_or(self.allow_network),
+        }
+    }
+}
+
+#[cfg(any(feature = "runner-python", feature = "runner-rhai"))]
 #[derive(Clone)]
 enum Compiled {
+    #[cfg(feature = "runner-rhai")]
     RhaiAST(RhaiAST),
+    // Code object of `compile(code, "<script>", "exec")`
+    #[cfg(feature = "runner-python")]
+    Python(Arc<Py<PyAny>>),
 }
 
+/// Runs scripts with `args`, or calls their handler, in Rhai, JS and Python.
+///
+/// Scripts get a `tools` binding making HTTP calls through the client of
+/// `BullGTools`: `tools.http_get(url)` and `tools.http_post(url, body)` in
+/// JS and Python, `tools::http_get(url)` and `tools::http_post(url, body)`
+/// in Rhai. Each returns the response body as text and fails on network
+/// errors, when `allow_network` is off, and once `max_time` since the start
+/// of the execution has passed.
+///
+/// Compiled scripts are cached by language and hash of the code. Rhai ASTs
+/// and Python code objects are shared by every thread of the runner. Boa
+/// values cannot leave the thread of their context, so JS runs on a pool of
+/// worker threads. Every JS execution gets a fresh context, nothing a script
+/// sets is seen by the next one, which each worker builds ahead once idle.
+/// JS stops at its `max_time`, checked every `JS_BUDGET` instructions.
 #[derive(Clone)]
 pub struct Runner {
     limits: RunnerLimits,
+    #[cfg_attr(not(any(feature = "runner-js", feature = "runner-python")), allow(dead_code))]
+    tools: Arc<BullGTools>,
+    #[cfg(feature = "runner-rhai")]
     rhai: Arc<RhaiEngine>,
+    #[cfg(any(feature = "runner-python", feature = "runner-rhai"))]
     cache: Arc<DashMap<(Lang, u64), Compiled>>,
 }
 
 impl Runner {
     pub fn new_with_limits(limits: RunnerLimits) -> Self {
+        Self::new_with_tools(limits, Arc::new(BullGTools::new()))
+    }
+
+    /// Runner whose scripts make their HTTP calls through `tools`
+    pub fn new_with_tools(limits: RunnerLimits, tools: Arc<BullGTools>) -> Self {
+        #[cfg(feature = "runner-python")]
+        pyo3::prepare_freethreaded_python();
+        #[cfg(feature = "runner-rhai")]
         let mut engine = RhaiEngine::new();
-        engine.set_max_operations(limits.rhai_max_ops);
-        engine.set_max_call_levels(limits.rhai_max_call_depth);
-        engine.on_progress(|_| None);
+        #[cfg(feature = "runner-rhai")]
+        {
+            engine.set_max_operations(limits.rhai_max_ops);
+            engine.set_max_call_levels(limits.rhai_max_call_depth);
+            // max_time for Rhai, the deadline is set per evaluation in run_rustlite
+            engine.on_progress(|_| {
+                let expired = RHAI_DEADLINE.with(|d| d.get()).is_some_and(|at| Instant::now() > at);
+                expired.then(|| RhaiDynamic::from("timeout"))
+            });
+            // Rhai functions do not see the scope, `tools` is a module
+            let net = ScriptNet { tools: tools.clone(), allow: limits.allow_network };
+            let deadline = || RHAI_DEADLINE.with(|d| d.get()).unwrap_or_else(Instant::now);
+            let mut module = rhai::Module::new();
+            let get = net.clone();
+            module.set_native_fn("http_get", move |url: &str| {
+                rhai_result(get.call(Method::GET, url, None, deadline()))
+            });
+            let post = net;
+            module.set_native_fn("http_post", move |url: &str, body: &str| {
+                rhai_result(post.call(Method::POST, url, Some(body.to_string()), deadline()))
+            });
+            engine.register_static_module("tools", module.into());
+        }
 
         Self {
             limits,
+            tools,
+            #[cfg(feature = "runner-rhai")]
             rhai: Arc::new(engine),
+            #[cfg(any(feature = "runner-python", feature = "runner-rhai"))]
             cache: Arc::new(DashMap::new()),
         }
     }
 
     pub fn new() -> Self {
-        pyo3::prepare_freethreaded_python();
         Self::new_with_limits(RunnerLimits::default())
     }
 
-    pub fn run(&mut self, lang: Lang, code: &str, args: &Args) -> Result<Value> {
-        if code.as_bytes().len() > self.limits.max_code_bytes {
+    #[cfg(any(feature = "runner-js", feature = "runner-python"))]
+    fn net(&self) -> ScriptNet {
+        ScriptNet { tools: self.tools.clone(), allow: self.limits.allow_network }
+    }
+
+    pub fn limits(&self) -> &RunnerLimits {
+        &self.limits
+    }
+
+    fn check_limits(&self, code: &str, args: &impl serde::Serialize) -> Result<()> {
+        if code.len() > self.limits.max_code_bytes {
             return Err(anyhow!("code too large"));
         }
         let args_json = serde_json::to_vec(args)?;
         if args_json.len() > self.limits.max_args_bytes {
             return Err(anyhow!("args too large"));
         }
+        Ok(())
+    }
+
+    /// Run `code` with `args` in scope and return its `result` variable, else
+    /// the value of its last expression in Rhai and JS, else `Value::Null`.
+    pub fn run(&mut self, lang: Lang, code: &str, args: &Args) -> Result<Value> {
+        self.check_limits(code, args)?;
 
         match lang {
+            #[cfg(feature = "runner-rhai")]
             Lang::RustLite => self.run_rustlite(code, args),
+            #[cfg(feature = "runner-js")]
             Lang::JavaScript => self.run_js_threaded(code.to_owned(), args.clone()),
+            #[cfg(feature = "runner-python")]
             Lang::Python => self.run_py_threaded(code.to_owned(), args.clone()),
+            #[allow(unreachable_patterns)]
+            other => Err(anyhow!(
+                "language {:?} is not enabled in this build, rebuild with the `{}` feature",
+                other,
+                other.feature()
+            )),
+        }
+    }
+
+    /// Compile `code`, call its function named `handler` with `args` as the
+    /// only argument and return what the function returns.
+    ///
+    /// The same contract in every language, `fn handler(ctx)` in Rhai,
+    /// `function handler(ctx)` in JS and `def handler(ctx)` in Python. A
+    /// missing or uncallable handler is an error, a function returning
+    /// nothing (`()`, `undefined`, `None`) gives `Value::Null`.
+    pub fn invoke(&mut self, lang: Lang, code: &str, handler: &str, args: &Value) -> Result<Value> {
+        self.check_limits(code, args)?;
+
+        match lang {
+            #[cfg(feature = "runner-rhai")]
+            Lang::RustLite => self.invoke_rustlite(code, handler, args),
+            #[cfg(feature = "runner-js")]
+            Lang::JavaScript => self.invoke_js_threaded(code.to_owned(), handler.to_owned(), args.clone()),
+            #[cfg(feature = "runner-python")]
+            Lang::Python => self.invoke_py_threaded(code.to_owned(), handler.to_owned(), args.clone()),
+            #[allow(unreachable_patterns)]
+            other => Err(anyhow!(
+                "cannot invoke `{handler}`, language {:?} is not enabled in this build, rebuild with the `{}` feature",
+                other,
+                other.feature()
+            )),
         }
     }
 
     // ---------------- Rhai ----------------
-    fn run_rustlite(&self, code: &str, args: &Args) -> Result<Value> {
+    #[cfg(feature = "runner-rhai")]
+    fn rhai_ast(&self, code: &str) -> Result<RhaiAST> {
         let key = (Lang::RustLite, fxhash64(code.as_bytes()));
-        let ast = match self.cache.get(&key) {
-            Some(c) => match &*c {
-                Compiled::RhaiAST(a) => a.clone(),
-            },
-            None => {
-                let ast = self
-                    .rhai
-                    .compile(code)
-                    .map_err(|e| anyhow!("rhai compile error: {:?}", e))?;
-                self.cache.insert(key, Compiled::RhaiAST(ast.clone()));
-                ast
+        if let Some(c) = self.cache.get(&key) {
+            #[allow(irrefutable_let_patterns)]
+            if let Compiled::RhaiAST(a) = &*c {
+                return Ok(a.clone());
             }
-        };
+        }
+        let ast = self
+            .rhai
+            .compile(code)
+            .map_err(|e| anyhow!("rhai compile error: {:?}", e))?;
+        self.cache.insert(key, Compiled::RhaiAST(ast.clone()));
+        Ok(ast)
+    }
+
+    #[cfg(feature = "runner-rhai")]
+    fn run_rustlite(&self, code: &str, args: &Args) -> Result<Value> {
+        let ast = self.rhai_ast(code)?;
 
         let mut scope = RhaiScope::new();
         if let Ok(dynamic_args) = rhai::serde::to_dynamic(args) {
             scope.push_dynamic("args", dynamic_args);
         }
 
-        let out: RhaiDynamic = self
-            .rhai
-            .eval_ast_with_scope(&mut scope, &ast)
-            .map_err(|e| anyhow!("rhai exec error: {:?}", e))?;
-        Ok(rhai_to_json(out)?)
+        RHAI_DEADLINE.with(|d| d.set(Some(Instant::now() + self.limits.max_time)));
+        let out = self.rhai.eval_ast_with_scope::<RhaiDynamic>(&mut scope, &ast);
+        RHAI_DEADLINE.with(|d| d.set(None));
+        let out = out.map_err(|e| anyhow!("rhai exec error: {:?}", e))?;
+        let out = scope.get_value::<RhaiDynamic>("result").unwrap_or(out);
+        if out.is_unit() {
+            return Ok(Value::Null);
+        }
+        rhai_to_json(out)
+    }
+
+    #[cfg(feature = "runner-rhai")]
+    fn invoke_rustlite(&self, code: &str, handler: &str, args: &Value) -> Result<Value> {
+        let ast = self.rhai_ast(code)?;
+        if !ast.iter_functions().any(|f| f.name == handler && f.params.len() == 1) {
+            return Err(anyhow!("rhai handler `{handler}` taking one argument not found"));
+        }
+        let arg = rhai::serde::to_dynamic(args).map_err(|e| anyhow!("json->rhai: {:?}", e))?;
+
+        let mut scope = RhaiScope::new();
+        RHAI_DEADLINE.with(|d| d.set(Some(Instant::now() + self.limits.max_time)));
+        let out = self.rhai.call_fn::<RhaiDynamic>(&mut scope, &ast, handler, (arg,));
+        RHAI_DEADLINE.with(|d| d.set(None));
+        let out = out.map_err(|e| anyhow!("rhai exec error: {:?}", e))?;
+        if out.is_unit() {
+            return Ok(Value::Null);
+        }
+        rhai_to_json(out)
     }
 
     // ---------------- JS ----------------
+    #[cfg(feature = "runner-js")]
     fn run_js_threaded(&self, code: String, args: Args) -> Result<Value> {
         let limits = self.limits.clone();
-        let handle = thread::spawn(move || -> Result<Value> {
-            let mut ctx = BoaContext::default();
+        let call = JsCall { net: self.net(), deadline: Instant::now() + limits.max_time };
+        on_js_worker(limits.max_time, move || -> Result<Value> {
+            let deadline = call.deadline;
+            let mut ctx = js_context();
+            js_tools(&mut ctx, Rc::new(RefCell::new(call)))?;
             let args_json = serde_json::to_string(&args)?;
             let inject_code = format!("const args = JSON.parse({});", js_str(&args_json));
             ctx.eval(BoaSource::from_bytes(inject_code.as_str()))
                 .map_err(|e| anyhow!("inject args failed: {:?}", e))?;
 
-            let exec_src = BoaSource::from_bytes(code.as_str());
-            let v = ctx
-                .eval(exec_src)
+            let last = js_eval(&mut ctx, &code, deadline)?;
+            // `typeof` reads an undeclared `result` without throwing
+            let result = ctx
+                .eval(BoaSource::from_bytes("typeof result === 'undefined' ? undefined : result"))
                 .map_err(|e| anyhow!("boa eval error: {:?}", e))?;
-            let s = v
-                .to_json(&mut ctx)
-                .map_err(|e| anyhow!("boa to_json error: {:?}", e))?
-                .to_string();
-
-            if s == "undefined" {
-                Ok(Value::Null)
-            } else {
-                Ok(serde_json::from_str(&s).unwrap_or(json!(s)))
+            let out = if result.is_undefined() { last } else { result };
+            if out.is_undefined() {
+                return Ok(Value::Null);
             }
-        });
+            out.to_json(&mut ctx).map_err(|e| anyhow!("boa to_json error: {:?}", e))
+        })
+    }
 
-        thread_utils::spawn_timeout(handle, limits.max_time)
+    #[cfg(feature = "runner-js")]
+    fn invoke_js_threaded(&self, code: String, handler: String, args: Value) -> Result<Value> {
+        if !is_js_ident(&handler) {
+            return Err(anyhow!("js handler `{handler}` is not an identifier"));
+        }
+        let limits = self.limits.clone();
+        let call = JsCall { net: self.net(), deadline: Instant::now() + limits.max_time };
+        on_js_worker(limits.max_time, move || -> Result<Value> {
+            let deadline = call.deadline;
+            let mut ctx = js_context();
+            js_tools(&mut ctx, Rc::new(RefCell::new(call)))?;
+            js_eval(&mut ctx, &code, deadline)?;
+
+            // Evaluating the name finds `function`, `const` and `let` handlers alike
+            let func = ctx
+                .eval(BoaSource::from_bytes(handler.as_str()))
+                .map_err(|_| anyhow!("js handler `{handler}` not found"))?;
+            if !func.is_callable() {
+                return Err(anyhow!("js handler `{handler}` is not a function"));
+            }
+            let arg = JsValue::from_json(&args, &mut ctx).map_err(|e| anyhow!("json->js: {:?}", e))?;
+            ctx.register_global_property(js_string!("__bullg_args"), arg, Attribute::empty())
+                .map_err(|e| anyhow!("inject args failed: {:?}", e))?;
+            let out = js_eval(&mut ctx, &format!("{handler}(__bullg_args)"), deadline)?;
+            if out.is_undefined() {
+                return Ok(Value::Null);
+            }
+            out.to_json(&mut ctx).map_err(|e| anyhow!("boa to_json error: {:?}", e))
+        })
     }
 
     // ---------------- Python via PyO3 ----------------
+    #[cfg(feature = "runner-python")]
     fn run_py_threaded(&self, code: String, args: Args) -> Result<Value> {
         let limits = self.limits.clone();
+        let tools = PyTools { net: self.net(), deadline: Instant::now() + limits.max_time };
+        let cache = self.cache.clone();
 
         // Spawn Python thread
         let handle = thread::spawn(move || -> Result<Value> {
             Python::with_gil(|py| {
+                let code = py_code(&cache, py, &code)?;
                 let locals = PyDict::new(py);
+                locals.set_item("tools", Py::new(py, tools)?)?;
                 let args_dict = PyDict::new(py);
 
                 // Convert Rust serde_json::Value to Python objects safely
@@ -169,45 +420,51 @@ impl Runner {
 
                 locals.set_item("args", args_dict)?;
 
-                let c_code =
-                    CString::new(code.clone()).map_err(|e| anyhow!("CString error: {:?}", e))?;
-
-                // Run the Python code
-                py.run(c_code.as_c_str(), None, Some(&locals))
+                // Run the Python code, with the globals of `__main__` like `py.run`
+                let globals = py.import("__main__")?.dict();
+                py.import("builtins")?
+                    .call_method1("exec", (code, globals, &locals))
                     .map_err(|e| anyhow!("python exec error: {:?}", e))?;
 
-                // Capture the last expression result if exists
-                locals.del_item("args")?;
-                let _ = cleanup_locals(&locals); // Clean up builtins
-                let mut map = serde_json::Map::new();
-                for (k, v) in locals.iter() {
-                    let key: String = k.extract().unwrap_or_default();
-                    let val: Value = pyany_to_value(v).unwrap_or(Value::Null);
-                    map.insert(key, val);
+                // Python has no value of the last statement, only `result`
+                match locals.get_item("result")? {
+                    Some(result) if !result.is_none() => pyany_to_value(result),
+                    _ => Ok(Value::Null),
                 }
+            })
+        });
 
-                Ok(Value::Object(map))
-
-                // if let Ok(res) = locals.get_item("result") {
-                //     if let Some(val) = res {
-                //         let res_str: String =
-                //             json_module.call_method1("dumps", (val,))?.extract()?;
-                //         let val: Value = serde_json::from_str(&res_str)?;
-                //         Ok(val)
-                //     } else {
-                //         Ok(Value::Null)
-                //     }
-                // } else {
-                //     // Convert locals to HashMap<String, serde_json::Value>
-                //     let mut map = serde_json::Map::new();
-                //     for (k, v) in locals.iter() {
-                //         let key: String = k.extract().unwrap_or_default();
-                //         let val: Value = pyany_to_value(v).unwrap_or(Value::Null);
-                //         map.insert(key, val);
-                //     }
-
-                //     Ok(Value::Object(map))
-                // }
+        thread_utils::spawn_timeout(handle, limits.max_time)
+    }
+
+    #[cfg(feature = "runner-python")]
+    fn invoke_py_threaded(&self, code: String, handler: String, args: Value) -> Result<Value> {
+        let limits = self.limits.clone();
+        let tools = PyTools { net: self.net(), deadline: Instant::now() + limits.max_time };
+        let cache = self.cache.clone();
+        let handle = thread::spawn(move || -> Result<Value> {
+            Python::with_gil(|py| {
+                let code = py_code(&cache, py, &code)?;
+                // One dict as globals so the handler sees the module level
+                // names and imports of the code
+                let globals = PyDict::new(py);
+                globals.set_item("tools", Py::new(py, tools)?)?;
+                py.import("builtins")?
+                    .call_method1("exec", (code, &globals))
+                    .map_err(|e| anyhow!("python exec error: {:?}", e))?;
+
+                let func = globals
+                    .get_item(handler.as_str())?
+                    .filter(|f| f.is_callable())
+                    .ok_or_else(|| anyhow!("python handler `{handler}` not found"))?;
+                let arg = py.import("json")?.call_method1("loads", (serde_json::to_string(&args)?,))?;
+                let out = func
+                    .call1((arg,))
+                    .map_err(|e| anyhow!("python exec error: {:?}", e))?;
+                if out.is_none() {
+                    return Ok(Value::Null);
+                }
+                pyany_to_value(out)
             })
         });
 
@@ -215,21 +472,290 @@ impl Runner {
     }
 }
 
+impl Default for Runner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// ---------------- Script tools ----------------
+/// HTTP calls of the scripts of one runner
+#[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+#[derive(Clone)]
+struct ScriptNet {
+    tools: Arc<BullGTools>,
+    allow: bool,
+}
+
+#[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+impl ScriptNet {
+    /// Response body of a call, bounded by the script deadline. Blocks the
+    /// calling thread, the call itself runs on the script network runtime.
+    fn call(&self, method: Method, url: &str, body: Option<String>, deadline: Instant) -> Result<String> {
+        if !self.allow {
+            return Err(anyhow!("network access is disabled for scripts"));
+        }
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(anyhow!("timeout"));
+        }
+        let (tools, url) = (self.tools.clone(), url.to_string());
+        let (tx, rx) = std::sync::mpsc::channel();
+        net_runtime()?.spawn(async move {
+            let call = async {
+                match body {
+                    Some(body) => tools.httpx_request(method, &url, body.into()).await,
+                    None => tools.httpx_request(method, &url, bytes::Bytes::new()).await,
+                }
+            };
+            let res = tokio::time::timeout(left, call).await.unwrap_or_else(|_| Err(anyhow!("timeout")));
+            let _ = tx.send(res);
+        });
+        rx.recv().map_err(|_| anyhow!("script http call dropped"))?
+    }
+}
+
+// Runtime of the script HTTP calls, shared by all runners. Scripts wait for
+// the calls on their own threads or on the caller's, which may belong to
+// another runtime that cannot be blocked on.
+#[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+fn net_runtime() -> Result<&'static tokio::runtime::Runtime> {
+    static NET: OnceLock<std::result::Result<tokio::runtime::Runtime, String>> = OnceLock::new();
+    NET.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("bullg-script-net")
+            .enable_all()
+            .build()
+            .map_err(|e| e.to_string())
+    })
+    .as_ref()
+    .map_err(|e| anyhow!("script network runtime: {e}"))
+}
+
+#[cfg(feature = "runner-rhai")]
+fn rhai_result(res: Result<String>) -> std::result::Result<String, Box<rhai::EvalAltResult>> {
+    res.map_err(|e| e.to_string().into())
+}
+
+#[cfg(feature = "runner-js")]
+fn js_tools(ctx: &mut BoaContext, call: Rc<RefCell<JsCall>>) -> Result<()> {
+    fn arg(args: &[JsValue], i: usize, ctx: &mut BoaContext) -> boa_engine::JsResult<String> {
+        Ok(args.get(i).cloned().unwrap_or_default().to_string(ctx)?.to_std_string_escaped())
+    }
+    fn result(res: Result<String>) -> boa_engine::JsResult<JsValue> {
+        res.map(|text| JsString::from(text.as_str()).into())
+            .map_err(|e| JsNativeError::error().with_message(e.to_string()).into())
+    }
+    let post = call.clone();
+    // SAFETY: the closures capture no garbage collected value
+    let (get, post) = unsafe {
+        (
+            NativeFunction::from_closure(move |_, args, ctx| {
+                let JsCall { net, deadline } = call.borrow().clone();
+                result(net.call(Method::GET, &arg(args, 0, ctx)?, None, deadline))
+            }),
+            NativeFunction::from_closure(move |_, args, ctx| {
+                let JsCall { net, deadline } = post.borrow().clone();
+                let (url, body) = (arg(args, 0, ctx)?, arg(args, 1, ctx)?);
+                result(net.call(Method::POST, &url, Some(body), deadline))
+            }),
+        )
+    };
+    let tools = ObjectInitializer::new(ctx)
+        .function(get, js_string!("http_get"), 1)
+        .function(post, js_string!("http_post"), 2)
+        .build();
+    ctx.register_global_property(js_string!("tools"), tools, Attribute::READONLY)
+        .map_err(|e| anyhow!("inject tools failed: {:?}", e))
+}
+
+/// `tools` of Python scripts
+#[cfg(feature = "runner-python")]
+#[pyclass(name = "Tools")]
+struct PyTools {
+    net: ScriptNet,
+    deadline: Instant,
+}
+
+#[cfg(feature = "runner-python")]
+#[pymethods]
+impl PyTools {
+    fn http_get(&self, py: Python<'_>, url: &str) -> PyResult<String> {
+        let (net, deadline) = (self.net.clone(), self.deadline);
+        py.allow_threads(|| net.call(Method::GET, url, None, deadline))
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+    }
+
+    fn http_post(&self, py: Python<'_>, url: &str, body: String) -> PyResult<String> {
+        let (net, deadline) = (self.net.clone(), self.deadline);
+        py.allow_threads(|| net.call(Method::POST, url, Some(body), deadline))
+            .map_err(|e| PyRuntimeError::new_err(e.to_string()))
+    }
+}
+
+// ---------------- JS workers ----------------
+// Instructions a JS execution runs between checks of its deadline
+#[cfg(feature = "runner-js")]
+const JS_BUDGET: u32 = 10_000;
+
+// Idle JS workers kept for later executions, more exit once done
+#[cfg(feature = "runner-js")]
+const JS_IDLE_WORKERS: usize = 16;
+
+// Runs an execution and gives what sends its result, once the worker is idle
+#[cfg(feature = "runner-js")]
+type JsJob = Box<dyn FnOnce() -> Box<dyn FnOnce()> + Send>;
+
+#[cfg(feature = "runner-js")]
+thread_local! {
+    // Fresh context for the next execution on this worker, built while idle
+    static JS_SPARE: RefCell<Option<BoaContext>> = const { RefCell::new(None) };
+}
+
+/// Network and deadline of the JS execution a context is running
+#[cfg(feature = "runner-js")]
+#[derive(Clone)]
+struct JsCall {
+    net: ScriptNet,
+    deadline: Instant,
+}
+
+/// Fresh context for one execution, the spare one of the worker if built
+#[cfg(feature = "runner-js")]
+fn js_context() -> BoaContext {
+    JS_SPARE.with_borrow_mut(Option::take).unwrap_or_default()
+}
+
+/// Evaluate `code` in `ctx` until `deadline`. A timed out script is left
+/// half run, its context must not be used again.
+#[cfg(feature = "runner-js")]
+fn js_eval(ctx: &mut BoaContext, code: &str, deadline: Instant) -> Result<JsValue> {
+    use std::task::{Context, Poll, Waker};
+    let script = BoaScript::parse(BoaSource::from_bytes(code), None, ctx)
+        .map_err(|e| anyhow!("boa eval error: {:?}", e))?;
+    // A budgeted evaluation is pending each time the budget is spent
+    let mut eval = std::pin::pin!(script.evaluate_async_with_budget(ctx, JS_BUDGET));
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        match eval.as_mut().poll(&mut cx) {
+            Poll::Ready(res) => return res.map_err(|e| anyhow!("boa eval error: {:?}", e)),
+            Poll::Pending if Instant::now() >= deadline => return Err(anyhow!("timeout")),
+            Poll::Pending => {}
+        }
+    }
+}
+
+/// Run `f` on an idle JS worker, else on a new one, and wait for it at most
+/// `timeout`
+#[cfg(feature = "runner-js")]
+fn on_js_worker<T: Send + 'static>(timeout: Duration, f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    static IDLE: Mutex<Vec<mpsc::Sender<JsJob>>> = Mutex::new(Vec::new());
+
+    let (tx, rx) = mpsc::channel();
+    let mut job: JsJob = Box::new(move || {
+        let res = f();
+        Box::new(move || {
+            let _ = tx.send(res);
+        })
+    });
+    loop {
+        let Some(worker) = IDLE.lock().unwrap_or_else(|e| e.into_inner()).pop() else {
+            break;
+        };
+        match worker.send(job) {
+            Ok(()) => return js_wait(&rx, timeout),
+            Err(mpsc::SendError(back)) => job = back,
+        }
+    }
+
+    let (worker, jobs) = mpsc::channel::<JsJob>();
+    thread::Builder::new()
+        .name("bullg-js".into())
+        .spawn(move || {
+            let mut job = job;
+            while let Ok(reply) = std::panic::catch_unwind(std::panic::AssertUnwindSafe(job)) {
+                // Idle before replying, so the next execution of the caller finds it
+                let kept = {
+                    let mut idle = IDLE.lock().unwrap_or_else(|e| e.into_inner());
+                    let kept = idle.len() < JS_IDLE_WORKERS;
+                    if kept {
+                        idle.push(worker.clone());
+                    }
+                    kept
+                };
+                reply();
+                if !kept {
+                    break;
+                }
+                JS_SPARE.with_borrow_mut(|spare| {
+                    spare.get_or_insert_with(BoaContext::default);
+                });
+                match jobs.recv() {
+                    Ok(next) => job = next,
+                    Err(_) => break,
+                }
+            }
+            // Boa values must go before the thread local state of Boa does
+            JS_SPARE.with_borrow_mut(Option::take);
+        })
+        .map_err(|e| anyhow!("spawn js worker: {e}"))?;
+    js_wait(&rx, timeout)
+}
+
+#[cfg(feature = "runner-js")]
+fn js_wait<T>(rx: &mpsc::Receiver<Result<T>>, timeout: Duration) -> Result<T> {
+    match rx.recv_timeout(timeout) {
+        Ok(res) => res,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(anyhow!("timeout")),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err(anyhow!("thread panicked")),
+    }
+}
+
 // ---------------- Helpers ----------------
+#[cfg(any(feature = "runner-python", feature = "runner-rhai"))]
 fn fxhash64(bytes: &[u8]) -> u64 {
     let mut h = FxHasher64::default();
     h.write(bytes);
     h.finish()
 }
 
+#[cfg(feature = "runner-js")]
 fn js_str(s: &str) -> String {
     format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
+#[cfg(feature = "runner-js")]
+fn is_js_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+}
+
+#[cfg(feature = "runner-rhai")]
 fn rhai_to_json(d: RhaiDynamic) -> Result<Value> {
     rhai::serde::from_dynamic::<serde_json::Value>(&d).map_err(|e| anyhow!("rhai->json: {:?}", e))
 }
 
+/// Code object of `code`, compiled once per runner
+#[cfg(feature = "runner-python")]
+fn py_code<'py>(cache: &DashMap<(Lang, u64), Compiled>, py: Python<'py>, code: &str) -> Result<Bound<'py, PyAny>> {
+    let key = (Lang::Python, fxhash64(code.as_bytes()));
+    if let Some(c) = cache.get(&key) {
+        #[allow(irrefutable_let_patterns)]
+        if let Compiled::Python(c) = &*c {
+            return Ok(c.bind(py).clone());
+        }
+    }
+    let compiled = py
+        .import("builtins")
+        .and_then(|b| b.call_method1("compile", (code, "<script>", "exec")))
+        .map_err(|e| anyhow!("python compile error: {:?}", e))?;
+    cache.insert(key, Compiled::Python(Arc::new(compiled.clone().unbind())));
+    Ok(compiled)
+}
+
+#[cfg(feature = "runner-python")]
 fn pyany_to_value(obj: Bound<PyAny>) -> Result<Value> {
     let py = obj.py();
 
@@ -248,31 +774,6 @@ fn pyany_to_value(obj: Bound<PyAny>) -> Result<Value> {
     Ok(val)
 }
 
-fn cleanup_locals(locals: &Bound<PyDict>) -> PyResult<()> {
-    // Collect keys to remove first, because we can't mutate while iterating
-    let py = locals.py();
-    let types = py.import("types")?;
-    let function_type = types.getattr("FunctionType")?;
-    let keys_to_remove: Vec<String> = locals
-        .iter()
-        .filter_map(|(k, v)| {
-            if v.is_none() || v.is_instance(&function_type).unwrap_or(false) {
-                Some(k.extract::<String>().ok()?) // get key as String
-            } else {
-                None
-            }
-        })
-        .collect();
-
-    // Remove the keys
-    for key in keys_to_remove {
-        locals.del_item(key)?;
-    }
-
-    Ok(())
-}
-
-
 // fn py_dict_to_value(py: Python, dict: &PyDict) -> PyResult<Value> {
 //     // Extract the dict as a standard Rust HashMap<String, serde_json::Value>
 //     let map: std::collections::HashMap<String, Value> = dict.extract()?;
@@ -281,6 +782,7 @@ fn cleanup_locals(locals: &Bound<PyDict>) -> PyResult<()> {
 // }
 
 // ---------------- Thread timeout helper ----------------
+#[cfg(feature = "runner-python")]
 mod thread_utils {
     use super::*;
     use std::sync::mpsc::channel;
@@ -307,6 +809,291 @@ mod thread_utils {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[cfg(feature = "runner-js")]
+    const COUNTER: &str = "var calls = 0; function handler(ctx) { calls += 1; return { calls: calls, path: ctx.path }; }";
+
+    fn runner(max_time: Duration) -> Runner {
+        Runner::new_with_limits(RunnerLimits { max_time, ..Default::default() })
+    }
+
+    #[test]
+    #[cfg(feature = "runner-js")]
+    fn js_executions_do_not_see_the_state_of_earlier_ones() {
+        let mut runner = runner(Duration::from_secs(5));
+        for _ in 0..3 {
+            let out = runner.invoke(Lang::JavaScript, COUNTER, "handler", &json!({"path": "/a"})).unwrap();
+            assert_eq!(out, json!({"calls": 1, "path": "/a"}));
+        }
+        let args = Args::from([("n".to_string(), json!(2))]);
+        assert_eq!(runner.run(Lang::JavaScript, "globalThis.leak = args.n; leak", &args).unwrap(), json!(2));
+        assert_eq!(runner.run(Lang::JavaScript, "typeof leak", &args).unwrap(), json!("undefined"));
+    }
+
+    #[test]
+    #[cfg(feature = "runner-js")]
+    fn js_handlers_that_are_missing_or_not_functions_fail() {
+        let mut runner = runner(Duration::from_secs(5));
+        let err = runner.invoke(Lang::JavaScript, "const handler = 1;", "handler", &json!({})).unwrap_err();
+        assert!(err.to_string().contains("is not a function"));
+        let err = runner.invoke(Lang::JavaScript, COUNTER, "other", &json!({})).unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
+
+    #[test]
+    #[cfg(feature = "runner-js")]
+    fn timed_out_js_gives_its_worker_back() {
+        let mut runner = runner(Duration::from_millis(20));
+        for _ in 0..24 {
+            let err = runner.invoke(Lang::JavaScript, "function spin() { while (true) {} }", "spin", &json!({}));
+            assert_eq!(err.unwrap_err().to_string(), "timeout");
+        }
+        thread::sleep(Duration::from_millis(200));
+        // Stuck workers would each still be spinning
+        let workers = std::fs::read_dir("/proc/self/task")
+            .map(|tasks| {
+                tasks
+                    .flatten()
+                    .filter(|t| std::fs::read_to_string(t.path().join("comm")).is_ok_and(|c| c.trim() == "bullg-js"))
+                    .count()
+            })
+            .unwrap_or(0);
+        assert!((1..=JS_IDLE_WORKERS + 2).contains(&workers), "{workers} js workers alive");
+        let out = runner.invoke(Lang::JavaScript, COUNTER, "handler", &json!({"path": "/"})).unwrap();
+        assert_eq!(out["calls"], 1);
+    }
+
+    #[test]
+    #[cfg(feature = "runner-js")]
+    fn js_handler_calls_stay_fast() {
+        let mut runner = runner(Duration::from_secs(5));
+        let args = json!({"path": "/users"});
+        runner.invoke(Lang::JavaScript, COUNTER, "handler", &args).unwrap();
+        let mut took: Vec<Duration> = (0..50)
+            .map(|_| {
+                let started = Instant::now();
+                runner.invoke(Lang::JavaScript, COUNTER, "handler", &args).unwrap();
+                started.elapsed()
+            })
+            .collect();
+        took.sort();
+        // Debug builds, a fresh context takes well under this
+        assert!(took[25] < Duration::from_millis(50), "median js call took {:?}", took[25]);
+    }
+
+    #[test]
+    #[cfg(feature = "runner-rhai")]
+    fn rhai_runs_when_enabled() {
+        let mut runner = runner(Duration::from_secs(5));
+        let args = Args::from([("n".to_string(), json!(20))]);
+        assert_eq!(runner.run(Lang::RustLite, "args.n * 2 + 2", &args).unwrap(), json!(42));
+        let out = runner.invoke(Lang::RustLite, "fn handler(ctx) { ctx.path }", "handler", &json!({"path": "/a"}));
+        assert_eq!(out.unwrap(), json!("/a"));
+    }
+
+    #[test]
+    #[cfg(feature = "runner-python")]
+    fn python_runs_when_enabled() {
+        let mut runner = runner(Duration::from_secs(5));
+        let args = Args::from([("n".to_string(), json!(20))]);
+        assert_eq!(runner.run(Lang::Python, "result = args['n'] * 2 + 2", &args).unwrap(), json!(42));
+        let out = runner.invoke(Lang::Python, "def handler(ctx):\n    return ctx['path']\n", "handler", &json!({"path": "/a"}));
+        assert_eq!(out.unwrap(), json!("/a"));
+    }
+
+    #[test]
+    #[cfg(feature = "runner-js")]
+    fn js_runs_when_enabled() {
+        let mut runner = runner(Duration::from_secs(5));
+        let args = Args::from([("n".to_string(), json!(20))]);
+        assert_eq!(runner.run(Lang::JavaScript, "args.n * 2 + 2", &args).unwrap(), json!(42));
+    }
+
+    #[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+    fn request() -> Value {
+        json!({"method": "GET", "path": "/users", "headers": {"x-id": "7"}})
+    }
+
+    #[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+    fn expected() -> Value {
+        json!({"status": 200, "echo": "GET /users", "id": "7", "tags": ["a", "b"], "empty": null})
+    }
+
+    #[test]
+    #[cfg(feature = "runner-rhai")]
+    fn rhai_handlers_are_invoked_by_name() {
+        let code = r#"
+            fn other(ctx) { 0 }
+            fn handler(ctx) {
+                #{ status: 200, echo: ctx.method + " " + ctx.path, id: ctx.headers["x-id"], tags: ["a", "b"], empty: () }
+            }
+        "#;
+        let mut runner = runner(Duration::from_secs(5));
+        assert_eq!(runner.invoke(Lang::RustLite, code, "handler", &request()).unwrap(), expected());
+        assert_eq!(runner.invoke(Lang::RustLite, code, "other", &request()).unwrap(), json!(0));
+        let err = runner.invoke(Lang::RustLite, code, "missing", &request()).unwrap_err();
+        assert!(err.to_string().contains("rhai handler `missing` taking one argument not found"), "{err}");
+    }
+
+    #[test]
+    #[cfg(feature = "runner-python")]
+    fn python_handlers_are_invoked_by_name() {
+        let code = "def other(ctx):\n    return 0\n\n\
+            def handler(ctx):\n    return {'status': 200, 'echo': ctx['method'] + ' ' + ctx['path'], \
+            'id': ctx['headers']['x-id'], 'tags': ['a', 'b'], 'empty': None}\n";
+        let mut runner = runner(Duration::from_secs(5));
+        assert_eq!(runner.invoke(Lang::Python, code, "handler", &request()).unwrap(), expected());
+        assert_eq!(runner.invoke(Lang::Python, code, "other", &request()).unwrap(), json!(0));
+        let err = runner.invoke(Lang::Python, code, "missing", &request()).unwrap_err();
+        assert!(err.to_string().contains("python handler `missing` not found"), "{err}");
+    }
+
+    #[test]
+    #[cfg(feature = "runner-js")]
+    fn js_handlers_are_invoked_by_name() {
+        let code = "function other(ctx) { return 0; }
+            function handler(ctx) {
+                return { status: 200, echo: ctx.method + ' ' + ctx.path, id: ctx.headers['x-id'], tags: ['a', 'b'], empty: null };
+            }";
+        let mut runner = runner(Duration::from_secs(5));
+        assert_eq!(runner.invoke(Lang::JavaScript, code, "handler", &request()).unwrap(), expected());
+        assert_eq!(runner.invoke(Lang::JavaScript, code, "other", &request()).unwrap(), json!(0));
+    }
+
+    /// HTTP server on a loopback port answering `<method> <path> <body>`,
+    /// after `delay` on /slow
+    #[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+    fn echo_server() -> String {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let mut read = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    let head_end = loop {
+                        if let Some(at) = read.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break at + 4;
+                        }
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => read.extend_from_slice(&buf[..n]),
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&read[..head_end]).to_string();
+                    let length = head
+                        .lines()
+                        .find_map(|l| l.to_ascii_lowercase().strip_prefix("content-length:").map(|v| v.trim().to_string()))
+                        .and_then(|v| v.parse::<usize>().ok())
+                        .unwrap_or(0);
+                    while read.len() < head_end + length {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => read.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let mut request_line = head.split_whitespace();
+                    let (method, path) = (request_line.next().unwrap_or_default(), request_line.next().unwrap_or_default());
+                    if path == "/slow" {
+                        std::thread::sleep(Duration::from_secs(2));
+                    }
+                    let body = format!("{method} {path} {}", String::from_utf8_lossy(&read[head_end..]));
+                    let answer = format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len());
+                    let _ = stream.write_all(answer.as_bytes());
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    #[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+    fn networked(max_time: Duration) -> Runner {
+        Runner::new_with_limits(RunnerLimits { max_time, allow_network: true, ..Default::default() })
+    }
+
+    #[cfg(any(feature = "runner-js", feature = "runner-python", feature = "runner-rhai"))]
+    fn url_args(base: &str, path: &str) -> Args {
+        Args::from([("url".to_string(), json!(format!("{base}{path}")))])
+    }
+
+    #[test]
+    #[cfg(feature = "runner-rhai")]
+    fn rhai_scripts_call_http_through_tools() {
+        let base = echo_server();
+        let code = "[tools::http_get(args.url), tools::http_post(args.url, \"hi\")]";
+        let mut online = networked(Duration::from_secs(5));
+        let out = online.run(Lang::RustLite, code, &url_args(&base, "/users")).unwrap();
+        assert_eq!(out, json!(["GET /users ", "POST /users hi"]));
+
+        let err = runner(Duration::from_secs(5)).run(Lang::RustLite, code, &url_args(&base, "/users")).unwrap_err();
+        assert!(err.to_string().contains("network access is disabled for scripts"), "{err}");
+        // The call counts against the script time
+        let started = Instant::now();
+        let mut online = networked(Duration::from_millis(200));
+        let err = online.run(Lang::RustLite, "tools::http_get(args.url)", &url_args(&base, "/slow")).unwrap_err();
+        assert!(err.to_string().contains("timeout"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[cfg(feature = "runner-python")]
+    fn python_scripts_call_http_through_tools() {
+        let base = echo_server();
+        let code = "result = [tools.http_get(args['url']), tools.http_post(args['url'], 'hi')]";
+        let mut online = networked(Duration::from_secs(5));
+        let out = online.run(Lang::Python, code, &url_args(&base, "/users")).unwrap();
+        assert_eq!(out, json!(["GET /users ", "POST /users hi"]));
+        let handler = "def handler(ctx):\n    return tools.http_get(ctx['url'])\n";
+        let out = online.invoke(Lang::Python, handler, "handler", &json!({"url": format!("{base}/orders")})).unwrap();
+        assert_eq!(out, json!("GET /orders "));
+
+        let err = runner(Duration::from_secs(5)).run(Lang::Python, code, &url_args(&base, "/users")).unwrap_err();
+        assert!(err.to_string().contains("network access is disabled for scripts"), "{err}");
+        let started = Instant::now();
+        let mut online = networked(Duration::from_millis(200));
+        let err = online.run(Lang::Python, "result = tools.http_get(args['url'])", &url_args(&base, "/slow")).unwrap_err();
+        assert!(err.to_string().contains("timeout"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    #[cfg(feature = "runner-js")]
+    fn js_scripts_call_http_through_tools() {
+        let base = echo_server();
+        let code = "[tools.http_get(args.url), tools.http_post(args.url, 'hi')]";
+        let mut online = networked(Duration::from_secs(5));
+        let out = online.run(Lang::JavaScript, code, &url_args(&base, "/users")).unwrap();
+        assert_eq!(out, json!(["GET /users ", "POST /users hi"]));
+        let handler = "function handler(ctx) { return tools.http_get(ctx.url); }";
+        let out = online.invoke(Lang::JavaScript, handler, "handler", &json!({"url": format!("{base}/orders")})).unwrap();
+        assert_eq!(out, json!("GET /orders "));
+
+        let err = runner(Duration::from_secs(5)).run(Lang::JavaScript, code, &url_args(&base, "/users")).unwrap_err();
+        assert!(err.to_string().contains("network access is disabled for scripts"), "{err}");
+        let started = Instant::now();
+        let mut online = networked(Duration::from_millis(200));
+        let err = online.run(Lang::JavaScript, "tools.http_get(args.url)", &url_args(&base, "/slow")).unwrap_err();
+        assert!(err.to_string().contains("timeout"), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn disabled_languages_name_their_feature() {
+        let mut runner = runner(Duration::from_secs(5));
+        for lang in [Lang::Python, Lang::JavaScript, Lang::RustLite].into_iter().filter(|l| !l.enabled()) {
+            let err = runner.run(lang, "1", &Args::new()).unwrap_err().to_string();
+            assert!(err.contains("is not enabled") && err.contains(lang.feature()), "{err}");
+            let err = runner.invoke(lang, "1", "handler", &json!({})).unwrap_err().to_string();
+            assert!(err.contains("cannot invoke `handler`") && err.contains(lang.feature()), "{err}");
+        }
+    }
+}
+
 // use anyhow::{Result, anyhow};
 // use dashmap::DashMap;
 // use fxhash::FxHasher64;
@@ -320,7 +1107,7 @@ mod thread_utils {
 // };
 
 // // Crates
-// use boa_engine::{Context as BoaContext, Source as BoaSource};
+// use boa_engine::{Context as BoaContext, JsValue, Source as BoaSource};
 // use pyo3::{prelude::*, types::PyDict};
 // use rhai::{AST as RhaiAST, Dynamic as RhaiDynamic, Engine as RhaiEngine, Scope as RhaiScope};
 // use std::ffi::CString;
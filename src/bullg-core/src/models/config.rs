@@ -0,0 +1,698 @@
+use crate::{HeaderMatch, Protocols};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// ---------- config.yaml (Version 1.0.1 onwards) ----------
+// Every section falls back to its `Default` so a partial config file still boots a usable node.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct GatewayConfig {
+    pub gateway: GatewayNode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GatewayNode {
+    pub name: String,
+    pub id: String,
+    pub r#type: String, // dataplane | tenantplane
+    pub version: String,
+    pub tags: Vec<String>,
+    pub labels: HashMap<String, String>,
+    pub description: String,
+    pub proxy: ProxyCfg,
+    pub host: String,
+    pub port: u16,
+    pub ssl: bool,
+    pub http3: bool,
+    pub ssl_port: u16,
+    pub cert: String,
+    pub key: String,
+    pub ca: String,
+    pub logging_mode: String,
+    pub access_log: AccessLog,
+    pub metrics: MetricsCfg,
+    pub health_check: HealthCfg,
+    pub tracing: TraceCfg,
+    pub control_plane: ControlPlane,
+    pub builtin: BuiltinCfg,
+    pub database: DatabaseCfg,
+    pub memory: MemoryCfg,
+    pub admin: AdminCfg,
+    pub methods: MethodsCfg,
+    pub request_id: RequestIdCfg,
+    pub state_limits: StateLimitsCfg,
+    pub load_shedding: LoadSheddingCfg,
+    pub body_buffer: BodyBufferCfg,
+    pub headers: HeadersCfg,
+    pub upstream: UpstreamCfg,
+    pub shutdown: ShutdownCfg,
+    pub paths: PathsCfg,
+    pub debug: DebugCfg,
+    pub maintenance: MaintenanceCfg,
+}
+
+impl Default for GatewayNode {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            id: String::new(),
+            r#type: "dataplane".into(),
+            version: String::new(),
+            tags: Vec::new(),
+            labels: HashMap::new(),
+            description: String::new(),
+            proxy: ProxyCfg::default(),
+            host: "0.0.0.0".into(),
+            port: 8000,
+            ssl: false,
+            http3: false,
+            ssl_port: 8443,
+            cert: String::new(),
+            key: String::new(),
+            ca: String::new(),
+            logging_mode: "info".into(),
+            access_log: AccessLog::default(),
+            metrics: MetricsCfg::default(),
+            health_check: HealthCfg::default(),
+            tracing: TraceCfg::default(),
+            control_plane: ControlPlane::default(),
+            builtin: BuiltinCfg::default(),
+            database: DatabaseCfg::default(),
+            memory: MemoryCfg::default(),
+            admin: AdminCfg::default(),
+            methods: MethodsCfg::default(),
+            request_id: RequestIdCfg::default(),
+            state_limits: StateLimitsCfg::default(),
+            load_shedding: LoadSheddingCfg::default(),
+            body_buffer: BodyBufferCfg::default(),
+            headers: HeadersCfg::default(),
+            upstream: UpstreamCfg::default(),
+            shutdown: ShutdownCfg::default(),
+            paths: PathsCfg::default(),
+            debug: DebugCfg::default(),
+            maintenance: MaintenanceCfg::default(),
+        }
+    }
+}
+
+impl GatewayNode {
+    pub fn get_address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn get_ssl_address(&self) -> String {
+        format!("{}:{}", self.host, self.ssl_port)
+    }
+
+    pub fn get_health_address(&self) -> String {
+        format!("{}:{}", self.host, self.health_check.port)
+    }
+
+    pub fn get_metrics_address(&self) -> String {
+        format!("{}:{}", self.host, self.metrics.port)
+    }
+
+    /// Name reported in traces and access logs, the node name unless set
+    pub fn service_name(&self) -> &str {
+        if self.tracing.service_name.is_empty() {
+            &self.name
+        } else {
+            &self.tracing.service_name
+        }
+    }
+
+    pub fn service_version(&self) -> &str {
+        if self.tracing.service_version.is_empty() {
+            &self.version
+        } else {
+            &self.tracing.service_version
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct ProxyCfg {
+    pub enabled: bool,
+    pub domains: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AccessLog {
+    pub enabled: bool,
+    pub sname: bool,
+    pub path: String,   // console | file path
+    pub format: String, // text | common | combined | json | otlp
+    pub max_size: u64,  // MB
+    pub max_backups: u32,
+    pub max_age: u32, // days
+    pub compress: bool,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sname: true,
+            path: "console".into(),
+            format: "text".into(),
+            max_size: 100,
+            max_backups: 10,
+            max_age: 30,
+            compress: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsCfg {
+    pub enabled: bool,
+    pub path: String,
+    pub port: u16,
+    pub format: String, // prometheus | json
+}
+
+impl Default for MetricsCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "/metrics".into(),
+            port: 9090,
+            format: "prometheus".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HealthCfg {
+    pub enabled: bool,
+    pub path: String,
+    pub port: u16,
+}
+
+impl Default for HealthCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "/health".into(),
+            port: 8080,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TraceCfg {
+    pub enabled: bool,
+    pub otlp_endpoint: String,
+    /// OTLP transport, `http` (protobuf over HTTP) or `grpc`
+    pub protocol: String,
+    pub service_name: String,
+    /// `service.version` of the spans, the gateway version when empty
+    pub service_version: String,
+    /// `deployment.environment` of the spans
+    pub environment: String,
+}
+
+impl Default for TraceCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            otlp_endpoint: String::new(),
+            protocol: "http".into(),
+            service_name: String::new(),
+            service_version: String::new(),
+            environment: "development".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlPlane {
+    pub enabled: bool,
+    pub protocols: Vec<Protocols>,
+    pub host: String,
+    pub id: String,
+    pub mtls_cert: String,
+    pub mtls_key: String,
+    pub mtls_ca: String, // CA the control plane certificate is checked against
+    pub poll_interval_sec: u64,
+    pub ws_ping_interval_sec: u64, // 0 never pings the sync websocket
+    pub ws_pong_timeout_sec: u64,
+    pub signing_alg: String, // ed25519 | hmac-sha256
+    pub signing_key: String, // base64 ed25519 public key or hmac secret
+    pub encryption_key: String, // shared secret state messages are encrypted with
+}
+
+impl Default for ControlPlane {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            protocols: vec![Protocols::HTTPS, Protocols::WSS],
+            host: "localhost".into(),
+            id: String::new(),
+            mtls_cert: String::new(),
+            mtls_key: String::new(),
+            mtls_ca: String::new(),
+            poll_interval_sec: 5,
+            ws_ping_interval_sec: 20,
+            ws_pong_timeout_sec: 10,
+            signing_alg: "ed25519".into(),
+            signing_key: String::new(),
+            encryption_key: String::new(),
+        }
+    }
+}
+
+impl ControlPlane {
+    pub fn get_ws_url(&self) -> String {
+        let scheme = if self.protocols.contains(&Protocols::WSS) { "wss" } else { "ws" };
+        format!("{}://{}/sync", scheme, self.host)
+    }
+
+    pub fn get_https_url(&self) -> String {
+        let scheme = if self.protocols.contains(&Protocols::HTTPS) { "https" } else { "http" };
+        format!("{}://{}", scheme, self.host)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct BuiltinCfg {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DatabaseCfg {
+    pub enabled: bool,
+    pub r#type: String, // sqlite | mysql | postgresql | mongodb | redis
+}
+
+impl Default for DatabaseCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            r#type: "sqlite".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MemoryCfg {
+    pub engine: String, // lmdb | memory
+    pub path: String,   // only used for lmdb
+    pub read_only: bool, // lmdb replicas reading the files of another node
+}
+
+impl Default for MemoryCfg {
+    fn default() -> Self {
+        Self {
+            engine: "lmdb".into(),
+            path: "./data/bullg.lmdb".into(),
+            read_only: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdminCfg {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub captures: usize, // size of the body capture ring buffer
+}
+
+impl Default for AdminCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "127.0.0.1".into(),
+            port: 8001,
+            captures: 100,
+        }
+    }
+}
+
+impl AdminCfg {
+    pub fn get_address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+/// Request id sent back to the client and forwarded to the upstream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RequestIdCfg {
+    pub header: String,
+    pub trust_inbound: bool, // reuse the id of an inbound request carrying the header
+}
+
+impl Default for RequestIdCfg {
+    fn default() -> Self {
+        Self {
+            header: "x-request-id".into(),
+            trust_inbound: true,
+        }
+    }
+}
+
+/// Caps on the size of an applied services state, a state above any of
+/// them is rejected and the running one kept
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StateLimitsCfg {
+    pub max_services: usize,
+    pub max_routes_per_service: usize,
+    pub max_plugins: usize, // global, service and route plugins together
+}
+
+impl Default for StateLimitsCfg {
+    fn default() -> Self {
+        Self {
+            max_services: 10_000,
+            max_routes_per_service: 1_000,
+            max_plugins: 100_000,
+        }
+    }
+}
+
+/// Request bodies are buffered in memory up to `memory_limit` bytes and
+/// spilled to a temp file in `spill_dir` above it. Bodies above `max_size`
+/// are rejected with 413, 0 accepts any size.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BodyBufferCfg {
+    pub memory_limit: u64,
+    pub max_size: u64,
+    pub spill_dir: String, // empty for the system temp dir
+}
+
+impl Default for BodyBufferCfg {
+    fn default() -> Self {
+        Self {
+            memory_limit: 1024 * 1024,
+            max_size: 0,
+            spill_dir: String::new(),
+        }
+    }
+}
+
+/// Deadlines of upstream requests, 0 disables one. An upstream missing
+/// `timeout_ms` or `response_timeout_ms` gets 504, as does a buffered body
+/// stalling for `read_timeout_ms`, services can lower or raise them with a
+/// `timeout` policy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpstreamCfg {
+    pub connect_timeout_ms: u64,
+    pub timeout_ms: u64, // until the response headers, and the body when buffered
+    pub response_timeout_ms: u64, // until the response headers of one attempt
+    pub read_timeout_ms: u64, // between two body chunks
+}
+
+impl Default for UpstreamCfg {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: 5_000,
+            timeout_ms: 30_000,
+            response_timeout_ms: 0,
+            read_timeout_ms: 0,
+        }
+    }
+}
+
+/// Plugin execution trace. A request carrying `header` set to `token` gets
+/// an x-bullg-plugin-trace response header listing the plugins that ran,
+/// disabled while `token` is empty. Such a request may also name the
+/// upstream it goes to in `upstream_header`, whatever the balancer, routing
+/// rules and health say. Neither header is ever forwarded upstream.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DebugCfg {
+    pub header: String,
+    pub token: String,
+    pub upstream_header: String,
+}
+
+impl Default for DebugCfg {
+    fn default() -> Self {
+        Self {
+            header: "x-bullg-debug".into(),
+            token: String::new(),
+            upstream_header: "x-bullg-upstream".into(),
+        }
+    }
+}
+
+/// Maintenance mode. While it is on for the whole gateway or for a service
+/// in `services`, the requests get `status` with `body`, except those to an
+/// `exempt_paths` prefix or from an `exempt_ips` address. `enabled` and
+/// `services` are the state at startup, the admin API flips it at runtime.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaintenanceCfg {
+    pub enabled: bool,
+    pub services: Vec<String>,
+    pub status: u16,
+    pub body: String,
+    pub content_type: String,
+    /// Retry-After of the answer in seconds, 0 leaves it out
+    pub retry_after_sec: u64,
+    pub exempt_paths: Vec<String>,
+    pub exempt_ips: Vec<String>,
+}
+
+impl Default for MaintenanceCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            services: Vec::new(),
+            status: 503,
+            body: "service under maintenance".into(),
+            content_type: "text/plain; charset=utf-8".into(),
+            retry_after_sec: 0,
+            exempt_paths: Vec::new(),
+            exempt_ips: Vec::new(),
+        }
+    }
+}
+
+/// On SIGTERM or SIGINT the listener stops accepting, open connections get
+/// up to `drain_timeout_ms` to finish their requests before the process
+/// exits. Connections and upgrade tunnels still open then, like long lived
+/// streams or WebSockets, are closed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShutdownCfg {
+    pub drain_timeout_ms: u64,
+}
+
+impl Default for ShutdownCfg {
+    fn default() -> Self {
+        Self { drain_timeout_ms: 30_000 }
+    }
+}
+
+/// Rejects requests with 503 while the node is above a threshold, a zero
+/// threshold is not checked. Each request is classified into a priority
+/// class, by the first matching rule, else by the priority header, else
+/// `default_class`. A class is shed once the load passes `shed_at` times
+/// the thresholds, so lower classes go first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoadSheddingCfg {
+    pub enabled: bool,
+    pub max_in_flight: u64,
+    pub max_connections: u64,
+    pub max_latency_ms: u64, // moving average of the request latency
+    pub priority_header: String,
+    pub consumer_header: String, // consumer id set by the auth layer
+    pub default_class: String,
+    pub classes: Vec<PriorityClass>,
+    pub rules: Vec<PriorityRule>,
+}
+
+impl Default for LoadSheddingCfg {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_in_flight: 0,
+            max_connections: 0,
+            max_latency_ms: 0,
+            priority_header: "x-bullg-priority".into(),
+            consumer_header: "x-consumer-id".into(),
+            default_class: "normal".into(),
+            classes: vec![
+                PriorityClass { name: "low".into(), shed_at: Some(0.8) },
+                PriorityClass { name: "normal".into(), shed_at: Some(1.0) },
+                PriorityClass { name: "high".into(), shed_at: None },
+            ],
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl LoadSheddingCfg {
+    pub fn class(&self, name: &str) -> Option<&PriorityClass> {
+        self.classes.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct PriorityClass {
+    pub name: String,
+    /// Fraction of the thresholds above which the class is shed, None never sheds
+    #[serde(default)]
+    pub shed_at: Option<f64>,
+}
+
+/// Puts matching requests in `class`. Every non empty condition must match
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct PriorityRule {
+    pub class: String,
+    pub services: Vec<String>,
+    pub routes: Vec<String>,
+    pub consumers: Vec<String>,
+    pub headers: Vec<HeaderMatch>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeadersCfg {
+    pub invalid_utf8: InvalidUtf8,
+    pub max_count: usize, // requests with more header fields get 431
+    pub normalize: HeaderNormalizeCfg,
+    /// Upstream response headers never sent to clients, names or prefixes
+    /// ending in `*`, matched without case
+    pub strip_response: Vec<String>,
+}
+
+impl Default for HeadersCfg {
+    fn default() -> Self {
+        Self {
+            invalid_utf8: InvalidUtf8::default(),
+            max_count: 100,
+            normalize: HeaderNormalizeCfg::default(),
+            strip_response: [
+                "server",
+                "x-powered-by",
+                "x-aspnet-version",
+                "x-aspnetmvc-version",
+                "x-runtime",
+                "x-generator",
+                "x-backend-server",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+/// Header clean up of requests sent upstream (`request`) and of responses
+/// sent to clients (`response`). Repeated headers are joined into one comma
+/// separated value, except Set-Cookie which cannot be, Cookie is joined with
+/// `; `. `title_case` writes HTTP/1 names as `Content-Type` rather than in
+/// lower case.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeaderNormalizeCfg {
+    pub request: bool,
+    pub response: bool,
+    pub title_case: bool,
+    pub merge_duplicates: bool,
+    pub strip_empty: bool,
+}
+
+impl Default for HeaderNormalizeCfg {
+    fn default() -> Self {
+        Self {
+            request: false,
+            response: false,
+            title_case: false,
+            merge_duplicates: true,
+            strip_empty: true,
+        }
+    }
+}
+
+/// What a plugin reading a header value that is not valid UTF-8 gets. The
+/// raw bytes stay readable through `header_get_bytes` either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidUtf8 {
+    /// The header reads as absent
+    #[default]
+    Ignore,
+    /// The request is answered with 400
+    Reject,
+}
+
+/// Request paths are brought to their canonical form before routing:
+/// unreserved percent-encodings decoded, `.` and `..` segments resolved and,
+/// with `merge_slashes`, repeated slashes collapsed. Paths climbing above the
+/// root or with a malformed percent-encoding are always rejected with 400.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathsCfg {
+    pub mode: PathMode,
+    pub merge_slashes: bool,
+}
+
+impl Default for PathsCfg {
+    fn default() -> Self {
+        Self {
+            mode: PathMode::default(),
+            merge_slashes: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PathMode {
+    /// The canonical path is routed and forwarded
+    #[default]
+    Normalize,
+    /// A path that is not canonical is answered with 400
+    Reject,
+}
+
+/// Gateway wide HTTP method filter, applied before routing
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct MethodsCfg {
+    pub allow: Vec<String>, // empty allows every method not denied
+    pub deny: Vec<String>,
+}
+
+const STANDARD_METHODS: [&str; 9] = ["GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH"];
+
+impl MethodsCfg {
+    pub fn permits(&self, method: &str) -> bool {
+        let listed = |list: &[String]| list.iter().any(|m| m.eq_ignore_ascii_case(method));
+        (self.allow.is_empty() || listed(&self.allow)) && !listed(&self.deny)
+    }
+
+    /// Value for the `Allow` header of a rejected request
+    pub fn allowed(&self) -> String {
+        let allow: Vec<String> = if self.allow.is_empty() {
+            STANDARD_METHODS.map(String::from).to_vec()
+        } else {
+            self.allow.iter().map(|m| m.to_ascii_uppercase()).collect()
+        };
+        allow
+            .into_iter()
+            .filter(|m| self.permits(m))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
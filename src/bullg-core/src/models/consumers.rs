@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use uuid::Uuid;
 //----------------- Consumers Structure ----------------------
 
@@ -6,16 +7,96 @@ fn def_consumer_id() -> String {
     Uuid::new_v4().into()
 }
 
+fn def_enabled() -> bool {
+    true
+}
+
+/// Store database the gateway keeps the consumers in, keyed by consumer id
+pub const CONSUMERS_DB: &str = "consumers";
+
+/// Store database indexing the consumers by credential, each entry lists
+/// the ids of the enabled consumers holding it, in template order. Only
+/// narrows the lookup, the credential is still checked on the consumer.
+pub const CONSUMER_INDEX_DB: &str = "consumer_index";
+
+/// `CONSUMER_INDEX_DB` entry of an API key or app key
+pub fn api_key_entry(key: &str) -> String {
+    format!("key:{key}")
+}
+
+/// `CONSUMER_INDEX_DB` entry of a JWT key issuer, empty for keys without one
+pub fn issuer_entry(issuer: &str) -> String {
+    format!("iss:{issuer}")
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ConsumersTemplate {
     pub consumers: Vec<Consumer>,
 }
+
+impl ConsumersTemplate {
+    /// Entries of `CONSUMER_INDEX_DB` for these consumers
+    pub fn index(&self) -> HashMap<String, Vec<String>> {
+        let mut index: HashMap<String, Vec<String>> = HashMap::new();
+        for c in self.consumers.iter().filter(|c| c.enabled) {
+            let auth = c.authentication.as_ref().filter(|a| a.enabled);
+            let keys = auth.iter().flat_map(|a| &a.api_key).filter(|k| k.enabled).map(|k| api_key_entry(&k.key));
+            let app_keys = c.apps.iter().flatten().flat_map(|app| app.keys.iter().flatten()).map(|k| api_key_entry(k));
+            let issuers = auth.iter().flat_map(|a| &a.jwt).filter(|j| j.enabled).map(|j| issuer_entry(&j.issuer));
+            for entry in keys.chain(app_keys).chain(issuers) {
+                let ids = index.entry(entry).or_default();
+                if !ids.contains(&c.id) {
+                    ids.push(c.id.clone());
+                }
+            }
+        }
+        index
+    }
+}
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Consumer {
     #[serde(default = "def_consumer_id")]
     pub id: String,
+    #[serde(default = "def_enabled")]
+    pub enabled: bool,
     pub apps: Option<Vec<App>>,
     pub metadata: Option<serde_json::Value>,
+    pub authentication: Option<ConsumerAuth>,
+}
+
+/// Credentials a consumer authenticates with
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ConsumerAuth {
+    #[serde(default = "def_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub api_key: Vec<ApiKeyCredential>,
+    #[serde(default)]
+    pub jwt: Vec<JwtCredential>,
+}
+
+/// Key a consumer sends to `key_auth`
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ApiKeyCredential {
+    pub key: String,
+    #[serde(default = "def_enabled")]
+    pub enabled: bool,
+}
+
+/// Key a consumer signs its JWTs with, `secret` for HS256 or a PEM
+/// `public_key` for RS256. Tokens must carry `issuer` and `audience` when set.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct JwtCredential {
+    #[serde(default)]
+    pub secret: String,
+    #[serde(default)]
+    pub public_key: String,
+    #[serde(default)]
+    pub issuer: String,
+    #[serde(default)]
+    pub audience: String,
+    #[serde(default = "def_enabled")]
+    pub enabled: bool,
 }
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct App {
@@ -23,4 +104,39 @@ pub struct App {
     pub id: String,
     pub keys: Option<Vec<String>>,
     pub metadata: Option<serde_json::Value>,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn consumer(id: &str, enabled: bool, key: &str, issuer: &str) -> Consumer {
+        Consumer {
+            id: id.into(),
+            enabled,
+            authentication: Some(ConsumerAuth {
+                enabled: true,
+                api_key: vec![ApiKeyCredential { key: key.into(), enabled: true }],
+                jwt: vec![JwtCredential { secret: "s".into(), issuer: issuer.into(), enabled: true, ..Default::default() }],
+            }),
+            apps: Some(vec![App { id: "app".into(), keys: Some(vec![format!("{id}-app")]), metadata: None }]),
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn the_index_lists_enabled_consumers_by_credential() {
+        let template = ConsumersTemplate {
+            consumers: vec![
+                consumer("alice", true, "shared", ""),
+                consumer("bob", true, "shared", "idp"),
+                consumer("carol", false, "carol-key", "idp"),
+            ],
+        };
+        let index = template.index();
+        assert_eq!(index[&api_key_entry("shared")], ["alice", "bob"]);
+        assert_eq!(index[&api_key_entry("bob-app")], ["bob"]);
+        assert_eq!(index[&issuer_entry("")], ["alice"]);
+        assert_eq!(index[&issuer_entry("idp")], ["bob"]);
+        assert!(!index.contains_key(&api_key_entry("carol-key")));
+    }
+}
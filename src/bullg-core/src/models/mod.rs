@@ -10,6 +10,7 @@ pub use globals::*;
 pub use plugins::*;
 pub use services::*;
 
+use anyhow::Context;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json;
@@ -125,24 +126,80 @@ impl RuntimeSnapshot {
     }
 }
 
+/// Substitute `${NAME}` with the environment variable NAME and
+/// `${NAME:-default}` with the default when NAME is unset or empty, in any
+/// file format and in comments too. `$${` is a literal `${`.
+pub fn expand_env(content: &str) -> anyhow::Result<String> {
+    expand_vars(content, |name| std::env::var(name).ok())
+}
+
+fn expand_vars(content: &str, lookup: impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(at) = rest.find('$') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+            continue;
+        }
+        let Some(after) = rest.strip_prefix("${") else {
+            out.push('$');
+            rest = &rest[1..];
+            continue;
+        };
+        let line = content[..content.len() - rest.len()].matches('\n').count() + 1;
+        let end = after
+            .find(['}', '\n'])
+            .filter(|&end| after[end..].starts_with('}'))
+            .ok_or_else(|| anyhow::anyhow!("line {line}: unclosed `${{`, write `$${{` for a literal one"))?;
+        let (name, default) = match after[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&after[..end], None),
+        };
+        let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            anyhow::bail!("line {line}: `{name}` is not an environment variable name");
+        }
+        match (lookup(name).filter(|v| default.is_none() || !v.is_empty()), default) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(default)) => out.push_str(default),
+            (None, None) => anyhow::bail!("line {line}: environment variable {name} is not set and has no default"),
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn parse<T: DeserializeOwned + Default>(path: &str, content: &str) -> Result<T, anyhow::Error> {
+    if path.ends_with(".yaml") || path.ends_with(".yml") {
+        serde_yml::from_str(content).map_err(Into::into)
+    } else if path.ends_with(".json") {
+        serde_json::from_str(content).map_err(Into::into)
+    } else if path.ends_with(".toml") {
+        toml::from_str(content).map_err(Into::into)
+    } else {
+        Ok(T::default())
+    }
+}
+
 fn read_file<T>(path: &str) -> T
 where
-    T: DeserializeOwned + Default + Debug,
+    T: DeserializeOwned + Serialize + Default + Debug,
 {
     let content = fs::read_to_string(path);
     //println!("🔍 Loading Content: {:?}", content);
 
-    match content {
-        Ok(content) => {
-            let parsed: Result<T, anyhow::Error> = if path.ends_with(".yaml") || path.ends_with(".yml") {
-                serde_yml::from_str(&content).map_err(Into::into)
-            } else if path.ends_with(".json") {
-                serde_json::from_str(&content).map_err(Into::into)
-            } else if path.ends_with(".toml") {
-                toml::from_str(&content).map_err(Into::into)
-            } else {
-                Ok(T::default())
-            };
+    match content.map(|content| expand_env(&content)) {
+        Ok(Err(e)) => {
+            eprintln!("⚠️ Failed to expand environment variables in `{}`: {}", path, e);
+            T::default()
+        }
+        Ok(Ok(content)) => {
+            let parsed: Result<T, anyhow::Error> = parse(path, &content);
 
             match parsed {
                 Ok(val) => {
@@ -150,7 +207,11 @@ where
                 },
                 Err(_e) => {
                     eprintln!("⚠️ Failed to parse config file `{}`: {}", path, _e);
-                    T::default()
+                    // Retry section by section when the document itself is well formed
+                    match parse::<serde_json::Value>(path, &content) {
+                        Ok(value) => salvage(path, value),
+                        Err(_) => T::default(),
+                    }
                 }
             }
         }
@@ -161,6 +222,91 @@ where
     }
 }
 
+/// Rebuild `T` from the sections of `parsed` that are valid on their own.
+///
+/// Starting from the defaults each section is merged in only if the result
+/// still deserializes. A broken object is salvaged key by key and a broken
+/// list keeps its valid items, everything else falls back to its default.
+fn salvage<T>(path: &str, parsed: serde_json::Value) -> T
+where
+    T: DeserializeOwned + Serialize + Default,
+{
+    let Ok(mut merged) = serde_json::to_value(T::default()) else {
+        return T::default();
+    };
+    if serde_json::from_value::<T>(merged.clone()).is_err() {
+        return T::default();
+    }
+    merge_section::<T>(path, &mut merged, &mut Vec::new(), &parsed);
+    serde_json::from_value(merged).unwrap_or_default()
+}
+
+fn merge_section<T: DeserializeOwned>(
+    path: &str,
+    merged: &mut serde_json::Value,
+    at: &mut Vec<String>,
+    parsed: &serde_json::Value,
+) {
+    let Some(fields) = parsed.as_object() else {
+        return;
+    };
+    for (k, v) in fields {
+        at.push(k.clone());
+        let mut trial = merged.clone();
+        *section_mut(&mut trial, at) = v.clone();
+        match serde_json::from_value::<T>(trial.clone()) {
+            Ok(_) => *merged = trial,
+            Err(_) if v.is_object() && section(merged, at).is_some_and(|s| s.is_object()) => {
+                merge_section::<T>(path, merged, at, v);
+            }
+            Err(_) if v.is_array() => merge_items::<T>(path, merged, at, v),
+            Err(e) => {
+                eprintln!("⚠️ `{}`: section `{}` is invalid, using defaults: {}", path, at.join("."), e);
+            }
+        }
+        at.pop();
+    }
+}
+
+fn merge_items<T: DeserializeOwned>(
+    path: &str,
+    merged: &mut serde_json::Value,
+    at: &[String],
+    parsed: &serde_json::Value,
+) {
+    let items = parsed.as_array().cloned().unwrap_or_default();
+    let mut kept = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        let mut trial = merged.clone();
+        let mut candidate = kept.clone();
+        candidate.push(item);
+        *section_mut(&mut trial, at) = serde_json::Value::Array(candidate.clone());
+        match serde_json::from_value::<T>(trial) {
+            Ok(_) => kept = candidate,
+            Err(e) => {
+                eprintln!("⚠️ `{}`: item `{}[{}]` is invalid, skipping it: {}", path, at.join("."), i, e);
+            }
+        }
+    }
+    *section_mut(merged, at) = serde_json::Value::Array(kept);
+}
+
+fn section<'a>(value: &'a serde_json::Value, at: &[String]) -> Option<&'a serde_json::Value> {
+    at.iter().try_fold(value, |v, k| v.get(k))
+}
+
+/// Section at `at`, created as an empty object if missing
+fn section_mut<'a>(value: &'a mut serde_json::Value, at: &[String]) -> &'a mut serde_json::Value {
+    at.iter().fold(value, |v, k| {
+        if !v.is_object() {
+            *v = serde_json::Value::Object(Default::default());
+        }
+        v.as_object_mut()
+            .map(|m| m.entry(k.clone()).or_insert(serde_json::Value::Null))
+            .unwrap()
+    })
+}
+
 pub fn load_all(config_p: &str, plugins_p: &str, consumers_p: &str, services_p: &str) -> RuntimeSnapshot {
     let config: GatewayConfig = read_file(config_p);
     let plugins_catalog: PluginsCatalog = read_file(plugins_p);
@@ -176,4 +322,114 @@ pub fn load_all(config_p: &str, plugins_p: &str, consumers_p: &str, services_p:
         services,
         snapshot_id: Uuid::new_v4(),
     }
-}
\ No newline at end of file
+}
+
+/// Read a file like `load_all` does, but failing when it cannot be read or
+/// parsed instead of falling back to defaults, for reloads that must keep
+/// the running state when a file is broken. An empty path is the default.
+pub fn try_read_file<T: DeserializeOwned + Default>(path: &str) -> anyhow::Result<T> {
+    if path.is_empty() {
+        return Ok(T::default());
+    }
+    let content = fs::read_to_string(path).with_context(|| format!("cannot read `{path}`"))?;
+    let content = expand_env(&content).with_context(|| format!("cannot expand `{path}`"))?;
+    parse(path, &content).with_context(|| format!("cannot parse `{path}`"))
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_file(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bullg-{}-{name}", Uuid::new_v4()));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn a_malformed_section_falls_back_to_its_default_only() {
+        let path = temp_file("config.yaml", "gateway:\n  name: edge\n  port: not-a-port\n  admin:\n    enabled: true\n");
+        let config: GatewayConfig = read_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.gateway.name, "edge");
+        assert_eq!(config.gateway.port, GatewayNode::default().port);
+        assert!(config.gateway.admin.enabled);
+    }
+
+    #[test]
+    fn invalid_list_items_are_skipped() {
+        let service = |id: &str| serde_json::to_value(Service { id: id.into(), ..Default::default() }).unwrap();
+        let mut broken = service("broken");
+        broken["name"] = json!(["not", "a", "name"]);
+        let parsed = json!({"services": [service("users"), broken, service("orders")]});
+        let services: ServicesTemplate = salvage("services.json", parsed);
+        let ids: Vec<&str> = services.services.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, ["users", "orders"]);
+    }
+
+    #[test]
+    fn unparsable_documents_are_the_default() {
+        let path = temp_file("services.json", "{\"services\": [");
+        let services: ServicesTemplate = read_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(services.services.is_empty());
+        assert!(try_read_file::<ServicesTemplate>(&path).is_err());
+    }
+
+    fn expand(content: &str) -> anyhow::Result<String> {
+        expand_vars(content, |name| match name {
+            "HOST" => Some("users.internal".into()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn variables_are_substituted_or_defaulted() {
+        assert_eq!(expand("host: ${HOST}:${PORT:-8080}").unwrap(), "host: users.internal:8080");
+        assert_eq!(expand("${HOST:-fallback}").unwrap(), "users.internal");
+        // Empty counts as unset when there is a default
+        assert_eq!(expand("[${EMPTY:-none}] [${EMPTY}] [${PORT:-}]").unwrap(), "[none] [] []");
+        assert_eq!(expand("url: ${SCHEME:-http://a:1/?q=$x}").unwrap(), "url: http://a:1/?q=$x");
+    }
+
+    #[test]
+    fn dollars_outside_references_are_kept() {
+        assert_eq!(expand("price: $5, $$, $${HOST} and ${HOST}").unwrap(), "price: $5, $$, ${HOST} and users.internal");
+        assert_eq!(expand("trailing $").unwrap(), "trailing $");
+        assert_eq!(expand("").unwrap(), "");
+    }
+
+    #[test]
+    fn broken_references_are_errors_with_their_line() {
+        let err = |content: &str| expand(content).unwrap_err().to_string();
+        assert_eq!(err("a: 1\nkey: ${API_KEY}"), "line 2: environment variable API_KEY is not set and has no default");
+        assert_eq!(err("a: ${HOST\n}"), "line 1: unclosed `${`, write `$${` for a literal one");
+        assert_eq!(err("a: ${HOST"), "line 1: unclosed `${`, write `$${` for a literal one");
+        assert_eq!(err("\n\na: ${1ST}"), "line 3: `1ST` is not an environment variable name");
+        assert_eq!(err("a: ${}"), "line 1: `` is not an environment variable name");
+    }
+
+    #[test]
+    fn every_format_is_expanded_before_parsing() {
+        let unset = "BULLG_TEST_UNSET_VARIABLE";
+        for (name, content) in [
+            ("config.yaml", format!("gateway:\n  name: ${{{unset}:-edge}}\n")),
+            ("config.json", format!("{{\"gateway\": {{\"name\": \"${{{unset}:-edge}}\"}}}}")),
+            ("config.toml", format!("[gateway]\nname = \"${{{unset}:-edge}}\"\n")),
+        ] {
+            let path = temp_file(name, &content);
+            let config: GatewayConfig = try_read_file(&path).unwrap();
+            assert_eq!(config.gateway.name, "edge", "{name}");
+            fs::remove_file(&path).unwrap();
+        }
+
+        let path = temp_file("config.yaml", &format!("gateway:\n  name: ${{{unset}}}\n"));
+        let err = try_read_file::<GatewayConfig>(&path).unwrap_err();
+        assert_eq!(format!("{err:#}"), format!("cannot expand `{path}`: line 2: environment variable {unset} is not set and has no default"));
+        // Loading falls back to the defaults
+        let config: GatewayConfig = read_file(&path);
+        assert_eq!(config.gateway.name, GatewayNode::default().name);
+        fs::remove_file(&path).unwrap();
+    }
+}
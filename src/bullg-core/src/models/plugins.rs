@@ -1,7 +1,11 @@
+use bullg_utils::{de_duration_opt, ser_duration_opt};
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::time::Duration;
 use uuid::Uuid;
 
+use crate::{Runner, RunnerLimits};
+
 // ---------- plugins Structure Models (catalog, not applied) ----------
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct PluginsCatalog {
@@ -9,6 +13,57 @@ pub struct PluginsCatalog {
     pub policies: Vec<CatalogPolicy>,
     pub required_policies: Vec<String>,
     pub required_plugins: Vec<String>,
+    #[serde(default)]
+    pub runner: RunnerLimitsCfg,
+}
+
+impl PluginsCatalog {
+    /// Script limits for a plugin: defaults, then the catalog wide `runner`
+    /// limits, then the plugin's own `limits`
+    pub fn runner_limits(&self, plugin_id: &str) -> RunnerLimits {
+        let global = RunnerLimits::default().with_overrides(&self.runner);
+        let plugin = self
+            .plugins
+            .builtin
+            .iter()
+            .map(|p| (&p.id, &p.limits))
+            .chain(self.plugins.custom.iter().flatten().map(|p| (&p.id, &p.limits)))
+            .chain(self.policies.iter().map(|p| (&p.id, &p.limits)))
+            .find(|(id, _)| id.as_str() == plugin_id)
+            .and_then(|(_, limits)| limits.as_ref());
+        match plugin {
+            Some(cfg) => global.with_overrides(cfg),
+            None => global,
+        }
+    }
+
+    /// Script runner built with `runner_limits` for the plugin
+    pub fn runner(&self, plugin_id: &str) -> Runner {
+        Runner::new_with_limits(self.runner_limits(plugin_id))
+    }
+}
+
+/// Script runner limits from config, unset values keep the defaults
+///
+/// ```yaml
+/// runner:
+///   max_time: 200ms
+///   max_code_bytes: 131072
+///   max_args_bytes: 65536
+///   rhai_max_ops: 200000
+///   rhai_max_call_depth: 64
+///   allow_network: false
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct RunnerLimitsCfg {
+    #[serde(deserialize_with = "de_duration_opt", serialize_with = "ser_duration_opt")]
+    pub max_time: Option<Duration>,
+    pub max_code_bytes: Option<usize>,
+    pub max_args_bytes: Option<usize>,
+    pub rhai_max_ops: Option<u64>,
+    pub rhai_max_call_depth: Option<usize>,
+    pub allow_network: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -34,6 +89,8 @@ pub struct BuiltinPluginSpec {
     pub phases: Vec<String>,
     pub schema: SchemaDecl,
     pub handler: HandlerDecl,
+    #[serde(default)]
+    pub limits: Option<RunnerLimitsCfg>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct CustomPluginSpec {
@@ -49,6 +106,8 @@ pub struct CustomPluginSpec {
     pub phases: Vec<String>,
     pub schema: SchemaDecl,
     pub handler: HandlerDecl,
+    #[serde(default)]
+    pub limits: Option<RunnerLimitsCfg>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct SchemaDecl {
@@ -83,4 +142,50 @@ pub struct CatalogPolicy {
     pub phases: Vec<String>,
     pub schema: SchemaDecl,
     pub handler: HandlerDecl,
-}
\ No newline at end of file
+    #[serde(default)]
+    pub limits: Option<RunnerLimitsCfg>,
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn custom(id: &str, limits: Option<RunnerLimitsCfg>) -> CustomPluginSpec {
+        CustomPluginSpec { id: id.into(), limits, ..Default::default() }
+    }
+
+    #[test]
+    fn plugin_limits_override_catalog_limits_over_defaults() {
+        let catalog = PluginsCatalog {
+            runner: RunnerLimitsCfg { max_time: Some(Duration::from_millis(50)), max_code_bytes: Some(10), ..Default::default() },
+            plugins: CatalogPlugins {
+                custom: Some(vec![custom(
+                    "auth",
+                    Some(RunnerLimitsCfg { max_time: Some(Duration::from_millis(5)), allow_network: Some(true), ..Default::default() }),
+                )]),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let auth = catalog.runner_limits("auth");
+        assert_eq!(auth.max_time, Duration::from_millis(5));
+        assert_eq!(auth.max_code_bytes, 10);
+        assert!(auth.allow_network);
+        assert_eq!(auth.rhai_max_ops, RunnerLimits::default().rhai_max_ops);
+
+        let other = catalog.runner_limits("other");
+        assert_eq!(other.max_time, Duration::from_millis(50));
+        assert!(!other.allow_network);
+        assert_eq!(catalog.runner("auth").limits().max_time, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn the_example_catalog_parses() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../examples/plugins.yaml");
+        let catalog: PluginsCatalog = crate::try_read_file(path).unwrap();
+        let auth = catalog.plugins.custom.iter().flatten().find(|p| p.id == "custom-auth").unwrap();
+        assert_eq!(auth.handler.language, "python");
+        assert_eq!(catalog.runner_limits("custom-auth").max_time, Duration::from_millis(200));
+        assert_eq!(catalog.runner_limits("cors").max_time, Duration::from_millis(50));
+    }
+}
@@ -131,6 +131,18 @@ pub struct GlobalApplied {
     pub policies: Vec<AppliedPolicy>,
 }
 
+/// Change to the running services template, services are matched by id
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct StateDelta {
+    /// Services added, or replacing the one with the same id
+    pub upsert: Vec<Service>,
+    /// Ids of the services removed
+    pub remove: Vec<String>,
+    /// Replaces the global plugins and policies when set
+    pub global: Option<GlobalApplied>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct ServiceMapper {
     pub key: String,
@@ -192,6 +204,16 @@ pub struct Service {
     pub routes: Vec<Route>,
     #[serde(default)]
     pub router: BullGRoute,
+    /// Upstream selection rules, checked after the matched route's own rules
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+    /// Headers added to every upstream response, before the route ones
+    #[serde(default)]
+    pub response_headers: Vec<ResponseHeader>,
+    /// Requests no auth plugin identified a consumer for are answered 401,
+    /// whatever plugins are applied. Routes may opt out.
+    #[serde(default)]
+    pub require_auth: bool,
 }
 
 impl ToServiceMapper for Service {
@@ -204,7 +226,7 @@ impl ToServiceMapper for Service {
             let key = self
                 .context_paths
                 .paths
-                .get(0)
+                .first()
                 .map(|cp| cp.path.as_str())
                 .unwrap_or(&self.name);
             maps.push(ServiceMapper { key: key.to_string(), value: self.clone() });
@@ -483,6 +505,16 @@ pub struct Upstream {
     pub port: u16,
     pub enabled: bool,
     pub versions: Vec<String>,
+    /// Base path put in front of every forwarded path, e.g. `/api`
+    #[serde(default)]
+    pub path: String,
+    /// Share of the requests under weighted balancing, 0 takes none
+    #[serde(default = "def_weight")]
+    pub weight: u32,
+}
+
+fn def_weight() -> u32 {
+    1
 }
 
 
@@ -498,7 +530,21 @@ impl Upstream {
     pub fn get_address(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
-    
+
+    /// Scheme used to reach the upstream: https on 443 or when plain http is not offered
+    pub fn get_scheme(&self) -> &'static str {
+        let https = self.protocols.contains(&Protocols::HTTPS);
+        if self.port == 443 || (https && !self.protocols.contains(&Protocols::HTTP)) {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    pub fn get_url(&self) -> String {
+        format!("{}://{}", self.get_scheme(), self.get_address())
+    }
+
 }
 
 
@@ -537,8 +583,26 @@ pub struct AppliedPlugin {
     pub version: Option<String>,
     pub versions: Option<Vec<String>>,
     pub config: Option<serde_json::Value>,
+    /// Run order among plugins of the same priority, lower first
     pub order: Option<u32>,
+    /// Plugins of a list run by priority, higher first
     pub priority: Option<u32>,
+    /// Keep serving when the plugin errors or panics (the default), false
+    /// answers the request with 500 instead
+    #[serde(default)]
+    pub fail_open: Option<bool>,
+}
+
+impl AppliedPlugin {
+    pub fn fails_open(&self) -> bool {
+        self.fail_open.unwrap_or(true)
+    }
+
+    /// Sort one plugin list in run order: higher `priority` first, then
+    /// lower `order`, unset ones counting as 0. Ties keep the listed order.
+    pub fn sort(plugins: &mut [AppliedPlugin]) {
+        plugins.sort_by_key(|p| (std::cmp::Reverse(p.priority.unwrap_or(0)), p.order.unwrap_or(0)));
+    }
 }
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct AppliedPolicy {
@@ -569,6 +633,47 @@ pub struct Route {
     pub versions: Vec<String>,
     pub config: RouteConfig,
     pub plugins: Vec<AppliedPlugin>,
+    #[serde(default)]
+    pub rules: Vec<RoutingRule>,
+    /// Overrides the service `require_auth` for this route
+    #[serde(default)]
+    pub require_auth: Option<bool>,
+}
+
+/// Sends matching requests to one of the service upstreams instead of the
+/// first enabled one. Rules are checked in order and the first match wins;
+/// a rule matches when every header matches and the request falls in
+/// `percent` (all requests when unset).
+///
+/// ```yaml
+/// rules:
+///   - id: beta
+///     upstream: users-v2
+///     headers:
+///       - { name: x-beta, value: "true" }
+///   - id: canary
+///     upstream: users-v2
+///     percent: 10
+///     headers:
+///       - { name: x-tenant } # only presence is checked
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RoutingRule {
+    #[serde(default)]
+    pub id: String,
+    pub upstream: String,
+    #[serde(default)]
+    pub headers: Vec<HeaderMatch>,
+    #[serde(default)]
+    pub percent: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct HeaderMatch {
+    pub name: String,
+    /// Exact value, case sensitive. None only checks the header is present
+    #[serde(default)]
+    pub value: Option<String>,
 }
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct RouteConfig {
@@ -576,6 +681,45 @@ pub struct RouteConfig {
     pub path: String,
     pub backend: String,
     pub methods: Vec<String>,
+    /// Forward the path without the route `path` prefix
+    #[serde(default)]
+    pub strip_path: bool,
+    /// Headers added to every upstream response of the route
+    #[serde(default)]
+    pub response_headers: Vec<ResponseHeader>,
+}
+
+/// Static header added to upstream responses, before the post plugins run.
+/// `override` replaces what the upstream sent, `append` keeps it and adds
+/// the value, `if_missing` only sets it when the upstream sent none.
+///
+/// ```yaml
+/// response_headers:
+///   - { name: cache-control, value: "public, max-age=60", mode: if_missing }
+///   - { name: x-api-version, value: "2" }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct ResponseHeader {
+    pub name: String,
+    pub value: String,
+    #[serde(default)]
+    pub mode: HeaderMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HeaderMode {
+    #[default]
+    Override,
+    Append,
+    IfMissing,
+}
+
+impl RouteConfig {
+    /// An empty `methods` list allows every method
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
 }
 
 
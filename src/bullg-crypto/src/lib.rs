@@ -1,9 +1,12 @@
 // src/lib.rs
-use base64::{ engine::general_purpose::URL_SAFE_NO_PAD, Engine as _ };
+use base64::{ engine::general_purpose::{ STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD }, Engine as _ };
 use regex::Regex;
 use sha2::{ Sha256, Sha512, Digest };
 use md5::{ Md5 };
 use hmac::{ Hmac, Mac };
+use ed25519_dalek::{ Signature, VerifyingKey };
+use argon2::{ Algorithm, Argon2, Params, Version };
+use argon2::password_hash::{ PasswordHasher, PasswordVerifier, phc::PasswordHash };
 use rand::Rng;
 use std::collections::HashMap;
 
@@ -15,6 +18,33 @@ pub fn remove_special_characters(text: &str) -> String {
     re.replace_all(text, "").to_string()
 }
 
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok()).collect()
+}
+
+pub use argon2::password_hash::Error as PasswordError;
+
+/// Argon2id cost of a password hash, the OWASP minimum by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordCost {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordCost {
+    fn default() -> Self {
+        Self { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+    }
+}
+
 pub struct BullGCrypto {
     key: String,
     version: String,
@@ -63,6 +93,30 @@ impl BullGCrypto {
         (0..len).map(|_| format!("{:x}", rng.random_range(0..16))).collect()
     }
 
+    /// Argon2id hash of a password at the default cost, as a PHC string
+    /// (`$argon2id$v=19$m=...`) carrying its random salt and cost
+    pub fn hash_password(password: &str) -> Result<String, PasswordError> {
+        Self::hash_password_with(password, &PasswordCost::default())
+    }
+
+    /// Argon2id hash of a password at `cost`, for callers that tune it to
+    /// their hardware. `verify_password` reads the cost back from the hash.
+    pub fn hash_password_with(password: &str, cost: &PasswordCost) -> Result<String, PasswordError> {
+        let params = Params::new(cost.memory_kib, cost.iterations, cost.parallelism, None)?;
+        let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password(password.as_bytes())?;
+        Ok(hash.to_string())
+    }
+
+    /// Check a password against a PHC string of `hash_password`, with the
+    /// cost it was hashed at. The hashes are compared in constant time.
+    pub fn verify_password(password: &str, hashed: &str) -> bool {
+        let Ok(hash) = PasswordHash::new(hashed) else {
+            return false;
+        };
+        Argon2::default().verify_password(password.as_bytes(), &hash).is_ok()
+    }
+
+    #[deprecated(note = "not a password KDF and fast to brute force, use `hash_password`")]
     pub fn hash_bullg_password(password: &str, salt: Option<&str>) -> (String, String) {
         let generated_salt = Self::generate_salt(16);
         let salt_val = salt.unwrap_or(&generated_salt);
@@ -71,22 +125,26 @@ impl BullGCrypto {
 
         let mut sha1 = sha1::Sha1::new();
         sha1.update(&bytes);
-        let md5_hash = Md5::digest(&sha1.finalize());
+        let md5_hash = Md5::digest(sha1.finalize());
 
         let mut sha512 = Sha512::new();
-        sha512.update(&md5_hash);
+        sha512.update(md5_hash);
         let sha512_result = sha512.finalize();
 
         let mut sha256 = Sha256::new();
-        sha256.update(&sha512_result);
+        sha256.update(sha512_result);
         let hashed = format!("{:x}", sha256.finalize());
 
         (hashed, salt_val.to_string())
     }
 
+    /// Check a password against a `hash_bullg_password` hash, in constant time
+    #[deprecated(note = "checks `hash_bullg_password` hashes, use `verify_password`")]
+    #[allow(deprecated)]
     pub fn check_password(password: &str, salt: &str, hashed: &str) -> bool {
         let (real, _) = Self::hash_bullg_password(password, Some(salt));
-        real == hashed
+        real.len() == hashed.len()
+            && real.bytes().zip(hashed.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
     }
 
     pub fn b64_encode_nopad(data: &str) -> String {
@@ -111,10 +169,19 @@ impl BullGCrypto {
         format!("{}{}{}", salt, b64, salt)
     }
 
+    /// Inverse of `encode_data`, empty when `data` was not encoded with `token`
     pub fn decode_data(data: &str, token: &str) -> String {
         let salt = Self::key_to_salt(token);
-        let decoded = String::from_utf8(Self::b64_decode_nopad(data)).unwrap_or_default();
-        decoded.replace(&salt, "")
+        let Some(inner) = data.strip_prefix(&salt).and_then(|d| d.strip_suffix(&salt)) else {
+            return String::new();
+        };
+        let decoded = String::from_utf8(Self::b64_decode_nopad(inner)).unwrap_or_default();
+        // Only the wrapping salts go, the data itself may contain the salt
+        decoded
+            .strip_prefix(&salt)
+            .and_then(|d| d.strip_suffix(&salt))
+            .unwrap_or_default()
+            .to_string()
     }
 
     pub fn encrypt_data(map: HashMap<String, String>, key: &str) -> HashMap<String, String> {
@@ -129,7 +196,171 @@ impl BullGCrypto {
             .collect()
     }
 
+    pub fn sign_hmac_sha256(data: &[u8], secret: &[u8]) -> Vec<u8> {
+        let mut mac = HmacSha256::new_from_slice(secret).unwrap();
+        mac.update(data);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Constant time check of an HMAC-SHA256 `signature` over `data`
+    pub fn verify_hmac_sha256(data: &[u8], secret: &[u8], signature: &[u8]) -> bool {
+        let Ok(mut mac) = HmacSha256::new_from_slice(secret) else {
+            return false;
+        };
+        mac.update(data);
+        mac.verify_slice(signature).is_ok()
+    }
+
+    /// Hex HMAC-SHA256 of `payload`, as webhook signatures are usually sent
+    pub fn hmac_sign(payload: &[u8], key: &[u8]) -> String {
+        to_hex(&Self::sign_hmac_sha256(payload, key))
+    }
+
+    /// Constant time check of a `hmac_sign` signature, given in hex or base64
+    pub fn hmac_verify(payload: &[u8], key: &[u8], signature: &str) -> bool {
+        let signature = signature.trim();
+        let Some(raw) = from_hex(signature).or_else(|| {
+            [&STANDARD, &URL_SAFE, &URL_SAFE_NO_PAD, &STANDARD_NO_PAD]
+                .iter()
+                .find_map(|engine| engine.decode(signature).ok())
+        }) else {
+            return false;
+        };
+        Self::verify_hmac_sha256(payload, key, &raw)
+    }
+
+    /// Lowercase hex SHA-256 of `data`
+    pub fn sha256_hex(data: &[u8]) -> String {
+        to_hex(&Sha256::digest(data))
+    }
+
+    /// Check an Ed25519 `signature` over `data` against a raw 32 byte public key
+    pub fn verify_ed25519(data: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+        let Ok(key) = <[u8; 32]>::try_from(public_key) else {
+            return false;
+        };
+        let Ok(key) = VerifyingKey::from_bytes(&key) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(signature) else {
+            return false;
+        };
+        key.verify_strict(data, &signature).is_ok()
+    }
+
     pub fn int_to_base64(value: u128) -> String {
         URL_SAFE_NO_PAD.encode(value.to_be_bytes()).trim_end_matches('=').to_string()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encoded_data_decodes_back() {
+        let salt = BullGCrypto::key_to_salt("map-key");
+        let cases = ["", "secret", "héllo wörld", "日本語 🦀", "a=b&c", &salt, &format!("{salt}x{salt}")];
+        for key in ["map-key", "", "clé-ключ"] {
+            for data in cases {
+                let encoded = BullGCrypto::encode_data(data, key);
+                assert_eq!(BullGCrypto::decode_data(&encoded, key), data, "{data:?} under {key:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn random_strings_round_trip() {
+        let mut rng = rand::rng();
+        for _ in 0..500 {
+            let len = rng.random_range(0..64);
+            let data: String = (0..len).map(|_| rng.random::<char>()).collect();
+            let key: String = (0..rng.random_range(0..16)).map(|_| rng.random::<char>()).collect();
+            let encoded = BullGCrypto::encode_data(&data, &key);
+            assert_eq!(BullGCrypto::decode_data(&encoded, &key), data, "{data:?} under {key:?}");
+        }
+    }
+
+    #[test]
+    fn data_of_another_key_decodes_empty() {
+        let encoded = BullGCrypto::encode_data("secret", "map-key");
+        assert_eq!(BullGCrypto::decode_data(&encoded, "other-key"), "");
+        assert_eq!(BullGCrypto::decode_data("secret", "map-key"), "");
+        assert_eq!(BullGCrypto::decode_data("", "map-key"), "");
+    }
+
+    #[test]
+    fn maps_round_trip() {
+        let map = HashMap::from([("user".to_string(), "alice".to_string()), ("note".to_string(), "ünïcode ✓".to_string())]);
+        let encrypted = BullGCrypto::encrypt_data(map.clone(), "map-key");
+        assert!(encrypted.values().all(|v| !map.values().any(|plain| plain == v)));
+        assert_eq!(BullGCrypto::decrypt_data(encrypted, "map-key"), map);
+    }
+
+    // Cheap enough for tests, the default takes 19 MiB per hash
+    const TEST_COST: PasswordCost = PasswordCost { memory_kib: 1024, iterations: 1, parallelism: 1 };
+
+    #[test]
+    fn password_hashes_verify() {
+        let hashed = BullGCrypto::hash_password("correct horse").unwrap();
+        assert!(hashed.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"), "{hashed}");
+        assert!(BullGCrypto::verify_password("correct horse", &hashed));
+        assert!(!BullGCrypto::verify_password("correct horse ", &hashed));
+        assert!(!BullGCrypto::verify_password("", &hashed));
+    }
+
+    #[test]
+    fn password_hashes_carry_their_cost_and_salt() {
+        let cost = PasswordCost { memory_kib: 2048, iterations: 3, parallelism: 2 };
+        let hashed = BullGCrypto::hash_password_with("secret", &cost).unwrap();
+        assert!(hashed.starts_with("$argon2id$v=19$m=2048,t=3,p=2$"), "{hashed}");
+        assert!(BullGCrypto::verify_password("secret", &hashed));
+
+        // A random salt per hash
+        let again = BullGCrypto::hash_password_with("secret", &cost).unwrap();
+        assert_ne!(hashed, again);
+        assert!(BullGCrypto::verify_password("secret", &again));
+
+        let too_cheap = PasswordCost { memory_kib: 1, ..TEST_COST };
+        assert!(BullGCrypto::hash_password_with("secret", &too_cheap).is_err());
+    }
+
+    #[test]
+    fn malformed_password_hashes_do_not_verify() {
+        let hashed = BullGCrypto::hash_password_with("secret", &TEST_COST).unwrap();
+        let (head, digest) = hashed.rsplit_once('$').unwrap();
+        let flipped = if digest.starts_with('A') { 'B' } else { 'A' };
+        for bad in [
+            String::new(),
+            "secret".to_string(),
+            "$argon2id$v=19$m=1024,t=1,p=1".to_string(),
+            hashed.replace("argon2id", "scrypt"),
+            format!("{head}$"),
+            format!("{head}${flipped}{}", &digest[1..]),
+        ] {
+            assert!(!BullGCrypto::verify_password("secret", &bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn hmac_signatures_verify_in_hex_or_base64() {
+        let payload = b"POST\n/hooks\nMon, 12 Oct 2026 10:00:00 +0000\ne3b0c442";
+        let signature = BullGCrypto::hmac_sign(payload, b"secret");
+        assert_eq!(signature.len(), 64);
+        assert!(signature.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)));
+        assert!(BullGCrypto::hmac_verify(payload, b"secret", &signature));
+        assert!(BullGCrypto::hmac_verify(payload, b"secret", &signature.to_uppercase()));
+        assert!(BullGCrypto::hmac_verify(payload, b"secret", &format!(" {signature}\n")));
+
+        let raw = BullGCrypto::sign_hmac_sha256(payload, b"secret");
+        for engine in [&STANDARD, &URL_SAFE, &URL_SAFE_NO_PAD, &STANDARD_NO_PAD] {
+            assert!(BullGCrypto::hmac_verify(payload, b"secret", &engine.encode(&raw)));
+        }
+
+        assert!(!BullGCrypto::hmac_verify(b"tampered", b"secret", &signature));
+        assert!(!BullGCrypto::hmac_verify(payload, b"other", &signature));
+        assert!(!BullGCrypto::hmac_verify(payload, b"secret", &signature[..62]));
+        assert!(!BullGCrypto::hmac_verify(payload, b"secret", ""));
+        assert!(!BullGCrypto::hmac_verify(payload, b"secret", "not a signature!"));
+    }
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode, header::HeaderValue};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::tokio::TokioIo;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::{Gateway, GatewayBody, simple};
+
+impl Gateway {
+    /// Serve the admin API, meant for a private interface only
+    pub async fn serve_admin(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("admin API listening on {}", addr);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let me = self.clone();
+            tokio::spawn(async move {
+                let io = TokioIo::new(stream);
+                let conn = http1::Builder::new().serve_connection(
+                    io,
+                    service_fn(move |req| {
+                        let me = me.clone();
+                        async move { Ok::<_, hyper::Error>(me.admin(req)) }
+                    }),
+                );
+                if let Err(e) = conn.await {
+                    error!("admin conn error: {e}");
+                }
+            });
+        }
+    }
+
+    fn admin(&self, req: Request<Incoming>) -> Response<GatewayBody> {
+        match (req.method(), req.uri().path()) {
+            (&Method::GET, "/captures") => json(&self.captures.list()),
+            (&Method::DELETE, "/captures") => {
+                self.captures.clear();
+                simple(StatusCode::NO_CONTENT, Bytes::new())
+            }
+            (&Method::GET, "/routes") => json(&self.catalog()),
+            (&Method::GET, "/routes/openapi") => json(&self.openapi()),
+            (&Method::GET, "/maintenance") => json(&self.maintenance.status()),
+            (method @ (&Method::PUT | &Method::DELETE), "/maintenance") => {
+                self.maintenance.set_global(method == Method::PUT);
+                json(&self.maintenance.status())
+            }
+            (method @ (&Method::PUT | &Method::DELETE), path)
+                if let Some(service) = path.strip_prefix("/maintenance/services/")
+                    && !service.is_empty() =>
+            {
+                self.maintenance.set_service(service, method == Method::PUT);
+                json(&self.maintenance.status())
+            }
+            _ => simple(StatusCode::NOT_FOUND, Bytes::from_static(b"not found")),
+        }
+    }
+}
+
+fn json<T: serde::Serialize>(value: &T) -> Response<GatewayBody> {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut resp = simple(StatusCode::OK, Bytes::from(body));
+            resp.headers_mut()
+                .insert("content-type", HeaderValue::from_static("application/json"));
+            resp
+        }
+        Err(e) => simple(StatusCode::INTERNAL_SERVER_ERROR, Bytes::from(e.to_string())),
+    }
+}
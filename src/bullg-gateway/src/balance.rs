@@ -0,0 +1,228 @@
+use bullg_core::Upstream;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Weight of the newest sample in the latency average
+const EWMA_WEIGHT: f64 = 0.2;
+
+/// Upstream load balancing (`type: load_balancer` on a service or global policy).
+///
+/// Picks among the enabled, healthy upstreams when no routing rule chose one,
+/// in turn unless a policy picks another strategy.
+///
+/// `weighted` spreads requests by the upstream `weight`, interleaved rather
+/// than in runs, equal weights go in turn in the service order and weight 0
+/// takes none. `least_conn` picks the upstream with the fewest requests in
+/// flight, ties go in turn like `round_robin`. `latency` tracks a moving
+/// average of each upstream response time and compares two random upstreams,
+/// the faster one is picked in proportion to how much faster it is.
+/// Upstreams without a sample yet are tried first.
+///
+/// ```yaml
+/// - id: svc-lb
+///   type: load_balancer
+///   enabled: true
+///   config:
+///     strategy: latency # first, round_robin (default), weighted, least_conn or latency
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct LoadBalancePolicy {
+    #[serde(default)]
+    pub strategy: Strategy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// The first upstream in the service order
+    First,
+    /// Each upstream in turn, a counter per service
+    #[default]
+    RoundRobin,
+    /// Each upstream in proportion to its `weight`
+    Weighted,
+    /// The upstream with the fewest requests in flight
+    LeastConn,
+    Latency,
+}
+
+impl LoadBalancePolicy {
+    pub const KIND: &'static str = "load_balancer";
+}
+
+fn key(service: &str, upstream: &str) -> String {
+    format!("{service}/{upstream}")
+}
+
+/// Balancing state shared by every request: round robin cursors and smooth
+/// weighted counters per service, requests in flight and latency averages per
+/// service upstream
+#[derive(Default)]
+pub struct Balancer {
+    cursors: DashMap<String, AtomicUsize>,
+    // service -> upstream id -> current weight
+    weights: DashMap<String, HashMap<String, i64>>,
+    in_flight: DashMap<String, Arc<AtomicUsize>>,
+    // f64 bits of the latency moving average in microseconds
+    latency: DashMap<String, AtomicU64>,
+}
+
+impl Balancer {
+    pub fn pick<'a>(&self, service: &str, policy: &LoadBalancePolicy, upstreams: &[&'a Upstream]) -> Option<&'a Upstream> {
+        if upstreams.len() <= 1 && policy.strategy != Strategy::Weighted {
+            return upstreams.first().copied();
+        }
+        match policy.strategy {
+            Strategy::First => upstreams.first().copied(),
+            Strategy::RoundRobin => upstreams.get(self.next(service) % upstreams.len()).copied(),
+            Strategy::Weighted => self.weighted(service, upstreams),
+            Strategy::LeastConn => {
+                // Scan from the round robin cursor so ties rotate
+                let start = self.next(service);
+                (0..upstreams.len())
+                    .map(|i| upstreams[(start + i) % upstreams.len()])
+                    .min_by_key(|u| self.in_flight(service, &u.id))
+            }
+            Strategy::Latency => {
+                let i = rand::random_range(0..upstreams.len());
+                let mut j = rand::random_range(0..upstreams.len() - 1);
+                if j >= i {
+                    j += 1;
+                }
+                let (a, b) = (upstreams[i], upstreams[j]);
+                let (la, lb) = (self.latency_us(service, &a.id), self.latency_us(service, &b.id));
+                let pick_a = match (la, lb) {
+                    (None, _) => true,
+                    (_, None) => false,
+                    // a wins with the share of the total time b takes
+                    (Some(la), Some(lb)) => rand::random::<f64>() * (la + lb) < lb,
+                };
+                Some(if pick_a { a } else { b })
+            }
+        }
+    }
+
+    fn next(&self, service: &str) -> usize {
+        self.cursors.entry(service.to_string()).or_default().fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Smooth weighted round robin: every pick adds each weight to its counter
+    // and takes the highest counter down by the total, which interleaves the
+    // upstreams instead of sending runs to the heaviest one
+    fn weighted<'a>(&self, service: &str, upstreams: &[&'a Upstream]) -> Option<&'a Upstream> {
+        let total: i64 = upstreams.iter().map(|u| i64::from(u.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut current = self.weights.entry(service.to_string()).or_default();
+        current.retain(|id, _| upstreams.iter().any(|u| &u.id == id));
+        let mut best: Option<(&'a Upstream, i64)> = None;
+        for &u in upstreams {
+            let c = current.entry(u.id.clone()).or_default();
+            *c += i64::from(u.weight);
+            if best.is_none_or(|(_, b)| *c > b) {
+                best = Some((u, *c));
+            }
+        }
+        let (chosen, _) = best?;
+        if let Some(c) = current.get_mut(&chosen.id) {
+            *c -= total;
+        }
+        Some(chosen)
+    }
+
+    /// Count a request to `upstream` as in flight until the guard is dropped
+    pub fn start(&self, service: &str, upstream: &str) -> InFlight {
+        let count = self.in_flight.entry(key(service, upstream)).or_default().clone();
+        count.fetch_add(1, Ordering::Relaxed);
+        InFlight(count)
+    }
+
+    pub fn in_flight(&self, service: &str, upstream: &str) -> usize {
+        self.in_flight
+            .get(&key(service, upstream))
+            .map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    /// Record the time `upstream` took to answer
+    pub fn observe(&self, service: &str, upstream: &str, elapsed: Duration) {
+        let sample = elapsed.as_micros() as f64;
+        let avg = self.latency.entry(key(service, upstream)).or_insert_with(|| AtomicU64::new(f64::NAN.to_bits()));
+        let _ = avg.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            let prev = f64::from_bits(bits);
+            let next = if prev.is_nan() { sample } else { prev + EWMA_WEIGHT * (sample - prev) };
+            Some(next.to_bits())
+        });
+    }
+
+    /// Latency average of an upstream, None before its first answer
+    pub fn latency_us(&self, service: &str, upstream: &str) -> Option<f64> {
+        let bits = self.latency.get(&key(service, upstream))?.load(Ordering::Relaxed);
+        Some(f64::from_bits(bits)).filter(|v| !v.is_nan())
+    }
+}
+
+/// A request counted in flight by `Balancer::start`
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstreams(ids: &[&str]) -> Vec<Upstream> {
+        ids.iter().map(|id| Upstream { id: id.to_string(), enabled: true, weight: 1, ..Default::default() }).collect()
+    }
+
+    fn picks(balancer: &Balancer, policy: &LoadBalancePolicy, upstreams: &[Upstream], n: usize) -> HashMap<String, usize> {
+        let candidates: Vec<&Upstream> = upstreams.iter().collect();
+        let mut counts = HashMap::new();
+        for _ in 0..n {
+            let u = balancer.pick("svc", policy, &candidates).unwrap();
+            *counts.entry(u.id.clone()).or_default() += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn latency_balancing_favours_the_faster_upstream() {
+        let balancer = Balancer::default();
+        let policy = LoadBalancePolicy { strategy: Strategy::Latency };
+        let ups = upstreams(&["fast", "slow"]);
+        balancer.observe("svc", "fast", Duration::from_millis(10));
+        balancer.observe("svc", "slow", Duration::from_millis(90));
+
+        let counts = picks(&balancer, &policy, &ups, 10_000);
+        // 10% expected for the slow one
+        let slow = counts.get("slow").copied().unwrap_or(0);
+        assert!((500..1_500).contains(&slow), "slow picked {slow} times");
+    }
+
+    #[test]
+    fn upstreams_without_a_sample_are_tried_first() {
+        let balancer = Balancer::default();
+        let policy = LoadBalancePolicy { strategy: Strategy::Latency };
+        let ups = upstreams(&["known", "new"]);
+        balancer.observe("svc", "known", Duration::from_millis(1));
+        assert_eq!(picks(&balancer, &policy, &ups, 100).get("new"), Some(&100));
+    }
+
+    #[test]
+    fn the_latency_average_moves_towards_new_samples() {
+        let balancer = Balancer::default();
+        assert_eq!(balancer.latency_us("svc", "a"), None);
+        balancer.observe("svc", "a", Duration::from_micros(1000));
+        assert_eq!(balancer.latency_us("svc", "a"), Some(1000.0));
+        balancer.observe("svc", "a", Duration::from_micros(2000));
+        assert_eq!(balancer.latency_us("svc", "a"), Some(1200.0));
+    }
+}
@@ -0,0 +1,259 @@
+use bytes::Bytes;
+use http::{HeaderMap, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Body capture sampling (`type: capture` on a service or global policy).
+///
+/// A `sample_rate` fraction of requests, plus every request carrying
+/// `header`, is recorded with its request and response bodies into the
+/// capture ring buffer served by the admin API. Listed headers and JSON
+/// fields are redacted and bodies are cut at `max_body_bytes`.
+///
+/// ```yaml
+/// - id: svc-capture
+///   type: capture
+///   enabled: true
+///   config:
+///     sample_rate: 0.01
+///     header: x-bullg-capture
+///     max_body_bytes: 4096
+///     redact_headers: [authorization, cookie, set-cookie]
+///     redact_fields: [password, token]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CapturePolicy {
+    #[serde(default)]
+    pub sample_rate: f64,
+    #[serde(default)]
+    pub header: Option<String>,
+    #[serde(default = "def_max_body_bytes")]
+    pub max_body_bytes: usize,
+    #[serde(default = "def_redact_headers")]
+    pub redact_headers: Vec<String>,
+    #[serde(default)]
+    pub redact_fields: Vec<String>,
+}
+
+fn def_max_body_bytes() -> usize {
+    4096
+}
+
+fn def_redact_headers() -> Vec<String> {
+    ["authorization", "proxy-authorization", "cookie", "set-cookie", "x-api-key"]
+        .map(String::from)
+        .to_vec()
+}
+
+impl CapturePolicy {
+    pub const KIND: &'static str = "capture";
+
+    /// Whether this request is captured, flagged requests always are
+    pub fn sampled(&self, headers: &HeaderMap) -> bool {
+        if let Some(flag) = &self.header
+            && headers.contains_key(flag.as_str())
+        {
+            return true;
+        }
+        self.sample_rate > 0.0 && rand::random::<f64>() < self.sample_rate
+    }
+
+    pub fn headers(&self, headers: &HeaderMap) -> BTreeMap<String, String> {
+        headers
+            .iter()
+            .map(|(k, v)| {
+                let redact = self.redact_headers.iter().any(|h| k.as_str().eq_ignore_ascii_case(h));
+                let v = if redact {
+                    REDACTED.to_string()
+                } else {
+                    String::from_utf8_lossy(v.as_bytes()).into_owned()
+                };
+                (k.to_string(), v)
+            })
+            .collect()
+    }
+
+    /// Redacted body cut at `max_body_bytes`, the flag tells if it was cut
+    pub fn body(&self, body: &Bytes) -> (String, bool) {
+        let text = match serde_json::from_slice::<Value>(body) {
+            Ok(mut json) if !self.redact_fields.is_empty() => {
+                redact_json(&mut json, &self.redact_fields);
+                json.to_string()
+            }
+            _ => String::from_utf8_lossy(body).into_owned(),
+        };
+        if text.len() <= self.max_body_bytes {
+            return (text, false);
+        }
+        let mut end = self.max_body_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        (text[..end].to_string(), true)
+    }
+}
+
+fn redact_json(value: &mut Value, fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (k, v) in map.iter_mut() {
+                if fields.iter().any(|f| f.eq_ignore_ascii_case(k)) {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(v, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|v| redact_json(v, fields)),
+        _ => {}
+    }
+}
+
+/// One captured request/response pair
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capture {
+    pub id: String,
+    /// RFC 3339 time the request was received
+    pub time: String,
+    pub service: String,
+    pub route: String,
+    pub method: String,
+    pub uri: String,
+    pub request_headers: BTreeMap<String, String>,
+    pub request_body: String,
+    pub status: u16,
+    pub response_headers: BTreeMap<String, String>,
+    /// None when the response was streamed or tunnelled
+    pub response_body: Option<String>,
+    pub truncated: bool,
+}
+
+impl Capture {
+    /// Fill in the response side of a capture
+    pub fn respond(
+        mut self,
+        policy: &CapturePolicy,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: Option<&Bytes>,
+    ) -> Self {
+        self.status = status.as_u16();
+        self.response_headers = policy.headers(headers);
+        if let Some(body) = body {
+            let (body, truncated) = policy.body(body);
+            self.response_body = Some(body);
+            self.truncated |= truncated;
+        }
+        self
+    }
+}
+
+/// Fixed size ring buffer of the latest captures
+pub struct Captures {
+    capacity: usize,
+    ring: Mutex<VecDeque<Capture>>,
+}
+
+impl Captures {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ring: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, capture: Capture) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut ring = self.ring.lock().unwrap_or_else(|e| e.into_inner());
+        if ring.len() == self.capacity {
+            ring.pop_front();
+        }
+        ring.push_back(capture);
+    }
+
+    /// Captures, oldest first
+    pub fn list(&self) -> Vec<Capture> {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner()).iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.ring.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(config: Value) -> CapturePolicy {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn only_the_sampled_fraction_is_captured() {
+        let none = HeaderMap::new();
+        let quarter = policy(json!({"sample_rate": 0.25}));
+        let sampled = (0..10_000).filter(|_| quarter.sampled(&none)).count();
+        assert!((2_000..3_000).contains(&sampled), "{sampled} of 10000 sampled");
+
+        let off = policy(json!({"header": "x-bullg-capture"}));
+        assert!((0..1_000).all(|_| !off.sampled(&none)));
+        let mut flagged = HeaderMap::new();
+        flagged.insert("x-bullg-capture", "1".parse().unwrap());
+        assert!(off.sampled(&flagged));
+    }
+
+    #[test]
+    fn headers_and_fields_are_redacted() {
+        let p = policy(json!({"redact_fields": ["password"]}));
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", "Bearer secret".parse().unwrap());
+        headers.insert("accept", "application/json".parse().unwrap());
+        let headers = p.headers(&headers);
+        assert_eq!(headers["authorization"], REDACTED);
+        assert_eq!(headers["accept"], "application/json");
+
+        let body = Bytes::from(r#"{"user":"ann","nested":[{"Password":"hunter2"}]}"#);
+        let (body, truncated) = p.body(&body);
+        assert!(!truncated);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body, json!({"user": "ann", "nested": [{"Password": REDACTED}]}));
+    }
+
+    #[test]
+    fn bodies_are_cut_at_the_size_cap() {
+        let p = policy(json!({"max_body_bytes": 4}));
+        assert_eq!(p.body(&Bytes::from("héllo")), ("hél".to_string(), true));
+        assert_eq!(p.body(&Bytes::from("hi")), ("hi".to_string(), false));
+    }
+
+    #[test]
+    fn the_ring_keeps_the_latest_captures() {
+        let ring = Captures::new(2);
+        for id in ["a", "b", "c"] {
+            ring.push(Capture {
+                id: id.into(),
+                time: String::new(),
+                service: String::new(),
+                route: String::new(),
+                method: "GET".into(),
+                uri: "/".into(),
+                request_headers: Default::default(),
+                request_body: String::new(),
+                status: 200,
+                response_headers: Default::default(),
+                response_body: None,
+                truncated: false,
+            });
+        }
+        let ids: Vec<String> = ring.list().into_iter().map(|c| c.id).collect();
+        assert_eq!(ids, ["b", "c"]);
+    }
+}
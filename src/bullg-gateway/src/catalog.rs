@@ -0,0 +1,152 @@
+use bullg_core::{MethodsCfg, Service};
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use std::collections::BTreeMap;
+
+use crate::routing;
+
+// Methods an OpenAPI path item can describe
+const OPENAPI_METHODS: [&str; 8] = ["get", "put", "post", "delete", "options", "head", "patch", "trace"];
+
+/// Services and enabled routes of the running state, served by the admin API
+/// to document what the gateway exposes
+#[derive(Debug, Clone, Serialize)]
+pub struct Catalog {
+    pub services: Vec<CatalogService>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogService {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub context_paths: Vec<String>,
+    pub routes: Vec<CatalogRoute>,
+}
+
+/// Route under one context path, a route of a service mounted on several
+/// context paths is listed once for each
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogRoute {
+    pub id: String,
+    pub name: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub context_path: String,
+    /// Context path joined with the route path
+    pub path: String,
+    /// Empty when the route takes every method the gateway permits
+    pub methods: Vec<String>,
+    pub params: Vec<String>,
+}
+
+impl Catalog {
+    /// Catalog of the version scoped services keyed by context path, in
+    /// context path order. Methods the gateway denies are left out, and
+    /// routes left with none.
+    pub fn build<'a>(services: impl IntoIterator<Item = (&'a str, &'a Service)>, methods: &MethodsCfg) -> Self {
+        let mut by_key: Vec<(&str, &Service)> = services.into_iter().collect();
+        by_key.sort_by_key(|(key, _)| *key);
+        let mut out: Vec<CatalogService> = Vec::new();
+        for (key, svc) in by_key {
+            let routes = svc.routes.iter().filter(|r| r.enabled).filter_map(|r| {
+                let allowed: Vec<String> = r
+                    .config
+                    .methods
+                    .iter()
+                    .map(|m| m.to_ascii_uppercase())
+                    .filter(|m| methods.permits(m))
+                    .collect();
+                // Every method it lists is denied, the route is unreachable
+                if allowed.is_empty() && !r.config.methods.is_empty() {
+                    return None;
+                }
+                Some(CatalogRoute {
+                    id: r.id.clone(),
+                    name: r.name.clone(),
+                    description: r.description.clone(),
+                    tags: r.tags.clone(),
+                    context_path: key.to_string(),
+                    path: format!("{}{}", key.trim_end_matches('/'), r.config.path),
+                    methods: allowed,
+                    params: routing::path_params(&r.config.path),
+                })
+            });
+            match out.iter_mut().find(|s| s.id == svc.id) {
+                Some(entry) => {
+                    entry.context_paths.push(key.to_string());
+                    entry.routes.extend(routes);
+                }
+                None => out.push(CatalogService {
+                    id: svc.id.clone(),
+                    name: svc.name.clone(),
+                    description: svc.description.clone(),
+                    tags: svc.tags.clone(),
+                    context_paths: vec![key.to_string()],
+                    routes: routes.collect(),
+                }),
+            }
+        }
+        Self { services: out }
+    }
+
+    /// Minimal OpenAPI 3 document of the catalog: one operation per route and
+    /// method, tagged with the service name, with the route parameters as
+    /// required string parameters of the path. Routes taking any method get an
+    /// operation for each method the gateway permits.
+    pub fn openapi(&self, title: &str, version: &str, methods: &MethodsCfg) -> Value {
+        let mut paths: BTreeMap<String, Map<String, Value>> = BTreeMap::new();
+        for svc in &self.services {
+            for route in &svc.routes {
+                let listed: Vec<String> = if route.methods.is_empty() {
+                    OPENAPI_METHODS.iter().filter(|m| methods.permits(m)).map(|m| m.to_string()).collect()
+                } else {
+                    route.methods.iter().map(|m| m.to_ascii_lowercase()).collect()
+                };
+                let parameters: Vec<Value> = route
+                    .params
+                    .iter()
+                    .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+                    .collect();
+                let item = paths.entry(openapi_path(&route.path)).or_default();
+                if !parameters.is_empty() {
+                    item.entry("parameters").or_insert_with(|| parameters.into());
+                }
+                for method in listed.into_iter().filter(|m| OPENAPI_METHODS.contains(&m.as_str())) {
+                    let summary = if route.name.is_empty() { &route.id } else { &route.name };
+                    item.entry(method.clone()).or_insert_with(|| {
+                        json!({
+                            "operationId": format!("{}.{}.{}", svc.id, route.id, method),
+                            "summary": summary,
+                            "description": route.description,
+                            "tags": [svc.name],
+                            "responses": { "default": { "description": "upstream response" } },
+                        })
+                    });
+                }
+            }
+        }
+        json!({
+            "openapi": "3.0.3",
+            "info": { "title": title, "version": version },
+            "tags": self
+                .services
+                .iter()
+                .map(|s| json!({ "name": s.name, "description": s.description }))
+                .collect::<Vec<_>>(),
+            "paths": paths,
+        })
+    }
+}
+
+// Route path in OpenAPI form, every parameter written as `{name}`
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match routing::segment_param(segment) {
+            Some(name) => format!("{{{name}}}"),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
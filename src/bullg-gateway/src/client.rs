@@ -0,0 +1,154 @@
+use bullg_utils::{de_duration_opt, ser_duration_opt};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, error};
+
+/// Connection tuning of the client proxying to a service
+/// (`type: upstream_client` on a service or global policy). Settings left
+/// out keep the client defaults.
+///
+/// The HTTP/2 stream limit is announced by the upstream server, the client
+/// follows it, so there is no setting for it here.
+///
+/// ```yaml
+/// - id: svc-client
+///   type: upstream_client
+///   enabled: true
+///   config:
+///     tcp_keepalive: 60s
+///     pool_idle_timeout: 90s
+///     pool_max_idle_per_host: 32
+///     http2_prior_knowledge: false # HTTP/2 without negotiation, for h2c upstreams
+///     http2_adaptive_window: true
+///     http2_initial_stream_window_size: 1048576
+///     http2_initial_connection_window_size: 4194304
+///     http2_keep_alive_interval: 30s
+///     http2_keep_alive_timeout: 10s
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct ClientPolicy {
+    #[serde(default, deserialize_with = "de_duration_opt", serialize_with = "ser_duration_opt")]
+    pub tcp_keepalive: Option<Duration>,
+    #[serde(default, deserialize_with = "de_duration_opt", serialize_with = "ser_duration_opt")]
+    pub pool_idle_timeout: Option<Duration>,
+    #[serde(default)]
+    pub pool_max_idle_per_host: Option<usize>,
+    #[serde(default)]
+    pub http2_prior_knowledge: bool,
+    #[serde(default)]
+    pub http2_adaptive_window: Option<bool>,
+    #[serde(default)]
+    pub http2_initial_stream_window_size: Option<u32>,
+    #[serde(default)]
+    pub http2_initial_connection_window_size: Option<u32>,
+    #[serde(default, deserialize_with = "de_duration_opt", serialize_with = "ser_duration_opt")]
+    pub http2_keep_alive_interval: Option<Duration>,
+    #[serde(default, deserialize_with = "de_duration_opt", serialize_with = "ser_duration_opt")]
+    pub http2_keep_alive_timeout: Option<Duration>,
+}
+
+impl ClientPolicy {
+    pub const KIND: &'static str = "upstream_client";
+
+    /// Client with these settings on top of the gateway wide ones in `b`
+    pub fn build(&self, mut b: reqwest::ClientBuilder) -> reqwest::Result<reqwest::Client> {
+        if let Some(v) = self.tcp_keepalive {
+            b = b.tcp_keepalive(v);
+        }
+        if let Some(v) = self.pool_idle_timeout {
+            b = b.pool_idle_timeout(v);
+        }
+        if let Some(v) = self.pool_max_idle_per_host {
+            b = b.pool_max_idle_per_host(v);
+        }
+        if self.http2_prior_knowledge {
+            b = b.http2_prior_knowledge();
+        }
+        if let Some(v) = self.http2_adaptive_window {
+            b = b.http2_adaptive_window(v);
+        }
+        if let Some(v) = self.http2_initial_stream_window_size {
+            b = b.http2_initial_stream_window_size(v);
+        }
+        if let Some(v) = self.http2_initial_connection_window_size {
+            b = b.http2_initial_connection_window_size(v);
+        }
+        if let Some(v) = self.http2_keep_alive_interval {
+            b = b.http2_keep_alive_interval(v);
+        }
+        if let Some(v) = self.http2_keep_alive_timeout {
+            b = b.http2_keep_alive_timeout(v);
+        }
+        b.build()
+    }
+}
+
+/// Clients of the services with their own tuning, rebuilt when the policy changes
+#[derive(Default)]
+pub struct Clients {
+    clients: DashMap<String, (ClientPolicy, reqwest::Client)>,
+}
+
+impl Clients {
+    /// Client for `service`, None when one cannot be built with `policy`
+    pub fn get(
+        &self,
+        service: &str,
+        policy: &ClientPolicy,
+        base: impl FnOnce() -> reqwest::ClientBuilder,
+    ) -> Option<reqwest::Client> {
+        if let Some(entry) = self.clients.get(service)
+            && entry.0 == *policy
+        {
+            return Some(entry.1.clone());
+        }
+        match policy.build(base()) {
+            Ok(client) => {
+                debug!("client for service {} built with {:?}", service, policy);
+                self.clients.insert(service.to_string(), (policy.clone(), client.clone()));
+                Some(client)
+            }
+            Err(e) => {
+                error!("invalid upstream client settings for service {}: {e}", service);
+                None
+            }
+        }
+    }
+    /// Forget the clients of services no longer configured
+    pub fn retain(&self, known: impl Fn(&str) -> bool) {
+        self.clients.retain(|service, _| known(service));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::cell::Cell;
+
+    #[test]
+    fn clients_are_built_once_per_service_and_policy() {
+        let clients = Clients::default();
+        let builds = Cell::new(0);
+        let base = || {
+            builds.set(builds.get() + 1);
+            reqwest::Client::builder()
+        };
+        let tuned: ClientPolicy =
+            serde_json::from_value(json!({"tcp_keepalive": "60s", "http2_adaptive_window": true})).unwrap();
+        assert_eq!(tuned.tcp_keepalive, Some(Duration::from_secs(60)));
+
+        assert!(clients.get("a", &tuned, base).is_some());
+        assert!(clients.get("a", &tuned, base).is_some());
+        assert_eq!(builds.get(), 1);
+        assert!(clients.get("b", &tuned, base).is_some());
+        assert!(clients.get("a", &ClientPolicy::default(), base).is_some());
+        assert_eq!(builds.get(), 3);
+
+        clients.retain(|service| service == "b");
+        assert!(clients.get("b", &tuned, base).is_some());
+        assert!(clients.get("a", &ClientPolicy::default(), base).is_some());
+        assert_eq!(builds.get(), 4);
+    }
+}
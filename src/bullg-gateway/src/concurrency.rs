@@ -0,0 +1,167 @@
+use bullg_utils::{de_duration, ser_duration};
+use dashmap::DashMap;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::policy::PolicyError;
+
+/// In-flight request limit (`type: concurrency` on a service or global policy).
+///
+/// Requests above `max_concurrent_requests` are either shed straight away or
+/// queued for up to `queue_timeout` before being rejected with `error`.
+///
+/// ```yaml
+/// - id: svc-concurrency
+///   type: concurrency
+///   enabled: true
+///   config:
+///     max_concurrent_requests: 100
+///     per: upstream # or service
+///     overflow: queue # or shed
+///     queue_timeout: 5s
+///     error:
+///       status_code: 503
+///       message: "Too Many Requests"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConcurrencyPolicy {
+    #[serde(default = "def_max")]
+    pub max_concurrent_requests: usize,
+    #[serde(default)]
+    pub per: LimitScope,
+    #[serde(default)]
+    pub overflow: Overflow,
+    #[serde(
+        default = "def_queue_timeout",
+        deserialize_with = "de_duration",
+        serialize_with = "ser_duration"
+    )]
+    pub queue_timeout: Duration,
+    #[serde(default = "def_error")]
+    pub error: PolicyError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitScope {
+    #[default]
+    Upstream,
+    Service,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    #[default]
+    Shed,
+    Queue,
+}
+
+fn def_max() -> usize {
+    100
+}
+
+fn def_queue_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn def_error() -> PolicyError {
+    PolicyError::new(StatusCode::SERVICE_UNAVAILABLE, "Too Many Requests")
+}
+
+impl ConcurrencyPolicy {
+    pub const KIND: &'static str = "concurrency";
+
+    /// Limiter key for a request to `upstream` of `service`
+    pub fn key(&self, service: &str, upstream: &str) -> String {
+        match self.per {
+            LimitScope::Upstream => format!("{}/{}", service, upstream),
+            LimitScope::Service => service.to_string(),
+        }
+    }
+}
+
+/// Semaphores for every limited service/upstream
+#[derive(Default)]
+pub struct Limiter {
+    // key -> (limit the semaphore was sized for, semaphore)
+    sems: DashMap<String, (usize, Arc<Semaphore>)>,
+}
+
+impl Limiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Permit for one in-flight request, None when the request is shed
+    pub async fn acquire(&self, key: String, policy: &ConcurrencyPolicy) -> Option<OwnedSemaphorePermit> {
+        let sem = self.semaphore(key, policy.max_concurrent_requests);
+        match policy.overflow {
+            Overflow::Shed => sem.try_acquire_owned().ok(),
+            Overflow::Queue => tokio::time::timeout(policy.queue_timeout, sem.acquire_owned())
+                .await
+                .ok()?
+                .ok(),
+        }
+    }
+
+    fn semaphore(&self, key: String, limit: usize) -> Arc<Semaphore> {
+        let mut entry = self
+            .sems
+            .entry(key)
+            .or_insert_with(|| (limit, Arc::new(Semaphore::new(limit))));
+        // Limit changed with a new policy, in-flight permits drain from the old one
+        if entry.0 != limit {
+            *entry = (limit, Arc::new(Semaphore::new(limit)));
+        }
+        entry.1.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(config: serde_json::Value) -> ConcurrencyPolicy {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn a_saturated_limit_sheds_requests_until_a_permit_is_released() {
+        let limiter = Limiter::new();
+        let shed = policy(json!({"max_concurrent_requests": 2}));
+        let first = limiter.acquire("svc/a".into(), &shed).await.unwrap();
+        let _second = limiter.acquire("svc/a".into(), &shed).await.unwrap();
+        assert!(limiter.acquire("svc/a".into(), &shed).await.is_none());
+        // Other upstreams have their own limit
+        assert!(limiter.acquire("svc/b".into(), &shed).await.is_some());
+        drop(first);
+        assert!(limiter.acquire("svc/a".into(), &shed).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn queued_requests_wait_for_a_permit_up_to_the_queue_timeout() {
+        let limiter = Arc::new(Limiter::new());
+        let queue = policy(json!({"max_concurrent_requests": 1, "overflow": "queue", "queue_timeout": "50ms"}));
+        let held = limiter.acquire("svc".into(), &queue).await.unwrap();
+        assert!(limiter.acquire("svc".into(), &queue).await.is_none());
+
+        let waiting = {
+            let (limiter, queue) = (limiter.clone(), queue.clone());
+            tokio::spawn(async move { limiter.acquire("svc".into(), &queue).await.is_some() })
+        };
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(held);
+        assert!(waiting.await.unwrap());
+    }
+
+    #[test]
+    fn limits_are_kept_per_upstream_or_per_service() {
+        assert_eq!(policy(json!({})).key("svc", "a"), "svc/a");
+        assert_eq!(policy(json!({"per": "service"})).key("svc", "a"), "svc");
+    }
+}
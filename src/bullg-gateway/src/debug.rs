@@ -0,0 +1,127 @@
+use bullg_core::DebugCfg;
+use bullg_plugin_api::Phase;
+use http::{HeaderMap, header::HeaderValue};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const PLUGIN_TRACE_HEADER: &str = "x-bullg-plugin-trace";
+
+/// Upstream id a debug request is forced to, see `DebugCfg`
+#[derive(Debug, Clone)]
+pub struct ForcedUpstream(pub String);
+
+impl ForcedUpstream {
+    /// Upstream asked for by a request, honored only when the request was
+    /// `granted` debugging. The header is removed either way.
+    pub fn take(cfg: &DebugCfg, headers: &mut HeaderMap, granted: bool) -> Option<Self> {
+        let value = headers.remove(cfg.upstream_header.as_str())?;
+        let id = value.to_str().ok()?.trim();
+        (granted && !id.is_empty()).then(|| Self(id.to_string()))
+    }
+}
+
+/// Plugins run on a request asking for a trace, in the order they ran
+#[derive(Default)]
+pub struct PluginTrace {
+    runs: Mutex<Vec<String>>,
+}
+
+impl PluginTrace {
+    /// Trace of a request carrying the debug header with the configured
+    /// token. The header is removed either way so it never reaches plugins
+    /// or the upstream.
+    pub fn take(cfg: &DebugCfg, headers: &mut HeaderMap) -> Option<Self> {
+        let value = headers.remove(cfg.header.as_str())?;
+        let granted = !cfg.token.is_empty() && constant_eq(value.as_bytes(), cfg.token.as_bytes());
+        granted.then(Self::default)
+    }
+
+    /// Record one plugin run, `stopped` when the chain ended after it
+    pub fn record(&self, r#type: &str, id: &str, phase: Phase, elapsed: Duration, failed: bool, stopped: bool) {
+        let phase = match phase {
+            Phase::Pre => "pre",
+            Phase::Post => "post",
+            Phase::Intermediate => "intermediate",
+        };
+        let mut run = format!("{type}/{id};phase={phase};dur={:.3}ms", elapsed.as_secs_f64() * 1000.0);
+        if failed {
+            run.push_str(";failed");
+        }
+        if stopped {
+            run.push_str(";short-circuit");
+        }
+        self.runs.lock().unwrap_or_else(|e| e.into_inner()).push(run);
+    }
+
+    /// Add `PLUGIN_TRACE_HEADER` to a response, runs with ids not valid in a
+    /// header are left out
+    pub fn attach(&self, headers: &mut HeaderMap) {
+        let runs = self.runs.lock().unwrap_or_else(|e| e.into_inner());
+        let value = runs
+            .iter()
+            .filter(|run| HeaderValue::from_str(run).is_ok())
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(PLUGIN_TRACE_HEADER, value);
+        }
+    }
+}
+
+// Compares every byte so the time taken does not tell how much of the token matched
+fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(token: &str) -> DebugCfg {
+        DebugCfg { token: token.into(), ..Default::default() }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs.iter().map(|(k, v)| (http::HeaderName::from_static(k), HeaderValue::from_static(v))).collect()
+    }
+
+    #[test]
+    fn traces_need_the_configured_token() {
+        let mut asked = headers(&[("x-bullg-debug", "s3cret")]);
+        assert!(PluginTrace::take(&cfg("s3cret"), &mut asked).is_some());
+        assert!(asked.is_empty());
+        for (token, value) in [("s3cret", "s3cre"), ("s3cret", "s3cret "), ("", "")] {
+            let mut asked = headers(&[("x-bullg-debug", value)]);
+            assert!(PluginTrace::take(&cfg(token), &mut asked).is_none(), "{value:?}");
+            assert!(asked.is_empty());
+        }
+        assert!(PluginTrace::take(&cfg("s3cret"), &mut HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn forced_upstreams_are_honored_when_granted() {
+        let mut asked = headers(&[("x-bullg-upstream", " green ")]);
+        assert_eq!(ForcedUpstream::take(&cfg("s3cret"), &mut asked, true).map(|f| f.0), Some("green".into()));
+        assert!(asked.is_empty());
+        let mut asked = headers(&[("x-bullg-upstream", "green")]);
+        assert!(ForcedUpstream::take(&cfg("s3cret"), &mut asked, false).is_none());
+        assert!(asked.is_empty());
+        let mut blank = headers(&[("x-bullg-upstream", " ")]);
+        assert!(ForcedUpstream::take(&cfg("s3cret"), &mut blank, true).is_none());
+    }
+
+    #[test]
+    fn traces_list_the_runs_in_order() {
+        let trace = PluginTrace::default();
+        trace.record("cors", "cors", Phase::Pre, Duration::from_micros(1500), false, false);
+        trace.record("basic_auth", "auth", Phase::Pre, Duration::ZERO, true, true);
+        trace.record("bad", "id\nwith newline", Phase::Post, Duration::ZERO, false, false);
+        let mut out = HeaderMap::new();
+        trace.attach(&mut out);
+        assert_eq!(
+            out[PLUGIN_TRACE_HEADER],
+            "cors/cors;phase=pre;dur=1.500ms, basic_auth/auth;phase=pre;dur=0.000ms;failed;short-circuit"
+        );
+    }
+}
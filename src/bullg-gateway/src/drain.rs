@@ -0,0 +1,106 @@
+use tokio::sync::watch;
+
+/// Connections and upgrade tunnels of a serving gateway. On shutdown they
+/// are asked to finish, those still open at the drain timeout are closed.
+pub struct Drain {
+    draining: watch::Sender<bool>,
+    forced: watch::Sender<bool>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self {
+            draining: watch::channel(false).0,
+            forced: watch::channel(false).0,
+        }
+    }
+}
+
+impl Drain {
+    /// Held by a connection or tunnel for as long as it is open
+    pub fn guard(&self) -> DrainGuard {
+        DrainGuard {
+            draining: self.draining.subscribe(),
+            forced: self.forced.subscribe(),
+        }
+    }
+
+    pub fn open(&self) -> usize {
+        self.draining.receiver_count()
+    }
+
+    pub fn draining(&self) -> bool {
+        *self.draining.borrow()
+    }
+
+    pub fn start(&self) {
+        self.draining.send_replace(true);
+    }
+
+    pub fn force(&self) {
+        self.forced.send_replace(true);
+    }
+
+    /// Every guard dropped
+    pub async fn closed(&self) {
+        self.draining.closed().await
+    }
+}
+
+pub struct DrainGuard {
+    draining: watch::Receiver<bool>,
+    forced: watch::Receiver<bool>,
+}
+
+impl DrainGuard {
+    /// Resolves once shutdown starts, at once if it already has
+    pub async fn draining(&mut self) {
+        let _ = self.draining.wait_for(|on| *on).await;
+    }
+
+    /// Resolves once the drain timeout passed
+    pub async fn forced(&mut self) {
+        let _ = self.forced.wait_for(|on| *on).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const SOON: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn guards_hear_the_drain_then_the_force() {
+        let drain = Drain::default();
+        let mut guard = drain.guard();
+        assert_eq!(drain.open(), 1);
+        assert!(!drain.draining());
+        assert!(timeout(SOON, guard.draining()).await.is_err());
+
+        drain.start();
+        assert!(drain.draining());
+        timeout(SOON, guard.draining()).await.unwrap();
+        // Guards taken after the start hear it at once
+        timeout(SOON, drain.guard().draining()).await.unwrap();
+        assert!(timeout(SOON, guard.forced()).await.is_err());
+
+        drain.force();
+        timeout(SOON, guard.forced()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_waits_for_every_guard() {
+        let drain = Drain::default();
+        timeout(SOON, drain.closed()).await.unwrap();
+        let (first, second) = (drain.guard(), drain.guard());
+        assert_eq!(drain.open(), 2);
+        drop(first);
+        assert!(timeout(SOON, drain.closed()).await.is_err());
+        drop(second);
+        timeout(SOON, drain.closed()).await.unwrap();
+        assert_eq!(drain.open(), 0);
+    }
+}
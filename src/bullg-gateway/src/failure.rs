@@ -0,0 +1,167 @@
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+/// What counts as an upstream failure (`type: upstream_failure` on a service
+/// or global policy).
+///
+/// Outlier detection counts these failures towards an ejection, and retries
+/// retry them: `retry_on_status` retries failing statuses unless it lists its
+/// own, `retry_on_connect_error` only retries while connection errors count.
+/// `statuses` takes codes, ranges like `"500-599"` and classes like `"5xx"`.
+/// Without the policy connection errors, timeouts and 5xx are failures.
+///
+/// ```yaml
+/// - id: svc-failures
+///   type: upstream_failure
+///   enabled: true
+///   config:
+///     statuses: ["5xx", 429]
+///     connect_errors: true
+///     timeouts: true
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailurePolicy {
+    #[serde(default = "def_statuses")]
+    pub statuses: Vec<StatusMatch>,
+    #[serde(default = "def_true")]
+    pub connect_errors: bool,
+    #[serde(default = "def_true")]
+    pub timeouts: bool,
+}
+
+fn def_statuses() -> Vec<StatusMatch> {
+    vec![StatusMatch { from: 500, to: 599 }]
+}
+
+fn def_true() -> bool {
+    true
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        Self {
+            statuses: def_statuses(),
+            connect_errors: def_true(),
+            timeouts: def_true(),
+        }
+    }
+}
+
+impl FailurePolicy {
+    pub const KIND: &'static str = "upstream_failure";
+
+    pub fn status_fails(&self, status: StatusCode) -> bool {
+        self.statuses.iter().any(|m| m.matches(status))
+    }
+
+    /// Whether a request that got no response failed, errors other than
+    /// connection errors and timeouts always do
+    pub fn error_fails(&self, e: &reqwest::Error) -> bool {
+        if e.is_connect() {
+            self.connect_errors
+        } else if e.is_timeout() {
+            self.timeouts
+        } else {
+            true
+        }
+    }
+}
+
+/// Inclusive range of status codes, one code is a range of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "StatusMatchRepr", into = "StatusMatchRepr")]
+pub struct StatusMatch {
+    pub from: u16,
+    pub to: u16,
+}
+
+impl StatusMatch {
+    pub fn matches(&self, status: StatusCode) -> bool {
+        (self.from..=self.to).contains(&status.as_u16())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StatusMatchRepr {
+    Code(u16),
+    Text(String),
+}
+
+impl TryFrom<StatusMatchRepr> for StatusMatch {
+    type Error = String;
+
+    fn try_from(repr: StatusMatchRepr) -> Result<Self, Self::Error> {
+        let code = |s: &str| {
+            s.trim()
+                .parse::<u16>()
+                .ok()
+                .filter(|c| (100..=599).contains(c))
+                .ok_or_else(|| format!("invalid status code `{s}`"))
+        };
+        let m = match repr {
+            StatusMatchRepr::Code(c) => {
+                let c = code(&c.to_string())?;
+                StatusMatch { from: c, to: c }
+            }
+            StatusMatchRepr::Text(s) => match s.trim().to_ascii_lowercase().as_bytes() {
+                [class @ b'1'..=b'5', b'x', b'x'] => {
+                    let from = u16::from(class - b'0') * 100;
+                    StatusMatch { from, to: from + 99 }
+                }
+                _ => match s.split_once('-') {
+                    Some((from, to)) => StatusMatch { from: code(from)?, to: code(to)? },
+                    None => {
+                        let c = code(&s)?;
+                        StatusMatch { from: c, to: c }
+                    }
+                },
+            },
+        };
+        if m.from > m.to {
+            return Err(format!("empty status range {}-{}", m.from, m.to));
+        }
+        Ok(m)
+    }
+}
+
+impl From<StatusMatch> for StatusMatchRepr {
+    fn from(m: StatusMatch) -> Self {
+        if m.from == m.to {
+            StatusMatchRepr::Code(m.from)
+        } else {
+            StatusMatchRepr::Text(format!("{}-{}", m.from, m.to))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(config: serde_json::Value) -> Result<FailurePolicy, serde_json::Error> {
+        serde_json::from_value(config)
+    }
+
+    #[test]
+    fn statuses_are_codes_ranges_or_classes() {
+        let failures = policy(json!({"statuses": ["5xx", 429, "408-409"]})).unwrap();
+        for (code, fails) in [(503, true), (599, true), (429, true), (408, true), (409, true), (404, false), (200, false)] {
+            assert_eq!(failures.status_fails(StatusCode::from_u16(code).unwrap()), fails, "{code}");
+        }
+        // The default counts 5xx only
+        let default = policy(json!({})).unwrap();
+        assert!(default.status_fails(StatusCode::SERVICE_UNAVAILABLE));
+        assert!(!default.status_fails(StatusCode::NOT_FOUND));
+        assert!(default.connect_errors && default.timeouts);
+        assert_eq!(serde_json::to_value(&failures.statuses).unwrap(), json!(["500-599", 429, "408-409"]));
+    }
+
+    #[test]
+    fn invalid_statuses_are_refused() {
+        for statuses in [json!(["6xx"]), json!([700]), json!(["503-500"]), json!(["abc"]), json!(["500-"])] {
+            assert!(policy(json!({"statuses": statuses})).is_err(), "{statuses}");
+        }
+    }
+}
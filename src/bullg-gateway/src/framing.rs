@@ -0,0 +1,93 @@
+use bullg_core::HeadersCfg;
+use http::{HeaderMap, StatusCode, header};
+
+/// Reject requests with more headers than allowed or with an ambiguous body
+/// length. A proxy reading the length one way while the upstream reads it
+/// another is how requests get smuggled (RFC 9112 §6.3), so anything but a
+/// single Content-Length or a Transfer-Encoding ending in chunked is refused.
+///
+/// On connections served by `serve` the HTTP/1 parser already answers 400 to
+/// differing lengths and a Transfer-Encoding not ending in chunked, and it
+/// drops a Content-Length sent next to Transfer-Encoding or repeated with the
+/// same value before the request gets here. The upstream then only sees the
+/// length of the decoded body.
+pub fn check(cfg: &HeadersCfg, headers: &HeaderMap) -> Result<(), (StatusCode, &'static str)> {
+    if headers.len() > cfg.max_count {
+        return Err((StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "too many headers"));
+    }
+
+    let lengths: Vec<_> = headers.get_all(header::CONTENT_LENGTH).iter().collect();
+    let encodings: Vec<_> = headers.get_all(header::TRANSFER_ENCODING).iter().collect();
+    if !lengths.is_empty() && !encodings.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "both content-length and transfer-encoding"));
+    }
+    match lengths.as_slice() {
+        [] => {}
+        [length] => {
+            let valid = !length.is_empty() && length.as_bytes().iter().all(u8::is_ascii_digit);
+            if !valid {
+                return Err((StatusCode::BAD_REQUEST, "invalid content-length"));
+            }
+        }
+        _ => return Err((StatusCode::BAD_REQUEST, "duplicate content-length")),
+    }
+    if !encodings.is_empty() {
+        let last = encodings
+            .iter()
+            .flat_map(|v| v.as_bytes().split(|b| *b == b','))
+            .map(|c| c.trim_ascii())
+            .next_back()
+            .unwrap_or_default();
+        if !last.eq_ignore_ascii_case(b"chunked") {
+            return Err((StatusCode::BAD_REQUEST, "transfer-encoding must end with chunked"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (k, v) in pairs {
+            headers.append(*k, HeaderValue::from_static(v));
+        }
+        headers
+    }
+
+    fn checked(pairs: &[(&'static str, &'static str)]) -> Result<(), &'static str> {
+        check(&HeadersCfg::default(), &headers(pairs)).map_err(|(_, msg)| msg)
+    }
+
+    #[test]
+    fn unambiguous_lengths_pass() {
+        assert_eq!(checked(&[]), Ok(()));
+        assert_eq!(checked(&[("content-length", "42")]), Ok(()));
+        assert_eq!(checked(&[("transfer-encoding", "gzip, chunked")]), Ok(()));
+        assert_eq!(checked(&[("transfer-encoding", "gzip"), ("transfer-encoding", "Chunked")]), Ok(()));
+    }
+
+    #[test]
+    fn conflicting_lengths_and_encodings_are_refused() {
+        assert_eq!(
+            checked(&[("content-length", "5"), ("transfer-encoding", "chunked")]),
+            Err("both content-length and transfer-encoding")
+        );
+        assert_eq!(checked(&[("content-length", "5"), ("content-length", "5")]), Err("duplicate content-length"));
+        assert_eq!(checked(&[("content-length", "5, 6")]), Err("invalid content-length"));
+        assert_eq!(checked(&[("content-length", "-1")]), Err("invalid content-length"));
+        assert_eq!(checked(&[("transfer-encoding", "chunked, gzip")]), Err("transfer-encoding must end with chunked"));
+    }
+
+    #[test]
+    fn header_counts_above_the_limit_are_refused() {
+        let cfg = HeadersCfg { max_count: 3, ..Default::default() };
+        let mut many = headers(&[("a", "1"), ("b", "1"), ("c", "1")]);
+        assert!(check(&cfg, &many).is_ok());
+        many.append("a", HeaderValue::from_static("2"));
+        assert_eq!(check(&cfg, &many), Err((StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, "too many headers")));
+    }
+}
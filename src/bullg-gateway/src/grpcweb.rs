@@ -0,0 +1,203 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use bullg_core::Route;
+use bytes::{BufMut, Bytes, BytesMut};
+use http::{HeaderMap, header, header::HeaderValue};
+use serde::{Deserialize, Serialize};
+
+const GRPC: &str = "application/grpc";
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+// Flag of the gRPC-Web frame carrying the trailers in the body
+const TRAILER_FLAG: u8 = 0x80;
+
+/// gRPC-Web bridging (`type: grpc_web` on a service or global policy).
+///
+/// gRPC-Web requests from browsers, `application/grpc-web` and the base64
+/// `application/grpc-web-text`, are sent to the upstream as gRPC over
+/// HTTP/2, and the answer comes back as gRPC-Web with the upstream trailers
+/// in the body. `routes` limits the bridge to those route ids, empty bridges
+/// every route of the service. Without an `upstream_client` policy the
+/// upstream is spoken to in HTTP/2 without negotiation, which h2c and TLS
+/// gRPC servers both accept. Responses are buffered, so server streaming
+/// calls reach the client when they end.
+///
+/// ```yaml
+/// - id: svc-grpc-web
+///   type: grpc_web
+///   enabled: true
+///   config:
+///     routes: [greeter]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct GrpcWebPolicy {
+    #[serde(default)]
+    pub routes: Vec<String>,
+}
+
+impl GrpcWebPolicy {
+    pub const KIND: &'static str = "grpc_web";
+
+    /// Bridged call of a gRPC-Web request on a bridged route
+    pub fn call(&self, route: &Route, headers: &HeaderMap) -> Option<GrpcWeb> {
+        if !self.routes.is_empty() && !self.routes.contains(&route.id) {
+            return None;
+        }
+        GrpcWeb::from_headers(headers)
+    }
+}
+
+/// Framing of one gRPC-Web call: text mode carries the frames in base64,
+/// `suffix` is the message format such as `+proto`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrpcWeb {
+    pub text: bool,
+    pub suffix: String,
+}
+
+impl GrpcWeb {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        let (text, suffix) = match essence.strip_prefix(GRPC_WEB_TEXT) {
+            Some(suffix) => (true, suffix),
+            None => (false, essence.strip_prefix(GRPC_WEB)?),
+        };
+        (suffix.is_empty() || suffix.starts_with('+')).then(|| Self { text, suffix: suffix.to_string() })
+    }
+
+    /// gRPC request to the upstream, the body decoded in text mode
+    pub fn request(&self, headers: &mut HeaderMap, body: Bytes) -> Result<Bytes, base64::DecodeError> {
+        let body = if self.text { decode_text(&body)? } else { body };
+        if let Ok(content_type) = HeaderValue::from_str(&format!("{GRPC}{}", self.suffix)) {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        headers.insert(header::TE, HeaderValue::from_static("trailers"));
+        headers.remove(header::CONTENT_LENGTH);
+        Ok(body)
+    }
+
+    /// gRPC-Web response of a gRPC upstream answer: the trailers are framed
+    /// after the messages and the whole body encoded in text mode. Answers
+    /// that are not gRPC are left as they are.
+    pub fn response(&self, headers: &mut HeaderMap, body: Bytes, trailers: &HeaderMap) -> Bytes {
+        let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+        let Some(suffix) = content_type.to_ascii_lowercase().strip_prefix(GRPC).map(str::to_string) else {
+            return body;
+        };
+        let base = if self.text { GRPC_WEB_TEXT } else { GRPC_WEB };
+        if let Ok(content_type) = HeaderValue::from_str(&format!("{base}{suffix}")) {
+            headers.insert(header::CONTENT_TYPE, content_type);
+        }
+        headers.remove(header::CONTENT_LENGTH);
+        let mut out = BytesMut::from(body);
+        if !trailers.is_empty() {
+            let mut block = Vec::new();
+            for (name, value) in trailers {
+                block.extend_from_slice(name.as_str().as_bytes());
+                block.extend_from_slice(b": ");
+                block.extend_from_slice(value.as_bytes());
+                block.extend_from_slice(b"\r\n");
+            }
+            out.put_u8(TRAILER_FLAG);
+            out.put_u32(block.len() as u32);
+            out.extend_from_slice(&block);
+        }
+        if self.text { Bytes::from(STANDARD.encode(&out)) } else { out.freeze() }
+    }
+}
+
+// Clients may send the frames as separately padded base64 chunks, so every
+// padded quantum ends a chunk
+fn decode_text(body: &[u8]) -> Result<Bytes, base64::DecodeError> {
+    let text: Vec<u8> = body.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut start = 0;
+    for (i, quantum) in text.chunks(4).enumerate() {
+        if quantum.contains(&b'=') {
+            let end = i * 4 + quantum.len();
+            STANDARD.decode_vec(&text[start..end], &mut out)?;
+            start = end;
+        }
+    }
+    STANDARD.decode_vec(&text[start..], &mut out)?;
+    Ok(Bytes::from(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(content_type: &'static str) -> HeaderMap {
+        HeaderMap::from_iter([(header::CONTENT_TYPE, HeaderValue::from_static(content_type))])
+    }
+
+    fn web(text: bool, suffix: &str) -> GrpcWeb {
+        GrpcWeb { text, suffix: suffix.into() }
+    }
+
+    #[test]
+    fn grpc_web_content_types_are_recognized() {
+        assert_eq!(GrpcWeb::from_headers(&typed("application/grpc-web")), Some(web(false, "")));
+        assert_eq!(GrpcWeb::from_headers(&typed("Application/gRPC-Web+proto; charset=utf-8")), Some(web(false, "+proto")));
+        assert_eq!(GrpcWeb::from_headers(&typed("application/grpc-web-text+json")), Some(web(true, "+json")));
+        for other in ["application/grpc", "application/grpc-webfoo", "application/json"] {
+            assert_eq!(GrpcWeb::from_headers(&typed(other)), None, "{other}");
+        }
+        assert_eq!(GrpcWeb::from_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn only_the_listed_routes_are_bridged() {
+        let route = |id: &str| Route { id: id.into(), ..Default::default() };
+        let headers = typed("application/grpc-web");
+        let all = GrpcWebPolicy::default();
+        assert!(all.call(&route("greeter"), &headers).is_some());
+        let some = GrpcWebPolicy { routes: vec!["greeter".into()] };
+        assert!(some.call(&route("greeter"), &headers).is_some());
+        assert!(some.call(&route("other"), &headers).is_none());
+        assert!(some.call(&route("greeter"), &typed("application/json")).is_none());
+    }
+
+    #[test]
+    fn requests_become_grpc() {
+        let mut headers = typed("application/grpc-web+proto");
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("7"));
+        let body = web(false, "+proto").request(&mut headers, Bytes::from_static(b"\0\0\0\0\x02hi")).unwrap();
+        assert_eq!(body, Bytes::from_static(b"\0\0\0\0\x02hi"));
+        assert_eq!(headers[header::CONTENT_TYPE], "application/grpc+proto");
+        assert_eq!(headers[header::TE], "trailers");
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+        // Frames padded one by one decode as a whole
+        let chunks = format!("{}\n{}", STANDARD.encode(b"\0\0\0\0\x01a"), STANDARD.encode(b"\0\0\0\0\x02bc"));
+        let body = web(true, "").request(&mut HeaderMap::new(), Bytes::from(chunks)).unwrap();
+        assert_eq!(body, Bytes::from_static(b"\0\0\0\0\x01a\0\0\0\0\x02bc"));
+        assert!(web(true, "").request(&mut HeaderMap::new(), Bytes::from_static(b"!!")).is_err());
+    }
+
+    #[test]
+    fn responses_carry_the_trailers_in_the_body() {
+        let trailers = HeaderMap::from_iter([(
+            header::HeaderName::from_static("grpc-status"),
+            HeaderValue::from_static("0"),
+        )]);
+        let mut headers = typed("application/grpc+proto");
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from_static("7"));
+        let body = web(false, "+proto").response(&mut headers, Bytes::from_static(b"\0\0\0\0\x02hi"), &trailers);
+        assert_eq!(body, Bytes::from_static(b"\0\0\0\0\x02hi\x80\0\0\0\x10grpc-status: 0\r\n"));
+        assert_eq!(headers[header::CONTENT_TYPE], "application/grpc-web+proto");
+        assert!(!headers.contains_key(header::CONTENT_LENGTH));
+
+        let mut headers = typed("application/grpc");
+        let body = web(true, "").response(&mut headers, Bytes::from_static(b"\0\0\0\0\x02hi"), &HeaderMap::new());
+        assert_eq!(body, STANDARD.encode(b"\0\0\0\0\x02hi"));
+        assert_eq!(headers[header::CONTENT_TYPE], "application/grpc-web-text");
+
+        // Answers that are not gRPC, such as gateway errors, are left alone
+        let mut headers = typed("text/plain");
+        let body = web(true, "").response(&mut headers, Bytes::from_static(b"upstream error"), &trailers);
+        assert_eq!(body, Bytes::from_static(b"upstream error"));
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+    }
+}
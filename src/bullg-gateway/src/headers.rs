@@ -0,0 +1,194 @@
+use anyhow::{Result, anyhow};
+use bullg_core::{HeaderMode, HeaderNormalizeCfg, HeadersCfg, ResponseHeader, Service};
+use http::{HeaderMap, HeaderName, header, header::HeaderValue};
+
+/// Drop empty headers and join repeated ones per `cfg`. Set-Cookie values
+/// stay separate, joining them would break cookies with an `Expires` date.
+pub fn normalize(cfg: &HeaderNormalizeCfg, headers: &mut HeaderMap) {
+    if !cfg.merge_duplicates && !cfg.strip_empty {
+        return;
+    }
+    let names: Vec<HeaderName> = headers.keys().cloned().collect();
+    for name in names {
+        let mut values: Vec<HeaderValue> = headers.get_all(&name).iter().cloned().collect();
+        let before = values.len();
+        if cfg.strip_empty {
+            values.retain(|v| !v.as_bytes().trim_ascii().is_empty());
+        }
+        let merge = cfg.merge_duplicates && values.len() > 1 && name != header::SET_COOKIE;
+        if values.len() == before && !merge {
+            continue;
+        }
+        headers.remove(&name);
+        if merge {
+            let separator: &[u8] = if name == header::COOKIE { b"; " } else { b", " };
+            let joined = values
+                .iter()
+                .map(|v| v.as_bytes().trim_ascii())
+                .collect::<Vec<_>>()
+                .join(separator);
+            if let Ok(value) = HeaderValue::from_bytes(&joined) {
+                values = vec![value];
+            }
+        }
+        for value in values {
+            headers.append(name.clone(), value);
+        }
+    }
+}
+
+/// Add the static `response_headers` of a service or route to an upstream
+/// response. Entries that are not valid headers are refused by `check_static`.
+pub fn inject(list: &[ResponseHeader], headers: &mut HeaderMap) {
+    for h in list {
+        let (Ok(name), Ok(value)) = (HeaderName::from_bytes(h.name.as_bytes()), HeaderValue::from_str(&h.value)) else {
+            continue;
+        };
+        match h.mode {
+            HeaderMode::Override => {
+                headers.insert(name, value);
+            }
+            HeaderMode::Append => {
+                headers.append(name, value);
+            }
+            HeaderMode::IfMissing => {
+                headers.entry(name).or_insert(value);
+            }
+        }
+    }
+}
+
+/// Refuse `response_headers` of a service or its routes that are not valid headers
+pub fn check_static(svc: &Service) -> Result<()> {
+    let routes = svc.routes.iter().map(|r| (r.id.as_str(), &r.config.response_headers));
+    for (owner, list) in std::iter::once((svc.id.as_str(), &svc.response_headers)).chain(routes) {
+        for h in list {
+            HeaderName::from_bytes(h.name.as_bytes())
+                .map_err(|_| anyhow!("{}: invalid response header name `{}`", owner, h.name))?;
+            HeaderValue::from_str(&h.value)
+                .map_err(|_| anyhow!("{}: invalid value for response header `{}`", owner, h.name))?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove the `strip_response` headers of an upstream response, those that
+/// fingerprint the backend or are only meant for internal hops
+pub fn strip(cfg: &HeadersCfg, headers: &mut HeaderMap) {
+    if cfg.strip_response.is_empty() {
+        return;
+    }
+    let stripped: Vec<HeaderName> = headers
+        .keys()
+        .filter(|name| {
+            cfg.strip_response.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name
+                    .as_str()
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+                None => name.as_str().eq_ignore_ascii_case(pattern),
+            })
+        })
+        .cloned()
+        .collect();
+    for name in stripped {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    fn values<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+        headers.get_all(name).iter().map(|v| v.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn duplicates_are_joined_except_set_cookie() {
+        let mut headers = map(&[
+            ("accept", "text/html"),
+            ("accept", " application/json "),
+            ("cookie", "a=1"),
+            ("cookie", "b=2"),
+            ("set-cookie", "a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT"),
+            ("set-cookie", "b=2"),
+            ("x-empty", " "),
+            ("x-single", "kept"),
+        ]);
+        normalize(&HeaderNormalizeCfg::default(), &mut headers);
+        assert_eq!(values(&headers, "accept"), ["text/html, application/json"]);
+        assert_eq!(values(&headers, "cookie"), ["a=1; b=2"]);
+        assert_eq!(values(&headers, "set-cookie"), ["a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT", "b=2"]);
+        assert_eq!(values(&headers, "x-single"), ["kept"]);
+        assert!(!headers.contains_key("x-empty"));
+    }
+
+    #[test]
+    fn normalization_steps_can_be_turned_off() {
+        let raw = [("accept", "a"), ("accept", "b"), ("x-empty", "")];
+        let mut headers = map(&raw);
+        normalize(&HeaderNormalizeCfg { merge_duplicates: false, ..Default::default() }, &mut headers);
+        assert_eq!(values(&headers, "accept"), ["a", "b"]);
+        assert!(!headers.contains_key("x-empty"));
+
+        let mut headers = map(&raw);
+        normalize(&HeaderNormalizeCfg { strip_empty: false, ..Default::default() }, &mut headers);
+        assert_eq!(values(&headers, "accept"), ["a, b"]);
+        assert_eq!(values(&headers, "x-empty"), [""]);
+    }
+
+    fn static_header(name: &str, value: &str, mode: HeaderMode) -> ResponseHeader {
+        ResponseHeader { name: name.into(), value: value.into(), mode }
+    }
+
+    #[test]
+    fn static_headers_override_append_or_fill_in() {
+        let mut headers = map(&[("x-api-version", "1"), ("vary", "accept"), ("cache-control", "no-store")]);
+        let list = [
+            static_header("x-api-version", "2", HeaderMode::Override),
+            static_header("vary", "origin", HeaderMode::Append),
+            static_header("cache-control", "max-age=60", HeaderMode::IfMissing),
+            static_header("x-frame-options", "DENY", HeaderMode::IfMissing),
+        ];
+        inject(&list, &mut headers);
+        assert_eq!(values(&headers, "x-api-version"), ["2"]);
+        assert_eq!(values(&headers, "vary"), ["accept", "origin"]);
+        assert_eq!(values(&headers, "cache-control"), ["no-store"]);
+        assert_eq!(values(&headers, "x-frame-options"), ["DENY"]);
+    }
+
+    #[test]
+    fn invalid_static_headers_are_refused() {
+        let mut svc = Service { id: "svc".into(), ..Default::default() };
+        svc.response_headers = vec![static_header("x-ok", "fine", HeaderMode::Append)];
+        assert!(check_static(&svc).is_ok());
+        svc.response_headers.push(static_header("bad name", "v", HeaderMode::Override));
+        assert_eq!(check_static(&svc).unwrap_err().to_string(), "svc: invalid response header name `bad name`");
+
+        svc.response_headers.pop();
+        svc.routes = vec![Default::default()];
+        svc.routes[0].id = "users".into();
+        svc.routes[0].config.response_headers = vec![static_header("x-line", "a\nb", HeaderMode::Override)];
+        assert_eq!(check_static(&svc).unwrap_err().to_string(), "users: invalid value for response header `x-line`");
+    }
+
+    #[test]
+    fn stripped_headers_match_names_or_prefixes_without_case() {
+        let cfg = HeadersCfg { strip_response: vec!["Server".into(), "x-internal-*".into()], ..Default::default() };
+        let mut headers =
+            map(&[("server", "nginx"), ("x-internal-trace", "1"), ("x-internal", "2"), ("x-servers", "3"), ("etag", "\"e\"")]);
+        strip(&cfg, &mut headers);
+        let mut kept: Vec<&str> = headers.keys().map(|k| k.as_str()).collect();
+        kept.sort();
+        assert_eq!(kept, ["etag", "x-internal", "x-servers"]);
+    }
+}
@@ -0,0 +1,535 @@
+use bullg_core::{Protocols, Service, Upstream};
+use bullg_utils::{de_duration, ser_duration};
+use bytes::{BufMut, Bytes, BytesMut};
+use dashmap::DashMap;
+use http::HeaderValue;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+use crate::Gateway;
+
+const GRPC_HEALTH_CHECK: &str = "/grpc.health.v1.Health/Check";
+// grpc.health.v1.HealthCheckResponse.ServingStatus
+const GRPC_SERVING: u64 = 1;
+
+/// Active upstream health checks (`type: health_check` on a service or global policy).
+///
+/// The probe follows the upstream protocol: gRPC upstreams are asked
+/// `grpc.health.v1.Health/Check` and are healthy only on `SERVING`, HTTP
+/// upstreams must answer `path` with a 2xx and any other upstream must accept
+/// a TCP connection. Unhealthy upstreams are skipped when selecting one.
+///
+/// ```yaml
+/// - id: svc-health
+///   type: health_check
+///   enabled: true
+///   config:
+///     interval: 10s
+///     timeout: 2s
+///     path: /health # HTTP probes
+///     grpc_service: "" # gRPC probes, empty checks the whole server
+///     unhealthy_threshold: 2
+///     healthy_threshold: 1
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheckPolicy {
+    #[serde(default = "def_interval", deserialize_with = "de_duration", serialize_with = "ser_duration")]
+    pub interval: Duration,
+    #[serde(default = "def_timeout", deserialize_with = "de_duration", serialize_with = "ser_duration")]
+    pub timeout: Duration,
+    #[serde(default = "def_path")]
+    pub path: String,
+    #[serde(default)]
+    pub grpc_service: String,
+    #[serde(default = "def_unhealthy")]
+    pub unhealthy_threshold: u32,
+    #[serde(default = "def_healthy")]
+    pub healthy_threshold: u32,
+}
+
+fn def_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn def_timeout() -> Duration {
+    Duration::from_secs(2)
+}
+
+fn def_path() -> String {
+    "/health".into()
+}
+
+fn def_unhealthy() -> u32 {
+    2
+}
+
+fn def_healthy() -> u32 {
+    1
+}
+
+impl HealthCheckPolicy {
+    pub const KIND: &'static str = "health_check";
+}
+
+/// Passive health checks (`type: outlier_detection` on a service or global
+/// policy).
+///
+/// Upstream answers are watched as they pass through. After
+/// `consecutive_failures` failures in a row, connection errors, timeouts or
+/// 5xx unless an `upstream_failure` policy says otherwise, the upstream is
+/// ejected for `ejection_time`. Once that is over it takes
+/// requests again, a single new failure ejects it once more while a success
+/// clears the count.
+///
+/// ```yaml
+/// - id: svc-outliers
+///   type: outlier_detection
+///   enabled: true
+///   config:
+///     consecutive_failures: 5
+///     ejection_time: 30s
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutlierPolicy {
+    #[serde(default = "def_consecutive_failures")]
+    pub consecutive_failures: u32,
+    #[serde(default = "def_ejection_time", deserialize_with = "de_duration", serialize_with = "ser_duration")]
+    pub ejection_time: Duration,
+}
+
+fn def_consecutive_failures() -> u32 {
+    5
+}
+
+fn def_ejection_time() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl OutlierPolicy {
+    pub const KIND: &'static str = "outlier_detection";
+}
+
+/// How an upstream is probed, picked from its protocols
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeMode {
+    Grpc { tls: bool },
+    Http,
+    Tcp,
+}
+
+impl ProbeMode {
+    pub fn for_upstream(upstream: &Upstream) -> Self {
+        let has = |p: Protocols| upstream.protocols.contains(&p);
+        if has(Protocols::GRPC) || has(Protocols::GRPCS) {
+            ProbeMode::Grpc { tls: has(Protocols::GRPCS) && !has(Protocols::GRPC) }
+        } else if has(Protocols::HTTP) || has(Protocols::HTTPS) {
+            ProbeMode::Http
+        } else {
+            ProbeMode::Tcp
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct UpstreamHealth {
+    healthy: bool,
+    successes: u32,
+    failures: u32,
+    next_probe: Option<Instant>,
+    // A probe slower than the interval is not stacked with the next one
+    probing: bool,
+}
+
+#[derive(Debug, Default)]
+struct Ejection {
+    failures: u32,
+    until: Option<Instant>,
+}
+
+/// Health of one service upstream as seen by the active and passive checks
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamStatus {
+    pub service: String,
+    pub upstream: String,
+    /// Passing the active probes, true when never probed
+    pub healthy: bool,
+    /// Taken out of rotation by the passive checks
+    pub ejected: bool,
+    pub consecutive_failures: u32,
+}
+
+/// Health of every probed or watched upstream, keyed by service and upstream
+/// id. Upstreams never probed count as healthy.
+pub struct Health {
+    status: DashMap<(String, String), UpstreamHealth>,
+    ejections: DashMap<(String, String), Ejection>,
+    client: reqwest::Client,
+    // gRPC needs HTTP/2, on plain connections without negotiation
+    grpc_client: reqwest::Client,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            status: DashMap::new(),
+            ejections: DashMap::new(),
+            client: reqwest::Client::new(),
+            grpc_client: reqwest::Client::builder()
+                .http2_prior_knowledge()
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn key(service: &str, upstream: &str) -> (String, String) {
+    (service.to_string(), upstream.to_string())
+}
+
+impl Health {
+    /// Whether the upstream may take requests: passing its probes and not
+    /// ejected by the passive checks
+    pub fn is_healthy(&self, service: &str, upstream: &str) -> bool {
+        let key = key(service, upstream);
+        self.status.get(&key).is_none_or(|h| h.healthy)
+            && self
+                .ejections
+                .get(&key)
+                .is_none_or(|e| e.until.is_none_or(|until| until <= Instant::now()))
+    }
+
+    /// Record the outcome of a proxied request for the passive checks
+    pub fn observe(&self, service: &str, upstream: &str, ok: bool, policy: &OutlierPolicy) {
+        let mut e = self.ejections.entry(key(service, upstream)).or_default();
+        let now = Instant::now();
+        // An ejection that ran out leaves the upstream one failure from the next
+        if e.until.is_some_and(|until| until <= now) {
+            e.until = None;
+            e.failures = policy.consecutive_failures.saturating_sub(1);
+        }
+        if ok {
+            e.failures = 0;
+            return;
+        }
+        e.failures += 1;
+        if e.until.is_none() && e.failures >= policy.consecutive_failures {
+            e.until = Some(now + policy.ejection_time);
+            warn!(
+                "upstream {} of service {} ejected for {}ms after {} failures",
+                upstream,
+                service,
+                policy.ejection_time.as_millis(),
+                e.failures
+            );
+        }
+    }
+
+    /// Current state of every upstream the checks know about
+    pub fn snapshot(&self) -> Vec<UpstreamStatus> {
+        let now = Instant::now();
+        let mut keys: Vec<(String, String)> = self.status.iter().map(|e| e.key().clone()).collect();
+        keys.extend(self.ejections.iter().map(|e| e.key().clone()));
+        keys.sort();
+        keys.dedup();
+        keys.into_iter()
+            .map(|key| {
+                let ejection = self.ejections.get(&key);
+                UpstreamStatus {
+                    healthy: self.status.get(&key).is_none_or(|h| h.healthy),
+                    ejected: ejection.as_ref().is_some_and(|e| e.until.is_some_and(|until| until > now)),
+                    consecutive_failures: ejection.map_or(0, |e| e.failures),
+                    service: key.0,
+                    upstream: key.1,
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the upstream is due for a probe, the next one is scheduled
+    /// `interval` from now
+    pub fn due(&self, service: &str, upstream: &str, interval: Duration) -> bool {
+        let now = Instant::now();
+        let mut h = self.status.entry(key(service, upstream)).or_insert_with(|| UpstreamHealth {
+            healthy: true,
+            ..Default::default()
+        });
+        if h.probing || h.next_probe.is_some_and(|at| at > now) {
+            return false;
+        }
+        h.next_probe = Some(now + interval);
+        h.probing = true;
+        true
+    }
+
+    /// Forget upstreams no longer in the state, `probed` are the ones still
+    /// under an active health check and `known` every upstream of the state
+    pub fn retain(&self, probed: &HashSet<(String, String)>, known: &HashSet<(String, String)>) {
+        self.status.retain(|k, _| probed.contains(k));
+        self.ejections.retain(|k, _| known.contains(k));
+    }
+
+    /// Probe one upstream and record the result
+    pub async fn check(&self, service: &str, upstream: &Upstream, policy: &HealthCheckPolicy) -> bool {
+        let ok = match tokio::time::timeout(policy.timeout, self.probe(upstream, policy)).await {
+            Ok(Ok(())) => true,
+            Ok(Err(e)) => {
+                debug!("health probe of upstream {} failed: {e}", upstream.id);
+                false
+            }
+            Err(_) => {
+                debug!("health probe of upstream {} timed out", upstream.id);
+                false
+            }
+        };
+        self.record(service, &upstream.id, ok, policy);
+        ok
+    }
+
+    async fn probe(&self, upstream: &Upstream, policy: &HealthCheckPolicy) -> anyhow::Result<()> {
+        match ProbeMode::for_upstream(upstream) {
+            ProbeMode::Grpc { tls } => {
+                let scheme = if tls { "https" } else { "http" };
+                let url = format!("{scheme}://{}{GRPC_HEALTH_CHECK}", upstream.get_address());
+                self.grpc_check(&url, &policy.grpc_service).await
+            }
+            ProbeMode::Http => {
+                let url = format!("{}{}", upstream.get_url(), policy.path);
+                let resp = self.client.get(url).send().await?;
+                if !resp.status().is_success() {
+                    anyhow::bail!("status {}", resp.status());
+                }
+                Ok(())
+            }
+            ProbeMode::Tcp => {
+                TcpStream::connect(upstream.get_address()).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn grpc_check(&self, url: &str, service: &str) -> anyhow::Result<()> {
+        let resp = self
+            .grpc_client
+            .post(url)
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(grpc_request(service))
+            .send()
+            .await?;
+        // A trailers only answer carries the error status in the headers
+        if let Some(status) = resp.headers().get("grpc-status")
+            && status != HeaderValue::from_static("0")
+        {
+            anyhow::bail!("grpc-status {:?}", status);
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("status {}", resp.status());
+        }
+        match serving_status(&resp.bytes().await?) {
+            Some(GRPC_SERVING) => Ok(()),
+            status => anyhow::bail!("serving status {:?}", status),
+        }
+    }
+
+    fn record(&self, service: &str, upstream: &str, ok: bool, policy: &HealthCheckPolicy) {
+        let mut h = self.status.entry(key(service, upstream)).or_insert_with(|| UpstreamHealth {
+            healthy: true,
+            ..Default::default()
+        });
+        h.probing = false;
+        if ok {
+            h.successes += 1;
+            h.failures = 0;
+            if !h.healthy && h.successes >= policy.healthy_threshold {
+                h.healthy = true;
+                info!("upstream {} of service {} is healthy", upstream, service);
+            }
+        } else {
+            h.failures += 1;
+            h.successes = 0;
+            if h.healthy && h.failures >= policy.unhealthy_threshold {
+                h.healthy = false;
+                warn!("upstream {} of service {} is unhealthy", upstream, service);
+            }
+        }
+    }
+}
+
+/// Length prefixed `HealthCheckRequest { service }` message
+fn grpc_request(service: &str) -> Bytes {
+    let mut msg = BytesMut::new();
+    if !service.is_empty() {
+        msg.put_u8(0x0a); // field 1, length delimited
+        put_varint(&mut msg, service.len() as u64);
+        msg.put_slice(service.as_bytes());
+    }
+    let mut frame = BytesMut::with_capacity(5 + msg.len());
+    frame.put_u8(0); // not compressed
+    frame.put_u32(msg.len() as u32);
+    frame.put_slice(&msg);
+    frame.freeze()
+}
+
+/// `status` of the first `HealthCheckResponse` in a gRPC body, 0 (UNKNOWN)
+/// when the field is absent
+fn serving_status(body: &[u8]) -> Option<u64> {
+    let len = u32::from_be_bytes(body.get(1..5)?.try_into().ok()?) as usize;
+    let mut msg = body.get(5..5 + len)?;
+    let mut status = 0;
+    while !msg.is_empty() {
+        let tag = read_varint(&mut msg)?;
+        match tag & 7 {
+            0 => {
+                let value = read_varint(&mut msg)?;
+                if tag >> 3 == 1 {
+                    status = value;
+                }
+            }
+            2 => {
+                let n = read_varint(&mut msg)? as usize;
+                msg = msg.get(n..)?;
+            }
+            _ => return None,
+        }
+    }
+    Some(status)
+}
+
+fn put_varint(buf: &mut BytesMut, mut v: u64) {
+    while v >= 0x80 {
+        buf.put_u8((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.put_u8(v as u8);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        v |= u64::from(b & 0x7f) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+impl Gateway {
+    /// Probe the upstreams of every service with a health check policy until
+    /// the task is aborted. Upstreams that left the state, or whose service
+    /// dropped its policy, are forgotten on the next tick.
+    pub async fn run_health_checks(self: Arc<Self>) {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            tick.tick().await;
+            // Version scoped copies share the id and the upstreams
+            let mut services: Vec<(Arc<Service>, Option<HealthCheckPolicy>)> =
+                self.state.iter().map(|e| (e.service.clone(), e.policies.health_check.clone())).collect();
+            services.sort_by(|a, b| a.0.id.cmp(&b.0.id));
+            services.dedup_by(|a, b| a.0.id == b.0.id);
+            let (mut probed, mut known) = (HashSet::new(), HashSet::new());
+            for (svc, policy) in services {
+                known.extend(svc.upstreams.iter().map(|u| key(&svc.id, &u.id)));
+                let Some(policy) = policy else {
+                    continue;
+                };
+                for upstream in svc.upstreams.iter().filter(|u| u.is_enabled()) {
+                    probed.insert(key(&svc.id, &upstream.id));
+                    if !self.health.due(&svc.id, &upstream.id, policy.interval) {
+                        continue;
+                    }
+                    let (health, svc, upstream, policy) =
+                        (self.health.clone(), svc.clone(), upstream.clone(), policy.clone());
+                    tokio::spawn(async move { health.check(&svc.id, &upstream, &policy).await });
+                }
+            }
+            self.health.retain(&probed, &known);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, StreamBody};
+    use hyper::body::Frame;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use std::convert::Infallible;
+
+    /// gRPC health server over h2c, SERVING for the whole server and
+    /// NOT_SERVING for any named service
+    async fn grpc_health() -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = hyper::service::service_fn(|req: http::Request<hyper::body::Incoming>| async move {
+                    assert_eq!(req.uri().path(), GRPC_HEALTH_CHECK);
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    let status: u8 = if body.len() > 5 { 2 } else { 1 };
+                    let mut trailers = http::HeaderMap::new();
+                    trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                    let frames = vec![
+                        Ok::<_, Infallible>(Frame::data(Bytes::from(vec![0, 0, 0, 0, 2, 0x08, status]))),
+                        Ok(Frame::trailers(trailers)),
+                    ];
+                    let resp = http::Response::builder()
+                        .header("content-type", "application/grpc")
+                        .body(StreamBody::new(futures_util::stream::iter(frames)))
+                        .unwrap();
+                    Ok::<_, Infallible>(resp)
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        addr
+    }
+
+    fn grpc_upstream(addr: std::net::SocketAddr) -> Upstream {
+        Upstream {
+            id: "grpc".into(),
+            protocols: vec![Protocols::GRPC],
+            host: addr.ip().to_string(),
+            port: addr.port(),
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    fn policy(grpc_service: &str) -> HealthCheckPolicy {
+        serde_json::from_value(serde_json::json!({"grpc_service": grpc_service, "unhealthy_threshold": 1})).unwrap()
+    }
+
+    #[tokio::test]
+    async fn grpc_upstreams_are_healthy_only_when_serving() {
+        let upstream = grpc_upstream(grpc_health().await);
+        assert_eq!(ProbeMode::for_upstream(&upstream), ProbeMode::Grpc { tls: false });
+        let health = Health::default();
+
+        assert!(health.check("svc", &upstream, &policy("")).await);
+        assert!(health.is_healthy("svc", "grpc"));
+        assert!(!health.check("svc", &upstream, &policy("users.v1.Users")).await);
+        assert!(!health.is_healthy("svc", "grpc"));
+        assert!(health.check("svc", &upstream, &policy("")).await);
+        assert!(health.is_healthy("svc", "grpc"));
+    }
+
+    #[test]
+    fn health_messages_are_encoded_and_decoded() {
+        assert_eq!(&grpc_request("")[..], [0, 0, 0, 0, 0]);
+        assert_eq!(&grpc_request("ab")[..], [0, 0, 0, 0, 4, 0x0a, 2, b'a', b'b']);
+        assert_eq!(serving_status(&[0, 0, 0, 0, 2, 0x08, 1]), Some(GRPC_SERVING));
+        // An empty message is UNKNOWN, a cut one is not a status at all
+        assert_eq!(serving_status(&[0, 0, 0, 0, 0]), Some(0));
+        assert_eq!(serving_status(&[0, 0, 0, 0, 2, 0x08]), None);
+    }
+}
@@ -1,304 +1,1700 @@
+pub mod admin;
+pub mod balance;
+pub mod capture;
+pub mod catalog;
+pub mod client;
+pub mod concurrency;
+pub mod debug;
+pub mod drain;
+pub mod framing;
+pub mod grpcweb;
+pub mod failure;
+pub mod headers;
+pub mod health;
+pub mod maintenance;
+pub mod metrics;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod normalize;
+pub mod policy;
+pub mod propagation;
+pub mod retry;
+pub mod routing;
+pub mod shadow;
+pub mod shedding;
+pub mod spool;
+pub mod status;
+pub mod stream;
+pub mod throttle;
+pub mod timeout;
+pub mod upgrade;
 
+#[cfg(test)]
+mod tests;
 
+use anyhow::{Result, anyhow, bail};
+use bullg_core::{
+    AppliedPlugin, AsyncMemory, CONSUMER_INDEX_DB, CONSUMERS_DB, ConsumersTemplate, GatewayNode, Memory, PluginsCatalog, Route, Service,
+    ServiceMapper, ServicesTemplate, StateDelta, StateLimitsCfg, ToServicesMapperVec,
+};
+use bullg_plugin_api::{BullGContext, BullGTools, MatchedRoute, Phase, Plugin};
+use bytes::{Bytes, BytesMut};
+use chrono::{Datelike, Utc};
+use dashmap::DashMap;
+use futures_util::FutureExt;
+use http::{HeaderMap, HeaderName, Method, Request, Response, StatusCode, Uri, header::HeaderValue};
+use http_body_util::{BodyExt, Full, combinators::BoxBody};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::tokio::TokioIo;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::panic::AssertUnwindSafe;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tracing::field::Empty;
+use tracing::{Instrument, debug, error, info, info_span, warn};
+use uuid::Uuid;
 
+use bullg_logger::access::{AccessFormat, AccessLogger, AccessRecord};
+use bullg_logger::rolling::Rotation;
 
+use crate::balance::Balancer;
+use crate::capture::{Capture, CapturePolicy, Captures};
+use crate::catalog::Catalog;
+use crate::client::Clients;
+use crate::concurrency::Limiter;
+use crate::debug::{ForcedUpstream, PluginTrace};
+use crate::drain::Drain;
+use crate::health::Health;
+use crate::maintenance::Maintenance;
+use crate::metrics::{Metrics, RouteLabels};
+use crate::policy::{Policies, PolicyError};
+use crate::retry::RETRY_COUNT_HEADER;
+use crate::routing::RouteTable;
+use crate::shadow::ShadowPolicy;
+use crate::spool::{BufferError, Buffered};
+use crate::status::Status;
+use crate::stream::{ErrorSignal, STREAM_ERROR_TRAILER, accepts_trailers};
+use crate::throttle::Throttle;
+use crate::timeout::TimeoutPolicy;
 
+// Inject app name & version at compile-time from Cargo.toml
+const APP_NAME: &str = env!("APP_NAME");
+const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-//Commented Version 1.0.0 Code for Gateway implementation for Version 1.0.1
-// Once Version 1.0.1 is stable, remove the comments and delete Version 1.0.0 code.
-// use anyhow::Result;
-// use bullg_core::{ AppliedPlugin, GatewayState, Route, Service };
-// use bullg_memory::Store;
-// use bullg_plugin_api::{ BullGContext, Phase, Plugin };
-// use bytes::Bytes;
-// use dashmap::DashMap;
-// use http::{ Request, Response, StatusCode, Uri, header::HeaderValue };
-// use hyper::body::Incoming;
-// use hyper::server::conn::http1;
-// use hyper::service::service_fn;
-// use http_body_util::{ BodyExt, Full };
-// use std::net::SocketAddr;
-// use std::sync::Arc;
-// use tokio::net::TcpListener;
-// use hyper_util::rt::tokio::TokioIo;
-// use tracing::{ error, info, debug };
-// use url::Url;
-// //use uuid::Uuid;
-// use std::time::Instant;
-// use chrono::{Datelike, Utc};
-
-// #[derive(Clone)]
-// pub struct Gateway {
-//     state: Arc<DashMap<String, Service>>,
-//     global_plugins: Arc<tokio::sync::RwLock<Vec<AppliedPlugin>>>, // interior mutability
-//     store: Arc<Store>,
-//     plugins: Arc<Vec<Box<dyn Plugin>>>,
-//     client: reqwest::Client,
-// }
-
-// // Inject app name & version at compile-time from Cargo.toml
-// const APP_NAME: &str = env!("APP_NAME");
-// const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
-
-// impl Gateway {
-//     pub fn new(store: Store) -> Self {
-//         Self {
-//             state: Arc::new(DashMap::new()),
-//             global_plugins: Arc::new(tokio::sync::RwLock::new(vec![])),
-//             store: Arc::new(store),
-//             plugins: Arc::new(bullg_plugins::builtin()),
-//             client: reqwest::Client::new(),
-//         }
-//     }
-
-//     pub fn get_store(&self) -> Arc<Store> {
-//         self.store.clone()
-//     }
-
-//     pub async fn update_state(&self, s: GatewayState) {
-//         //debug!("updating state with {} services", s.services.len());
-//         debug!("current state: {:?}", s);
-//         self.state.clear();
-//         for svc in s.services {
-//             self.state.insert(svc.id.clone(), svc);
-//         }
-//         let mut gp = self.global_plugins.write().await;
-//         *gp = s.global_plugins;
-//         debug!("state updated: {} services", self.state.len());
-//     }
-
-//     fn match_route(&self, uri: &Uri) -> Option<(Service, Route)> {
-//         let path = uri.path();
-//         debug!("matching route for path: {}", path);
-//         debug!("current state: {:?}", self.state);
-//         for svc in self.state.iter() {
-//             for r in &svc.routes {
-//                 if path.starts_with(&r.path) {
-//                     return Some((svc.clone(), r.clone()));
-//                 }
-//             }
-//         }
-//         None
-//     }
-
-//     async fn run_plugins(&self, phase: Phase, ctx: &BullGContext, list: &[AppliedPlugin]) {
-//         for ap in list {
-//             if
-//                 let Some(p) = self.plugins
-//                     .iter()
-//                     .find(|p| p.name() == ap.name && p.phase() == phase)
-//             {
-//                 if let Err(e) = p.apply(ctx, &ap.config) {
-//                     error!("plugin {} failed: {e}", ap.name);
-//                 }
-//                 if ctx.status.read().is_some() && phase == Phase::Pre {
-//                     break;
-//                 }
-//             }
-//         }
-//     }
-
-//     pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
-//         let listener = TcpListener::bind(addr).await?;
-//         info!("{} listening on {}", APP_NAME, addr);
-//         loop {
-//             let (stream, _) = listener.accept().await?;
-//             let me = self.clone();
-//             tokio::spawn(async move {
-//                 let io = TokioIo::new(stream);
-//                 let conn = http1::Builder::new().serve_connection(
-//                     io,
-//                     service_fn(move |req| {
-//                         let me = me.clone();
-//                         async move { me.handle(req).await }
-//                     })
-//                 );
-//                 if let Err(e) = conn.await {
-//                     error!("conn error: {e}");
-//                 }
-//             });
-//         }
-//     }
-
-//     async fn handle(&self, req: Request<Incoming>) -> Result<Response<Full<Bytes>>, hyper::Error> {
-//         let start = Instant::now();
-//         // Generate a unique request ID
-//         //let request_id = Uuid::new_v4().to_string();
-
-//         let (parts, body) = req.into_parts();
-//         let body_bytes = body.collect().await?.to_bytes();
-//         let ctx = BullGContext::new(
-//             parts.method.clone(),
-//             parts.uri.clone(),
-//             parts.headers.clone(),
-//             body_bytes.clone()
-//         );
-//         let request_id = ctx.get_id().to_string();
-
-//         info!("Handling request {}: {} {}", request_id, parts.method.clone(), parts.uri.clone());
-
-//         let gp = self.global_plugins.read().await;
-//         self.run_plugins(Phase::Pre, &ctx, &gp).await;
-//         if let Some(code) = *ctx.status.read() {
-//             return Ok(self.default_headers(simple(code, ctx.get_body()), &request_id, start));
-//         }
-
-//         let (svc, route) = match self.match_route(&parts.uri) {
-//             Some(x) => x,
-//             None => {
-//                 return Ok(
-//                     self.default_headers(
-//                         simple(
-//                             StatusCode::NOT_FOUND,
-//                             Bytes::from(
-//                                 format!(
-//                                     "<html><head><title>BullG: Route Not Found</title></head>\
-//                                     <body><h1>Not Found</h1>\
-//                                     <h2>Route not found</h2>\
-//                                     <p><b>Request id:</b> {request_id}</p>\
-//                                     <br/><hr/> \
-//                                     <center><p>{app_name} Gateway {app_version} &copy; {year}</p></center>\
-//                                     </body></html>",
-//                                     request_id = request_id,
-//                                     app_name = APP_NAME,
-//                                     app_version = APP_VERSION,
-//                                     year = Utc::now().year()
-//                                 )
-//                             )
-//                         ),
-//                         &request_id,
-//                         start
-//                     )
-//                 );
-//             }
-//         };
-
-//         let upstream = format!("{}{}", svc.url, route.path);
-//         let mut url = Url::parse(&upstream).unwrap();
-//         url.set_path(parts.uri.path());
-//         url.set_query(parts.uri.query());
-
-//         let upstream_host = url.host_str().unwrap_or_default();
-
-//         // Modify headers: preserve original host and set forwarding headers
-//         {
-//             let mut headers = ctx.headers.write();
-//             if let Some(orig_host) = headers.get("host").cloned() {
-//                 headers.insert("x-forwarded-host", orig_host);
-//             }
-//             headers.insert("host", HeaderValue::from_str(&upstream_host).unwrap());
-//             headers.insert("via", HeaderValue::from_str(&APP_NAME).unwrap());
-//             headers.insert("referer", HeaderValue::from_str(&parts.uri.to_string()).unwrap());
-//         }
-
-//         let mut rb = self.client.request(parts.method.clone(), url.as_str());
-//         for (k, v) in ctx.headers.read().iter() {
-//             rb = rb.header(k, v);
-//         }
-
-//         debug!(
-//             "upstream request: {} {:?} {:?}",
-//             parts.method.clone(),
-//             url.as_str(),
-//             ctx.headers.read()
-//         );
-//         let upstart = Instant::now();
-//         let resp = match rb.body(ctx.get_body().to_vec()).send().await {
-//             Ok(r) => r,
-//             Err(e) => {
-//                 error!("upstream error: {e}");
-//                 return Ok(
-//                     self.default_headers(
-//                         simple(StatusCode::BAD_GATEWAY, Bytes::from_static(b"upstream error")),
-//                         &request_id,
-//                         start
-//                     )
-//                 );
-//             }
-//         };
-//         info!("upstream Latency: {:?}", upstart.elapsed().as_millis().to_string());
-//         let status = StatusCode::from_u16(resp.status().as_u16()).unwrap();
-//         ctx.set_headers(resp.headers().clone());
-//         let bytes = resp.bytes().await.unwrap_or(Bytes::new());
-//         debug!("upstream response: {} {:?}", status, bytes);
-//         ctx.set_body(bytes.clone());
-
-//         ctx.set_status(status);
-
-//         self.run_plugins(Phase::Post, &ctx, &gp).await;
-
-//         Ok(self.default_headers_from_ctx(&ctx, &request_id, start))
-//     }
-
-//     fn default_headers(
-//         &self,
-//         mut resp: Response<Full<Bytes>>,
-//         request_id: &str,
-//         start: Instant
-//     ) -> Response<Full<Bytes>> {
-//         let latency_us = start.elapsed().as_micros().to_string();
-//         let latency_ms = start.elapsed().as_millis().to_string();
-
-//         resp.headers_mut().insert("Via", HeaderValue::from_str(APP_NAME).unwrap());
-//         if !resp.headers_mut().get("Content-Type").is_some() {
-//             let accept = resp.headers_mut().get("Accept");
-
-//             //println!("Accept = {:?}", accept);
-//             if ! accept.is_some() {
-//                 resp.headers_mut().insert(
-//                     "Content-Type",
-//                     HeaderValue::from_str("text/html").unwrap()
-//                 );
-//             } else {
-//                 println!("Accept = {:?}", accept);
-//                 //resp.headers_mut().insert("Content-Type", );
-//             }
-//         }
-//         resp.headers_mut().insert(
-//             "Server",
-//             HeaderValue::from_str(&format!("{}/{}", APP_NAME, APP_VERSION)).unwrap()
-//         );
-//         resp.headers_mut().insert(
-//             "X-Powered-By",
-//             HeaderValue::from_str(&format!("{}-{}/VIKSHRO", APP_NAME, APP_VERSION)).unwrap()
-//         );
-//         resp.headers_mut().insert(
-//             "X-Server",
-//             HeaderValue::from_str(&format!("{}/{}", APP_NAME, APP_VERSION)).unwrap()
-//         );
-//         resp.headers_mut().insert(
-//             "X-Gateway",
-//             HeaderValue::from_str(&format!("{} Gateway/{}", APP_NAME, APP_VERSION)).unwrap()
-//         );
-//         resp.headers_mut().insert("X-Latency-Us", HeaderValue::from_str(&latency_us).unwrap());
-//         resp.headers_mut().insert("X-Latency", HeaderValue::from_str(&latency_ms).unwrap());
-//         resp.headers_mut().insert("X-Request-Id", HeaderValue::from_str(request_id).unwrap());
-
-//         resp
-//     }
-
-//     fn default_headers_from_ctx(
-//         &self,
-//         ctx: &BullGContext,
-//         request_id: &str,
-//         start: Instant
-//     ) -> Response<Full<Bytes>> {
-//         //debug!("response: {:?}", ctx.get_body());
-//         let mut resp = Response::builder()
-//             .status(*ctx.status.read().as_ref().unwrap_or(&StatusCode::OK))
-//             .body(Full::new(ctx.get_body()))
-//             .unwrap();
-
-//         // Apply headers from context
-//         for (k, v) in ctx.headers.read().iter() {
-//             resp.headers_mut().insert(k.clone(), v.clone());
-//         }
-
-//         // Add default headers
-//         self.default_headers(resp, request_id, start)
-//     }
-// }
-
-// fn simple(status: StatusCode, body: Bytes) -> Response<Full<Bytes>> {
-//     Response::builder().status(status).body(Full::new(body)).unwrap()
-// }
+// Connection scoped headers which must not be forwarded by a proxy (RFC 9110 §7.6.1)
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+/// Response body, either fully buffered or streamed from the upstream
+pub type GatewayBody = BoxBody<Bytes, BoxError>;
+
+/// A matched service/route pair for an inbound request
+#[derive(Debug, Clone)]
+pub struct RouteMatch {
+    pub service: Arc<Service>,
+    pub route: Route,
+    /// Request path with the service context path stripped
+    pub path: String,
+    /// Values of the `{name}` segments of the route path
+    pub params: HashMap<String, String>,
+    pub policies: Arc<Policies>,
+}
+
+/// A version scoped service of the state and its typed policies
+#[derive(Clone)]
+struct Scoped {
+    service: Arc<Service>,
+    policies: Arc<Policies>,
+}
+
+/// Why a request matched no route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteMiss {
+    NotFound,
+    /// A route matched the path but not the method, these methods would
+    MethodNotAllowed(Vec<String>),
+}
+
+/// Where an applied plugin is attached in the gateway state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginScope {
+    Global,
+    /// Service id, every version scoped copy of the service is updated
+    Service(String),
+    Route { service: String, route: String },
+}
+
+#[derive(Clone)]
+pub struct Gateway {
+    config: Arc<GatewayNode>,
+    // context path -> version scoped service
+    state: Arc<DashMap<String, Scoped>>,
+    // Last applied template, the base of state deltas
+    template: Arc<tokio::sync::RwLock<ServicesTemplate>>,
+    routes: Arc<std::sync::RwLock<RouteTable>>,
+    global_plugins: Arc<tokio::sync::RwLock<Vec<AppliedPlugin>>>,
+    store: Arc<Memory>,
+    tools: Arc<BullGTools>,
+    plugins: Arc<Vec<Arc<dyn Plugin>>>,
+    client: reqwest::Client,
+    // Clients of services tuned by an upstream_client policy
+    clients: Arc<Clients>,
+    // Upgrades are only defined for HTTP/1.1
+    upgrade_client: reqwest::Client,
+    // gRPC needs HTTP/2, bridged gRPC-Web calls use it without negotiation
+    grpc_client: reqwest::Client,
+    limiter: Arc<Limiter>,
+    throttle: Arc<Throttle>,
+    captures: Arc<Captures>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    access_log: Option<Arc<AccessLogger>>,
+    balancer: Arc<Balancer>,
+    maintenance: Arc<Maintenance>,
+    drain: Arc<Drain>,
+    status: Arc<Status>,
+}
+
+impl Gateway {
+    pub fn new(config: GatewayNode, store: Memory) -> Self {
+        let store = Arc::new(store);
+        let metrics = Arc::new(Metrics::default());
+        let access_log = access_logger(&config).map(Arc::new);
+        Self {
+            tools: Arc::new(BullGTools::with_store(AsyncMemory::new(store.clone())).with_load(metrics.load.clone())),
+            captures: Arc::new(Captures::new(config.admin.captures)),
+            metrics,
+            state: Arc::new(DashMap::new()),
+            template: Arc::new(tokio::sync::RwLock::new(ServicesTemplate::default())),
+            routes: Arc::new(std::sync::RwLock::new(RouteTable::default())),
+            global_plugins: Arc::new(tokio::sync::RwLock::new(vec![])),
+            store,
+            plugins: Arc::new(bullg_plugins::builtin().into_iter().map(Arc::from).collect()),
+            client: client_builder(&config).build().unwrap_or_default(),
+            clients: Arc::new(Clients::default()),
+            upgrade_client: client_builder(&config).http1_only().build().unwrap_or_default(),
+            grpc_client: client_builder(&config).http2_prior_knowledge().build().unwrap_or_default(),
+            limiter: Arc::new(Limiter::new()),
+            throttle: Arc::new(Throttle::default()),
+            health: Arc::new(Health::default()),
+            access_log,
+            balancer: Arc::new(Balancer::default()),
+            maintenance: Arc::new(Maintenance::new(&config.maintenance)),
+            drain: Arc::new(Drain::default()),
+            status: Arc::new(Status::default()),
+            config: Arc::new(config),
+        }
+    }
+
+    pub fn get_config(&self) -> Arc<GatewayNode> {
+        self.config.clone()
+    }
+
+    pub fn get_store(&self) -> Arc<Memory> {
+        self.store.clone()
+    }
+
+    /// Add a plugin implementation next to the builtin ones, applied plugins
+    /// refer to it by its `name()`
+    pub fn with_plugin(mut self, plugin: Box<dyn Plugin>) -> Self {
+        let mut plugins: Vec<Arc<dyn Plugin>> = self.plugins.iter().cloned().collect();
+        plugins.push(Arc::from(plugin));
+        self.plugins = Arc::new(plugins);
+        self
+    }
+
+    /// Add the script plugins of the custom plugins of `catalog`, see
+    /// `bullg_plugins::Script`. Fails on a handler this build cannot run.
+    pub fn with_catalog(self, catalog: &PluginsCatalog) -> Result<Self> {
+        let custom = bullg_plugins::custom(catalog)?;
+        if !custom.is_empty() {
+            info!("{} custom plugins loaded from the catalog", custom.len());
+        }
+        Ok(custom.into_iter().fold(self, Gateway::with_plugin))
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    pub fn health(&self) -> Arc<Health> {
+        self.health.clone()
+    }
+
+    /// Services and enabled routes of the running state
+    pub fn catalog(&self) -> Catalog {
+        let services: Vec<(String, Arc<Service>)> =
+            self.state.iter().map(|e| (e.key().clone(), e.service.clone())).collect();
+        Catalog::build(services.iter().map(|(key, svc)| (key.as_str(), svc.as_ref())), &self.config.methods)
+    }
+
+    /// OpenAPI skeleton of the running state, titled with the node name
+    pub fn openapi(&self) -> serde_json::Value {
+        let title = if self.config.name.is_empty() { "BullGateway" } else { &self.config.name };
+        let version = if self.config.version.is_empty() { env!("CARGO_PKG_VERSION") } else { &self.config.version };
+        self.catalog().openapi(title, version, &self.config.methods)
+    }
+
+    /// Swap in a new services template, rejected as a whole if it is above
+    /// the configured state limits or any applied plugin or policy fails
+    /// validation. Every plugin list is kept sorted in run order, see
+    /// `AppliedPlugin::sort`. Services are replaced in place, requests never
+    /// see an empty state.
+    pub async fn update_state(&self, mut s: ServicesTemplate) -> Result<()> {
+        check_state_limits(&self.config.state_limits, &s)?;
+        self.check_global(&mut s.global.plugins)?;
+        let global = Policies::parse(&s.global.policies).map_err(|e| anyhow!("global: {e}"))?;
+        let maps = self.prepare(&s.services, &global)?;
+
+        let mut template = self.template.write().await;
+        self.install(maps, |_| true);
+        *self.global_plugins.write().await = s.global.plugins.clone();
+        *template = s;
+        debug!("state updated: {} services", self.state.len());
+        Ok(())
+    }
+
+    /// Apply a change to the last template, only the services it names are
+    /// validated and swapped. Rejected as a whole like `update_state`.
+    pub async fn apply_delta(&self, delta: StateDelta) -> Result<()> {
+        let mut template = self.template.write().await;
+        let mut next = template.clone();
+        next.services.retain(|svc| !delta.remove.contains(&svc.id));
+        for svc in delta.upsert.iter() {
+            match next.services.iter_mut().find(|s| s.id == svc.id) {
+                Some(current) => *current = svc.clone(),
+                None => next.services.push(svc.clone()),
+            }
+        }
+        if let Some(global) = &delta.global {
+            next.global = global.clone();
+            self.check_global(&mut next.global.plugins)?;
+        }
+        check_state_limits(&self.config.state_limits, &next)?;
+        let global = Policies::parse(&next.global.policies).map_err(|e| anyhow!("global: {e}"))?;
+
+        if delta.global.is_some() {
+            // Every service resolves its policies against the new global ones
+            let maps = self.prepare(&next.services, &global)?;
+            self.install(maps, |_| true);
+            *self.global_plugins.write().await = next.global.plugins.clone();
+        } else {
+            let maps = self.prepare(&delta.upsert, &global)?;
+            let touched: HashSet<&str> =
+                delta.remove.iter().chain(delta.upsert.iter().map(|s| &s.id)).map(String::as_str).collect();
+            self.install(maps, |svc| touched.contains(svc.id.as_str()));
+        }
+        *template = next;
+        debug!(
+            "state delta applied: {} upserted, {} removed, {} services",
+            delta.upsert.len(),
+            delta.remove.len(),
+            self.state.len()
+        );
+        Ok(())
+    }
+
+    fn check_global(&self, plugins: &mut [AppliedPlugin]) -> Result<()> {
+        AppliedPlugin::sort(plugins);
+        for ap in plugins.iter() {
+            if self.plugins.iter().any(|p| p.name() == ap.r#type && p.needs_route()) {
+                bail!("plugin {}: {} needs the matched route and cannot be applied globally", ap.id, ap.r#type);
+            }
+            self.check_plugin(ap, None)?;
+        }
+        Ok(())
+    }
+
+    /// Validated version scoped copies of services, plugin lists sorted, and
+    /// their policies resolved against the `global` ones
+    fn prepare(&self, services: &[Service], global: &Policies) -> Result<Vec<(ServiceMapper, Arc<Policies>)>> {
+        // Before version scoping, a rule may target the upstream of another version
+        for svc in services {
+            routing::check_rules(svc)?;
+        }
+        let template = ServicesTemplate { services: services.to_vec(), ..Default::default() };
+        let mut maps = template.get_services_map_vec().services;
+        for map in maps.iter_mut() {
+            AppliedPlugin::sort(&mut map.value.plugins);
+            for route in map.value.routes.iter_mut() {
+                AppliedPlugin::sort(&mut route.plugins);
+            }
+            routing::scope_rules(&mut map.value);
+        }
+        let mut prepared = Vec::with_capacity(maps.len());
+        for map in maps {
+            let svc = &map.value;
+            routing::check_paths(svc)?;
+            headers::check_static(svc)?;
+            let route_plugins = svc.routes.iter().flat_map(|r| r.plugins.iter());
+            for ap in svc.plugins.iter().chain(route_plugins) {
+                self.check_plugin(ap, None)?;
+            }
+            let policies = Policies::parse(&svc.policies)
+                .map_err(|e| anyhow!("service {}: {e}", svc.id))?
+                .or(global);
+            if let Some(tuning) = &policies.upstream_client
+                && let Err(e) = tuning.build(client_builder(&self.config))
+            {
+                bail!("service {}: invalid upstream_client policy: {e}", svc.id);
+            }
+            prepared.push((map, Arc::new(policies)));
+        }
+        Ok(prepared)
+    }
+
+    /// Put version scoped services in the state and drop the other copies
+    /// of the `replaced` services. The route table is swapped under its lock,
+    /// so routing never sees a route of a service not in the state.
+    fn install(&self, maps: Vec<(ServiceMapper, Arc<Policies>)>, replaced: impl Fn(&Service) -> bool) {
+        let keys: HashSet<String> = maps.iter().map(|(m, _)| m.key.clone()).collect();
+        let mut routes = self.routes.write().unwrap_or_else(|e| e.into_inner());
+        for (map, policies) in maps {
+            self.state.insert(map.key, Scoped { service: Arc::new(map.value), policies });
+        }
+        self.state.retain(|key, scoped| keys.contains(key) || !replaced(&scoped.service));
+        let services: Vec<(String, Arc<Service>)> =
+            self.state.iter().map(|e| (e.key().clone(), e.service.clone())).collect();
+        *routes = RouteTable::build(services.iter().map(|(key, svc)| (key.as_str(), svc.as_ref())));
+        drop(routes);
+        self.clients.retain(|id| self.state.iter().any(|e| e.service.id == id));
+    }
+
+    /// Replace the consumers kept in the store, where the auth plugins look
+    /// up their credentials. A read-only store keeps those of its writer.
+    pub async fn update_consumers(&self, c: ConsumersTemplate) -> Result<()> {
+        if self.store.is_read_only() {
+            debug!("read-only store, consumers are managed by the writer");
+            return Ok(());
+        }
+        let count = c.consumers.len();
+        AsyncMemory::new(self.store.clone())
+            .run(move |m| {
+                let stale: Vec<String> = m
+                    .all_map::<serde_json::Value>(CONSUMERS_DB)?
+                    .into_keys()
+                    .filter(|id| !c.consumers.iter().any(|c| &c.id == id))
+                    .collect();
+                m.delete_many(CONSUMERS_DB, &stale)?;
+                // Written after the consumers, an entry read in between only
+                // names consumers whose credentials are checked anyway
+                let index = c.index();
+                let stale: Vec<String> = m
+                    .all_map::<Vec<String>>(CONSUMER_INDEX_DB)?
+                    .into_keys()
+                    .filter(|entry| !index.contains_key(entry))
+                    .collect();
+                m.insert_many(CONSUMERS_DB, c.consumers.into_iter().map(|c| (c.id.clone(), c)))?;
+                m.delete_many(CONSUMER_INDEX_DB, &stale)?;
+                m.insert_many(CONSUMER_INDEX_DB, index)
+            })
+            .await?;
+        debug!("consumers updated: {}", count);
+        Ok(())
+    }
+
+    /// Replace the config of one applied plugin in place.
+    ///
+    /// The new config is validated by the plugin implementation first, the
+    /// route table is left as is and only the owning service is swapped. The
+    /// last template is updated too, under its lock, so later deltas keep it.
+    pub async fn update_plugin_config(
+        &self,
+        scope: &PluginScope,
+        plugin_id: &str,
+        config: serde_json::Value,
+    ) -> Result<()> {
+        let mut template = self.template.write().await;
+        if let PluginScope::Global = scope {
+            let ap = template
+                .global
+                .plugins
+                .iter_mut()
+                .find(|p| p.id == plugin_id)
+                .ok_or_else(|| anyhow!("global plugin {} not found", plugin_id))?;
+            self.check_plugin(ap, Some(&config))?;
+            ap.config = Some(config);
+            *self.global_plugins.write().await = template.global.plugins.clone();
+            debug!("global plugin {} config updated", plugin_id);
+            return Ok(());
+        }
+
+        let (service_id, route_id) = match scope {
+            PluginScope::Service(service) => (service.as_str(), None),
+            PluginScope::Route { service, route } => (service.as_str(), Some(route.as_str())),
+            PluginScope::Global => unreachable!(),
+        };
+        let ap = template
+            .services
+            .iter_mut()
+            .find(|svc| svc.id == service_id)
+            .and_then(|svc| find_plugin(svc, route_id, plugin_id))
+            .ok_or_else(|| anyhow!("plugin {} not found in {:?}", plugin_id, scope))?;
+        self.check_plugin(ap, Some(&config))?;
+        ap.config = Some(config.clone());
+
+        let mut updated = 0;
+        for mut entry in self.state.iter_mut() {
+            if entry.service.id != service_id {
+                continue;
+            }
+            let mut svc = Service::clone(&entry.service);
+            // Not in the copies of versions the plugin does not apply to
+            let Some(ap) = find_plugin(&mut svc, route_id, plugin_id) else {
+                continue;
+            };
+            ap.config = Some(config.clone());
+            // In flight requests keep the previous copy
+            entry.value_mut().service = Arc::new(svc);
+            updated += 1;
+        }
+        debug!("plugin {} config updated in {} service entries", plugin_id, updated);
+        Ok(())
+    }
+
+    /// Validate an applied plugin against its implementation: the pinned
+    /// phase must be supported and the config (or `config` if given) valid.
+    /// Plugin types without an implementation are skipped at runtime, as are
+    /// disabled plugins, which are still validated so enabling one later
+    /// cannot break the state.
+    fn check_plugin(&self, ap: &AppliedPlugin, config: Option<&serde_json::Value>) -> Result<()> {
+        let phase = ap
+            .phase
+            .as_deref()
+            .map(Phase::from_str)
+            .transpose()
+            .map_err(|e| anyhow!("plugin {}: {e}", ap.id))?;
+        let Some(p) = self.plugins.iter().find(|p| p.name() == ap.r#type) else {
+            if config.is_some() {
+                bail!("unknown plugin type {}", ap.r#type);
+            }
+            warn!("plugin {}: no implementation for type {}", ap.id, ap.r#type);
+            return Ok(());
+        };
+        if let Some(phase) = phase
+            && !p.supported_phases().contains(&phase)
+        {
+            bail!("plugin {}: {} does not run in the {:?} phase", ap.id, ap.r#type, phase);
+        }
+        let empty = serde_json::Value::Null;
+        p.validate(config.or(ap.config.as_ref()).unwrap_or(&empty))
+            .map_err(|e| anyhow!("invalid config for plugin {}: {e}", ap.id))
+    }
+
+    /// Route for a request: the longest matching route whose methods allow
+    /// the request method
+    fn match_route(&self, method: &Method, uri: &Uri) -> Result<RouteMatch, RouteMiss> {
+        let path = uri.path();
+        debug!("matching route for {} {}", method, path);
+        // Methods of the routes that matched the path only
+        let mut allowed: Vec<String> = Vec::new();
+        let table = self.routes.read().unwrap_or_else(|e| e.into_inner());
+        for (key, idx, rest, params) in table.candidates(path) {
+            let Some(scoped) = self.state.get(key) else {
+                continue;
+            };
+            let Some(r) = scoped.service.routes.get(idx) else {
+                continue;
+            };
+            if !r.config.allows_method(method.as_str()) {
+                allowed.extend(r.config.methods.iter().map(|m| m.to_ascii_uppercase()));
+                continue;
+            }
+            return Ok(RouteMatch {
+                service: scoped.service.clone(),
+                route: r.clone(),
+                path: rest.to_string(),
+                params: params.into_iter().collect(),
+                policies: scoped.policies.clone(),
+            });
+        }
+        allowed.retain(|m| self.config.methods.permits(m));
+        if allowed.is_empty() {
+            return Err(RouteMiss::NotFound);
+        }
+        allowed.sort();
+        allowed.dedup();
+        Err(RouteMiss::MethodNotAllowed(allowed))
+    }
+
+    async fn run_plugins(&self, phase: Phase, ctx: &BullGContext, list: &[AppliedPlugin], trace: Option<&PluginTrace>) {
+        let empty = serde_json::Value::Null;
+        if ctx.invalid_header().is_some() {
+            return;
+        }
+        for ap in list.iter().filter(|ap| ap.enabled) {
+            let Some(p) = self.plugins.iter().find(|p| p.name() == ap.r#type) else {
+                continue;
+            };
+            // An explicit phase pins the plugin to it, validated in check_plugin
+            let runs = match ap.phase.as_deref().map(Phase::from_str) {
+                Some(Ok(pinned)) => pinned == phase,
+                _ => p.supported_phases().contains(&phase),
+            };
+            if !runs {
+                continue;
+            }
+            // A panicking plugin must not take the connection down with it
+            let config = ap.config.as_ref().unwrap_or(&empty);
+            let started = Instant::now();
+            let failed = match AssertUnwindSafe(p.apply(ctx, phase, config)).catch_unwind().await {
+                Ok(Ok(())) => false,
+                Ok(Err(e)) => {
+                    error!("plugin {} failed: {e}", ap.name);
+                    true
+                }
+                Err(payload) => {
+                    let msg = payload
+                        .downcast_ref::<&str>()
+                        .map(|s| s.to_string())
+                        .or_else(|| payload.downcast_ref::<String>().cloned())
+                        .unwrap_or_default();
+                    error!("plugin {} ({}) panicked on request {}: {msg}", ap.name, ap.r#type, ctx.request_id());
+                    self.metrics.plugin_panic(&ap.r#type);
+                    true
+                }
+            };
+            let stop = if failed && !ap.fails_open() {
+                ctx.set_status(StatusCode::INTERNAL_SERVER_ERROR);
+                ctx.set_body(Bytes::from_static(b"plugin failure"));
+                true
+            } else if let Some(name) = ctx.invalid_header() {
+                // Rejected under `headers.invalid_utf8: reject`, later plugins no longer run
+                ctx.set_status(StatusCode::BAD_REQUEST);
+                ctx.set_body(Bytes::from(format!("invalid UTF-8 in header {name}")));
+                true
+            } else {
+                ctx.status.read().is_some() && phase != Phase::Post
+            };
+            if let Some(trace) = trace {
+                trace.record(&ap.r#type, &ap.id, phase, started.elapsed(), failed, stop);
+            }
+            if stop {
+                break;
+            }
+        }
+    }
+
+    /// Post phase in the reverse order of Pre: route, service then global plugins
+    async fn run_post_plugins(
+        &self,
+        ctx: &BullGContext,
+        m: &RouteMatch,
+        global: &[AppliedPlugin],
+        trace: Option<&PluginTrace>,
+    ) {
+        for list in [&m.route.plugins, &m.service.plugins] {
+            self.run_plugins(Phase::Post, ctx, list, trace).await;
+        }
+        self.run_plugins(Phase::Post, ctx, global, trace).await;
+    }
+
+    /// Response of a Pre plugin that set a status, skipping the rest of the chain
+    fn short_circuit(&self, ctx: &BullGContext, request_id: &str, start: Instant) -> Option<Response<GatewayBody>> {
+        let code = (*ctx.status.read())?;
+        let mut resp = simple(code, ctx.get_body());
+        resp.headers_mut().extend(ctx.response_headers.read().clone());
+        Some(self.default_headers(resp, request_id, start))
+    }
+
+    pub async fn serve(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        self.serve_with_shutdown(addr, std::future::pending()).await
+    }
+
+    /// Serve until `shutdown` resolves, then stop accepting and give open
+    /// connections `shutdown.drain_timeout_ms` to finish their requests.
+    /// Connections and upgrade tunnels still open then are closed, counted
+    /// in `connections_force_closed_total`. Upstream health checks run for
+    /// as long as this does.
+    pub async fn serve_with_shutdown(
+        self: Arc<Self>,
+        addr: SocketAddr,
+        shutdown: impl Future<Output = ()>,
+    ) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("{} listening on {}", APP_NAME, addr);
+        let mut shutdown = std::pin::pin!(shutdown);
+        let health_checks = tokio::spawn(self.clone().run_health_checks());
+        let served = loop {
+            let (stream, peer) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => break Err(e.into()),
+                },
+                _ = &mut shutdown => break Ok(()),
+            };
+            let me = self.clone();
+            let mut guard = self.drain.guard();
+            tokio::spawn(async move {
+                let _conn = me.metrics.load.connection();
+                let metrics = me.metrics.clone();
+                let io = TokioIo::new(stream);
+                let mut builder = http1::Builder::new();
+                // The parser answers 431 itself above its header buffer, 100
+                // by default, a custom size moves the buffer to the heap
+                if me.config.headers.max_count != 100 {
+                    builder.max_headers(me.config.headers.max_count);
+                }
+                let normalize = &me.config.headers.normalize;
+                builder.title_case_headers(normalize.response && normalize.title_case);
+                let conn = builder.serve_connection(
+                    io,
+                    service_fn(move |mut req| {
+                        let me = me.clone();
+                        // Read back in `handle` as the client address of the context
+                        req.extensions_mut().insert(peer);
+                        async move { Ok::<_, hyper::Error>(me.handle_request(req).await) }
+                    }),
+                ).with_upgrades();
+                let mut conn = std::pin::pin!(conn);
+                let res = tokio::select! {
+                    res = conn.as_mut() => res,
+                    _ = guard.draining() => {
+                        // Finishes the request in flight, then closes
+                        conn.as_mut().graceful_shutdown();
+                        tokio::select! {
+                            res = conn.as_mut() => res,
+                            _ = guard.forced() => {
+                                metrics.connection_force_closed();
+                                Ok(())
+                            }
+                        }
+                    }
+                };
+                if let Err(e) = res {
+                    error!("conn error: {e}");
+                }
+            });
+        };
+        health_checks.abort();
+        drop(listener);
+        info!("draining {} connections", self.drain.open());
+        self.drain.start();
+        let timeout = Duration::from_millis(self.config.shutdown.drain_timeout_ms);
+        match tokio::time::timeout(timeout, self.drain.closed()).await {
+            Ok(()) => info!("connections drained"),
+            Err(_) => {
+                warn!("closing {} connections still open after {}ms", self.drain.open(), timeout.as_millis());
+                self.drain.force();
+                self.drain.closed().await;
+            }
+        }
+        served
+    }
+
+    /// Run one request through the gateway pipeline. `serve` calls this for
+    /// every connection, tests can call it with any body type and no socket.
+    pub async fn handle_request<B>(&self, mut req: Request<B>) -> Response<GatewayBody>
+    where
+        B: hyper::body::Body,
+        B::Error: std::fmt::Display,
+    {
+        let trace = PluginTrace::take(&self.config.debug, req.headers_mut());
+        if let Some(forced) = ForcedUpstream::take(&self.config.debug, req.headers_mut(), trace.is_some()) {
+            req.extensions_mut().insert(forced);
+        }
+        // The fields only known once handled are recorded at the end
+        let span = info_span!(
+            "request",
+            otel.kind = "server",
+            request_id = Empty,
+            service = Empty,
+            route = Empty,
+            http.request.method = %req.method(),
+            url.path = %req.uri().path(),
+            http.response.status_code = Empty,
+            upstream_latency_ms = Empty,
+        );
+        propagation::continue_trace(&span, req.headers());
+        let logged = self.access_log.as_ref().map(|log| (log, RequestLine::of(&req)));
+        let start = Instant::now();
+        let mut handled = Handled::default();
+        let resp = self
+            .handle(req, trace.as_ref(), &mut handled)
+            .instrument(span.clone())
+            .await;
+        let resp = self.respond(resp, trace.as_ref());
+        self.metrics.request_done(handled.labels.as_ref(), resp.status());
+        let request_id = resp
+            .headers()
+            .get(self.config.request_id.header.as_str())
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        span.record("request_id", request_id);
+        span.record("http.response.status_code", resp.status().as_u16());
+        if let Some(labels) = &handled.labels {
+            span.record("service", labels.service.as_str());
+            span.record("route", labels.route.as_str());
+        }
+        if let Some(upstream) = handled.upstream {
+            span.record("upstream_latency_ms", upstream.as_micros() as f64 / 1000.0);
+        }
+
+        let Some((access_log, line)) = logged else {
+            return resp;
+        };
+        access_log.log(&AccessRecord {
+            time: Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            request_id: request_id.to_string(),
+            method: line.method,
+            path: line.path,
+            version: line.version,
+            status: resp.status().as_u16(),
+            duration_ms: start.elapsed().as_micros() as f64 / 1000.0,
+            upstream_ms: handled.upstream.map(|d| d.as_micros() as f64 / 1000.0),
+            client_ip: line.client_ip,
+            bytes: hyper::body::Body::size_hint(resp.body()).exact(),
+            referer: line.referer,
+            user_agent: line.user_agent,
+            service_name: self.config.access_log.sname.then(|| self.config.service_name().to_string()),
+        });
+        resp
+    }
+
+    /// Last touches on every response: header normalization and the plugin
+    /// trace of a debug request
+    fn respond(&self, mut resp: Response<GatewayBody>, trace: Option<&PluginTrace>) -> Response<GatewayBody> {
+        if self.config.headers.normalize.response {
+            headers::normalize(&self.config.headers.normalize, resp.headers_mut());
+        }
+        if let Some(trace) = trace {
+            trace.attach(resp.headers_mut());
+        }
+        resp
+    }
+
+    async fn handle<B>(
+        &self,
+        mut req: Request<B>,
+        trace: Option<&PluginTrace>,
+        handled: &mut Handled,
+    ) -> Response<GatewayBody>
+    where
+        B: hyper::body::Body,
+        B::Error: std::fmt::Display,
+    {
+        let start = Instant::now();
+
+        if !self.config.methods.permits(req.method().as_str()) {
+            warn!("method {} is not allowed on this gateway", req.method());
+            let mut resp = simple(StatusCode::METHOD_NOT_ALLOWED, Bytes::from_static(b"method not allowed"));
+            if let Ok(allow) = HeaderValue::from_str(&self.config.methods.allowed()) {
+                resp.headers_mut().insert(http::header::ALLOW, allow);
+            }
+            let request_id = self.inbound_request_id(req.headers()).unwrap_or_else(|| Uuid::new_v4().to_string());
+            return self.default_headers(resp, &request_id, start);
+        }
+
+        if let Err((status, msg)) = framing::check(&self.config.headers, req.headers()) {
+            warn!("rejecting {} {}: {msg}", req.method(), req.uri());
+            let request_id = self.inbound_request_id(req.headers()).unwrap_or_else(|| Uuid::new_v4().to_string());
+            return self.default_headers(simple(status, Bytes::from_static(msg.as_bytes())), &request_id, start);
+        }
+
+        let raw_path = req.uri().path().to_string();
+        match normalize::apply(&self.config.paths, req.uri_mut()) {
+            Ok(()) if raw_path != req.uri().path() => debug!("path {} normalized to {}", raw_path, req.uri().path()),
+            Ok(()) => {}
+            Err(msg) => {
+                warn!("rejecting {} {}: {msg}", req.method(), raw_path);
+                let request_id = self.inbound_request_id(req.headers()).unwrap_or_else(|| Uuid::new_v4().to_string());
+                return self.default_headers(simple(StatusCode::BAD_REQUEST, Bytes::from_static(msg.as_bytes())), &request_id, start);
+            }
+        }
+
+        // Services are only matched this early while one is in maintenance
+        let maintenance = &self.config.maintenance;
+        if (self.maintenance.global() || self.maintenance.any_service())
+            && !self.maintenance.exempt(maintenance, req.uri().path(), req.extensions().get::<SocketAddr>().map(|a| a.ip()))
+            && (self.maintenance.global()
+                || self
+                    .match_route(req.method(), req.uri())
+                    .is_ok_and(|m| self.maintenance.service(&m.service.id)))
+        {
+            debug!("maintenance mode, answering {} {}", req.method(), req.uri());
+            let request_id = self.inbound_request_id(req.headers()).unwrap_or_else(|| Uuid::new_v4().to_string());
+            return self.default_headers(maintenance::response(maintenance), &request_id, start);
+        }
+
+        let shed = &self.config.load_shedding;
+        let pressure = if shed.enabled { self.metrics.load.pressure(shed) } else { 0.0 };
+        let mut load_guard = self.metrics.load.request();
+        // Classified only under load, the route is matched again below
+        if pressure > 0.0
+            && let class = shedding::classify(shed, self.match_route(req.method(), req.uri()).ok().as_ref(), req.headers())
+            && class.shed_at.is_some_and(|at| pressure > at)
+        {
+            load_guard.discard();
+            self.metrics.request_shed();
+            warn!("overloaded, shedding {} {} of class {}", req.method(), req.uri(), class.name);
+            let mut resp = simple(StatusCode::SERVICE_UNAVAILABLE, Bytes::from_static(b"overloaded"));
+            resp.headers_mut().insert(http::header::RETRY_AFTER, HeaderValue::from_static("1"));
+            let request_id = self.inbound_request_id(req.headers()).unwrap_or_else(|| Uuid::new_v4().to_string());
+            return self.default_headers(resp, &request_id, start);
+        }
+
+        let inbound_upgrade = upgrade::is_upgrade(req.headers()).then(|| hyper::upgrade::on(&mut req));
+        let (parts, body) = req.into_parts();
+        let buffered = match spool::buffer(body, &self.config.body_buffer).await {
+            Ok(buffered) => buffered,
+            Err(e) => {
+                let (status, msg): (_, &'static [u8]) = match e {
+                    BufferError::TooLarge => (StatusCode::PAYLOAD_TOO_LARGE, b"request body too large"),
+                    BufferError::Read(e) => {
+                        warn!("failed to read request body: {e}");
+                        (StatusCode::BAD_REQUEST, b"invalid request body")
+                    }
+                    BufferError::Io(e) => {
+                        error!("failed to spill request body: {e}");
+                        (StatusCode::INTERNAL_SERVER_ERROR, b"failed to buffer request body")
+                    }
+                };
+                let request_id = self.inbound_request_id(&parts.headers).unwrap_or_else(|| Uuid::new_v4().to_string());
+                return self.default_headers(simple(status, Bytes::from_static(msg)), &request_id, start);
+            }
+        };
+        let mut ctx = BullGContext::with_tools(
+            parts.method.clone(),
+            parts.uri.clone(),
+            parts.headers.clone(),
+            Bytes::new(),
+            self.tools.clone(),
+        )
+        .with_invalid_utf8(self.config.headers.invalid_utf8);
+        if let Some(peer) = parts.extensions.get::<SocketAddr>() {
+            ctx = ctx.with_client_addr(*peer);
+        }
+        match buffered {
+            Buffered::Memory(bytes) => ctx.set_body(bytes),
+            Buffered::Spilled(spilled) => ctx.set_spilled(spilled),
+        }
+        let request_id = self
+            .inbound_request_id(&parts.headers)
+            .unwrap_or_else(|| ctx.get_id().to_string());
+        ctx.set_request_id(&request_id);
+
+        info!("Handling request {}: {} {}", request_id, parts.method, parts.uri);
+
+        let gp = self.global_plugins.read().await.clone();
+        self.run_plugins(Phase::Pre, &ctx, &gp, trace).await;
+        if let Some(resp) = self.short_circuit(&ctx, &request_id, start) {
+            return resp;
+        }
+
+        let m = match self.match_route(&parts.method, &parts.uri) {
+            Ok(m) => m,
+            Err(RouteMiss::NotFound) => return self.default_headers(not_found(&request_id), &request_id, start),
+            Err(RouteMiss::MethodNotAllowed(allowed)) => {
+                warn!("method {} is not allowed on {}", parts.method, parts.uri.path());
+                let mut resp = simple(StatusCode::METHOD_NOT_ALLOWED, Bytes::from_static(b"method not allowed"));
+                if let Ok(allow) = HeaderValue::from_str(&allowed.join(", ")) {
+                    resp.headers_mut().insert(http::header::ALLOW, allow);
+                }
+                return self.default_headers(resp, &request_id, start);
+            }
+        };
+
+        let labels = RouteLabels {
+            service: m.service.id.clone(),
+            route: m.route.id.clone(),
+        };
+        let route_in_flight = self.metrics.in_flight(&labels);
+        handled.labels = Some(labels.clone());
+        ctx.set_params(m.params.clone());
+        let require_auth = m.route.require_auth.unwrap_or(m.service.require_auth);
+        ctx.set_matched_route(MatchedRoute {
+            service: m.service.id.clone(),
+            version: m.service.context_paths.paths.first().and_then(|p| p.versions.first()).cloned().unwrap_or_default(),
+            route: m.route.id.clone(),
+            require_auth,
+        });
+        // Global plugins ran before routing, then service and route ones
+        for list in [&m.service.plugins, &m.route.plugins] {
+            self.run_plugins(Phase::Pre, &ctx, list, trace).await;
+            if let Some(resp) = self.short_circuit(&ctx, &request_id, start) {
+                return resp;
+            }
+        }
+        if require_auth && ctx.consumer_id().is_none() {
+            warn!("rejecting {} {}: no authenticated consumer", parts.method, parts.uri.path());
+            let resp = simple(StatusCode::UNAUTHORIZED, Bytes::from_static(b"authentication required"));
+            return self.default_headers(resp, &request_id, start);
+        }
+
+        let capture = self.capture(&m, &ctx, &parts.headers).await;
+
+        let balancing = m.policies.load_balancer.clone().unwrap_or_default();
+        let upstream = match parts.extensions.get::<ForcedUpstream>() {
+            // Diagnostics may target an upstream the health checks took out
+            Some(ForcedUpstream(id)) => match m.service.upstreams.iter().find(|u| &u.id == id) {
+                Some(upstream) => {
+                    info!("debug request {} forced to upstream {}", request_id, id);
+                    Some(upstream)
+                }
+                None => {
+                    warn!("debug request {} names unknown upstream {}", request_id, id);
+                    return self.default_headers(
+                        simple(StatusCode::BAD_REQUEST, Bytes::from(format!("unknown upstream {id}"))),
+                        &request_id,
+                        start,
+                    );
+                }
+            },
+            None => routing::select_upstream(
+                &m.service,
+                &m.route,
+                &ctx.headers.read(),
+                |u| self.health.is_healthy(&m.service.id, &u.id),
+                |candidates| self.balancer.pick(&m.service.id, &balancing, candidates),
+            ),
+        };
+        let Some(upstream) = upstream else {
+            warn!("no enabled upstream for service {}", m.service.id);
+            return self.default_headers(
+                simple(StatusCode::SERVICE_UNAVAILABLE, Bytes::from_static(b"no upstream available")),
+                &request_id,
+                start,
+            );
+        };
+
+        if let Some(rate) = &m.policies.upstream_rate_limit
+            && !self.throttle.acquire(rate.key(&m.service.id, &upstream.id), rate).await
+        {
+            warn!("upstream rate limit reached for upstream {}", upstream.id);
+            return self.default_headers(
+                simple(rate.error.status(), Bytes::from(rate.error.message.clone())),
+                &request_id,
+                start,
+            );
+        }
+
+        // Held until the response body is fully sent
+        let in_flight = self.balancer.start(&m.service.id, &upstream.id);
+        let permit = match &m.policies.concurrency {
+            Some(limit) => {
+                let key = limit.key(&m.service.id, &upstream.id);
+                match self.limiter.acquire(key, limit).await {
+                    Some(permit) => Some(permit),
+                    None => {
+                        warn!("concurrency limit reached for upstream {}", upstream.id);
+                        return self.default_headers(
+                            simple(limit.error.status(), Bytes::from(limit.error.message.clone())),
+                            &request_id,
+                            start,
+                        );
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let url = match routing::upstream_url(upstream, &m.route, &m.path, ctx.query().as_deref()) {
+            Ok(url) => url,
+            Err(e) => {
+                error!("invalid upstream url for {}: {e}", upstream.id);
+                return self.default_headers(
+                    simple(StatusCode::BAD_GATEWAY, Bytes::from_static(b"upstream error")),
+                    &request_id,
+                    start,
+                );
+            }
+        };
+
+        let upstream_host = authority(&url);
+
+        // Modify headers: preserve original host and set forwarding headers
+        {
+            let mut headers = ctx.headers.write();
+            strip_hop_by_hop(&mut headers);
+            if let Some(orig_host) = headers.get("host").cloned() {
+                headers.insert("x-forwarded-host", orig_host);
+            }
+            if let Ok(host) = HeaderValue::from_str(&upstream_host) {
+                headers.insert("host", host);
+            }
+            headers.insert("via", HeaderValue::from_static(APP_NAME));
+            if let Ok(name) = HeaderName::from_str(&self.config.request_id.header)
+                && let Ok(id) = HeaderValue::from_str(&request_id)
+            {
+                headers.insert(name, id);
+            }
+            if self.config.headers.normalize.request {
+                headers::normalize(&self.config.headers.normalize, &mut headers);
+            }
+        }
+
+        // Intermediate plugins see the outbound request and may send it elsewhere
+        ctx.set_upstream_url(url.as_str());
+        for list in [&gp, &m.service.plugins, &m.route.plugins] {
+            self.run_plugins(Phase::Intermediate, &ctx, list, trace).await;
+            if let Some(resp) = self.short_circuit(&ctx, &request_id, start) {
+                return resp;
+            }
+        }
+        let url = match ctx.upstream_url().filter(|u| u != url.as_str()).map(|u| url::Url::parse(&u)) {
+            Some(Ok(rewritten)) => {
+                debug!("upstream url rewritten from {} to {}", url, rewritten);
+                let host = authority(&rewritten);
+                if host != upstream_host
+                    && let Ok(host) = HeaderValue::from_str(&host)
+                {
+                    ctx.headers.write().insert("host", host);
+                }
+                rewritten
+            }
+            Some(Err(e)) => {
+                error!("invalid upstream url set by a plugin: {e}");
+                return self.default_headers(
+                    simple(StatusCode::BAD_GATEWAY, Bytes::from_static(b"upstream error")),
+                    &request_id,
+                    start,
+                );
+            }
+            None => url,
+        };
+
+        if let Some(inbound) = inbound_upgrade
+            && let Some(protocol) = parts.headers.get(http::header::UPGRADE).cloned()
+        {
+            let headers = ctx.headers.read().clone();
+            let resp = self
+                .proxy_upgrade(protocol, &parts.method, url, headers, inbound, (permit, in_flight, route_in_flight), &request_id, start)
+                .await;
+            self.store_capture(capture, resp.status(), resp.headers(), None);
+            return resp;
+        }
+
+        let policies = m.policies.clone();
+        let retry = policies.retry.clone().unwrap_or_default();
+        let timeouts = match &policies.timeout {
+            Some(t) => t.clone().inherit(&self.config.upstream),
+            None => TimeoutPolicy::from_config(&self.config.upstream),
+        };
+        let deadline = timeouts.deadline();
+        let failures = policies.upstream_failure.as_ref();
+        let classify = failures.cloned().unwrap_or_default();
+        let observe = |ok: bool| {
+            if let Some(policy) = &policies.outlier_detection {
+                self.health.observe(&m.service.id, &upstream.id, ok, policy);
+            }
+        };
+        let grpc_web = policies.grpc_web.as_ref().and_then(|p| p.call(&m.route, &parts.headers));
+        let client = match &policies.upstream_client {
+            Some(tuning) => self
+                .clients
+                .get(&m.service.id, tuning, || client_builder(&self.config))
+                .unwrap_or_else(|| self.client.clone()),
+            None if grpc_web.is_some() => self.grpc_client.clone(),
+            None => self.client.clone(),
+        };
+        let mut headers = ctx.headers.read().clone();
+        // A spilled body is streamed from its file on every attempt
+        let spilled = ctx.spilled();
+        let mut body = if spilled.is_some() { Bytes::new() } else { ctx.get_body() };
+        if let Some(web) = &grpc_web {
+            if web.text && spilled.is_some() {
+                warn!("grpc-web-text body of {} too large to decode", request_id);
+                return self.default_headers(
+                    simple(StatusCode::PAYLOAD_TOO_LARGE, Bytes::from_static(b"request body too large")),
+                    &request_id,
+                    start,
+                );
+            }
+            body = match web.request(&mut headers, body) {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("invalid grpc-web-text body: {e}");
+                    return self.default_headers(
+                        simple(StatusCode::BAD_REQUEST, Bytes::from_static(b"invalid grpc-web-text body")),
+                        &request_id,
+                        start,
+                    );
+                }
+            };
+        }
+
+        let shadow = match &policies.shadow {
+            Some(policy) if spilled.is_none() && grpc_web.is_none() && policy.sampled() => {
+                self.shadow_request(policy.clone(), &parts.method, &url, &headers, &body, &request_id)
+            }
+            _ => None,
+        };
+
+        // Every attempt runs in this span, the upstream continues its trace
+        let upstream_span = info_span!(
+            "upstream",
+            otel.kind = "client",
+            upstream = %upstream.id,
+            http.request.method = %parts.method,
+            url.full = %url,
+            http.response.status_code = Empty,
+            retries = Empty,
+        );
+        propagation::inject(&upstream_span, &mut headers);
+
+        debug!("upstream request: {} {} {:?}", parts.method, url, headers);
+        let upstart = Instant::now();
+        let expires = deadline.map(|d| tokio::time::Instant::from_std(upstart + d));
+        let (mut connect_retries, mut status_retries) = (0, 0);
+        let resp = loop {
+            let attempt_body = match &spilled {
+                Some(spilled) => match spilled.open() {
+                    Ok(file) => reqwest::Body::from(tokio::fs::File::from_std(file)),
+                    Err(e) => {
+                        error!("failed to open spilled body {}: {e}", spilled.path().display());
+                        return self.default_headers(
+                            simple(StatusCode::INTERNAL_SERVER_ERROR, Bytes::from_static(b"failed to buffer request body")),
+                            &request_id,
+                            start,
+                        );
+                    }
+                },
+                None => reqwest::Body::from(body.clone()),
+            };
+            let rb = client
+                .request(parts.method.clone(), url.as_str())
+                .headers(headers.clone())
+                .body(attempt_body);
+            // The deadline or the wait for this attempt's headers, whichever ends first
+            let answer_by = timeouts.response_timeout().map(|t| tokio::time::Instant::now() + t);
+            let sent = match expires.into_iter().chain(answer_by).min() {
+                Some(at) => match tokio::time::timeout_at(at, rb.send().instrument(upstream_span.clone())).await {
+                    Ok(sent) => sent,
+                    Err(_) => {
+                        warn!("upstream {} timed out after {}ms", url, upstart.elapsed().as_millis());
+                        observe(!classify.timeouts);
+                        return self.upstream_timeout(&timeouts.error, &request_id, start);
+                    }
+                },
+                None => rb.send().instrument(upstream_span.clone()).await,
+            };
+            observe(match &sent {
+                Ok(r) => !classify.status_fails(r.status()),
+                Err(e) => !classify.error_fails(e),
+            });
+            match sent {
+                Ok(r) if retry.retry_status(&parts.method, r.status(), status_retries, failures) => {
+                    status_retries += 1;
+                    warn!("upstream {} returned {}, retry {}", url, r.status(), status_retries);
+                }
+                Ok(r) => break r,
+                Err(e) if e.is_connect() && retry.retry_connect(connect_retries, failures) => {
+                    connect_retries += 1;
+                    warn!("upstream {} connect failed: {e}, retry {}", url, connect_retries);
+                }
+                Err(e) if e.is_timeout() => {
+                    warn!("upstream {} timed out: {e}", url);
+                    return self.upstream_timeout(&timeouts.error, &request_id, start);
+                }
+                Err(e) => {
+                    error!("upstream error: {e}");
+                    let mut resp = simple(StatusCode::BAD_GATEWAY, Bytes::from_static(b"upstream error"));
+                    retry_count(resp.headers_mut(), connect_retries + status_retries);
+                    return self.default_headers(resp, &request_id, start);
+                }
+            }
+            let delay = retry.backoff.delay(connect_retries + status_retries);
+            let wake = tokio::time::Instant::now() + delay;
+            // Past the deadline the next attempt times out at once
+            tokio::time::sleep_until(expires.map_or(wake, |at| wake.min(at))).await;
+        };
+        upstream_span.record("http.response.status_code", resp.status().as_u16());
+        upstream_span.record("retries", connect_retries + status_retries);
+        drop(upstream_span);
+        info!("upstream Latency: {}ms", upstart.elapsed().as_millis());
+        self.balancer.observe(&m.service.id, &upstream.id, upstart.elapsed());
+        self.metrics.upstream_latency(&labels, upstart.elapsed());
+        handled.upstream = Some(upstart.elapsed());
+
+        let status = resp.status();
+        let mut resp_headers = resp.headers().clone();
+        strip_hop_by_hop(&mut resp_headers);
+        headers::strip(&self.config.headers, &mut resp_headers);
+        retry_count(&mut resp_headers, connect_retries + status_retries);
+        headers::inject(&m.service.response_headers, &mut resp_headers);
+        headers::inject(&m.route.config.response_headers, &mut resp_headers);
+        ctx.set_response_headers(resp_headers);
+
+        let streaming = policies.streaming.clone().unwrap_or_default();
+        let content_type = resp
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if grpc_web.is_none() && streaming.applies(content_type) {
+            // Body is not buffered, post plugins only see status and headers
+            debug!("streaming upstream response: {}", status);
+            ctx.set_status(status);
+            ctx.set_streamed();
+            self.send_shadow(shadow, status, None);
+            self.run_post_plugins(&ctx, &m, &gp, trace).await;
+            let signal = streaming.signal(&resp, accepts_trailers(&parts.headers));
+            let body = streaming.body(resp, signal, timeouts.read_timeout(), (permit, in_flight, route_in_flight));
+            self.store_capture(capture, status, &ctx.headers.read(), None);
+            let mut out = self.response_from_ctx(&ctx, body, &request_id, start);
+            if signal == ErrorSignal::Trailer {
+                // Trailers need a chunked body declaring them up front
+                out.headers_mut().remove(http::header::CONTENT_LENGTH);
+                out.headers_mut()
+                    .insert(http::header::TRAILER, HeaderValue::from_static(STREAM_ERROR_TRAILER));
+            }
+            return out;
+        }
+
+        let bytes = match read_body(resp, expires, timeouts.read_timeout()).await {
+            Ok((bytes, trailers)) => match &grpc_web {
+                Some(web) => web.response(&mut ctx.headers.write(), bytes, &trailers),
+                None => bytes,
+            },
+            Err(BodyError::Timeout) => {
+                warn!("upstream {} body timed out after {}ms", url, upstart.elapsed().as_millis());
+                return self.upstream_timeout(&timeouts.error, &request_id, start);
+            }
+            Err(BodyError::Stalled(idle)) => {
+                warn!("upstream {} body stalled for {}ms", url, idle.as_millis());
+                return self.upstream_timeout(&timeouts.error, &request_id, start);
+            }
+            Err(BodyError::Upstream(e)) => {
+                // Nothing was sent yet, a cut body is reported as a gateway error
+                error!("upstream body error: {e}");
+                return self.default_headers(
+                    simple(StatusCode::BAD_GATEWAY, Bytes::from_static(b"upstream error")),
+                    &request_id,
+                    start,
+                );
+            }
+        };
+        debug!("upstream response: {} {:?}", status, bytes);
+        self.send_shadow(shadow, status, Some(bytes.clone()));
+        ctx.set_body(bytes);
+        ctx.set_status(status);
+        drop((permit, in_flight, route_in_flight));
+
+        self.run_post_plugins(&ctx, &m, &gp, trace).await;
+        let status = ctx.status.read().unwrap_or(status);
+        // A post plugin rewriting the body leaves the upstream length behind
+        if parts.method != Method::HEAD && !bodyless(status) {
+            let len = ctx.body_len();
+            let mut headers = ctx.headers.write();
+            if headers.get(http::header::CONTENT_LENGTH).is_some_and(|v| v.as_bytes() != len.to_string().as_bytes()) {
+                headers.insert(http::header::CONTENT_LENGTH, HeaderValue::from(len));
+            }
+        }
+
+        self.store_capture(capture, status, &ctx.headers.read(), Some(&ctx.get_body()));
+        self.default_headers_from_ctx(&ctx, &request_id, start)
+    }
+
+    /// Copy of the upstream request for the shadow backend
+    fn shadow_request(
+        &self,
+        policy: ShadowPolicy,
+        method: &Method,
+        url: &url::Url,
+        headers: &HeaderMap,
+        body: &Bytes,
+        request_id: &str,
+    ) -> Option<(ShadowPolicy, reqwest::RequestBuilder, String)> {
+        let shadow_url = match policy.url(url) {
+            Ok(shadow_url) => shadow_url,
+            Err(e) => {
+                warn!("invalid shadow url `{}`: {e}", policy.url);
+                return None;
+            }
+        };
+        let mut headers = headers.clone();
+        if let Ok(host) = HeaderValue::from_str(&authority(&shadow_url)) {
+            headers.insert("host", host);
+        }
+        let rb = self
+            .client
+            .request(method.clone(), shadow_url)
+            .headers(headers)
+            .body(body.clone())
+            .timeout(policy.timeout());
+        Some((policy, rb, request_id.to_string()))
+    }
+
+    /// Send the shadow request in the background and compare its answer to
+    /// the primary one, the client response never waits for it
+    fn send_shadow(
+        &self,
+        shadow: Option<(ShadowPolicy, reqwest::RequestBuilder, String)>,
+        status: StatusCode,
+        body: Option<Bytes>,
+    ) {
+        let Some((policy, rb, request_id)) = shadow else {
+            return;
+        };
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            let answer = match rb.send().await {
+                Ok(resp) => {
+                    let shadow_status = resp.status();
+                    resp.bytes().await.map(|bytes| (shadow_status, bytes))
+                }
+                Err(e) => Err(e),
+            };
+            match answer {
+                Ok((shadow_status, shadow_body)) => {
+                    let diffs = policy.compare((status, body.as_ref()), (shadow_status, &shadow_body));
+                    if !diffs.is_empty() {
+                        warn!("shadow response of {} differs: {}", request_id, diffs.join(", "));
+                    }
+                    metrics.shadow_compared(!diffs.is_empty());
+                }
+                Err(e) => {
+                    warn!("shadow request of {} failed: {e}", request_id);
+                    metrics.shadow_failed();
+                }
+            }
+        });
+    }
+
+    /// Request side of a body capture when the capture policy samples it
+    async fn capture(
+        &self,
+        m: &RouteMatch,
+        ctx: &BullGContext,
+        headers: &HeaderMap,
+    ) -> Option<(CapturePolicy, Capture)> {
+        let policy = m.policies.capture.clone()?;
+        if !policy.sampled(headers) {
+            return None;
+        }
+        let (request_body, truncated) = match ctx.spilled() {
+            // Only part of it would fit, and redaction needs the whole body
+            Some(spilled) => (format!("<{} bytes spilled to disk>", spilled.len()), true),
+            None => policy.body(&ctx.get_body()),
+        };
+        let capture = Capture {
+            id: ctx.get_id().to_string(),
+            time: Utc::now().to_rfc3339(),
+            service: m.service.id.clone(),
+            route: m.route.id.clone(),
+            method: ctx.method.to_string(),
+            uri: ctx.uri.to_string(),
+            request_headers: policy.headers(headers),
+            request_body,
+            status: 0,
+            response_headers: Default::default(),
+            response_body: None,
+            truncated,
+        };
+        Some((policy, capture))
+    }
+
+    fn store_capture(
+        &self,
+        capture: Option<(CapturePolicy, Capture)>,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: Option<&Bytes>,
+    ) {
+        if let Some((policy, capture)) = capture {
+            self.captures.push(capture.respond(&policy, status, headers, body));
+        }
+    }
+
+    /// Request id of the inbound request when it is trusted and sane
+    fn inbound_request_id(&self, headers: &HeaderMap) -> Option<String> {
+        if !self.config.request_id.trust_inbound {
+            return None;
+        }
+        let id = headers.get(self.config.request_id.header.as_str())?.to_str().ok()?.trim();
+        let sane = !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic());
+        sane.then(|| id.to_string())
+    }
+
+    fn upstream_timeout(&self, error: &PolicyError, request_id: &str, start: Instant) -> Response<GatewayBody> {
+        self.default_headers(simple(error.status(), Bytes::from(error.message.clone())), request_id, start)
+    }
+
+    fn default_headers(
+        &self,
+        mut resp: Response<GatewayBody>,
+        request_id: &str,
+        start: Instant,
+    ) -> Response<GatewayBody> {
+        let latency_us = start.elapsed().as_micros().to_string();
+        let latency_ms = start.elapsed().as_millis().to_string();
+        let server = format!("{}/{}", APP_NAME, APP_VERSION);
+
+        let switching = resp.status() == StatusCode::SWITCHING_PROTOCOLS;
+        let headers = resp.headers_mut();
+        headers.insert("Via", HeaderValue::from_static(APP_NAME));
+        if !headers.contains_key("Content-Type") && !switching {
+            headers.insert("Content-Type", HeaderValue::from_static("text/html"));
+        }
+        if let Ok(v) = HeaderValue::from_str(&server) {
+            headers.insert("Server", v.clone());
+            headers.insert("X-Server", v);
+        }
+        if let Ok(v) = HeaderValue::from_str(&format!("{}-{}/VIKSHRO", APP_NAME, APP_VERSION)) {
+            headers.insert("X-Powered-By", v);
+        }
+        if let Ok(v) = HeaderValue::from_str(&format!("{} Gateway/{}", APP_NAME, APP_VERSION)) {
+            headers.insert("X-Gateway", v);
+        }
+        if let Ok(v) = HeaderValue::from_str(&latency_us) {
+            headers.insert("X-Latency-Us", v);
+        }
+        if let Ok(v) = HeaderValue::from_str(&latency_ms) {
+            headers.insert("X-Latency", v);
+        }
+        if let Ok(name) = HeaderName::from_str(&self.config.request_id.header)
+            && let Ok(v) = HeaderValue::from_str(request_id)
+        {
+            headers.insert(name, v);
+        }
+
+        resp
+    }
+
+    fn default_headers_from_ctx(
+        &self,
+        ctx: &BullGContext,
+        request_id: &str,
+        start: Instant,
+    ) -> Response<GatewayBody> {
+        self.response_from_ctx(ctx, full(ctx.get_body()), request_id, start)
+    }
+
+    fn response_from_ctx(
+        &self,
+        ctx: &BullGContext,
+        body: GatewayBody,
+        request_id: &str,
+        start: Instant,
+    ) -> Response<GatewayBody> {
+        let status = ctx.status.read().unwrap_or(StatusCode::OK);
+        let body = if bodyless(status) { full(Bytes::new()) } else { body };
+        let mut resp = Response::new(body);
+        *resp.status_mut() = status;
+
+        // Apply headers from context
+        for (k, v) in ctx.headers.read().iter() {
+            resp.headers_mut().append(k.clone(), v.clone());
+        }
+        resp.headers_mut().extend(ctx.response_headers.read().clone());
+        if bodyless(status) {
+            // A 304 length is the one of the representation it validates
+            if status != StatusCode::NOT_MODIFIED {
+                resp.headers_mut().remove(http::header::CONTENT_LENGTH);
+            }
+            resp.headers_mut().remove(http::header::TRANSFER_ENCODING);
+        }
+
+        // Add default headers
+        self.default_headers(resp, request_id, start)
+    }
+}
+
+enum BodyError {
+    /// The deadline of the request passed
+    Timeout,
+    /// No chunk came for the read timeout
+    Stalled(Duration),
+    Upstream(reqwest::Error),
+}
+
+/// Whole upstream body and its trailers, read frame by frame so a stalled
+/// upstream is noticed
+async fn read_body(
+    resp: reqwest::Response,
+    expires: Option<tokio::time::Instant>,
+    idle: Option<Duration>,
+) -> Result<(Bytes, HeaderMap), BodyError> {
+    let mut body = BytesMut::with_capacity(resp.content_length().unwrap_or(0).min(1 << 20) as usize);
+    let mut trailers = HeaderMap::new();
+    let mut frames = http::Response::<reqwest::Body>::from(resp).into_body();
+    loop {
+        let stalled = idle.map(|idle| tokio::time::Instant::now() + idle);
+        let frame = match expires.into_iter().chain(stalled).min() {
+            Some(at) => match tokio::time::timeout_at(at, frames.frame()).await {
+                Ok(frame) => frame,
+                Err(_) if expires.is_some_and(|e| e <= at) => return Err(BodyError::Timeout),
+                Err(_) => return Err(BodyError::Stalled(idle.unwrap_or_default())),
+            },
+            None => frames.frame().await,
+        };
+        let Some(frame) = frame.transpose().map_err(BodyError::Upstream)? else {
+            return Ok((body.freeze(), trailers));
+        };
+        match frame.into_data() {
+            Ok(chunk) => body.extend_from_slice(&chunk),
+            Err(frame) => trailers.extend(frame.into_trailers().unwrap_or_default()),
+        }
+    }
+}
+
+/// Statuses whose responses never carry a body, whatever the upstream or a
+/// plugin put in it
+fn bodyless(status: StatusCode) -> bool {
+    status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED
+}
+
+/// Host header value of an upstream URL
+fn authority(url: &url::Url) -> String {
+    match url.port() {
+        Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+        None => url.host_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn retry_count(headers: &mut HeaderMap, retries: u32) {
+    if retries > 0 {
+        headers.insert(RETRY_COUNT_HEADER, HeaderValue::from(retries));
+    }
+}
+
+/// Upstream client settings shared by every client: the connect timeout and
+/// title case header names
+fn client_builder(config: &GatewayNode) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::Client::builder();
+    if config.upstream.connect_timeout_ms > 0 {
+        builder = builder.connect_timeout(Duration::from_millis(config.upstream.connect_timeout_ms));
+    }
+    if config.headers.normalize.request && config.headers.normalize.title_case {
+        builder = builder.http1_title_case_headers();
+    }
+    builder
+}
+
+/// What `handle` learned of a request, for the metrics and access log
+#[derive(Default)]
+struct Handled {
+    labels: Option<RouteLabels>,
+    // Time to the upstream response headers
+    upstream: Option<Duration>,
+}
+
+/// Request fields of the access log, taken before the request is handled
+struct RequestLine {
+    method: String,
+    path: String,
+    version: String,
+    client_ip: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl RequestLine {
+    fn of<B>(req: &Request<B>) -> Self {
+        let header = |name| req.headers().get(name).and_then(|v: &HeaderValue| v.to_str().ok()).map(str::to_string);
+        Self {
+            method: req.method().to_string(),
+            path: req.uri().path().to_string(),
+            version: format!("{:?}", req.version()),
+            client_ip: req.extensions().get::<SocketAddr>().map(|a| a.ip().to_string()),
+            referer: header(http::header::REFERER),
+            user_agent: header(http::header::USER_AGENT),
+        }
+    }
+}
+
+/// None when access logging is off or its sink could not be opened
+fn access_logger(config: &GatewayNode) -> Option<AccessLogger> {
+    let cfg = &config.access_log;
+    if !cfg.enabled {
+        return None;
+    }
+    let endpoint = Some(config.tracing.otlp_endpoint.as_str());
+    let rotation = Rotation {
+        max_size: cfg.max_size.saturating_mul(1024 * 1024),
+        max_backups: cfg.max_backups,
+        max_age: (cfg.max_age > 0).then(|| Duration::from_secs(u64::from(cfg.max_age) * 24 * 3600)),
+        compress: cfg.compress,
+    };
+    match AccessFormat::parse(&cfg.format)
+        .and_then(|format| AccessLogger::new(format, &cfg.path, rotation, endpoint, config.service_name()))
+    {
+        Ok(logger) => Some(logger),
+        Err(e) => {
+            error!("access log disabled: {e}");
+            None
+        }
+    }
+}
+
+fn check_state_limits(limits: &StateLimitsCfg, s: &ServicesTemplate) -> Result<()> {
+    if s.services.len() > limits.max_services {
+        bail!("{} services, the limit is {}", s.services.len(), limits.max_services);
+    }
+    let mut plugins = s.global.plugins.len();
+    for svc in s.services.iter() {
+        if svc.routes.len() > limits.max_routes_per_service {
+            bail!(
+                "service {} has {} routes, the limit is {}",
+                svc.id,
+                svc.routes.len(),
+                limits.max_routes_per_service
+            );
+        }
+        plugins += svc.plugins.len() + svc.routes.iter().map(|r| r.plugins.len()).sum::<usize>();
+    }
+    if plugins > limits.max_plugins {
+        bail!("{} plugins, the limit is {}", plugins, limits.max_plugins);
+    }
+    Ok(())
+}
+
+fn find_plugin<'a>(
+    svc: &'a mut Service,
+    route_id: Option<&str>,
+    plugin_id: &str,
+) -> Option<&'a mut AppliedPlugin> {
+    let plugins = match route_id {
+        Some(route) => &mut svc.routes.iter_mut().find(|r| r.id == route)?.plugins,
+        None => &mut svc.plugins,
+    };
+    plugins.iter_mut().find(|p| p.id == plugin_id)
+}
+
+pub(crate) fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+fn not_found(request_id: &str) -> Response<GatewayBody> {
+    simple(
+        StatusCode::NOT_FOUND,
+        Bytes::from(format!(
+            "<html><head><title>BullG: Route Not Found</title></head>\
+            <body><h1>Not Found</h1>\
+            <h2>Route not found</h2>\
+            <p><b>Request id:</b> {request_id}</p>\
+            <br/><hr/> \
+            <center><p>{app_name} Gateway {app_version} &copy; {year}</p></center>\
+            </body></html>",
+            request_id = request_id,
+            app_name = APP_NAME,
+            app_version = APP_VERSION,
+            year = Utc::now().year()
+        )),
+    )
+}
+
+pub(crate) fn full(body: Bytes) -> GatewayBody {
+    Full::new(body).map_err(|never| match never {}).boxed()
+}
+
+pub(crate) fn simple(status: StatusCode, body: Bytes) -> Response<GatewayBody> {
+    let mut resp = Response::new(full(body));
+    *resp.status_mut() = status;
+    resp
+}
@@ -0,0 +1,152 @@
+use bullg_core::MaintenanceCfg;
+use bytes::Bytes;
+use http::{Response, StatusCode, header, header::HeaderValue};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{info, warn};
+
+use crate::{GatewayBody, simple};
+
+/// Whether the gateway or a service is in maintenance, flipped at runtime
+/// by the admin API
+pub struct Maintenance {
+    global: AtomicBool,
+    services: RwLock<BTreeSet<String>>,
+    exempt_ips: Vec<IpAddr>,
+}
+
+/// Maintenance state reported by the admin API
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    pub services: Vec<String>,
+}
+
+impl Maintenance {
+    pub fn new(cfg: &MaintenanceCfg) -> Self {
+        let exempt_ips = cfg
+            .exempt_ips
+            .iter()
+            .filter_map(|ip| match ip.parse() {
+                Ok(ip) => Some(IpAddr::to_canonical(&ip)),
+                Err(_) => {
+                    warn!("ignoring maintenance exempt ip `{ip}`: not an IP address");
+                    None
+                }
+            })
+            .collect();
+        Self {
+            global: AtomicBool::new(cfg.enabled),
+            services: RwLock::new(cfg.services.iter().cloned().collect()),
+            exempt_ips,
+        }
+    }
+
+    pub fn set_global(&self, on: bool) {
+        self.global.store(on, Ordering::Relaxed);
+        info!("maintenance mode {}", if on { "on" } else { "off" });
+    }
+
+    pub fn set_service(&self, service: &str, on: bool) {
+        let mut services = self.services.write().unwrap_or_else(|e| e.into_inner());
+        if on {
+            services.insert(service.to_string());
+        } else {
+            services.remove(service);
+        }
+        info!("maintenance mode {} for service {}", if on { "on" } else { "off" }, service);
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        MaintenanceStatus {
+            enabled: self.global.load(Ordering::Relaxed),
+            services: self
+                .services
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn global(&self) -> bool {
+        self.global.load(Ordering::Relaxed)
+    }
+
+    /// Whether any service is in maintenance, so requests only get routed
+    /// early when one may be
+    pub fn any_service(&self) -> bool {
+        !self.services.read().unwrap_or_else(|e| e.into_inner()).is_empty()
+    }
+
+    pub fn service(&self, service: &str) -> bool {
+        self.services.read().unwrap_or_else(|e| e.into_inner()).contains(service)
+    }
+
+    /// Requests served as usual during maintenance: to an exempt path prefix,
+    /// matched on whole segments, or from an exempt client address
+    pub fn exempt(&self, cfg: &MaintenanceCfg, path: &str, client: Option<IpAddr>) -> bool {
+        let path_exempt = cfg.exempt_paths.iter().any(|prefix| {
+            let prefix = prefix.trim_end_matches('/');
+            path.strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.is_empty())
+        });
+        path_exempt || client.is_some_and(|ip| self.exempt_ips.contains(&ip.to_canonical()))
+    }
+}
+
+/// Maintenance page answered instead of the request
+pub fn response(cfg: &MaintenanceCfg) -> Response<GatewayBody> {
+    let status = StatusCode::from_u16(cfg.status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+    let mut resp = simple(status, Bytes::from(cfg.body.clone()));
+    if let Ok(content_type) = HeaderValue::from_str(&cfg.content_type) {
+        resp.headers_mut().insert(header::CONTENT_TYPE, content_type);
+    }
+    if cfg.retry_after_sec > 0 {
+        resp.headers_mut()
+            .insert(header::RETRY_AFTER, HeaderValue::from(cfg.retry_after_sec));
+    }
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exempt_paths_match_whole_segments_and_ips_their_canonical_form() {
+        let cfg = MaintenanceCfg {
+            exempt_paths: vec!["/health/".into(), "/status".into()],
+            exempt_ips: vec!["10.0.0.9".into(), "not-an-ip".into()],
+            ..Default::default()
+        };
+        let maintenance = Maintenance::new(&cfg);
+        for (path, exempt) in [("/health", true), ("/health/live", true), ("/status", true), ("/statuses", false), ("/api", false)] {
+            assert_eq!(maintenance.exempt(&cfg, path, None), exempt, "{path}");
+        }
+        assert!(maintenance.exempt(&cfg, "/api", Some("10.0.0.9".parse().unwrap())));
+        assert!(maintenance.exempt(&cfg, "/api", Some("::ffff:10.0.0.9".parse().unwrap())));
+        assert!(!maintenance.exempt(&cfg, "/api", Some("10.0.0.8".parse().unwrap())));
+    }
+
+    #[test]
+    fn flags_start_from_the_config_and_flip_at_runtime() {
+        let maintenance = Maintenance::new(&MaintenanceCfg { services: vec!["orders".into()], ..Default::default() });
+        assert!(!maintenance.global() && maintenance.service("orders") && maintenance.any_service());
+        maintenance.set_service("orders", false);
+        maintenance.set_service("users", true);
+        maintenance.set_global(true);
+        let status = maintenance.status();
+        assert!(status.enabled);
+        assert_eq!(status.services, ["users"]);
+
+        let resp = response(&MaintenanceCfg { retry_after_sec: 120, ..Default::default() });
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()[header::RETRY_AFTER], "120");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+    }
+}
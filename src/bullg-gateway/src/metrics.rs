@@ -0,0 +1,259 @@
+use bullg_core::LoadStats;
+use dashmap::DashMap;
+use http::StatusCode;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds in seconds of the upstream latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// In process gateway counters
+pub struct Metrics {
+    // plugin type -> panics caught
+    plugin_panics: DashMap<String, AtomicU64>,
+    /// Connections, in flight requests and latency, shared with plugins
+    pub load: Arc<LoadStats>,
+    shed: AtomicU64,
+    force_closed: AtomicU64,
+    shadowed: AtomicU64,
+    shadow_mismatches: AtomicU64,
+    shadow_errors: AtomicU64,
+    // (labels, status code) -> answered requests
+    requests: DashMap<(RouteLabels, u16), AtomicU64>,
+    in_flight: DashMap<RouteLabels, AtomicU64>,
+    upstream_latency: DashMap<RouteLabels, Histogram>,
+}
+
+/// Service and route ids a request was routed to, empty for requests
+/// answered before routing
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct RouteLabels {
+    pub service: String,
+    pub route: String,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // Non cumulative counts, the last one above every bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_us: AtomicU64,
+}
+
+/// Counts a request in `requests_in_flight` until dropped
+pub struct InFlight {
+    metrics: Arc<Metrics>,
+    labels: RouteLabels,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(count) = self.metrics.in_flight.get(&self.labels) {
+            count.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// One exported series
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            plugin_panics: DashMap::new(),
+            load: LoadStats::new(),
+            shed: AtomicU64::new(0),
+            force_closed: AtomicU64::new(0),
+            shadowed: AtomicU64::new(0),
+            shadow_mismatches: AtomicU64::new(0),
+            shadow_errors: AtomicU64::new(0),
+            requests: DashMap::new(),
+            in_flight: DashMap::new(),
+            upstream_latency: DashMap::new(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn request_shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `requests_shed_total`
+    pub fn requests_shed_total(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    pub fn connection_force_closed(&self) {
+        self.force_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `connections_force_closed_total`, connections and tunnels still open
+    /// at the shutdown drain timeout
+    pub fn connections_force_closed_total(&self) -> u64 {
+        self.force_closed.load(Ordering::Relaxed)
+    }
+
+    /// A shadow request answered, `mismatch` when the answers differ
+    pub fn shadow_compared(&self, mismatch: bool) {
+        self.shadowed.fetch_add(1, Ordering::Relaxed);
+        if mismatch {
+            self.shadow_mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn shadow_failed(&self) {
+        self.shadowed.fetch_add(1, Ordering::Relaxed);
+        self.shadow_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `shadow_requests_total`, failed ones included
+    pub fn shadow_requests_total(&self) -> u64 {
+        self.shadowed.load(Ordering::Relaxed)
+    }
+
+    /// `shadow_mismatches_total`, shadow answers differing from the primary
+    pub fn shadow_mismatches_total(&self) -> u64 {
+        self.shadow_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// `shadow_errors_total`, shadow requests that failed or timed out
+    pub fn shadow_errors_total(&self) -> u64 {
+        self.shadow_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn plugin_panic(&self, plugin: &str) {
+        self.plugin_panics
+            .entry(plugin.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `plugin_panics_total` by plugin type
+    pub fn plugin_panics_total(&self) -> Vec<(String, u64)> {
+        self.plugin_panics
+            .iter()
+            .map(|e| (e.key().clone(), e.value().load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// `requests_total` by service, route and status code
+    pub fn request_done(&self, labels: Option<&RouteLabels>, status: StatusCode) {
+        let key = (labels.cloned().unwrap_or_default(), status.as_u16());
+        self.requests.entry(key).or_default().fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `requests_in_flight` by service and route, held until the response
+    /// is fully sent
+    pub fn in_flight(self: &Arc<Self>, labels: &RouteLabels) -> InFlight {
+        self.in_flight.entry(labels.clone()).or_default().fetch_add(1, Ordering::Relaxed);
+        InFlight {
+            metrics: self.clone(),
+            labels: labels.clone(),
+        }
+    }
+
+    /// `upstream_latency_seconds` by service and route, the time to the
+    /// upstream response headers, retries included
+    pub fn upstream_latency(&self, labels: &RouteLabels, elapsed: Duration) {
+        let histogram = self.upstream_latency.entry(labels.clone()).or_default();
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS.iter().position(|le| secs <= *le).unwrap_or(LATENCY_BUCKETS.len());
+        histogram.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        histogram.sum_us.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Every series, histograms as their cumulative `_bucket`, `_sum` and
+    /// `_count` series
+    pub fn samples(&self) -> Vec<Sample> {
+        let sample = |name: &str, labels: Vec<(&'static str, String)>, value: f64| Sample {
+            name: name.to_string(),
+            labels,
+            value,
+        };
+        let route = |l: &RouteLabels| vec![("service", l.service.clone()), ("route", l.route.clone())];
+        let mut samples = vec![
+            sample("connections_open", vec![], self.load.snapshot().connections as f64),
+            sample("requests_shed_total", vec![], self.requests_shed_total() as f64),
+            sample("connections_force_closed_total", vec![], self.connections_force_closed_total() as f64),
+            sample("shadow_requests_total", vec![], self.shadow_requests_total() as f64),
+            sample("shadow_mismatches_total", vec![], self.shadow_mismatches_total() as f64),
+            sample("shadow_errors_total", vec![], self.shadow_errors_total() as f64),
+        ];
+        for (plugin, count) in self.plugin_panics_total() {
+            samples.push(sample("plugin_panics_total", vec![("plugin", plugin)], count as f64));
+        }
+        for e in self.requests.iter() {
+            let (labels, code) = e.key();
+            let mut labels = route(labels);
+            labels.push(("code", code.to_string()));
+            samples.push(sample("requests_total", labels, e.value().load(Ordering::Relaxed) as f64));
+        }
+        for e in self.in_flight.iter() {
+            samples.push(sample("requests_in_flight", route(e.key()), e.value().load(Ordering::Relaxed) as f64));
+        }
+        for e in self.upstream_latency.iter() {
+            let mut count = 0;
+            for (i, bucket) in e.value().buckets.iter().enumerate() {
+                count += bucket.load(Ordering::Relaxed);
+                let le = LATENCY_BUCKETS.get(i).map_or("+Inf".to_string(), |le| le.to_string());
+                let mut labels = route(e.key());
+                labels.push(("le", le));
+                samples.push(sample("upstream_latency_seconds_bucket", labels, count as f64));
+            }
+            let sum = e.value().sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+            samples.push(sample("upstream_latency_seconds_sum", route(e.key()), sum));
+            samples.push(sample("upstream_latency_seconds_count", route(e.key()), count as f64));
+        }
+        samples
+    }
+
+    /// `format: json` export, a list of `{name, labels, value}`
+    pub fn json(&self) -> serde_json::Value {
+        let samples = self.samples().into_iter().map(|s| {
+            let labels: serde_json::Map<String, serde_json::Value> =
+                s.labels.into_iter().map(|(k, v)| (k.to_string(), v.into())).collect();
+            serde_json::json!({ "name": s.name, "labels": labels, "value": s.value })
+        });
+        serde_json::Value::Array(samples.collect())
+    }
+
+    /// Prometheus text exposition format
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut typed = std::collections::HashSet::new();
+        for s in self.samples() {
+            let family = if s.name.starts_with("upstream_latency_seconds") {
+                "upstream_latency_seconds"
+            } else {
+                s.name.as_str()
+            };
+            if typed.insert(family.to_string()) {
+                let kind = match family {
+                    "connections_open" | "requests_in_flight" => "gauge",
+                    "upstream_latency_seconds" => "histogram",
+                    _ => "counter",
+                };
+                let _ = writeln!(out, "# TYPE {family} {kind}");
+            }
+            out.push_str(&s.name);
+            if !s.labels.is_empty() {
+                let labels: Vec<String> = s.labels.iter().map(|(k, v)| format!("{k}=\"{}\"", escape(v))).collect();
+                let _ = write!(out, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(out, " {}", s.value);
+        }
+        out
+    }
+}
+
+// Label value escaping of the text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
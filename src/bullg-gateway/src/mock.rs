@@ -0,0 +1,193 @@
+use anyhow::Result;
+use bullg_core::{ContextPath, Protocols, Route, RouteConfig, Service, ServiceContextPaths, Upstream};
+use bytes::Bytes;
+use http::{HeaderMap, Method, Request, Response, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::tokio::TokioIo;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// Request as received by a `MockUpstream`
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub method: Method,
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+type Handler = dyn Fn(&Recorded) -> Response<Bytes> + Send + Sync;
+
+/// Upstream on a loopback port for driving `Gateway::handle_request` in
+/// tests. Every request is recorded and answered by the handler, the server
+/// stops when the mock is dropped.
+///
+/// ```ignore
+/// let up = MockUpstream::start(|_| Response::new(Bytes::from("ok"))).await?;
+/// gateway.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() }).await?;
+/// let resp = gateway.handle_request(Request::get("/api/users").body(Full::new(Bytes::new()))?).await;
+/// assert_eq!(up.requests()[0].uri.path(), "/users");
+/// ```
+pub struct MockUpstream {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Recorded>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockUpstream {
+    pub async fn start<F>(handler: F) -> Result<Self>
+    where
+        F: Fn(&Recorded) -> Response<Bytes> + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let log = requests.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (log, handler) = (log.clone(), handler.clone());
+                tokio::spawn(async move {
+                    let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                        let (log, handler) = (log.clone(), handler.clone());
+                        async move {
+                            let (parts, body) = req.into_parts();
+                            let recorded = Recorded {
+                                method: parts.method,
+                                uri: parts.uri,
+                                headers: parts.headers,
+                                body: body.collect().await?.to_bytes(),
+                            };
+                            let resp = handler(&recorded).map(Full::new);
+                            log.lock().unwrap_or_else(|e| e.into_inner()).push(recorded);
+                            Ok::<_, hyper::Error>(resp)
+                        }
+                    });
+                    let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+                });
+            }
+        });
+
+        Ok(Self { addr, requests, task })
+    }
+
+    /// Upstream answering every connection with the raw bytes of `parts`,
+    /// each written after its delay, then closing it. For answers a handler
+    /// cannot give: streamed, stalled or cut short. Only the request line and
+    /// headers are read, the recorded body is empty.
+    pub async fn raw(parts: Vec<(Duration, Bytes)>) -> Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let log = requests.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let (log, parts) = (log.clone(), parts.clone());
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    let mut byte = [0u8; 1];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut byte).await {
+                            Ok(1) => head.push(byte[0]),
+                            _ => return,
+                        }
+                    }
+                    if let Some(recorded) = parse_head(&head) {
+                        log.lock().unwrap_or_else(|e| e.into_inner()).push(recorded);
+                    }
+                    for (delay, part) in parts {
+                        tokio::time::sleep(delay).await;
+                        if stream.write_all(&part).await.is_err() {
+                            return;
+                        }
+                    }
+                    let _ = stream.shutdown().await;
+                });
+            }
+        });
+
+        Ok(Self { addr, requests, task })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Requests received so far, oldest first
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.requests.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Enabled upstream pointing at this mock
+    pub fn upstream(&self) -> Upstream {
+        Upstream {
+            id: "mock".into(),
+            name: "mock".into(),
+            protocols: vec![Protocols::HTTP],
+            host: self.addr.ip().to_string(),
+            port: self.addr.port(),
+            enabled: true,
+            weight: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Service served under `context_path` with one route proxied to this mock
+    pub fn service(&self, context_path: &str, route_path: &str) -> Service {
+        Service {
+            id: "mock".into(),
+            name: "mock".into(),
+            upstreams: vec![self.upstream()],
+            context_paths: ServiceContextPaths {
+                enable: true,
+                paths: vec![ContextPath {
+                    path: context_path.into(),
+                    versions: vec![],
+                }],
+            },
+            routes: vec![Route {
+                id: "mock".into(),
+                name: "mock".into(),
+                enabled: true,
+                config: RouteConfig {
+                    protocols: vec![Protocols::HTTP],
+                    path: route_path.into(),
+                    backend: route_path.into(),
+                    methods: vec![],
+                    strip_path: false,
+                    response_headers: vec![],
+                },
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+}
+
+/// Request line and headers of a raw request
+fn parse_head(head: &[u8]) -> Option<Recorded> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.parse().ok()?;
+    let uri = request_line.next()?.parse().ok()?;
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .filter_map(|(k, v)| Some((k.trim().parse().ok()?, v.trim().parse().ok()?)))
+        .collect();
+    Some(Recorded { method, uri, headers, body: Bytes::new() })
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
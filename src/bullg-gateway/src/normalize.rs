@@ -0,0 +1,142 @@
+use bullg_core::{PathMode, PathsCfg};
+use http::Uri;
+use http::uri::PathAndQuery;
+
+/// Bring the request path to its canonical form (RFC 3986 §6.2.2) so routes
+/// and plugins matching on a prefix see the same path the upstream resolves,
+/// `/api/%2e%2e/admin` and `/api/../admin` both being `/admin`.
+///
+/// Only unreserved characters are decoded, an encoded `/` stays encoded and
+/// never starts a segment. Under `PathMode::Reject` a path that is not
+/// already canonical is refused instead of rewritten.
+pub fn apply(cfg: &PathsCfg, uri: &mut Uri) -> Result<(), &'static str> {
+    let path = uri.path();
+    // `*` of OPTIONS and authority forms have nothing to resolve
+    if !path.starts_with('/') {
+        return Ok(());
+    }
+    let canonical = canonical(path, cfg.merge_slashes)?;
+    if canonical == path {
+        return Ok(());
+    }
+    if cfg.mode == PathMode::Reject {
+        return Err("path is not canonical");
+    }
+    let pq = match uri.query() {
+        Some(query) => format!("{canonical}?{query}"),
+        None => canonical,
+    };
+    let mut parts = std::mem::take(uri).into_parts();
+    parts.path_and_query = Some(PathAndQuery::try_from(pq).map_err(|_| "invalid path")?);
+    *uri = Uri::from_parts(parts).map_err(|_| "invalid path")?;
+    Ok(())
+}
+
+/// Canonical form of an absolute path
+pub fn canonical(path: &str, merge_slashes: bool) -> Result<String, &'static str> {
+    let decoded = decode_unreserved(path)?;
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing = false;
+    let mut rest = decoded[1..].split('/').peekable();
+    while let Some(segment) = rest.next() {
+        trailing = matches!(segment, "" | "." | "..");
+        match segment {
+            "." => {}
+            // The last empty segment is the trailing slash
+            "" if merge_slashes || rest.peek().is_none() => {}
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err("path escapes the root");
+                }
+            }
+            _ => segments.push(segment),
+        }
+    }
+    let mut out = String::with_capacity(decoded.len());
+    for segment in &segments {
+        out.push('/');
+        out.push_str(segment);
+    }
+    if trailing || out.is_empty() {
+        out.push('/');
+    }
+    Ok(out)
+}
+
+// Decode percent-encoded unreserved characters, other escapes are kept with
+// uppercase hex digits
+fn decode_unreserved(path: &str) -> Result<String, &'static str> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+            .ok_or("malformed percent-encoding")?;
+        let b = (hex_value(hex[0]) << 4) | hex_value(hex[1]);
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b);
+        } else {
+            out.extend_from_slice(format!("%{b:02X}").as_bytes());
+        }
+        i += 3;
+    }
+    // Only ASCII was decoded, the rest is copied from a str
+    String::from_utf8(out).map_err(|_| "invalid path")
+}
+
+fn hex_value(digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(mode: PathMode, uri: &str) -> Result<String, &'static str> {
+        let mut uri: Uri = uri.parse().unwrap();
+        apply(&PathsCfg { mode, ..Default::default() }, &mut uri).map(|()| uri.to_string())
+    }
+
+    #[test]
+    fn dot_segments_and_encoded_traversals_are_resolved() {
+        for (raw, canonical) in [
+            ("/api/../admin", "/admin"),
+            ("/api/%2e%2e/admin", "/admin"),
+            ("/api/%2E./admin?x=1", "/admin?x=1"),
+            ("/api/./users/", "/api/users/"),
+            ("/api//users", "/api/users"),
+            ("/api/users/..", "/api/"),
+            ("/%7Euser/%61", "/~user/a"),
+        ] {
+            assert_eq!(normalized(PathMode::Normalize, raw).unwrap(), canonical, "{raw}");
+        }
+        // An encoded slash is kept and never starts a segment
+        assert_eq!(normalized(PathMode::Normalize, "/api/..%2fadmin").unwrap(), "/api/..%2Fadmin");
+        assert_eq!(canonical("/api//users", false).unwrap(), "/api//users");
+    }
+
+    #[test]
+    fn rejected_paths() {
+        assert_eq!(normalized(PathMode::Reject, "/api/users?x=1").unwrap(), "/api/users?x=1");
+        for raw in ["/api/../admin", "/api/%2e%2e/admin", "/api//users"] {
+            assert_eq!(normalized(PathMode::Reject, raw), Err("path is not canonical"), "{raw}");
+        }
+        // Whatever the mode
+        for mode in [PathMode::Normalize, PathMode::Reject] {
+            assert_eq!(normalized(mode, "/../etc/passwd"), Err("path escapes the root"));
+            assert_eq!(normalized(mode, "/api/%2"), Err("malformed percent-encoding"));
+            assert_eq!(normalized(mode, "/api/%zz"), Err("malformed percent-encoding"));
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{Result, bail};
+use bullg_core::AppliedPolicy;
+use http::StatusCode;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::balance::LoadBalancePolicy;
+use crate::capture::CapturePolicy;
+use crate::client::ClientPolicy;
+use crate::concurrency::ConcurrencyPolicy;
+use crate::failure::FailurePolicy;
+use crate::grpcweb::GrpcWebPolicy;
+use crate::health::{HealthCheckPolicy, OutlierPolicy};
+use crate::retry::RetryPolicy;
+use crate::shadow::ShadowPolicy;
+use crate::stream::StreamPolicy;
+use crate::throttle::UpstreamRatePolicy;
+use crate::timeout::TimeoutPolicy;
+
+/// Typed policies of a version scoped service, parsed once when the state is
+/// applied. Each is the first enabled policy of its kind, service level
+/// policies taking precedence over global ones.
+#[derive(Debug, Clone, Default)]
+pub struct Policies {
+    pub retry: Option<RetryPolicy>,
+    pub timeout: Option<TimeoutPolicy>,
+    pub load_balancer: Option<LoadBalancePolicy>,
+    pub upstream_rate_limit: Option<UpstreamRatePolicy>,
+    pub concurrency: Option<ConcurrencyPolicy>,
+    pub health_check: Option<HealthCheckPolicy>,
+    pub outlier_detection: Option<OutlierPolicy>,
+    pub upstream_failure: Option<FailurePolicy>,
+    pub grpc_web: Option<GrpcWebPolicy>,
+    pub upstream_client: Option<ClientPolicy>,
+    pub shadow: Option<ShadowPolicy>,
+    pub capture: Option<CapturePolicy>,
+    pub streaming: Option<StreamPolicy>,
+}
+
+impl Policies {
+    /// Policies of one list. Every policy of a known kind must have a valid
+    /// config, disabled ones too so enabling one later cannot break the
+    /// state. Policies of other kinds are left to whoever reads them.
+    pub fn parse(list: &[AppliedPolicy]) -> Result<Self> {
+        Ok(Self {
+            retry: first(list, RetryPolicy::KIND)?,
+            timeout: first(list, TimeoutPolicy::KIND)?,
+            load_balancer: first(list, LoadBalancePolicy::KIND)?,
+            upstream_rate_limit: first(list, UpstreamRatePolicy::KIND)?,
+            concurrency: first(list, ConcurrencyPolicy::KIND)?,
+            health_check: first(list, HealthCheckPolicy::KIND)?,
+            outlier_detection: first(list, OutlierPolicy::KIND)?,
+            upstream_failure: first(list, FailurePolicy::KIND)?,
+            grpc_web: first(list, GrpcWebPolicy::KIND)?,
+            upstream_client: first(list, ClientPolicy::KIND)?,
+            shadow: first(list, ShadowPolicy::KIND)?,
+            capture: first(list, CapturePolicy::KIND)?,
+            streaming: first(list, StreamPolicy::KIND)?,
+        })
+    }
+
+    /// These policies, else those of `global` for the kinds these lack
+    pub fn or(&self, global: &Policies) -> Policies {
+        Policies {
+            retry: self.retry.clone().or_else(|| global.retry.clone()),
+            timeout: self.timeout.clone().or_else(|| global.timeout.clone()),
+            load_balancer: self.load_balancer.clone().or_else(|| global.load_balancer.clone()),
+            upstream_rate_limit: self.upstream_rate_limit.clone().or_else(|| global.upstream_rate_limit.clone()),
+            concurrency: self.concurrency.clone().or_else(|| global.concurrency.clone()),
+            health_check: self.health_check.clone().or_else(|| global.health_check.clone()),
+            outlier_detection: self.outlier_detection.clone().or_else(|| global.outlier_detection.clone()),
+            upstream_failure: self.upstream_failure.clone().or_else(|| global.upstream_failure.clone()),
+            grpc_web: self.grpc_web.clone().or_else(|| global.grpc_web.clone()),
+            upstream_client: self.upstream_client.clone().or_else(|| global.upstream_client.clone()),
+            shadow: self.shadow.clone().or_else(|| global.shadow.clone()),
+            capture: self.capture.clone().or_else(|| global.capture.clone()),
+            streaming: self.streaming.clone().or_else(|| global.streaming.clone()),
+        }
+    }
+}
+
+/// Typed config of the first enabled policy of `kind` in `list`, after
+/// checking the config of every policy of that kind
+fn first<T: DeserializeOwned>(list: &[AppliedPolicy], kind: &str) -> Result<Option<T>> {
+    let mut found = None;
+    for policy in list.iter().filter(|p| p.r#type == kind) {
+        let config = policy.config.clone().unwrap_or_else(|| serde_json::json!({}));
+        let cfg: T = match serde_json::from_value(config) {
+            Ok(cfg) => cfg,
+            Err(e) => bail!("invalid config for {} policy {}: {e}", kind, policy.id),
+        };
+        if policy.enabled && found.is_none() {
+            found = Some(cfg);
+        }
+    }
+    Ok(found)
+}
+
+/// Response returned when a policy rejects a request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyError {
+    pub status_code: u16,
+    pub message: String,
+}
+
+impl PolicyError {
+    pub fn new(status: StatusCode, message: &str) -> Self {
+        Self {
+            status_code: status.as_u16(),
+            message: message.to_string(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
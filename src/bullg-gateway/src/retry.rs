@@ -0,0 +1,155 @@
+use crate::failure::FailurePolicy;
+use bullg_utils::{de_duration, ser_duration};
+use http::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Header telling the client how many times the upstream request was retried
+pub const RETRY_COUNT_HEADER: &str = "x-retry-count";
+
+/// Upstream retry policy (`type: retry` on a service or global policy).
+///
+/// Connection failures and retryable statuses are tracked separately, each
+/// with its own cap. A section that is absent disables that kind of retry.
+/// An `upstream_failure` policy decides which statuses and errors are
+/// retried when the sections do not say.
+/// Retries wait for an exponential backoff, and only happen before anything
+/// of the response reached the client. Retried responses carry
+/// `X-Retry-Count`.
+///
+/// ```yaml
+/// - id: svc-retry
+///   type: retry
+///   enabled: true
+///   config:
+///     retry_on_connect_error:
+///       max_retries: 3
+///     retry_on_status:
+///       max_retries: 2
+///       statuses: [502, 503, 504]
+///       non_idempotent: false
+///     backoff:
+///       base_delay: 25ms
+///       max_delay: 1s
+///       jitter: true
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct RetryPolicy {
+    #[serde(default)]
+    pub retry_on_connect_error: Option<ConnectRetry>,
+    #[serde(default)]
+    pub retry_on_status: Option<StatusRetry>,
+    #[serde(default)]
+    pub backoff: Backoff,
+}
+
+/// Wait before retry `n`: `base_delay` doubled on every retry up to
+/// `max_delay`. With `jitter` the wait is a random share of that so clients
+/// failing together do not retry together.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backoff {
+    #[serde(default = "def_base_delay", deserialize_with = "de_duration", serialize_with = "ser_duration")]
+    pub base_delay: Duration,
+    #[serde(default = "def_max_delay", deserialize_with = "de_duration", serialize_with = "ser_duration")]
+    pub max_delay: Duration,
+    #[serde(default = "def_jitter")]
+    pub jitter: bool,
+}
+
+fn def_base_delay() -> Duration {
+    Duration::from_millis(25)
+}
+
+fn def_max_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn def_jitter() -> bool {
+    true
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base_delay: def_base_delay(),
+            max_delay: def_max_delay(),
+            jitter: def_jitter(),
+        }
+    }
+}
+
+impl Backoff {
+    /// Wait before the `retry`th retry, counted from 1
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .saturating_mul(1 << retry.saturating_sub(1).min(16))
+            .min(self.max_delay);
+        if self.jitter && !exp.is_zero() {
+            exp.mul_f64(rand::random::<f64>())
+        } else {
+            exp
+        }
+    }
+}
+
+fn def_max_retries() -> u32 {
+    2
+}
+
+fn def_statuses() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+/// Retry when the upstream connection could not be established.
+/// The request never reached the upstream, so this is safe for every method.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectRetry {
+    #[serde(default = "def_max_retries")]
+    pub max_retries: u32,
+}
+
+/// Retry when the upstream answered with one of `statuses`, without them
+/// with a failing status of the `upstream_failure` policy, else 502, 503 or
+/// 504. Only idempotent methods are retried unless `non_idempotent` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusRetry {
+    #[serde(default = "def_max_retries")]
+    pub max_retries: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub statuses: Option<Vec<u16>>,
+    #[serde(default)]
+    pub non_idempotent: bool,
+}
+
+impl RetryPolicy {
+    pub const KIND: &'static str = "retry";
+
+    /// `retries` is the number of connect retries already made, connection
+    /// errors the `failures` policy does not count are not retried
+    pub fn retry_connect(&self, retries: u32, failures: Option<&FailurePolicy>) -> bool {
+        self.retry_on_connect_error
+            .as_ref()
+            .is_some_and(|c| retries < c.max_retries && failures.is_none_or(|f| f.connect_errors))
+    }
+
+    /// `retries` is the number of status retries already made
+    pub fn retry_status(&self, method: &Method, status: StatusCode, retries: u32, failures: Option<&FailurePolicy>) -> bool {
+        self.retry_on_status.as_ref().is_some_and(|s| {
+            let failed = match (&s.statuses, failures) {
+                (Some(statuses), _) => statuses.contains(&status.as_u16()),
+                (None, Some(failures)) => failures.status_fails(status),
+                (None, None) => def_statuses().contains(&status.as_u16()),
+            };
+            retries < s.max_retries && failed && (s.non_idempotent || is_idempotent(method))
+        })
+    }
+}
+
+/// Idempotent methods as defined by RFC 9110 §9.2.2
+pub fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS | Method::TRACE
+    )
+}
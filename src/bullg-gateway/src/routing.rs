@@ -0,0 +1,427 @@
+use anyhow::{Result, bail};
+use url::Url;
+use bullg_core::{HeaderMatch, Route, RoutingRule, Service, Upstream};
+use http::HeaderMap;
+use std::collections::HashSet;
+use tracing::{debug, warn};
+
+/// Upstream for a request: the upstream of the first matching rule of the
+/// route, then of the service, else the one `balance` picks among the
+/// enabled upstreams. Upstreams `healthy` rejects are passed over.
+pub fn select_upstream<'a>(
+    svc: &'a Service,
+    route: &Route,
+    headers: &HeaderMap,
+    healthy: impl Fn(&Upstream) -> bool,
+    balance: impl FnOnce(&[&'a Upstream]) -> Option<&'a Upstream>,
+) -> Option<&'a Upstream> {
+    for rule in route.rules.iter().chain(svc.rules.iter()) {
+        if !matches(rule, headers) {
+            continue;
+        }
+        match svc.upstreams.iter().find(|u| u.id == rule.upstream && u.is_enabled() && healthy(u)) {
+            Some(upstream) => {
+                debug!("routing rule {} selected upstream {}", rule.id, upstream.id);
+                return Some(upstream);
+            }
+            None => warn!("routing rule {}: upstream {} is disabled or unhealthy", rule.id, rule.upstream),
+        }
+    }
+    let candidates: Vec<&Upstream> = svc.upstreams.iter().filter(|u| u.is_enabled() && healthy(u)).collect();
+    balance(&candidates)
+}
+
+fn matches(rule: &RoutingRule, headers: &HeaderMap) -> bool {
+    rule.headers.iter().all(|h| header_matches(h, headers))
+        && rule.percent.is_none_or(|p| rand::random::<f64>() * 100.0 < p)
+}
+
+pub(crate) fn header_matches(m: &HeaderMatch, headers: &HeaderMap) -> bool {
+    let mut values = headers.get_all(m.name.as_str()).iter();
+    match &m.value {
+        Some(want) => values.any(|v| v.as_bytes() == want.as_bytes()),
+        None => values.next().is_some(),
+    }
+}
+
+/// Upstream URL of a request: the upstream base path joined with the path
+/// left after the context path, and the route prefix when `strip_path` is
+/// set, with the query kept as sent. Dot segments may not climb above the
+/// base path.
+pub fn upstream_url(upstream: &Upstream, route: &Route, path: &str, query: Option<&str>) -> Result<Url> {
+    let path = match route.config.strip_path {
+        true => match_path(&route.config.path, path).map_or(path, |(len, _)| &path[len..]),
+        false => path,
+    };
+    let base = match upstream.path.trim_end_matches('/') {
+        base if base.is_empty() || base.starts_with('/') => base.to_string(),
+        base => format!("/{base}"),
+    };
+    let sep = if path.starts_with('/') { "" } else { "/" };
+    let mut raw = format!("{}{base}{sep}{path}", upstream.get_url());
+    if let Some(query) = query {
+        raw.push('?');
+        raw.push_str(query);
+    }
+    let url = Url::parse(&raw)?;
+    if !url.path().starts_with(&base) {
+        bail!("path {} leaves the upstream base path {}", path, base);
+    }
+    Ok(url)
+}
+
+/// Length of the start of `path` a route path matches, with the values of
+/// its parameters. Route paths without parameters match as a plain prefix,
+/// as they always did. `{name}` or `:name` matches one non empty segment and
+/// a last `{*name}` segment the rest of the path, left out of the length so
+/// `strip_path` keeps it. Other segments match as they are and the match
+/// ends at a segment boundary.
+pub fn match_path(route: &str, path: &str) -> Option<(usize, Vec<(String, String)>)> {
+    if !route.split('/').any(|segment| param(segment).is_some() || tail(segment).is_some()) {
+        return path.starts_with(route).then(|| (route.len(), Vec::new()));
+    }
+    let mut params = Vec::new();
+    let mut matched = 0;
+    let mut segments = path.split('/');
+    for (i, want) in route.split('/').enumerate() {
+        // `matched` stops before the slash ahead of the current segment
+        let start = if i == 0 { 0 } else { matched + 1 };
+        if let Some(name) = tail(want) {
+            params.push((name.to_string(), path.get(start..).unwrap_or_default().to_string()));
+            return Some((matched, params));
+        }
+        let got = segments.next()?;
+        match param(want) {
+            Some(_) if got.is_empty() => return None,
+            Some(name) => params.push((name.to_string(), got.to_string())),
+            None if want != got => return None,
+            None => {}
+        }
+        matched = start + got.len();
+    }
+    Some((matched, params))
+}
+
+/// Names of the parameters of a route path, in path order
+pub fn path_params(route: &str) -> Vec<String> {
+    route.split('/').filter_map(segment_param).map(String::from).collect()
+}
+
+/// Name of a parameter segment of a route path, of any form
+pub(crate) fn segment_param(segment: &str) -> Option<&str> {
+    param(segment).or_else(|| tail(segment))
+}
+
+// Name of a `{name}` or `:name` route path segment
+fn param(segment: &str) -> Option<&str> {
+    segment
+        .strip_prefix('{')
+        .and_then(|s| s.strip_suffix('}'))
+        .or_else(|| segment.strip_prefix(':'))
+        .filter(|name| !name.starts_with('*'))
+}
+
+// Name of a `{*name}` route path segment
+fn tail(segment: &str) -> Option<&str> {
+    segment.strip_prefix("{*").and_then(|s| s.strip_suffix('}'))
+}
+
+/// Characters of a route path outside its tail, more of them make a more
+/// specific route. A `{name}` segment counts only its slash, so it beats the
+/// same path without it but not a static segment in its place.
+fn static_len(route: &str) -> usize {
+    route
+        .split('/')
+        .filter(|segment| tail(segment).is_none())
+        .map(|segment| if param(segment).is_some() { 1 } else { segment.len() + 1 })
+        .sum::<usize>()
+        .saturating_sub(1)
+}
+
+/// Route paths of a service must have well formed parameters, a `{*name}`
+/// tail only as their last segment
+pub fn check_paths(svc: &Service) -> Result<()> {
+    for route in &svc.routes {
+        let path = &route.config.path;
+        let segments: Vec<&str> = path.split('/').collect();
+        for (i, segment) in segments.iter().enumerate() {
+            if !segment.contains(['{', '}']) && !segment.starts_with(':') {
+                continue;
+            }
+            let name = segment_param(segment);
+            let Some(name) = name.filter(|n| !n.is_empty() && !n.contains(['{', '}', '*', ':'])) else {
+                bail!("service {}: route {} path {}: invalid parameter {}", svc.id, route.id, path, segment);
+            };
+            if tail(segment).is_some() && i + 1 != segments.len() {
+                bail!("service {}: route {} path {}: {{*{}}} must be the last segment", svc.id, route.id, path, name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Drop the rules of a version scoped service copy whose upstream does not
+/// serve that version
+pub fn scope_rules(svc: &mut Service) {
+    let ids: HashSet<String> = svc.upstreams.iter().map(|u| u.id.clone()).collect();
+    svc.rules.retain(|rule| ids.contains(&rule.upstream));
+    for route in svc.routes.iter_mut() {
+        route.rules.retain(|rule| ids.contains(&rule.upstream));
+    }
+}
+
+/// Rules of a service and its routes must point at upstreams of the service
+pub fn check_rules(svc: &Service) -> Result<()> {
+    let rules = svc.rules.iter().chain(svc.routes.iter().flat_map(|r| r.rules.iter()));
+    for rule in rules {
+        if !svc.upstreams.iter().any(|u| u.id == rule.upstream) {
+            bail!("service {}: routing rule {} targets unknown upstream {}", svc.id, rule.id, rule.upstream);
+        }
+        if let Some(p) = rule.percent
+            && !(0.0..=100.0).contains(&p)
+        {
+            bail!("service {}: routing rule {} percent {} is not within 0-100", svc.id, rule.id, p);
+        }
+    }
+    Ok(())
+}
+
+/// Enabled routes of the state in matching order: the longest context path
+/// plus route path first, not counting route parameters, ties broken by
+/// context path and route order, so the match does not depend on the state
+/// map iteration order.
+#[derive(Debug, Default)]
+pub struct RouteTable {
+    entries: Vec<RouteEntry>,
+}
+
+#[derive(Debug)]
+struct RouteEntry {
+    // state key of the version scoped service
+    key: String,
+    prefix: String,
+    route: usize,
+    path: String,
+}
+
+impl RouteTable {
+    pub fn build<'a>(services: impl IntoIterator<Item = (&'a str, &'a Service)>) -> Self {
+        let mut entries: Vec<RouteEntry> = services
+            .into_iter()
+            .flat_map(|(key, svc)| {
+                svc.routes.iter().enumerate().filter(|(_, r)| r.enabled).map(move |(i, r)| RouteEntry {
+                    key: key.to_string(),
+                    prefix: key.trim_end_matches('/').to_string(),
+                    route: i,
+                    path: r.config.path.clone(),
+                })
+            })
+            .collect();
+        entries.sort_by(|a, b| {
+            (b.prefix.len() + static_len(&b.path))
+                .cmp(&(a.prefix.len() + static_len(&a.path)))
+                .then_with(|| a.key.cmp(&b.key))
+                .then_with(|| a.route.cmp(&b.route))
+        });
+        Self { entries }
+    }
+
+    /// Routes whose prefix matches `path`, best first, as the state key, the
+    /// route index in the service, the path with the context path stripped
+    /// and the route parameters
+    pub fn candidates<'a>(&'a self, path: &'a str) -> impl Iterator<Item = (&'a str, usize, &'a str, Vec<(String, String)>)> {
+        self.entries.iter().filter_map(move |e| {
+            let rest = path.strip_prefix(e.prefix.as_str())?;
+            if !rest.is_empty() && !rest.starts_with('/') {
+                return None;
+            }
+            let rest = if rest.is_empty() { "/" } else { rest };
+            let (_, params) = match_path(&e.path, rest)?;
+            Some((e.key.as_str(), e.route, rest, params))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upstream(id: &str, versions: &[&str]) -> Upstream {
+        Upstream {
+            id: id.into(),
+            enabled: true,
+            weight: 1,
+            versions: versions.iter().map(|v| v.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn rule(id: &str, upstream: &str, header: Option<(&str, Option<&str>)>, percent: Option<f64>) -> RoutingRule {
+        RoutingRule {
+            id: id.into(),
+            upstream: upstream.into(),
+            headers: header
+                .map(|(name, value)| HeaderMatch { name: name.into(), value: value.map(String::from) })
+                .into_iter()
+                .collect(),
+            percent,
+        }
+    }
+
+    fn service(rules: Vec<RoutingRule>) -> Service {
+        Service {
+            id: "svc".into(),
+            upstreams: vec![upstream("stable", &[]), upstream("beta", &[])],
+            rules,
+            ..Default::default()
+        }
+    }
+
+    fn select<'a>(svc: &'a Service, route: &Route, headers: &HeaderMap) -> Option<&'a str> {
+        select_upstream(svc, route, headers, |_| true, |c| c.first().copied()).map(|u| u.id.as_str())
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs.iter().map(|(k, v)| (http::HeaderName::from_static(k), http::HeaderValue::from_static(v))).collect()
+    }
+
+    #[test]
+    fn header_rules_select_their_upstream() {
+        let svc = service(vec![rule("beta", "beta", Some(("x-beta", Some("true"))), None)]);
+        let route = Route::default();
+        assert_eq!(select(&svc, &route, &headers(&[("x-beta", "true")])), Some("beta"));
+        assert_eq!(select(&svc, &route, &headers(&[("x-beta", "TRUE")])), Some("stable"));
+        assert_eq!(select(&svc, &route, &HeaderMap::new()), Some("stable"));
+
+        // Presence only
+        let svc = service(vec![rule("beta", "beta", Some(("x-beta", None)), None)]);
+        assert_eq!(select(&svc, &route, &headers(&[("x-beta", "anything")])), Some("beta"));
+    }
+
+    #[test]
+    fn route_rules_come_before_service_rules() {
+        let svc = service(vec![rule("svc", "stable", None, None)]);
+        let route = Route { rules: vec![rule("route", "beta", None, None)], ..Default::default() };
+        assert_eq!(select(&svc, &route, &HeaderMap::new()), Some("beta"));
+    }
+
+    #[test]
+    fn percentage_rules_take_their_share() {
+        let route = Route::default();
+        let all = service(vec![rule("all", "beta", None, Some(100.0))]);
+        let none = service(vec![rule("none", "beta", None, Some(0.0))]);
+        let half = service(vec![rule("half", "beta", None, Some(50.0))]);
+        for _ in 0..100 {
+            assert_eq!(select(&all, &route, &HeaderMap::new()), Some("beta"));
+            assert_eq!(select(&none, &route, &HeaderMap::new()), Some("stable"));
+        }
+        let beta = (0..2000).filter(|_| select(&half, &route, &HeaderMap::new()) == Some("beta")).count();
+        assert!((800..1200).contains(&beta), "{beta} of 2000");
+    }
+
+    #[test]
+    fn rules_pass_over_unhealthy_upstreams() {
+        let svc = service(vec![rule("beta", "beta", None, None)]);
+        let picked = select_upstream(&svc, &Route::default(), &HeaderMap::new(), |u| u.id != "beta", |c| c.first().copied());
+        assert_eq!(picked.map(|u| u.id.as_str()), Some("stable"));
+    }
+
+    #[test]
+    fn rules_must_target_an_upstream_of_the_service() {
+        assert!(check_rules(&service(vec![rule("beta", "beta", None, None)])).is_ok());
+        let err = check_rules(&service(vec![rule("gone", "gone", None, None)])).unwrap_err();
+        assert!(err.to_string().contains("unknown upstream gone"));
+    }
+
+    #[test]
+    fn scoping_drops_rules_of_other_versions() {
+        let mut svc = service(vec![rule("beta", "beta", None, None), rule("stable", "stable", None, None)]);
+        svc.routes = vec![Route { rules: vec![rule("route", "beta", None, None)], ..Default::default() }];
+        svc.upstreams.retain(|u| u.id == "stable");
+        scope_rules(&mut svc);
+        assert_eq!(svc.rules.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(), ["stable"]);
+        assert!(svc.routes[0].rules.is_empty());
+    }
+
+    fn routed(paths: &[&str]) -> Service {
+        Service {
+            routes: paths
+                .iter()
+                .map(|path| {
+                    let mut route = Route { id: path.to_string(), enabled: true, ..Default::default() };
+                    route.config.path = path.to_string();
+                    route
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn best<'a>(table: &'a RouteTable, path: &'a str) -> Option<(&'a str, usize, &'a str)> {
+        table.candidates(path).next().map(|(key, route, rest, _)| (key, route, rest))
+    }
+
+    #[test]
+    fn the_longest_prefix_wins_across_services_whatever_their_order() {
+        let (api, v2) = (routed(&["/"]), routed(&["/users"]));
+        for services in [vec![("/api/", &api), ("/api/v2/", &v2)], vec![("/api/v2/", &v2), ("/api/", &api)]] {
+            let table = RouteTable::build(services);
+            assert_eq!(best(&table, "/api/v2/users"), Some(("/api/v2/", 0, "/users")));
+            assert_eq!(best(&table, "/api/v2/orders"), Some(("/api/", 0, "/v2/orders")));
+            assert_eq!(best(&table, "/api"), Some(("/api/", 0, "/")));
+            // Context paths end at a segment boundary
+            assert_eq!(best(&table, "/apiv2/users"), None);
+        }
+    }
+
+    #[test]
+    fn more_specific_routes_of_a_service_win() {
+        let svc = routed(&["/users", "/users/{id}", "/users/me", "/users/admin", "/users/a"]);
+        let table = RouteTable::build([("/", &svc)]);
+        assert_eq!(best(&table, "/users/admin/keys").map(|b| b.1), Some(3));
+        assert_eq!(best(&table, "/users/me").map(|b| b.1), Some(2));
+        assert_eq!(best(&table, "/users/42").map(|b| b.1), Some(1));
+        assert_eq!(best(&table, "/users/a").map(|b| b.1), Some(4));
+        assert_eq!(best(&table, "/users").map(|b| b.1), Some(0));
+
+        let (_, _, _, params) = table.candidates("/users/42").next().unwrap();
+        assert_eq!(params, [("id".to_string(), "42".to_string())]);
+    }
+
+    #[test]
+    fn disabled_routes_are_left_out() {
+        let mut svc = routed(&["/users", "/users/admin"]);
+        svc.routes[1].enabled = false;
+        let table = RouteTable::build([("/", &svc)]);
+        assert_eq!(best(&table, "/users/admin").map(|b| b.1), Some(0));
+    }
+
+    fn based(path: &str) -> Upstream {
+        Upstream { host: "backend".into(), port: 8080, path: path.into(), ..upstream("u", &[]) }
+    }
+
+    fn url(upstream: &Upstream, route: &Route, path: &str, query: Option<&str>) -> String {
+        upstream_url(upstream, route, path, query).unwrap().to_string()
+    }
+
+    #[test]
+    fn upstream_urls_join_the_base_path_and_keep_the_query_once() {
+        let route = routed(&["/users"]).routes.remove(0);
+        assert_eq!(url(&based(""), &route, "/users", None), "http://backend:8080/users");
+        assert_eq!(url(&based("/api"), &route, "/users", Some("page=2")), "http://backend:8080/api/users?page=2");
+        assert_eq!(url(&based("/api/"), &route, "/users/7", None), "http://backend:8080/api/users/7");
+        assert_eq!(url(&based("api"), &route, "/users", None), "http://backend:8080/api/users");
+
+        let mut stripped = route.clone();
+        stripped.config.strip_path = true;
+        assert_eq!(url(&based("/api"), &stripped, "/users/7", Some("a=1")), "http://backend:8080/api/7?a=1");
+        assert_eq!(url(&based("/api"), &stripped, "/users", None), "http://backend:8080/api/");
+    }
+
+    #[test]
+    fn upstream_urls_stay_under_the_base_path() {
+        let route = routed(&["/"]).routes.remove(0);
+        assert!(upstream_url(&based("/api"), &route, "/../admin", None).is_err());
+        assert_eq!(url(&based("/api"), &route, "/a/../b", None), "http://backend:8080/api/b");
+    }
+}
@@ -0,0 +1,240 @@
+use bytes::Bytes;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+// Differences listed in one mismatch log line
+const MAX_DIFFS: usize = 10;
+
+/// Shadow traffic (`type: shadow` on a service or global policy).
+///
+/// A `sample_rate` fraction of requests is sent again to the backend at
+/// `url`, keeping the upstream path and query, once the primary upstream
+/// answered. The client only ever gets the primary response: the shadow one
+/// is compared to it and dropped. `compare.status` checks the status codes,
+/// `compare.body` is `none`, `exact` or `json`, the latter ignoring key order
+/// and the `ignore_fields` keys at any depth. Mismatches are logged and
+/// counted. Requests with a body spilled to disk, upgrades and gRPC-Web calls
+/// are not shadowed, and streamed responses are compared on status only.
+///
+/// ```yaml
+/// - id: svc-shadow
+///   type: shadow
+///   enabled: true
+///   config:
+///     url: http://users-next:8080
+///     sample_rate: 0.1
+///     timeout_ms: 5000
+///     compare:
+///       status: true
+///       body: json
+///       ignore_fields: [timestamp, request_id]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowPolicy {
+    pub url: String,
+    #[serde(default = "def_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "def_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default)]
+    pub compare: ShadowCompare,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShadowCompare {
+    #[serde(default = "def_true")]
+    pub status: bool,
+    #[serde(default)]
+    pub body: BodyCompare,
+    #[serde(default)]
+    pub ignore_fields: Vec<String>,
+}
+
+impl Default for ShadowCompare {
+    fn default() -> Self {
+        Self {
+            status: true,
+            body: BodyCompare::default(),
+            ignore_fields: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyCompare {
+    #[default]
+    None,
+    Exact,
+    /// Bodies that are not both JSON are compared exactly
+    Json,
+}
+
+fn def_sample_rate() -> f64 {
+    1.0
+}
+
+fn def_timeout_ms() -> u64 {
+    5000
+}
+
+fn def_true() -> bool {
+    true
+}
+
+impl ShadowPolicy {
+    pub const KIND: &'static str = "shadow";
+
+    pub fn sampled(&self) -> bool {
+        self.sample_rate > 0.0 && rand::random::<f64>() < self.sample_rate
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// Shadow url of an upstream url: the shadow base with the upstream path
+    /// appended and the upstream query
+    pub fn url(&self, upstream: &url::Url) -> Result<url::Url, url::ParseError> {
+        let mut url = url::Url::parse(&self.url)?;
+        let path = format!("{}{}", url.path().trim_end_matches('/'), upstream.path());
+        url.set_path(&path);
+        url.set_query(upstream.query());
+        Ok(url)
+    }
+
+    /// Differences of the shadow answer from the primary one, the primary body
+    /// is None when it was streamed
+    pub fn compare(
+        &self,
+        primary: (StatusCode, Option<&Bytes>),
+        shadow: (StatusCode, &Bytes),
+    ) -> Vec<String> {
+        let mut diffs = Vec::new();
+        if self.compare.status && primary.0 != shadow.0 {
+            diffs.push(format!("status {} != {}", primary.0.as_u16(), shadow.0.as_u16()));
+        }
+        let Some(body) = primary.1 else {
+            return diffs;
+        };
+        let json = || {
+            let a = serde_json::from_slice::<Value>(body).ok()?;
+            let b = serde_json::from_slice::<Value>(shadow.1).ok()?;
+            Some((a, b))
+        };
+        match self.compare.body {
+            BodyCompare::None => {}
+            BodyCompare::Json if let Some((a, b)) = json() => {
+                diff_json("$", &a, &b, &self.compare.ignore_fields, &mut diffs);
+            }
+            BodyCompare::Exact | BodyCompare::Json => {
+                if body != shadow.1 {
+                    diffs.push(format!("body {} bytes != {} bytes", body.len(), shadow.1.len()));
+                }
+            }
+        }
+        diffs
+    }
+}
+
+fn diff_json(path: &str, a: &Value, b: &Value, ignore: &[String], diffs: &mut Vec<String>) {
+    if diffs.len() >= MAX_DIFFS {
+        return;
+    }
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            let keys = a.keys().chain(b.keys().filter(|k| !a.contains_key(*k)));
+            for key in keys.filter(|k| !ignore.iter().any(|f| f.eq_ignore_ascii_case(k))) {
+                let at = format!("{path}.{key}");
+                match (a.get(key), b.get(key)) {
+                    (Some(a), Some(b)) => diff_json(&at, a, b, ignore, diffs),
+                    (Some(_), None) => diffs.push(format!("{at} missing")),
+                    (None, _) => diffs.push(format!("{at} added")),
+                }
+            }
+        }
+        (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+            for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                diff_json(&format!("{path}[{i}]"), a, b, ignore, diffs);
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => diffs.push(format!("{path} length {} != {}", a.len(), b.len())),
+        _ if a != b => diffs.push(format!("{path} {a} != {b}")),
+        _ => {}
+    }
+    diffs.truncate(MAX_DIFFS);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn shadow(compare: Value) -> ShadowPolicy {
+        serde_json::from_value(json!({"url": "http://next:8080/v2/", "compare": compare})).unwrap()
+    }
+
+    fn body(value: Value) -> Bytes {
+        Bytes::from(value.to_string())
+    }
+
+    #[test]
+    fn shadow_urls_keep_the_upstream_path_and_query() {
+        let upstream = url::Url::parse("http://users:80/users/1?expand=true").unwrap();
+        assert_eq!(shadow(json!({})).url(&upstream).unwrap().as_str(), "http://next:8080/v2/users/1?expand=true");
+        let bad = ShadowPolicy { url: "next".into(), ..shadow(json!({})) };
+        assert!(bad.url(&upstream).is_err());
+    }
+
+    #[test]
+    fn sampling_follows_the_rate() {
+        let always = shadow(json!({}));
+        assert_eq!((always.sample_rate, always.timeout()), (1.0, Duration::from_secs(5)));
+        assert!(always.sampled());
+        let never = ShadowPolicy { sample_rate: 0.0, ..always };
+        assert!(!never.sampled());
+    }
+
+    #[test]
+    fn json_bodies_are_compared_field_by_field() {
+        let policy = shadow(json!({"body": "json", "ignore_fields": ["At"]}));
+        let primary = body(json!({"id": 1, "tags": ["a", "b"], "user": {"name": "alice", "at": 1}, "gone": true}));
+        let next = body(json!({"user": {"at": 2, "name": "bob"}, "id": 1, "tags": ["a"], "new": null}));
+        assert_eq!(
+            policy.compare((StatusCode::OK, Some(&primary)), (StatusCode::CREATED, &next)),
+            ["status 200 != 201", "$.gone missing", "$.tags length 2 != 1", "$.user.name \"alice\" != \"bob\"", "$.new added"]
+        );
+        // Key order and ignored fields make no difference
+        let reordered = body(json!({"gone": true, "user": {"at": 9, "name": "alice"}, "tags": ["a", "b"], "id": 1}));
+        assert!(policy.compare((StatusCode::OK, Some(&primary)), (StatusCode::OK, &reordered)).is_empty());
+        // Bodies that are not both JSON are compared exactly
+        let text = Bytes::from_static(b"not json");
+        assert_eq!(policy.compare((StatusCode::OK, Some(&primary)), (StatusCode::OK, &text)), [format!(
+            "body {} bytes != 8 bytes",
+            primary.len()
+        )]);
+    }
+
+    #[test]
+    fn comparisons_can_be_turned_off() {
+        let (a, b) = (Bytes::from_static(b"{\"a\":1}"), Bytes::from_static(b"{\"a\": 1}"));
+        let exact = shadow(json!({"status": false, "body": "exact"}));
+        assert_eq!(exact.compare((StatusCode::OK, Some(&a)), (StatusCode::BAD_GATEWAY, &b)), ["body 7 bytes != 8 bytes"]);
+        assert!(shadow(json!({})).compare((StatusCode::OK, Some(&a)), (StatusCode::OK, &b)).is_empty());
+        // A streamed primary is compared on status only
+        assert!(exact.compare((StatusCode::OK, None), (StatusCode::OK, &b)).is_empty());
+        let streamed = shadow(json!({"body": "exact"})).compare((StatusCode::OK, None), (StatusCode::NOT_FOUND, &b));
+        assert_eq!(streamed, ["status 200 != 404"]);
+    }
+
+    #[test]
+    fn mismatch_lists_are_capped() {
+        let wide = |offset: u32| Value::Object((0..20).map(|i| (format!("k{i:02}"), json!(i + offset))).collect());
+        let policy = shadow(json!({"body": "json"}));
+        let diffs = policy.compare((StatusCode::OK, Some(&body(wide(0)))), (StatusCode::OK, &body(wide(1))));
+        assert_eq!(diffs.len(), MAX_DIFFS);
+        assert_eq!(diffs[0], "$.k00 0 != 1");
+    }
+}
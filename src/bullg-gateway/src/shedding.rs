@@ -0,0 +1,76 @@
+use bullg_core::{LoadSheddingCfg, PriorityClass, PriorityRule};
+use http::HeaderMap;
+
+use crate::RouteMatch;
+use crate::routing::header_matches;
+
+/// Priority class of a request: the class of the first matching rule, then
+/// the class named by the priority header, else the default class. A class
+/// missing from the config is shed at the thresholds.
+pub fn classify(cfg: &LoadSheddingCfg, m: Option<&RouteMatch>, headers: &HeaderMap) -> PriorityClass {
+    let named = |name: &str| cfg.class(name).cloned();
+    cfg.rules
+        .iter()
+        .find(|r| matches(cfg, r, m, headers))
+        .and_then(|r| named(&r.class))
+        .or_else(|| header(headers, &cfg.priority_header).and_then(named))
+        .or_else(|| named(&cfg.default_class))
+        .unwrap_or_else(|| PriorityClass {
+            name: cfg.default_class.clone(),
+            shed_at: Some(1.0),
+        })
+}
+
+fn matches(cfg: &LoadSheddingCfg, rule: &PriorityRule, m: Option<&RouteMatch>, headers: &HeaderMap) -> bool {
+    let listed = |ids: &[String], id: Option<&str>| ids.is_empty() || id.is_some_and(|id| ids.iter().any(|i| i == id));
+    listed(&rule.services, m.map(|m| m.service.id.as_str()))
+        && listed(&rule.routes, m.map(|m| m.route.id.as_str()))
+        && listed(&rule.consumers, header(headers, &cfg.consumer_header))
+        && rule.headers.iter().all(|h| header_matches(h, headers))
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok()).map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn cfg(rules: serde_json::Value) -> LoadSheddingCfg {
+        LoadSheddingCfg { rules: serde_json::from_value(rules).unwrap(), ..Default::default() }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs.iter().map(|(k, v)| (http::HeaderName::from_static(k), http::HeaderValue::from_static(v))).collect()
+    }
+
+    #[test]
+    fn rules_then_the_priority_header_then_the_default_class() {
+        let cfg = cfg(json!([
+            {"class": "high", "consumers": ["vip"]},
+            {"class": "low", "headers": [{"name": "x-batch"}]},
+        ]));
+        let class = |pairs| classify(&cfg, None, &headers(pairs)).name;
+        assert_eq!(class(&[]), "normal");
+        assert_eq!(class(&[("x-bullg-priority", "HIGH")]), "high");
+        assert_eq!(class(&[("x-consumer-id", "vip"), ("x-bullg-priority", "low")]), "high");
+        assert_eq!(class(&[("x-batch", "1"), ("x-bullg-priority", "high")]), "low");
+        // An unknown class falls back to the default one
+        assert_eq!(class(&[("x-bullg-priority", "urgent")]), "normal");
+    }
+
+    #[test]
+    fn a_class_missing_from_the_config_sheds_at_the_thresholds() {
+        let cfg = LoadSheddingCfg { default_class: "bulk".into(), ..Default::default() };
+        let class = classify(&cfg, None, &HeaderMap::new());
+        assert_eq!((class.name.as_str(), class.shed_at), ("bulk", Some(1.0)));
+    }
+
+    #[test]
+    fn route_rules_need_a_matched_route() {
+        let cfg = cfg(json!([{"class": "high", "routes": ["health"]}]));
+        assert_eq!(classify(&cfg, None, &HeaderMap::new()).name, "normal");
+    }
+}
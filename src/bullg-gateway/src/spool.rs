@@ -0,0 +1,116 @@
+use bullg_core::BodyBufferCfg;
+use bullg_plugin_api::SpilledBody;
+use bytes::{Buf, Bytes, BytesMut};
+use http_body_util::BodyExt;
+use std::fmt::Display;
+use tokio::io::AsyncWriteExt;
+use tracing::debug;
+
+/// Request body read by `buffer`
+pub enum Buffered {
+    Memory(Bytes),
+    Spilled(SpilledBody),
+}
+
+#[derive(Debug)]
+pub enum BufferError {
+    /// Above `max_size`
+    TooLarge,
+    /// The client body failed
+    Read(String),
+    /// The temp file failed
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for BufferError {
+    fn from(e: std::io::Error) -> Self {
+        BufferError::Io(e)
+    }
+}
+
+/// Read a request body, in memory up to `memory_limit` bytes, the whole
+/// body goes to a temp file once it is above
+pub async fn buffer<B>(body: B, cfg: &BodyBufferCfg) -> Result<Buffered, BufferError>
+where
+    B: hyper::body::Body,
+    B::Error: Display,
+{
+    let mut body = std::pin::pin!(body);
+    let mut mem = BytesMut::new();
+    let mut spill: Option<(tempfile::NamedTempFile, tokio::fs::File)> = None;
+    let mut len = 0u64;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|e| BufferError::Read(e.to_string()))?;
+        let Ok(mut data) = frame.into_data() else {
+            continue;
+        };
+        let chunk = data.copy_to_bytes(data.remaining());
+        len += chunk.len() as u64;
+        if cfg.max_size > 0 && len > cfg.max_size {
+            return Err(BufferError::TooLarge);
+        }
+        if let Some((_, file)) = spill.as_mut() {
+            file.write_all(&chunk).await?;
+            continue;
+        }
+        mem.extend_from_slice(&chunk);
+        if len > cfg.memory_limit {
+            let tmp = match cfg.spill_dir.as_str() {
+                "" => tempfile::Builder::new().prefix("bullg-body-").tempfile()?,
+                dir => tempfile::Builder::new().prefix("bullg-body-").tempfile_in(dir)?,
+            };
+            debug!("request body above {} bytes, spilling to {}", cfg.memory_limit, tmp.path().display());
+            let mut file = tokio::fs::File::from_std(tmp.reopen()?);
+            file.write_all(&mem).await?;
+            mem = BytesMut::new();
+            spill = Some((tmp, file));
+        }
+    }
+    match spill {
+        Some((tmp, mut file)) => {
+            file.flush().await?;
+            Ok(Buffered::Spilled(SpilledBody::new(tmp, len)))
+        }
+        None => Ok(Buffered::Memory(mem.freeze())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Full;
+    use std::io::Read;
+
+    fn cfg(memory_limit: u64, max_size: u64, spill_dir: &std::path::Path) -> BodyBufferCfg {
+        BodyBufferCfg { memory_limit, max_size, spill_dir: spill_dir.display().to_string() }
+    }
+
+    #[tokio::test]
+    async fn bodies_above_the_memory_limit_spill_to_a_removed_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let small = buffer(Full::new(Bytes::from("small")), &cfg(16, 0, dir.path())).await.unwrap();
+        assert!(matches!(small, Buffered::Memory(b) if b == "small"));
+
+        let body = "0123456789".repeat(100);
+        let Buffered::Spilled(spilled) = buffer(Full::new(Bytes::from(body.clone())), &cfg(16, 0, dir.path())).await.unwrap() else {
+            panic!("not spilled");
+        };
+        assert_eq!(spilled.len(), 1000);
+        assert!(spilled.path().starts_with(dir.path()));
+        let mut read = String::new();
+        spilled.open().unwrap().read_to_string(&mut read).unwrap();
+        assert_eq!(read, body);
+
+        let path = spilled.path().to_path_buf();
+        drop(spilled);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn bodies_above_the_max_size_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = buffer(Full::new(Bytes::from(vec![0u8; 101])), &cfg(16, 100, dir.path())).await;
+        assert!(matches!(err, Err(BufferError::TooLarge)));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::Result;
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode, header::HeaderValue};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::tokio::TokioIo;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+use crate::{Gateway, GatewayBody, simple};
+
+/// What the health endpoint reports besides the state and metrics
+pub struct Status {
+    started: Instant,
+    // Set once by the control plane sync client
+    control_plane: OnceLock<Arc<AtomicBool>>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            control_plane: OnceLock::new(),
+        }
+    }
+}
+
+/// Body of the health endpoint
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// `ok`, `draining` once shutdown started, or `unavailable` without
+    /// control plane connection nor any service to serve
+    pub status: &'static str,
+    pub services: usize,
+    /// `connected`, `disconnected` or `disabled`
+    pub control_plane: &'static str,
+    pub uptime_sec: u64,
+}
+
+impl Gateway {
+    /// Report the control plane as connected while `connected` is true, see
+    /// `SyncClient::connection`
+    pub fn track_control_plane(&self, connected: Arc<AtomicBool>) {
+        let _ = self.status.control_plane.set(connected);
+    }
+
+    pub async fn health_report(&self) -> HealthReport {
+        let services = self.template.read().await.services.len();
+        let control_plane = match self.status.control_plane.get() {
+            _ if !self.config.control_plane.enabled => "disabled",
+            Some(connected) if connected.load(Ordering::Relaxed) => "connected",
+            _ => "disconnected",
+        };
+        let status = if self.drain.draining() {
+            "draining"
+        } else if control_plane == "disconnected" && services == 0 {
+            "unavailable"
+        } else {
+            "ok"
+        };
+        HealthReport {
+            status,
+            services,
+            control_plane,
+            uptime_sec: self.status.started.elapsed().as_secs(),
+        }
+    }
+
+    /// Serve `health_check.path`, answering 200 with the health report, or
+    /// 503 unless its status is `ok`. Runs until the returned future is
+    /// dropped, serving through the drain so load balancers see it.
+    pub async fn serve_health(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("health endpoint listening on {}{}", addr, self.config.health_check.path);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let me = self.clone();
+            tokio::spawn(async move {
+                let io = TokioIo::new(stream);
+                let conn = http1::Builder::new().serve_connection(
+                    io,
+                    service_fn(move |req| {
+                        let me = me.clone();
+                        async move { Ok::<_, hyper::Error>(me.health_endpoint(req).await) }
+                    }),
+                );
+                if let Err(e) = conn.await {
+                    error!("health conn error: {e}");
+                }
+            });
+        }
+    }
+
+    /// Serve the gateway metrics at `metrics.path`, in the Prometheus text
+    /// format or as JSON with `metrics.format: json`
+    pub async fn serve_metrics(self: Arc<Self>, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("metrics listening on {}{}", addr, self.config.metrics.path);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let me = self.clone();
+            tokio::spawn(async move {
+                let io = TokioIo::new(stream);
+                let conn = http1::Builder::new().serve_connection(
+                    io,
+                    service_fn(move |req| {
+                        let me = me.clone();
+                        async move { Ok::<_, hyper::Error>(me.metrics_endpoint(req)) }
+                    }),
+                );
+                if let Err(e) = conn.await {
+                    error!("metrics conn error: {e}");
+                }
+            });
+        }
+    }
+
+    fn metrics_endpoint(&self, req: Request<Incoming>) -> Response<GatewayBody> {
+        if req.uri().path() != self.config.metrics.path {
+            return simple(StatusCode::NOT_FOUND, Bytes::from_static(b"not found"));
+        }
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return simple(StatusCode::METHOD_NOT_ALLOWED, Bytes::new());
+        }
+        let (body, content_type) = match self.config.metrics.format.as_str() {
+            "json" => (self.metrics.json().to_string(), "application/json"),
+            _ => (self.metrics.prometheus(), "text/plain; version=0.0.4"),
+        };
+        let mut resp = simple(StatusCode::OK, Bytes::from(body));
+        resp.headers_mut().insert("content-type", HeaderValue::from_static(content_type));
+        resp
+    }
+
+    async fn health_endpoint(&self, req: Request<Incoming>) -> Response<GatewayBody> {
+        if req.uri().path() != self.config.health_check.path {
+            return simple(StatusCode::NOT_FOUND, Bytes::from_static(b"not found"));
+        }
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return simple(StatusCode::METHOD_NOT_ALLOWED, Bytes::new());
+        }
+        let report = self.health_report().await;
+        let code = match report.status {
+            "ok" => StatusCode::OK,
+            _ => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        let body = serde_json::to_vec(&report).unwrap_or_default();
+        let mut resp = simple(code, Bytes::from(body));
+        resp.headers_mut().insert("content-type", HeaderValue::from_static("application/json"));
+        resp.headers_mut().insert("cache-control", HeaderValue::from_static("no-store"));
+        resp
+    }
+}
@@ -0,0 +1,259 @@
+use bytes::{Bytes, BytesMut};
+use futures_util::stream;
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::Frame;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::{Instant, timeout_at};
+use tracing::{debug, error};
+
+use crate::{BoxError, GatewayBody};
+
+/// Response streaming policy (`type: streaming` on a service or global policy).
+///
+/// Matching responses are forwarded as they arrive instead of being buffered.
+/// Bytes are flushed once `chunk_size` is buffered or the oldest buffered
+/// byte waited `flush_interval_ms`. Server-sent events also flush at every
+/// event boundary so they reach the client immediately.
+///
+/// When the upstream fails after the headers went out, a server-sent event
+/// stream ends with an `event: error` event. Other bodies end with an
+/// `x-bullg-stream-error` trailer when the client sent `TE: trailers`, or
+/// else the chunked body is left unterminated so the client sees a truncated
+/// transfer instead of a clean end. `error_event: false` only aborts.
+///
+/// ```yaml
+/// - id: svc-streaming
+///   type: streaming
+///   enabled: true
+///   config:
+///     chunk_size: 65536
+///     flush_interval_ms: 50
+///     content_types: [text/event-stream, application/octet-stream] # empty streams every response
+///     error_event: true
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamPolicy {
+    #[serde(default = "def_chunk_size")]
+    pub chunk_size: usize,
+    #[serde(default = "def_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+    #[serde(default = "def_content_types")]
+    pub content_types: Vec<String>,
+    #[serde(default = "def_error_event")]
+    pub error_event: bool,
+}
+
+fn def_chunk_size() -> usize {
+    16 * 1024
+}
+
+fn def_flush_interval_ms() -> u64 {
+    100
+}
+
+fn def_content_types() -> Vec<String> {
+    vec![SSE.to_string()]
+}
+
+fn def_error_event() -> bool {
+    true
+}
+
+const SSE: &str = "text/event-stream";
+
+/// Trailer carrying the upstream error of an interrupted streamed body
+pub const STREAM_ERROR_TRAILER: &str = "x-bullg-stream-error";
+
+impl Default for StreamPolicy {
+    fn default() -> Self {
+        Self {
+            chunk_size: def_chunk_size(),
+            flush_interval_ms: def_flush_interval_ms(),
+            content_types: def_content_types(),
+            error_event: def_error_event(),
+        }
+    }
+}
+
+impl StreamPolicy {
+    pub const KIND: &'static str = "streaming";
+
+    /// Whether a response with `content_type` is streamed
+    pub fn applies(&self, content_type: &str) -> bool {
+        let mime = essence(content_type);
+        self.content_types.is_empty()
+            || self.content_types.iter().any(|c| c.eq_ignore_ascii_case(mime))
+    }
+
+    /// How an upstream failure is reported for this response, `trailers` is
+    /// whether the client accepts trailers
+    pub fn signal(&self, resp: &reqwest::Response, trailers: bool) -> ErrorSignal {
+        let sse = resp
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|c| essence(c).eq_ignore_ascii_case(SSE));
+        match (self.error_event, sse, trailers) {
+            (false, _, _) => ErrorSignal::Abort,
+            (true, true, _) => ErrorSignal::Event,
+            (true, false, true) => ErrorSignal::Trailer,
+            (true, false, false) => ErrorSignal::Abort,
+        }
+    }
+
+    /// Stream the upstream body through the chunk buffer, `hold` is dropped
+    /// once the upstream body is finished. An upstream sending nothing for
+    /// `read_timeout` fails the stream.
+    pub fn body<H: Send + 'static>(
+        &self,
+        resp: reqwest::Response,
+        signal: ErrorSignal,
+        read_timeout: Option<Duration>,
+        hold: H,
+    ) -> GatewayBody {
+        let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, BoxError>>(4);
+        let policy = self.clone();
+        tokio::spawn(async move {
+            pump(resp, tx, policy, signal, read_timeout).await;
+            drop(hold);
+        });
+        let frames = stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|f| (f, rx)) });
+        StreamBody::new(frames).boxed()
+    }
+}
+
+/// Whether the request headers accept trailers (`TE: trailers`)
+pub fn accepts_trailers(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all(http::header::TE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// How a failure after the response headers is reported to the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorSignal {
+    /// Terminal `event: error` server-sent event
+    Event,
+    /// `x-bullg-stream-error` trailer
+    Trailer,
+    /// Connection closed without terminating the body
+    Abort,
+}
+
+/// MIME type without parameters, `text/event-stream; charset=utf-8` -> `text/event-stream`
+fn essence(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+async fn pump(
+    mut resp: reqwest::Response,
+    tx: mpsc::Sender<Result<Frame<Bytes>, BoxError>>,
+    policy: StreamPolicy,
+    signal: ErrorSignal,
+    read_timeout: Option<Duration>,
+) {
+    let sse = signal == ErrorSignal::Event;
+    let chunk_size = policy.chunk_size.max(1);
+    let interval = Duration::from_millis(policy.flush_interval_ms);
+    let mut buf = BytesMut::with_capacity(chunk_size);
+    let mut deadline: Option<Instant> = None;
+    let mut stalled_at = read_timeout.map(|t| Instant::now() + t);
+
+    loop {
+        let next = match deadline.into_iter().chain(stalled_at).min() {
+            Some(at) => match timeout_at(at, resp.chunk()).await {
+                Ok(next) => next,
+                Err(_) if deadline.is_some_and(|d| d <= at) => {
+                    // Flush interval elapsed while waiting for more bytes
+                    if !flush(&tx, &mut buf).await {
+                        return;
+                    }
+                    deadline = None;
+                    continue;
+                }
+                Err(_) => {
+                    let idle = read_timeout.unwrap_or_default();
+                    error!("upstream stream stalled for {}ms", idle.as_millis());
+                    fail(&tx, &mut buf, signal, "upstream stream stalled".into()).await;
+                    return;
+                }
+            },
+            None => resp.chunk().await,
+        };
+        match next {
+            Ok(Some(chunk)) => {
+                stalled_at = read_timeout.map(|t| Instant::now() + t);
+                buf.extend_from_slice(&chunk);
+                while buf.len() >= chunk_size {
+                    let part = buf.split_to(chunk_size).freeze();
+                    if tx.send(Ok(Frame::data(part))).await.is_err() {
+                        return;
+                    }
+                }
+                if buf.is_empty() {
+                    deadline = None;
+                } else if interval.is_zero() || (sse && ends_event(&buf)) {
+                    if !flush(&tx, &mut buf).await {
+                        return;
+                    }
+                    deadline = None;
+                } else if deadline.is_none() {
+                    deadline = Some(Instant::now() + interval);
+                }
+            }
+            Ok(None) => {
+                flush(&tx, &mut buf).await;
+                debug!("upstream stream finished");
+                return;
+            }
+            Err(e) => {
+                error!("upstream stream error: {e}");
+                fail(&tx, &mut buf, signal, e.into()).await;
+                return;
+            }
+        }
+    }
+}
+
+/// Send what is buffered then end the stream the way `signal` reports errors
+async fn fail(tx: &mpsc::Sender<Result<Frame<Bytes>, BoxError>>, buf: &mut BytesMut, signal: ErrorSignal, e: BoxError) {
+    if !flush(tx, buf).await {
+        return;
+    }
+    let last = match signal {
+        ErrorSignal::Event => Ok(Frame::data(Bytes::from_static(ERROR_EVENT))),
+        ErrorSignal::Trailer => Ok(Frame::trailers(error_trailer())),
+        ErrorSignal::Abort => Err(e),
+    };
+    let _ = tx.send(last).await;
+}
+
+/// Send whatever is buffered, false once the client went away
+async fn flush(tx: &mpsc::Sender<Result<Frame<Bytes>, BoxError>>, buf: &mut BytesMut) -> bool {
+    if buf.is_empty() {
+        return true;
+    }
+    tx.send(Ok(Frame::data(buf.split().freeze()))).await.is_ok()
+}
+
+/// Terminal SSE event, the leading blank line ends an event the upstream
+/// stopped in the middle of and is a no-op otherwise
+const ERROR_EVENT: &[u8] = b"\nevent: error\ndata: {\"error\":\"upstream stream interrupted\"}\n\n";
+
+fn error_trailer() -> http::HeaderMap {
+    let mut trailers = http::HeaderMap::new();
+    trailers.insert(
+        STREAM_ERROR_TRAILER,
+        http::HeaderValue::from_static("upstream stream interrupted"),
+    );
+    trailers
+}
+
+fn ends_event(buf: &[u8]) -> bool {
+    buf.ends_with(b"\n\n") || buf.ends_with(b"\r\n\r\n") || buf.ends_with(b"\r\r")
+}
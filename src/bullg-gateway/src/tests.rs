@@ -0,0 +1,1741 @@
+use super::*;
+use crate::balance::LoadBalancePolicy;
+use crate::mock::{MockUpstream, Recorded};
+use crate::client::ClientPolicy;
+use crate::concurrency::ConcurrencyPolicy;
+use crate::debug::PLUGIN_TRACE_HEADER;
+use crate::failure::FailurePolicy;
+use crate::grpcweb::GrpcWebPolicy;
+use crate::health::OutlierPolicy;
+use crate::retry::RetryPolicy;
+use crate::shadow::ShadowPolicy;
+use crate::stream::{STREAM_ERROR_TRAILER, StreamPolicy};
+use crate::throttle::UpstreamRatePolicy;
+use crate::timeout::TimeoutPolicy;
+use bullg_core::{AppliedPolicy, GlobalApplied, Upstream};
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+fn gateway() -> Gateway {
+    Gateway::new(GatewayNode::default(), Memory::memory())
+}
+
+fn policy(kind: &str, config: serde_json::Value) -> AppliedPolicy {
+    AppliedPolicy { id: kind.into(), r#type: kind.into(), enabled: true, config: Some(config), ..Default::default() }
+}
+
+fn request(method: Method, path: &str) -> Request<Full<Bytes>> {
+    Request::builder().method(method).uri(path).body(Full::new(Bytes::new())).unwrap()
+}
+
+async fn send(gw: &Gateway, req: Request<Full<Bytes>>) -> (StatusCode, HeaderMap, Bytes) {
+    let resp = gw.handle_request(req).await;
+    let (parts, body) = resp.into_parts();
+    (parts.status, parts.headers, body.collect().await.map(|b| b.to_bytes()).unwrap_or_default())
+}
+
+/// Gateway proxying `/api/users` to `up` with the given service policies
+async fn proxied(up: &MockUpstream, policies: Vec<AppliedPolicy>) -> Gateway {
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.policies = policies;
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    gw
+}
+
+fn status(code: u16) -> Response<Bytes> {
+    let mut resp = Response::new(Bytes::from(code.to_string()));
+    *resp.status_mut() = StatusCode::from_u16(code).unwrap();
+    resp
+}
+
+/// Handler answering `first` to the first `times` requests, 200 after
+fn failing(first: u16, times: usize) -> impl Fn(&Recorded) -> Response<Bytes> + Send + Sync + 'static {
+    let seen = AtomicUsize::new(0);
+    move |_| if seen.fetch_add(1, Ordering::SeqCst) < times { status(first) } else { status(200) }
+}
+
+fn retry(config: serde_json::Value) -> AppliedPolicy {
+    let mut config = config;
+    // No jitter or waiting in tests
+    config["backoff"] = json!({"base_delay": "1ms", "max_delay": "1ms", "jitter": false});
+    policy(RetryPolicy::KIND, config)
+}
+
+#[tokio::test]
+async fn requests_are_proxied_to_the_mock_upstream_and_back() {
+    let up = MockUpstream::start(|r| {
+        let mut resp = Response::new(Bytes::from(format!("created from {}", String::from_utf8_lossy(&r.body))));
+        *resp.status_mut() = StatusCode::CREATED;
+        resp.headers_mut().insert("x-upstream", HeaderValue::from_static("users"));
+        resp
+    })
+    .await
+    .unwrap();
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("/api/users?notify=true")
+        .header("x-client", "test")
+        .body(Full::new(Bytes::from("alice")))
+        .unwrap();
+    let (status, headers, body) = send(&gw, req).await;
+    assert_eq!((status, body), (StatusCode::CREATED, Bytes::from("created from alice")));
+    assert_eq!(headers["x-upstream"], "users");
+
+    let seen = up.requests();
+    assert_eq!(seen.len(), 1);
+    assert_eq!((&seen[0].method, seen[0].uri.to_string()), (&Method::POST, "/users?notify=true".to_string()));
+    assert_eq!(seen[0].headers["x-client"], "test");
+    assert_eq!(seen[0].body, "alice");
+}
+
+#[tokio::test]
+async fn unrouted_requests_and_unreachable_upstreams_are_answered_by_the_gateway() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    // Nothing listens there once the mock is dropped
+    let closed = MockUpstream::start(|_| status(200)).await.unwrap().addr().port();
+    let mut svc = up.service("/api/", "/users");
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/orders")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert!(up.requests().is_empty());
+
+    svc.upstreams[0].port = closed;
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn retries_a_503_then_answers_the_200() {
+    let up = MockUpstream::start(failing(503, 1)).await.unwrap();
+    let gw = proxied(&up, vec![retry(json!({"retry_on_status": {"max_retries": 2}}))]).await;
+
+    let (status, headers, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "200");
+    assert_eq!(headers[RETRY_COUNT_HEADER], "1");
+    assert_eq!(up.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn retries_connection_errors_up_to_the_cap() {
+    // A port nothing listens on once the mock is gone
+    let closed = MockUpstream::start(|_| status(200)).await.unwrap();
+    let svc = closed.service("/api/", "/users");
+    drop(closed);
+    tokio::task::yield_now().await;
+
+    let gw = gateway();
+    let mut svc = svc;
+    svc.policies = vec![retry(json!({"retry_on_connect_error": {"max_retries": 2}}))];
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    // Connect retries are safe for every method
+    let (status, headers, _) = send(&gw, request(Method::POST, "/api/users")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(headers[RETRY_COUNT_HEADER], "2");
+}
+
+#[tokio::test]
+async fn post_does_not_retry_a_500_by_default() {
+    let up = MockUpstream::start(failing(500, 1)).await.unwrap();
+    let gw = proxied(&up, vec![retry(json!({"retry_on_status": {"max_retries": 2, "statuses": [500]}}))]).await;
+
+    let (status, headers, _) = send(&gw, request(Method::POST, "/api/users")).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(headers.get(RETRY_COUNT_HEADER).is_none());
+    assert_eq!(up.requests().len(), 1);
+
+    // Idempotent methods with the same policy are retried
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn rejects_a_state_with_an_invalid_policy_and_keeps_the_old_one() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = proxied(&up, vec![]).await;
+
+    let mut svc = up.service("/api/", "/users");
+    svc.policies = vec![policy(RetryPolicy::KIND, json!({"retry_on_status": {"max_retries": "many"}}))];
+    let err = gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await;
+    assert!(err.unwrap_err().to_string().contains("retry"));
+
+    // Also when disabled, enabling it later would break the state
+    svc.policies[0].enabled = false;
+    assert!(gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.is_err());
+
+    let mut global = ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() };
+    global.global.policies = vec![policy(TimeoutPolicy::KIND, json!({"total": 5}))];
+    assert!(gw.update_state(global).await.is_err());
+
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn service_policies_take_precedence_over_global_ones() {
+    let up = MockUpstream::start(failing(503, 1)).await.unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.policies = vec![retry(json!({"retry_on_status": {"max_retries": 0}}))];
+    let mut state = ServicesTemplate { services: vec![svc], ..Default::default() };
+    state.global.policies = vec![retry(json!({"retry_on_status": {"max_retries": 3}}))];
+    gw.update_state(state).await.unwrap();
+
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}
+
+#[tokio::test]
+async fn a_global_policy_delta_applies_to_untouched_services() {
+    let up = MockUpstream::start(failing(503, 1)).await.unwrap();
+    let gw = proxied(&up, vec![]).await;
+
+    let global = GlobalApplied {
+        policies: vec![retry(json!({"retry_on_status": {"max_retries": 1}}))],
+        ..Default::default()
+    };
+    gw.apply_delta(StateDelta { global: Some(global), ..Default::default() }).await.unwrap();
+
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn rules_may_target_the_upstream_of_another_version() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut svc = up.service("/api/", "/users");
+    let mut v2 = up.upstream();
+    v2.id = "v2".into();
+    v2.versions = vec!["v2".into()];
+    svc.upstreams[0].versions = vec!["v1".into()];
+    svc.upstreams.push(v2);
+    svc.versions = ["v1", "v2"].map(|v| bullg_core::ServiceVersion { id: v.into(), enabled: true, ..Default::default() }).to_vec();
+    svc.rules = vec![bullg_core::RoutingRule { id: "beta".into(), upstream: "v2".into(), ..Default::default() }];
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+
+    svc.rules[0].upstream = "gone".into();
+    assert!(gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.is_err());
+}
+
+#[tokio::test]
+async fn the_example_services_are_accepted() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../examples/services.yaml");
+    let services: ServicesTemplate = bullg_core::try_read_file(path).unwrap();
+    gateway().update_state(services).await.unwrap();
+}
+
+fn plugin(kind: &str, config: serde_json::Value) -> AppliedPlugin {
+    AppliedPlugin { id: kind.into(), r#type: kind.into(), enabled: true, config: Some(config), ..Default::default() }
+}
+
+#[tokio::test]
+async fn plugin_config_updates_survive_later_deltas() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![plugin("request_termination", json!({"enabled": false}))];
+    let mut state = ServicesTemplate { services: vec![svc], ..Default::default() };
+    state.global.plugins = vec![plugin("cors", json!({}))];
+    gw.update_state(state).await.unwrap();
+
+    let scope = PluginScope::Service("mock".into());
+    gw.update_plugin_config(&scope, "request_termination", json!({"enabled": true, "status": 418}))
+        .await
+        .unwrap();
+    assert!(gw.update_plugin_config(&scope, "request_termination", json!({"status": 1000})).await.is_err());
+    assert!(gw.update_plugin_config(&scope, "missing", json!({})).await.is_err());
+    gw.update_plugin_config(&PluginScope::Global, "cors", json!({"max_age": 60})).await.unwrap();
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::IM_A_TEAPOT);
+
+    // A delta re-preparing every service starts from the updated template
+    let global = gw.template.read().await.global.clone();
+    assert_eq!(global.plugins[0].config, Some(json!({"max_age": 60})));
+    gw.apply_delta(StateDelta { global: Some(global), ..Default::default() }).await.unwrap();
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::IM_A_TEAPOT);
+    assert_eq!(gw.global_plugins.read().await[0].config, Some(json!({"max_age": 60})));
+}
+
+#[tokio::test]
+async fn custom_catalog_plugins_run_in_the_plugin_chain() {
+    use bullg_core::{CatalogPlugins, CustomPluginSpec, HandlerDecl, PluginsCatalog};
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let spec = CustomPluginSpec {
+        id: "gate".into(),
+        enabled: true,
+        handler: HandlerDecl {
+            name: "gate".into(),
+            language: "rhai".into(),
+            code: r#"fn gate(ctx) { if ctx.query != "open" { #{ status: 403 } } }"#.into(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let catalog = PluginsCatalog {
+        plugins: CatalogPlugins { custom: Some(vec![spec]), ..Default::default() },
+        ..Default::default()
+    };
+    let gw = gateway().with_catalog(&catalog).unwrap();
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![plugin("gate", json!({}))];
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(send(&gw, request(Method::GET, "/api/users?open")).await.0, StatusCode::OK);
+    assert_eq!(up.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn plugins_needing_the_route_cannot_be_global() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut state = ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() };
+    state.global.plugins = vec![plugin("proxy_cache", json!({}))];
+    let err = gateway().update_state(state).await.unwrap_err();
+    assert!(err.to_string().contains("cannot be applied globally"));
+}
+
+#[tokio::test]
+async fn proxy_cache_answers_repeated_requests_of_a_route() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![plugin("proxy_cache", json!({"ttl_sec": 60}))];
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    let (_, headers, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(headers["x-cache"], "MISS");
+    let (status, headers, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!((status, body), (StatusCode::OK, Bytes::from("200")));
+    assert_eq!(headers["x-cache"], "HIT");
+    assert_eq!(up.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn routes_requiring_auth_reject_anonymous_requests() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.require_auth = true;
+    svc.plugins = vec![plugin("basic_auth", json!({"user": "alice", "pass": "s3cret"}))];
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+
+    let mut authed = request(Method::GET, "/api/users");
+    authed.headers_mut().insert("authorization", HeaderValue::from_static("Basic YWxpY2U6czNjcmV0"));
+    assert_eq!(send(&gw, authed).await.0, StatusCode::OK);
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::UNAUTHORIZED);
+
+    // Without any auth plugin nothing identifies the consumer
+    svc.plugins.clear();
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::UNAUTHORIZED);
+
+    svc.routes[0].require_auth = Some(false);
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::OK);
+    assert_eq!(up.requests().len(), 2);
+}
+
+#[tokio::test]
+async fn key_auth_follows_consumer_key_rotations() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![plugin("key_auth", json!({}))];
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let consumers = |key: &str| -> ConsumersTemplate {
+        serde_json::from_value(json!({"consumers": [{"id": "alice", "authentication": {"api_key": [{"key": key}]}}]}))
+            .unwrap()
+    };
+    let with_key = |key: &'static str| {
+        let mut req = request(Method::GET, "/api/users");
+        req.headers_mut().insert("apikey", HeaderValue::from_static(key));
+        req
+    };
+
+    gw.update_consumers(consumers("old-key")).await.unwrap();
+    assert_eq!(send(&gw, with_key("old-key")).await.0, StatusCode::OK);
+    gw.update_consumers(consumers("new-key")).await.unwrap();
+    assert_eq!(send(&gw, with_key("old-key")).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(send(&gw, with_key("new-key")).await.0, StatusCode::OK);
+    assert!(gw.store.get::<Vec<String>>(CONSUMER_INDEX_DB, "key:old-key").unwrap().is_none());
+}
+
+/// Raw chunked response of `content_type`, each chunk written after its delay in ms
+fn chunked(content_type: &str, chunks: &[(u64, &str)]) -> Vec<(Duration, Bytes)> {
+    let head = format!("HTTP/1.1 200 OK\r\ncontent-type: {content_type}\r\ntransfer-encoding: chunked\r\n\r\n");
+    let mut parts = vec![(Duration::ZERO, Bytes::from(head))];
+    for (delay, chunk) in chunks {
+        parts.push((Duration::from_millis(*delay), Bytes::from(format!("{:x}\r\n{chunk}\r\n", chunk.len()))));
+    }
+    parts.push((Duration::ZERO, Bytes::from_static(b"0\r\n\r\n")));
+    parts
+}
+
+/// Data frames of a response body with when they arrived since `started`
+async fn frames(resp: Response<GatewayBody>, started: Instant) -> Vec<(Duration, Bytes)> {
+    let mut body = resp.into_body();
+    let mut frames = Vec::new();
+    while let Some(Ok(frame)) = body.frame().await {
+        if let Ok(data) = frame.into_data() {
+            frames.push((started.elapsed(), data));
+        }
+    }
+    frames
+}
+
+#[tokio::test]
+async fn server_sent_events_flush_at_each_event() {
+    let up = MockUpstream::raw(chunked("text/event-stream", &[(0, "data: a\n\n"), (400, "data: b\n\n")])).await.unwrap();
+    let streaming = policy(StreamPolicy::KIND, json!({"chunk_size": 65536, "flush_interval_ms": 10000}));
+    let gw = proxied(&up, vec![streaming]).await;
+
+    let started = Instant::now();
+    let frames = frames(gw.handle_request(request(Method::GET, "/api/users")).await, started).await;
+    assert_eq!(frames.iter().map(|(_, f)| f.clone()).collect::<Vec<_>>(), ["data: a\n\n", "data: b\n\n"]);
+    assert!(frames[0].0 < Duration::from_millis(300), "first event after {:?}", frames[0].0);
+}
+
+#[tokio::test]
+async fn large_bodies_stream_in_chunks_of_the_configured_size() {
+    let body = "x".repeat(5000);
+    let up = MockUpstream::raw(chunked("application/octet-stream", &[(0, &body)])).await.unwrap();
+    let streaming = policy(StreamPolicy::KIND, json!({"chunk_size": 1024, "content_types": []}));
+    let gw = proxied(&up, vec![streaming]).await;
+
+    let frames = frames(gw.handle_request(request(Method::GET, "/api/users")).await, Instant::now()).await;
+    let sizes: Vec<usize> = frames.iter().map(|(_, f)| f.len()).collect();
+    assert_eq!(sizes, [1024, 1024, 1024, 1024, 904]);
+}
+
+#[tokio::test]
+async fn partial_chunks_flush_after_the_interval() {
+    let up = MockUpstream::raw(chunked("application/octet-stream", &[(0, "head"), (600, "tail")])).await.unwrap();
+    let streaming = policy(StreamPolicy::KIND, json!({"chunk_size": 1024, "flush_interval_ms": 50, "content_types": []}));
+    let gw = proxied(&up, vec![streaming]).await;
+
+    let started = Instant::now();
+    let frames = frames(gw.handle_request(request(Method::GET, "/api/users")).await, started).await;
+    assert_eq!(frames[0].1, "head");
+    assert!(frames[0].0 < Duration::from_millis(400), "partial chunk after {:?}", frames[0].0);
+}
+
+#[tokio::test]
+async fn timing_runs_in_the_pre_and_post_phases() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![plugin("timing", json!({}))];
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+
+    let (_, headers, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert!(headers["x-response-time"].to_str().unwrap().ends_with("ms"));
+
+    // Pinned to a phase it does not run in
+    svc.plugins[0].phase = Some("intermediate".into());
+    let err = gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await;
+    assert!(err.unwrap_err().to_string().contains("does not run in the Intermediate phase"));
+    svc.plugins[0].phase = Some("someday".into());
+    assert!(gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.is_err());
+}
+
+#[tokio::test]
+async fn requests_over_the_upstream_concurrency_limit_are_shed() {
+    let parts = vec![(Duration::from_millis(300), Bytes::from_static(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok"))];
+    let up = MockUpstream::raw(parts).await.unwrap();
+    let limit = policy(ConcurrencyPolicy::KIND, json!({"max_concurrent_requests": 1}));
+    let gw = Arc::new(proxied(&up, vec![limit]).await);
+
+    let slow = tokio::spawn({
+        let gw = gw.clone();
+        async move { send(&gw, request(Method::GET, "/api/users")).await.0 }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (status, _, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body, "Too Many Requests");
+    assert_eq!(slow.await.unwrap(), StatusCode::OK);
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::OK);
+}
+
+/// Serve `gw` on a loopback port until the returned sender fires or is dropped
+async fn serving(gw: Gateway) -> (SocketAddr, tokio::sync::oneshot::Sender<()>, tokio::task::JoinHandle<Result<()>>) {
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(Arc::new(gw).serve_with_shutdown(addr, async move {
+        let _ = stopped.await;
+    }));
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    (addr, stop, server)
+}
+
+/// Read from `stream` until `end` was received
+async fn read_until(stream: &mut tokio::net::TcpStream, end: &[u8]) -> Vec<u8> {
+    use tokio::io::AsyncReadExt;
+    let mut read = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !read.windows(end.len()).any(|w| w == end) {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => read.extend_from_slice(&buf[..n]),
+        }
+    }
+    read
+}
+
+#[tokio::test]
+async fn custom_upgrades_are_tunnelled_end_to_end() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    // Upstream switching to a protocol answering each line upper cased
+    let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let head = read_until(&mut stream, b"\r\n\r\n").await;
+        assert!(String::from_utf8_lossy(&head).to_ascii_lowercase().contains("upgrade: shout"));
+        stream.write_all(b"HTTP/1.1 101 Switching Protocols\r\nconnection: upgrade\r\nupgrade: shout\r\n\r\n").await.unwrap();
+        let mut buf = [0u8; 64];
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 || stream.write_all(&buf[..n].to_ascii_uppercase()).await.is_err() {
+                break;
+            }
+        }
+    });
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut svc = up.service("/api/", "/users");
+    svc.upstreams[0].port = echo_port;
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let (addr, _stop, _server) = serving(gw).await;
+
+    let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(b"GET /api/users HTTP/1.1\r\nhost: gw\r\nconnection: upgrade\r\nupgrade: shout\r\n\r\n")
+        .await
+        .unwrap();
+    let head = read_until(&mut client, b"\r\n\r\n").await;
+    assert!(head.starts_with(b"HTTP/1.1 101"), "{}", String::from_utf8_lossy(&head));
+    for line in ["hello\n", "again\n"] {
+        client.write_all(line.as_bytes()).await.unwrap();
+        let echoed = read_until(&mut client, b"\n").await;
+        assert_eq!(echoed, line.to_ascii_uppercase().as_bytes());
+    }
+}
+
+#[tokio::test]
+async fn flagged_requests_are_captured_redacted() {
+    let up = MockUpstream::start(|_| {
+        let mut resp = Response::new(Bytes::from(r#"{"token":"t0ken","ok":true}"#));
+        resp.headers_mut().insert("set-cookie", "session=1".parse().unwrap());
+        resp
+    })
+    .await
+    .unwrap();
+    let capture = json!({"header": "x-bullg-capture", "redact_fields": ["password", "token"]});
+    let gw = proxied(&up, vec![policy(CapturePolicy::KIND, capture)]).await;
+
+    send(&gw, request(Method::POST, "/api/users")).await;
+    assert!(gw.captures.list().is_empty());
+
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("/api/users")
+        .header("x-bullg-capture", "1")
+        .header("authorization", "Basic YTpi")
+        .body(Full::new(Bytes::from(r#"{"name":"ann","password":"hunter2"}"#)))
+        .unwrap();
+    let (status, _, body) = send(&gw, req).await;
+    assert_eq!(status, StatusCode::OK);
+    // The client gets the unredacted response
+    assert!(String::from_utf8_lossy(&body).contains("t0ken"));
+
+    let captures = gw.captures.list();
+    assert_eq!(captures.len(), 1);
+    let c = &captures[0];
+    assert_eq!((c.method.as_str(), c.status), ("POST", 200));
+    assert_eq!(c.request_headers["authorization"], "[REDACTED]");
+    assert!(c.request_body.contains("ann") && !c.request_body.contains("hunter2"));
+    assert_eq!(c.response_headers["set-cookie"], "[REDACTED]");
+    let response = c.response_body.as_deref().unwrap();
+    assert!(response.contains("\"ok\":true") && !response.contains("t0ken"));
+}
+
+/// Chunked response whose upstream drops the connection inside its second chunk
+fn dropped(content_type: &str, first: &str) -> Vec<(Duration, Bytes)> {
+    let mut parts = chunked(content_type, &[(0, first)]);
+    parts.pop();
+    parts.push((Duration::from_millis(50), Bytes::from_static(b"40\r\ncut short")));
+    parts
+}
+
+#[tokio::test]
+async fn interrupted_event_streams_end_with_an_error_event() {
+    let up = MockUpstream::raw(dropped("text/event-stream", "data: a\n\n")).await.unwrap();
+    let gw = proxied(&up, vec![policy(StreamPolicy::KIND, json!({"flush_interval_ms": 0}))]).await;
+
+    let frames = frames(gw.handle_request(request(Method::GET, "/api/users")).await, Instant::now()).await;
+    let body: Vec<u8> = frames.iter().flat_map(|(_, f)| f.to_vec()).collect();
+    let body = String::from_utf8(body).unwrap();
+    assert!(body.starts_with("data: a\n\n"), "{body}");
+    assert!(body.ends_with("\nevent: error\ndata: {\"error\":\"upstream stream interrupted\"}\n\n"), "{body}");
+}
+
+#[tokio::test]
+async fn interrupted_bodies_end_with_a_trailer_or_an_error() {
+    let up = MockUpstream::raw(dropped("application/octet-stream", "head")).await.unwrap();
+    let streaming = policy(StreamPolicy::KIND, json!({"flush_interval_ms": 0, "content_types": []}));
+    let gw = proxied(&up, vec![streaming]).await;
+
+    let req = Request::builder().uri("/api/users").header("te", "trailers").body(Full::new(Bytes::new())).unwrap();
+    let mut body = gw.handle_request(req).await.into_body();
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        if let Ok(t) = frame.unwrap().into_trailers() {
+            trailers = Some(t);
+        }
+    }
+    assert_eq!(trailers.unwrap()[STREAM_ERROR_TRAILER], "upstream stream interrupted");
+
+    // Without trailers the body fails rather than ending cleanly
+    let mut body = gw.handle_request(request(Method::GET, "/api/users")).await.into_body();
+    let mut failed = false;
+    while let Some(frame) = body.frame().await {
+        failed |= frame.is_err();
+    }
+    assert!(failed);
+}
+
+#[tokio::test]
+async fn globally_denied_methods_are_rejected_before_routing() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.methods.deny = vec!["trace".into(), "PATCH".into()];
+    let gw = Gateway::new(node, Memory::memory());
+    // The route accepts every method
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+
+    for method in [Method::TRACE, Method::PATCH] {
+        let (status, headers, _) = send(&gw, request(method, "/api/users")).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers[http::header::ALLOW], "GET, HEAD, POST, PUT, DELETE, CONNECT, OPTIONS");
+    }
+    assert!(up.requests().is_empty());
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // An allow list permits only the listed methods, minus denied ones
+    let mut node = GatewayNode::default();
+    node.methods.allow = vec!["get".into(), "post".into()];
+    node.methods.deny = vec!["POST".into()];
+    let gw = Gateway::new(node, Memory::memory());
+    let (status, headers, _) = send(&gw, request(Method::DELETE, "/api/users")).await;
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(headers[http::header::ALLOW], "GET");
+}
+
+#[tokio::test]
+async fn the_upstream_gets_the_request_id_of_the_response() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = proxied(&up, vec![]).await;
+
+    let (_, headers, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    let id = headers["x-request-id"].to_str().unwrap();
+    assert!(Uuid::parse_str(id).is_ok(), "{id}");
+    assert_eq!(up.requests()[0].headers["x-request-id"], id);
+
+    // An inbound id is reused
+    let req = Request::builder().uri("/api/users").header("x-request-id", "abc-123").body(Full::new(Bytes::new())).unwrap();
+    let (_, headers, _) = send(&gw, req).await;
+    assert_eq!(headers["x-request-id"], "abc-123");
+    assert_eq!(up.requests()[1].headers["x-request-id"], "abc-123");
+}
+
+#[tokio::test]
+async fn the_request_id_header_is_configurable() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.request_id.header = "x-correlation-id".into();
+    node.request_id.trust_inbound = false;
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+
+    let req = Request::builder().uri("/api/users").header("x-correlation-id", "spoofed").body(Full::new(Bytes::new())).unwrap();
+    let (_, headers, _) = send(&gw, req).await;
+    let id = &headers["x-correlation-id"];
+    assert_ne!(id, "spoofed");
+    assert!(headers.get("x-request-id").is_none());
+    assert_eq!(&up.requests()[0].headers["x-correlation-id"], id);
+}
+
+#[tokio::test]
+async fn oversized_states_are_rejected_and_the_old_one_kept() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.state_limits.max_services = 2;
+    node.state_limits.max_routes_per_service = 2;
+    node.state_limits.max_plugins = 1;
+    let gw = Gateway::new(node, Memory::memory());
+    let service = |id: &str| {
+        let mut svc = up.service(&format!("/{id}/"), "/users");
+        svc.id = id.into();
+        svc
+    };
+    gw.update_state(ServicesTemplate { services: vec![service("api")], ..Default::default() }).await.unwrap();
+
+    let many = ServicesTemplate { services: vec![service("a"), service("b"), service("c")], ..Default::default() };
+    let err = gw.update_state(many).await.unwrap_err();
+    assert_eq!(err.to_string(), "3 services, the limit is 2");
+
+    let mut routes = service("a");
+    routes.routes = vec![routes.routes[0].clone(); 3];
+    let err = gw.update_state(ServicesTemplate { services: vec![routes], ..Default::default() }).await.unwrap_err();
+    assert_eq!(err.to_string(), "service a has 3 routes, the limit is 2");
+
+    // Global, service and route plugins count together
+    let mut plugins = ServicesTemplate { services: vec![service("a")], ..Default::default() };
+    plugins.global.plugins = vec![plugin("timing", json!({}))];
+    plugins.services[0].routes[0].plugins = vec![plugin("timing", json!({}))];
+    let err = gw.update_state(plugins).await.unwrap_err();
+    assert_eq!(err.to_string(), "2 plugins, the limit is 1");
+
+    let delta = StateDelta { upsert: vec![service("b"), service("c")], ..Default::default() };
+    assert!(gw.apply_delta(delta).await.is_err());
+
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _, _) = send(&gw, request(Method::GET, "/b/users")).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+struct Panicking;
+
+#[bullg_plugin_api::async_trait]
+impl Plugin for Panicking {
+    fn name(&self) -> &'static str {
+        "panicking"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, _ctx: &BullGContext, _phase: Phase, _config: &serde_json::Value) -> Result<()> {
+        panic!("bad unwrap");
+    }
+}
+
+#[tokio::test]
+async fn panicking_plugins_are_caught_and_follow_their_failure_policy() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = gateway().with_plugin(Box::new(Panicking));
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![plugin("panicking", json!({}))];
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+
+    // Plugins fail open unless configured otherwise
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(gw.metrics.plugin_panics_total(), [("panicking".to_string(), 1)]);
+
+    svc.plugins[0].fail_open = Some(false);
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let (status, _, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, "plugin failure");
+    assert_eq!(up.requests().len(), 1);
+    assert_eq!(gw.metrics.plugin_panics_total(), [("panicking".to_string(), 2)]);
+}
+
+/// Gateway proxying `/api/users` to `up` that sheds above `max_in_flight`
+async fn shedding(up: &MockUpstream, max_in_flight: u64, rules: serde_json::Value) -> Gateway {
+    let mut node = GatewayNode::default();
+    node.load_shedding.enabled = true;
+    node.load_shedding.max_in_flight = max_in_flight;
+    node.load_shedding.rules = serde_json::from_value(rules).unwrap();
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+    gw
+}
+
+#[tokio::test]
+async fn shedding_engages_under_overload_and_disengages_after() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = shedding(&up, 2, json!([])).await;
+
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Simulated requests in flight
+    let held: Vec<_> = (0..2).map(|_| gw.metrics.load.request()).collect();
+    let (status, headers, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(headers[http::header::RETRY_AFTER], "1");
+    assert_eq!(body, "overloaded");
+    assert_eq!(up.requests().len(), 1);
+
+    drop(held);
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn low_priority_requests_are_shed_first() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let rules = json!([{"class": "high", "routes": ["mock"], "headers": [{"name": "x-health"}]}]);
+    let gw = shedding(&up, 10, rules).await;
+    let with = |name: &'static str, value: &'static str| {
+        Request::builder().uri("/api/users").header(name, value).body(Full::new(Bytes::new())).unwrap()
+    };
+
+    // 0.9 of the thresholds, only low sheds
+    let held: Vec<_> = (0..8).map(|_| gw.metrics.load.request()).collect();
+    assert_eq!(send(&gw, with("x-bullg-priority", "low")).await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::OK);
+
+    // Above them normal sheds too, high never does
+    let more: Vec<_> = (0..2).map(|_| gw.metrics.load.request()).collect();
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(send(&gw, with("x-bullg-priority", "high")).await.0, StatusCode::OK);
+    assert_eq!(send(&gw, with("x-health", "1")).await.0, StatusCode::OK);
+    drop((held, more));
+}
+
+#[tokio::test]
+async fn spilled_bodies_reach_the_upstream_and_their_file_is_removed() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let mut node = GatewayNode::default();
+    node.body_buffer.memory_limit = 64;
+    node.body_buffer.spill_dir = dir.path().display().to_string();
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+
+    let body: String = (0..2000).map(|i| char::from(b'a' + (i % 26) as u8)).collect();
+    let req = Request::builder().method(Method::POST).uri("/api/users").body(Full::new(Bytes::from(body.clone()))).unwrap();
+    let (status, _, _) = send(&gw, req).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(up.requests()[0].body, body);
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn invalid_utf8_headers_read_by_a_plugin_follow_the_configured_mode() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let auth_request = || {
+        Request::builder()
+            .uri("/api/users")
+            .header("authorization", HeaderValue::from_bytes(b"Basic \xff\xfe").unwrap())
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    };
+    for (mode, expected) in [
+        (bullg_core::InvalidUtf8::Ignore, StatusCode::UNAUTHORIZED),
+        (bullg_core::InvalidUtf8::Reject, StatusCode::BAD_REQUEST),
+    ] {
+        let mut node = GatewayNode::default();
+        node.headers.invalid_utf8 = mode;
+        let gw = Gateway::new(node, Memory::memory());
+        let mut svc = up.service("/api/", "/users");
+        svc.plugins = vec![plugin("basic_auth", json!({"user": "alice", "pass": "s3cret"}))];
+        gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+        let (status, _, body) = send(&gw, auth_request()).await;
+        assert_eq!(status, expected, "{mode:?}");
+        if mode == bullg_core::InvalidUtf8::Reject {
+            assert_eq!(body, "invalid UTF-8 in header authorization");
+        }
+    }
+    assert!(up.requests().is_empty());
+}
+
+#[tokio::test]
+async fn latency_balancing_sends_less_to_a_slow_upstream() {
+    let answer = |delay| vec![(Duration::from_millis(delay), Bytes::from_static(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok"))];
+    let fast = MockUpstream::raw(answer(0)).await.unwrap();
+    let slow = MockUpstream::raw(answer(40)).await.unwrap();
+    let mut svc = fast.service("/api/", "/users");
+    svc.upstreams[0].id = "fast".into();
+    let mut slow_upstream = slow.upstream();
+    slow_upstream.id = "slow".into();
+    svc.upstreams.push(slow_upstream);
+    svc.policies = vec![policy(LoadBalancePolicy::KIND, json!({"strategy": "latency"}))];
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    for _ in 0..60 {
+        let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    // Both are tried once, then the slow one only wins a small share
+    let (fast, slow) = (fast.requests().len(), slow.requests().len());
+    assert_eq!(fast + slow, 60);
+    assert!((1..15).contains(&slow), "slow upstream got {slow} of 60");
+}
+
+#[tokio::test]
+async fn smuggling_attempts_and_header_floods_are_refused() {
+    use tokio::io::AsyncWriteExt;
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.headers.max_count = 20;
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+
+    let mut flood = Request::builder().uri("/api/users");
+    for i in 0..21 {
+        flood = flood.header(format!("x-h{i}"), "1");
+    }
+    let (status, _, body) = send(&gw, flood.body(Full::new(Bytes::new())).unwrap()).await;
+    assert_eq!((status, body), (StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, Bytes::from("too many headers")));
+
+    let (addr, _stop, _server) = serving(gw).await;
+    for head in [
+        "POST /api/users HTTP/1.1\r\nhost: gw\r\ncontent-length: 3\r\ncontent-length: 4\r\n\r\nabcd",
+        "POST /api/users HTTP/1.1\r\nhost: gw\r\ntransfer-encoding: chunked, identity\r\n\r\n0\r\n\r\n",
+    ] {
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client.write_all(head.as_bytes()).await.unwrap();
+        let answer = read_until(&mut client, b"\r\n\r\n").await;
+        assert!(answer.starts_with(b"HTTP/1.1 400"), "{}", String::from_utf8_lossy(&answer));
+    }
+    assert!(up.requests().is_empty());
+}
+
+#[tokio::test]
+async fn the_upstream_base_path_prefixes_the_forwarded_path() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut svc = up.service("/api/", "/users");
+    svc.upstreams[0].path = "/backend/v1".into();
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users/7?expand=roles")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(up.requests()[0].uri, "/backend/v1/users/7?expand=roles");
+}
+
+fn slow(delay_ms: u64) -> Vec<(Duration, Bytes)> {
+    vec![(Duration::from_millis(delay_ms), Bytes::from_static(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok"))]
+}
+
+#[tokio::test]
+async fn slow_upstreams_are_answered_504_with_the_default_headers() {
+    let up = MockUpstream::raw(slow(500)).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.upstream.timeout_ms = 100;
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+
+    let started = Instant::now();
+    let (status, headers, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(body, "upstream timeout");
+    assert!(started.elapsed() < Duration::from_millis(400));
+    assert!(Uuid::parse_str(headers["x-request-id"].to_str().unwrap()).is_ok());
+    assert!(headers.contains_key("x-latency") && headers.contains_key("x-latency-us"));
+
+    // A service timeout policy replaces the gateway wide deadline
+    let gw = proxied(&up, vec![policy(TimeoutPolicy::KIND, json!({"timeout": "1s"}))]).await;
+    let (status, _, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!((status, body), (StatusCode::OK, Bytes::from_static(b"ok")));
+    let timeout = json!({"timeout": "50ms", "error": {"status_code": 503, "message": "too slow"}});
+    let gw = proxied(&up, vec![policy(TimeoutPolicy::KIND, timeout)]).await;
+    let (status, headers, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!((status, body), (StatusCode::SERVICE_UNAVAILABLE, Bytes::from_static(b"too slow")));
+    assert!(headers.contains_key("x-request-id"));
+}
+
+#[tokio::test]
+async fn paths_are_normalized_or_rejected_before_routing() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let gw = proxied(&up, vec![]).await;
+    for raw in ["/static/../api/users", "/static/%2e%2e/api/users", "/api//./users"] {
+        let (status, _, _) = send(&gw, request(Method::GET, raw)).await;
+        assert_eq!(status, StatusCode::OK, "{raw}");
+    }
+    assert!(up.requests().iter().all(|r| r.uri.path() == "/users"), "{:?}", up.requests());
+
+    let (status, _, body) = send(&gw, request(Method::GET, "/../api/users")).await;
+    assert_eq!((status, body), (StatusCode::BAD_REQUEST, Bytes::from_static(b"path escapes the root")));
+
+    let mut node = GatewayNode::default();
+    node.paths.mode = bullg_core::PathMode::Reject;
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+    let seen = up.requests().len();
+    let (status, _, body) = send(&gw, request(Method::GET, "/static/%2e%2e/api/users")).await;
+    assert_eq!((status, body), (StatusCode::BAD_REQUEST, Bytes::from_static(b"path is not canonical")));
+    assert_eq!(up.requests().len(), seen);
+    let (status, _, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn requests_over_the_upstream_rate_are_throttled() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let rate = json!({"requests_per_second": 1, "burst": 3, "overflow": "shed"});
+    let gw = proxied(&up, vec![policy(UpstreamRatePolicy::KIND, rate)]).await;
+
+    let mut statuses = Vec::new();
+    for _ in 0..6 {
+        statuses.push(send(&gw, request(Method::GET, "/api/users")).await.0);
+    }
+    assert_eq!(statuses[..3], [StatusCode::OK; 3]);
+    assert_eq!(statuses[3..], [StatusCode::SERVICE_UNAVAILABLE; 3]);
+    assert_eq!(up.requests().len(), 3);
+    let (_, _, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(body, "Upstream rate limit exceeded");
+}
+
+/// Upstream speaking only HTTP/2 without TLS, answering its HTTP version
+async fn h2c() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service = service_fn(|req: Request<hyper::body::Incoming>| async move {
+                Ok::<_, std::convert::Infallible>(Response::new(Full::new(Bytes::from(format!("{:?}", req.version())))))
+            });
+            tokio::spawn(
+                hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn services_get_a_client_with_their_own_settings() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let addr = h2c().await;
+    let service = |id: &str, context_path: &str, policies: Vec<AppliedPolicy>| {
+        let mut svc = up.service(context_path, "/users");
+        svc.id = id.into();
+        svc.upstreams[0].port = addr.port();
+        svc.policies = policies;
+        svc
+    };
+    let tuned = policy(ClientPolicy::KIND, json!({"http2_prior_knowledge": true, "tcp_keepalive": "30s"}));
+    let services = vec![service("tuned", "/tuned/", vec![tuned]), service("plain", "/plain/", vec![])];
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services, ..Default::default() }).await.unwrap();
+
+    let (status, _, body) = send(&gw, request(Method::GET, "/tuned/users")).await;
+    assert_eq!((status, body), (StatusCode::OK, Bytes::from_static(b"HTTP/2.0")));
+    // The shared client speaks HTTP/1.1, which this upstream refuses
+    let (status, _, _) = send(&gw, request(Method::GET, "/plain/users")).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn plugin_traces_need_the_debug_header_and_token() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let traced = |token: &str| {
+        let mut node = GatewayNode::default();
+        node.debug.token = token.into();
+        Gateway::new(node, Memory::memory())
+    };
+    let debug = |value: &'static str| {
+        Request::builder().uri("/api/users").header("x-bullg-debug", value).body(Full::new(Bytes::new())).unwrap()
+    };
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![plugin("timing", json!({}))];
+
+    let gw = traced("s3cret");
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+    let (_, headers, _) = send(&gw, debug("s3cret")).await;
+    let trace = headers[PLUGIN_TRACE_HEADER].to_str().unwrap();
+    let runs: Vec<&str> = trace.split(", ").collect();
+    assert_eq!(runs.len(), 2, "{trace}");
+    assert!(runs[0].starts_with("timing/timing;phase=pre;dur=") && runs[1].contains(";phase=post;"), "{trace}");
+    assert!(up.requests().iter().all(|r| !r.headers.contains_key("x-bullg-debug")));
+
+    // A plugin ending the chain is marked
+    let mut guarded = svc.clone();
+    guarded.plugins.push(plugin("basic_auth", json!({"user": "alice", "pass": "s3cret"})));
+    gw.update_state(ServicesTemplate { services: vec![guarded], ..Default::default() }).await.unwrap();
+    let (status, headers, _) = send(&gw, debug("s3cret")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let trace = headers[PLUGIN_TRACE_HEADER].to_str().unwrap();
+    assert!(trace.contains("basic_auth/basic_auth;phase=pre;") && trace.ends_with(";short-circuit"), "{trace}");
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+
+    for req in [debug("guess"), request(Method::GET, "/api/users")] {
+        let (status, headers, _) = send(&gw, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key(PLUGIN_TRACE_HEADER));
+    }
+    // Without a token tracing is off
+    let gw = traced("");
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let (_, headers, _) = send(&gw, debug("")).await;
+    assert!(!headers.contains_key(PLUGIN_TRACE_HEADER));
+}
+
+#[tokio::test]
+async fn headers_are_normalized_both_ways_with_set_cookie_kept_apart() {
+    let answer = "HTTP/1.1 200 OK\r\nx-tag: a\r\nX-Tag: b\r\nx-empty: \r\n\
+        set-cookie: a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT\r\nset-cookie: b=2\r\ncontent-length: 2\r\n\r\nok";
+    let up = MockUpstream::raw(vec![(Duration::ZERO, Bytes::from(answer))]).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.headers.normalize.request = true;
+    node.headers.normalize.response = true;
+    node.headers.normalize.title_case = true;
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+    let (addr, _stop, _server) = serving(gw).await;
+
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let req = "GET /api/users HTTP/1.1\r\nhost: gw\r\nx-req: 1\r\nX-Req: 2\r\nconnection: close\r\n\r\n";
+    tokio::io::AsyncWriteExt::write_all(&mut stream, req.as_bytes()).await.unwrap();
+    let response = String::from_utf8(read_until(&mut stream, b"\r\n\r\nok").await).unwrap();
+
+    assert!(response.contains("\r\nX-Tag: a, b\r\n"), "{response}");
+    assert!(response.contains("\r\nSet-Cookie: a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT\r\nSet-Cookie: b=2\r\n"), "{response}");
+    assert!(response.contains("\r\nContent-Length: 2\r\n") && !response.contains("X-Empty"), "{response}");
+    let forwarded = &up.requests()[0].headers;
+    assert_eq!(forwarded.get_all("x-req").iter().collect::<Vec<_>>(), ["1, 2"]);
+}
+
+#[tokio::test]
+async fn the_failure_policy_decides_what_ejects_and_retries() {
+    let up = MockUpstream::start(|r: &Recorded| status(if r.uri.path().ends_with("/missing") { 404 } else { 503 }))
+        .await
+        .unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.routes[0].config.path = "/".into();
+    svc.routes[0].config.backend = "/".into();
+    svc.policies = vec![
+        policy(FailurePolicy::KIND, json!({"statuses": [503]})),
+        policy(OutlierPolicy::KIND, json!({"consecutive_failures": 2, "ejection_time": "1m"})),
+        retry(json!({"retry_on_status": {"max_retries": 1}})),
+    ];
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    // A 404 is an answer, not a failure: no retry and no ejection
+    for _ in 0..3 {
+        let (status, headers, _) = send(&gw, request(Method::GET, "/api/missing")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(!headers.contains_key(RETRY_COUNT_HEADER));
+    }
+    assert_eq!(up.requests().len(), 3);
+    assert!(gw.health.is_healthy("mock", "mock"));
+
+    // A 503 is retried once, both attempts count towards the ejection
+    let (status, headers, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(headers[RETRY_COUNT_HEADER], "1");
+    assert_eq!(up.requests().len(), 5);
+    assert!(!gw.health.is_healthy("mock", "mock"));
+}
+
+#[tokio::test]
+async fn static_response_headers_combine_with_the_upstream_ones() {
+    let up = MockUpstream::start(|_| {
+        let mut resp = status(200);
+        resp.headers_mut().insert("cache-control", HeaderValue::from_static("no-store"));
+        resp.headers_mut().insert("x-api-version", HeaderValue::from_static("1"));
+        resp
+    })
+    .await
+    .unwrap();
+    let header = |name: &str, value: &str, mode: &str| {
+        serde_json::from_value::<bullg_core::ResponseHeader>(json!({"name": name, "value": value, "mode": mode})).unwrap()
+    };
+    let mut svc = up.service("/api/", "/users");
+    svc.response_headers =
+        vec![header("cache-control", "max-age=60", "if_missing"), header("x-api-version", "2", "override")];
+    // Route entries apply after those of the service
+    svc.routes[0].config.response_headers = vec![header("x-api-version", "3", "append"), header("x-route", "users", "override")];
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+
+    let (_, headers, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!(headers["cache-control"], "no-store");
+    assert_eq!(headers.get_all("x-api-version").iter().collect::<Vec<_>>(), ["2", "3"]);
+    assert_eq!(headers["x-route"], "users");
+
+    svc.response_headers.push(header("x-bad", "a\r\nb", "override"));
+    let err = gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap_err();
+    assert!(err.to_string().contains("invalid value for response header `x-bad`"), "{err}");
+}
+
+#[tokio::test]
+async fn maintenance_answers_all_but_exempt_traffic_and_is_flipped_by_the_admin_api() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.maintenance.enabled = true;
+    node.maintenance.body = "back soon".into();
+    node.maintenance.exempt_paths = vec!["/health".into()];
+    node.maintenance.exempt_ips = vec!["10.0.0.9".into()];
+    let gw = Arc::new(Gateway::new(node, Memory::memory()));
+    let mut orders = up.service("/orders/", "/list");
+    orders.id = "orders".into();
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users"), orders], ..Default::default() })
+        .await
+        .unwrap();
+    let from = |ip: &str| {
+        let mut req = request(Method::GET, "/api/users");
+        req.extensions_mut().insert(SocketAddr::new(ip.parse().unwrap(), 40000));
+        req
+    };
+
+    let (status, headers, body) = send(&gw, from("10.0.0.1")).await;
+    assert_eq!((status, body), (StatusCode::SERVICE_UNAVAILABLE, Bytes::from_static(b"back soon")));
+    assert!(headers.contains_key("x-request-id"));
+    assert_eq!(send(&gw, request(Method::GET, "/orders/list")).await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(send(&gw, from("10.0.0.9")).await.0, StatusCode::OK);
+    // Routed as usual, there is no such route
+    assert_eq!(send(&gw, request(Method::GET, "/health")).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(up.requests().len(), 1);
+
+    let admin = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    tokio::spawn(gw.clone().serve_admin(admin));
+    let client = reqwest::Client::new();
+    let call = |method: reqwest::Method, path: &str| {
+        let url = format!("http://{admin}{path}");
+        let client = client.clone();
+        async move {
+            for _ in 0..100 {
+                if let Ok(resp) = client.request(method.clone(), &url).send().await {
+                    return resp.json::<serde_json::Value>().await.unwrap();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("admin API not listening");
+        }
+    };
+    assert_eq!(call(reqwest::Method::DELETE, "/maintenance").await, json!({"enabled": false, "services": []}));
+    assert_eq!(send(&gw, from("10.0.0.1")).await.0, StatusCode::OK);
+
+    // One service only, the others are served
+    let state = call(reqwest::Method::PUT, "/maintenance/services/orders").await;
+    assert_eq!(state, json!({"enabled": false, "services": ["orders"]}));
+    assert_eq!(send(&gw, request(Method::GET, "/orders/list")).await.0, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(send(&gw, from("10.0.0.1")).await.0, StatusCode::OK);
+    call(reqwest::Method::DELETE, "/maintenance/services/orders").await;
+    assert_eq!(send(&gw, request(Method::GET, "/orders/list")).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn fingerprinting_upstream_headers_do_not_reach_clients() {
+    let leaky = |_: &Recorded| {
+        let mut resp = status(200);
+        for (name, value) in [("server", "nginx/1.25"), ("x-powered-by", "PHP/8"), ("x-internal-node", "db-3"), ("etag", "\"v1\"")] {
+            resp.headers_mut().insert(name, HeaderValue::from_static(value));
+        }
+        resp
+    };
+    let up = MockUpstream::start(leaky).await.unwrap();
+    let gw = proxied(&up, vec![]).await;
+    let (_, headers, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    // The gateway names itself instead
+    assert_eq!(headers["server"].to_str().unwrap(), format!("{APP_NAME}/{APP_VERSION}"));
+    assert!(headers["x-powered-by"].to_str().unwrap().starts_with(APP_NAME));
+    assert_eq!(headers["x-internal-node"], "db-3");
+    assert_eq!(headers["etag"], "\"v1\"");
+
+    let mut node = GatewayNode::default();
+    node.headers.strip_response = vec!["X-Internal-*".into()];
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+    let (_, headers, _) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert!(!headers.contains_key("x-internal-node"));
+    assert_eq!(headers["etag"], "\"v1\"");
+}
+
+/// Response of 10 bytes whose second half comes after `stall_ms`
+fn stalling(stall_ms: u64) -> Vec<(Duration, Bytes)> {
+    vec![
+        (Duration::ZERO, Bytes::from_static(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nhello")),
+        (Duration::from_millis(stall_ms), Bytes::from_static(b"world")),
+    ]
+}
+
+#[tokio::test]
+async fn response_and_read_timeouts_bound_the_headers_and_each_body_chunk() {
+    let mut node = GatewayNode::default();
+    node.upstream.response_timeout_ms = 100;
+    node.upstream.read_timeout_ms = 100;
+    let gateway_for = |up: &MockUpstream| {
+        let gw = Gateway::new(node.clone(), Memory::memory());
+        let svc = up.service("/api/", "/users");
+        async move {
+            gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+            gw
+        }
+    };
+
+    // Headers slower than the response timeout, well within the deadline
+    let up = MockUpstream::raw(slow(400)).await.unwrap();
+    let gw = gateway_for(&up).await;
+    let started = Instant::now();
+    let (status, _, body) = send(&gw, request(Method::GET, "/api/users")).await;
+    assert_eq!((status, body), (StatusCode::GATEWAY_TIMEOUT, Bytes::from_static(b"upstream timeout")));
+    assert!(started.elapsed() < Duration::from_millis(350), "{:?}", started.elapsed());
+
+    // A body stalling longer than the read timeout, while a slow but steady one passes
+    let up = MockUpstream::raw(stalling(400)).await.unwrap();
+    let (status, _, _) = send(&gateway_for(&up).await, request(Method::GET, "/api/users")).await;
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    let up = MockUpstream::raw(stalling(50)).await.unwrap();
+    let (status, _, body) = send(&gateway_for(&up).await, request(Method::GET, "/api/users")).await;
+    assert_eq!((status, body), (StatusCode::OK, Bytes::from_static(b"helloworld")));
+
+    // A streamed body is ended as interrupted instead
+    let up = MockUpstream::raw(stalling(400)).await.unwrap();
+    let streaming = policy(StreamPolicy::KIND, json!({"flush_interval_ms": 0, "content_types": []}));
+    let timeouts = policy(TimeoutPolicy::KIND, json!({"timeout": "5s", "read_timeout": "100ms"}));
+    let gw = proxied(&up, vec![streaming, timeouts]).await;
+    let req = Request::builder().uri("/api/users").header("te", "trailers").body(Full::new(Bytes::new())).unwrap();
+    let mut body = gw.handle_request(req).await.into_body();
+    let (mut data, mut trailers) = (Vec::new(), None);
+    while let Some(frame) = body.frame().await {
+        match frame.unwrap().into_data() {
+            Ok(chunk) => data.extend_from_slice(&chunk),
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+    }
+    assert_eq!(data, b"hello");
+    assert_eq!(trailers.unwrap()[STREAM_ERROR_TRAILER], "upstream stream interrupted");
+}
+
+#[tokio::test]
+async fn the_route_table_is_exported_as_a_catalog_and_openapi() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut svc = up.service("/api/", "/users/{id}");
+    svc.name = "Users".into();
+    svc.description = "User accounts".into();
+    svc.routes[0].id = "get-user".into();
+    svc.routes[0].name = "Get a user".into();
+    svc.routes[0].config.methods = vec!["get".into()];
+    let mut list = svc.routes[0].clone();
+    list.id = "list-users".into();
+    list.name = String::new();
+    list.config.path = "/users".into();
+    list.config.methods = vec![];
+    let mut purge = list.clone();
+    purge.id = "purge".into();
+    purge.config.methods = vec!["TRACE".into()];
+    let mut disabled = list.clone();
+    disabled.id = "disabled".into();
+    disabled.enabled = false;
+    svc.routes.extend([list, purge, disabled]);
+
+    let mut node = GatewayNode::default();
+    node.methods.allow = vec!["GET".into(), "POST".into(), "TRACE".into()];
+    node.methods.deny = vec!["TRACE".into()];
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    let route = |id: &str, name: &str, context_path: &str, path: &str, methods: &[&str], params: &[&str]| {
+        json!({
+            "id": id, "name": name, "description": "", "tags": [], "context_path": context_path,
+            "path": path, "methods": methods, "params": params,
+        })
+    };
+    assert_eq!(
+        serde_json::to_value(gw.catalog()).unwrap(),
+        json!({"services": [{
+            "id": "mock", "name": "Users", "description": "User accounts", "tags": [],
+            "context_paths": ["/api/"],
+            "routes": [
+                route("get-user", "Get a user", "/api/", "/api/users/{id}", &["GET"], &["id"]),
+                route("list-users", "", "/api/", "/api/users", &[], &[]),
+            ],
+        }]})
+    );
+
+    let openapi = gw.openapi();
+    assert_eq!(openapi["openapi"], "3.0.3");
+    assert_eq!(openapi["info"], json!({"title": "BullGateway", "version": env!("CARGO_PKG_VERSION")}));
+    assert_eq!(openapi["tags"], json!([{"name": "Users", "description": "User accounts"}]));
+    let paths = openapi["paths"].as_object().unwrap();
+    assert_eq!(paths.keys().collect::<Vec<_>>(), ["/api/users", "/api/users/{id}"]);
+    let get_user = &paths["/api/users/{id}"];
+    assert_eq!(get_user["get"]["operationId"], "mock.get-user.get");
+    assert_eq!(get_user["parameters"], json!([{"name": "id", "in": "path", "required": true, "schema": {"type": "string"}}]));
+    // Any method means each one the gateway permits
+    let list = paths["/api/users"].as_object().unwrap();
+    assert_eq!(list.keys().collect::<Vec<_>>(), ["get", "post"]);
+    assert_eq!(list["get"]["summary"], "list-users");
+}
+
+#[tokio::test]
+async fn plugins_run_by_priority_then_order() {
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let timing = |id: &str, priority: Option<u32>, order: Option<u32>| AppliedPlugin {
+        id: id.into(),
+        priority,
+        order,
+        ..plugin("timing", json!({}))
+    };
+    let mut svc = up.service("/api/", "/users");
+    svc.plugins = vec![
+        timing("late", None, Some(2)),
+        timing("first", Some(10), None),
+        timing("early", None, Some(1)),
+        timing("second", Some(10), None),
+        timing("tied", None, Some(1)),
+    ];
+    let mut node = GatewayNode::default();
+    node.debug.token = "s3cret".into();
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+
+    let req = Request::builder().uri("/api/users").header("x-bullg-debug", "s3cret").body(Full::new(Bytes::new())).unwrap();
+    let (status, headers, _) = send(&gw, req).await;
+    assert_eq!(status, StatusCode::OK);
+    let trace = headers[PLUGIN_TRACE_HEADER].to_str().unwrap();
+    let ran: Vec<&str> = trace
+        .split(", ")
+        .filter(|run| run.contains(";phase=pre;"))
+        .filter_map(|run| run.split(['/', ';']).nth(1))
+        .collect();
+    // Ties keep the listed order
+    assert_eq!(ran, ["first", "second", "early", "tied", "late"], "{trace}");
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HelloRequest {
+    #[prost(string, tag = "1")]
+    name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct HelloReply {
+    #[prost(string, tag = "1")]
+    message: String,
+}
+
+#[derive(Clone)]
+struct Greeter;
+
+impl tonic::server::UnaryService<HelloRequest> for Greeter {
+    type Response = HelloReply;
+    type Future = std::future::Ready<Result<tonic::Response<HelloReply>, tonic::Status>>;
+
+    fn call(&mut self, req: tonic::Request<HelloRequest>) -> Self::Future {
+        let name = req.into_inner().name;
+        std::future::ready(match name.is_empty() {
+            true => Err(tonic::Status::invalid_argument("name is required")),
+            false => Ok(tonic::Response::new(HelloReply { message: format!("Hello {name}") })),
+        })
+    }
+}
+
+/// tonic unary service over h2c, answering every path as the greeter
+async fn greeter() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let service = service_fn(|req: Request<hyper::body::Incoming>| async move {
+                let mut grpc = tonic::server::Grpc::new(tonic::codec::ProstCodec::<HelloReply, HelloRequest>::default());
+                Ok::<_, std::convert::Infallible>(grpc.unary(Greeter, req).await)
+            });
+            tokio::spawn(
+                hyper::server::conn::http2::Builder::new(hyper_util::rt::TokioExecutor::new())
+                    .serve_connection(TokioIo::new(stream), service),
+            );
+        }
+    });
+    addr
+}
+
+fn grpc_frame(flag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![flag];
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Message and trailer block of a gRPC-Web response body
+fn grpc_web_frames(mut body: &[u8]) -> (Vec<u8>, String) {
+    let (mut message, mut trailers) = (Vec::new(), String::new());
+    while body.len() >= 5 {
+        let len = u32::from_be_bytes(body[1..5].try_into().unwrap()) as usize;
+        let payload = &body[5..5 + len];
+        match body[0] {
+            0x80 => trailers = String::from_utf8(payload.to_vec()).unwrap(),
+            _ => message = payload.to_vec(),
+        }
+        body = &body[5 + len..];
+    }
+    (message, trailers)
+}
+
+#[tokio::test]
+async fn grpc_web_calls_are_bridged_to_a_grpc_backend() {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use prost::Message;
+
+    let up = MockUpstream::start(|_| status(200)).await.unwrap();
+    let mut svc = up.service("/rpc/", "/helloworld.Greeter/SayHello");
+    svc.routes[0].id = "greeter".into();
+    svc.upstreams[0].port = greeter().await.port();
+    svc.policies = vec![policy(GrpcWebPolicy::KIND, json!({"routes": ["greeter"]}))];
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let call = |content_type: &'static str, body: Vec<u8>| {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/rpc/helloworld.Greeter/SayHello")
+            .header("content-type", content_type)
+            .header("x-grpc-web", "1")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    };
+    let hello = |name: &str| grpc_frame(0, &HelloRequest { name: name.into() }.encode_to_vec());
+
+    let (status, headers, body) = send(&gw, call("application/grpc-web+proto", hello("web"))).await;
+    assert_eq!(status, StatusCode::OK);
+    // tonic answers without a message format suffix
+    assert_eq!(headers["content-type"], "application/grpc-web");
+    let (message, trailers) = grpc_web_frames(&body);
+    assert_eq!(HelloReply::decode(message.as_slice()).unwrap().message, "Hello web");
+    assert!(trailers.contains("grpc-status: 0\r\n"), "{trailers}");
+
+    // Text mode carries the frames in base64 both ways
+    let (status, headers, body) =
+        send(&gw, call("application/grpc-web-text+proto", STANDARD.encode(hello("text")).into_bytes())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["content-type"], "application/grpc-web-text");
+    let (message, trailers) = grpc_web_frames(&STANDARD.decode(&body).unwrap());
+    assert_eq!(HelloReply::decode(message.as_slice()).unwrap().message, "Hello text");
+    assert!(trailers.contains("grpc-status: 0\r\n"), "{trailers}");
+
+    // A failed call keeps its status, sent by tonic as trailers only
+    let (status, headers, _) = send(&gw, call("application/grpc-web+proto", hello(""))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["grpc-status"], "3");
+    assert_eq!(headers["grpc-message"], "name%20is%20required");
+
+    let (status, _, body) = send(&gw, call("application/grpc-web-text", b"not base64!".to_vec())).await;
+    assert_eq!((status, body), (StatusCode::BAD_REQUEST, Bytes::from_static(b"invalid grpc-web-text body")));
+}
+
+#[tokio::test]
+async fn connections_left_open_at_the_drain_timeout_are_force_closed() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let up = MockUpstream::raw(slow(5_000)).await.unwrap();
+    let mut node = GatewayNode::default();
+    node.shutdown.drain_timeout_ms = 200;
+    let gw = Gateway::new(node, Memory::memory());
+    gw.update_state(ServicesTemplate { services: vec![up.service("/api/", "/users")], ..Default::default() })
+        .await
+        .unwrap();
+    let metrics = gw.metrics.clone();
+    let (addr, stop, server) = serving(gw).await;
+
+    // An idle keep-alive connection closes once shutdown starts, the one
+    // waiting on the upstream is still open at the drain timeout
+    let mut idle = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let mut waiting = tokio::net::TcpStream::connect(addr).await.unwrap();
+    waiting.write_all(b"GET /api/users HTTP/1.1\r\nhost: gw\r\n\r\n").await.unwrap();
+    while up.requests().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let started = Instant::now();
+    stop.send(()).unwrap();
+    server.await.unwrap().unwrap();
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(2), "{elapsed:?}");
+    let mut read = Vec::new();
+    assert_eq!(waiting.read_to_end(&mut read).await.unwrap_or_default(), 0);
+    assert_eq!(idle.read_to_end(&mut read).await.unwrap_or_default(), 0);
+    assert_eq!(metrics.connections_force_closed_total(), 1);
+}
+
+#[tokio::test]
+async fn shadow_answers_are_compared_without_reaching_the_client() {
+    let json_body = |name: &str, at: u32| {
+        let mut resp = Response::new(Bytes::from(json!({"id": 1, "name": name, "at": at}).to_string()));
+        resp.headers_mut().insert("content-type", HeaderValue::from_static("application/json"));
+        resp
+    };
+    let up = MockUpstream::start(move |_| json_body("alice", 1)).await.unwrap();
+    // Differs on page 2 only, `at` is ignored
+    let next = MockUpstream::start(move |r| match r.uri.query() {
+        Some("page=2") => json_body("bob", 2),
+        _ => json_body("alice", 2),
+    })
+    .await
+    .unwrap();
+    let mut svc = up.service("/api/", "/users");
+    let compare = json!({"status": true, "body": "json", "ignore_fields": ["at"]});
+    svc.policies = vec![policy(ShadowPolicy::KIND, json!({"url": format!("http://{}/next/", next.addr()), "compare": compare}))];
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let metrics = gw.metrics.clone();
+    let shadowed = |count: u64| {
+        let metrics = metrics.clone();
+        async move {
+            for _ in 0..200 {
+                if metrics.shadow_requests_total() + metrics.shadow_errors_total() >= count {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    };
+
+    let (status, _, body) = send(&gw, request(Method::GET, "/api/users?page=2")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(&body).unwrap()["name"], "alice");
+    shadowed(1).await;
+    assert_eq!((gw.metrics.shadow_requests_total(), gw.metrics.shadow_mismatches_total()), (1, 1));
+    assert_eq!(next.requests()[0].uri, "/next/users?page=2");
+
+    send(&gw, request(Method::GET, "/api/users?page=1")).await;
+    shadowed(2).await;
+    assert_eq!((gw.metrics.shadow_requests_total(), gw.metrics.shadow_mismatches_total()), (2, 1));
+    assert_eq!(gw.metrics.shadow_errors_total(), 0);
+}
+
+#[tokio::test]
+async fn bodyless_statuses_are_sent_without_a_body() {
+    let raw = |head: &'static str| vec![(Duration::ZERO, Bytes::from_static(head.as_bytes()))];
+    let no_content = MockUpstream::raw(raw("HTTP/1.1 204 No Content\r\ncontent-length: 0\r\nx-up: 1\r\n\r\n")).await.unwrap();
+    let not_modified =
+        MockUpstream::raw(raw("HTTP/1.1 304 Not Modified\r\netag: \"v1\"\r\ncontent-length: 42\r\n\r\n")).await.unwrap();
+    let mut users = no_content.service("/api/", "/users");
+    // A post plugin wrapping bodies finds nothing to wrap
+    users.plugins = vec![plugin("response_transform", json!({"envelope": "data", "add": {"ok": true}}))];
+    let mut orders = not_modified.service("/orders/", "/orders");
+    orders.id = "orders".into();
+    let gw = gateway();
+    gw.update_state(ServicesTemplate { services: vec![users, orders], ..Default::default() }).await.unwrap();
+
+    let (status, headers, body) = send(&gw, request(Method::DELETE, "/api/users")).await;
+    assert_eq!((status, body), (StatusCode::NO_CONTENT, Bytes::new()));
+    assert_eq!(headers["x-up"], "1");
+    assert!(!headers.contains_key("content-length") && !headers.contains_key("transfer-encoding"));
+
+    // The length of a 304 is the one of the cached representation
+    let (status, headers, body) = send(&gw, request(Method::GET, "/orders/orders")).await;
+    assert_eq!((status, body), (StatusCode::NOT_MODIFIED, Bytes::new()));
+    assert_eq!(headers["etag"], "\"v1\"");
+    assert_eq!(headers["content-length"], "42");
+    assert!(!headers.contains_key("transfer-encoding"));
+}
+
+#[tokio::test]
+async fn debug_requests_may_force_an_upstream() {
+    let blue = MockUpstream::start(|_| Response::new(Bytes::from("blue"))).await.unwrap();
+    let green = MockUpstream::start(|_| Response::new(Bytes::from("green"))).await.unwrap();
+    let mut svc = blue.service("/api/", "/users");
+    svc.upstreams[0].id = "blue".into();
+    // Never picked by the balancer
+    svc.upstreams.push(Upstream { id: "green".into(), enabled: false, ..green.upstream() });
+    let forced = |token: &str, debug: Option<&'static str>, upstream: &'static str| {
+        let mut node = GatewayNode::default();
+        node.debug.token = token.into();
+        let gw = Gateway::new(node, Memory::memory());
+        let mut req = Request::builder().uri("/api/users").header("x-bullg-upstream", upstream);
+        if let Some(debug) = debug {
+            req = req.header("x-bullg-debug", debug);
+        }
+        (gw, req.body(Full::new(Bytes::new())).unwrap())
+    };
+
+    for (token, debug, answer) in [
+        ("s3cret", Some("s3cret"), "green"),
+        ("s3cret", None, "blue"),
+        ("s3cret", Some("guess"), "blue"),
+        ("", Some(""), "blue"),
+    ] {
+        let (gw, req) = forced(token, debug, "green");
+        gw.update_state(ServicesTemplate { services: vec![svc.clone()], ..Default::default() }).await.unwrap();
+        let (status, _, body) = send(&gw, req).await;
+        assert_eq!((status, body), (StatusCode::OK, Bytes::from(answer)), "{debug:?}");
+    }
+    let seen = [blue.requests(), green.requests()].concat();
+    assert_eq!((blue.requests().len(), green.requests().len()), (3, 1));
+    assert!(seen.iter().all(|r| !r.headers.contains_key("x-bullg-upstream") && !r.headers.contains_key("x-bullg-debug")));
+
+    let (gw, req) = forced("s3cret", Some("s3cret"), "nope");
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    let (status, _, body) = send(&gw, req).await;
+    assert_eq!((status, body), (StatusCode::BAD_REQUEST, Bytes::from("unknown upstream nope")));
+}
+
+#[tokio::test]
+async fn the_metrics_endpoint_exports_the_route_series() {
+    let up = MockUpstream::start(|_| status(201)).await.unwrap();
+    let gw = gateway();
+    let mut svc = up.service("/api/", "/users");
+    svc.id = "users".into();
+    svc.routes[0].id = "list-users".into();
+    gw.update_state(ServicesTemplate { services: vec![svc], ..Default::default() }).await.unwrap();
+    assert_eq!(send(&gw, request(Method::GET, "/api/users")).await.0, StatusCode::CREATED);
+    assert_eq!(send(&gw, request(Method::GET, "/nowhere")).await.0, StatusCode::NOT_FOUND);
+
+    let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    tokio::spawn(Arc::new(gw).serve_metrics(addr));
+    let client = reqwest::Client::new();
+    let mut exported = None;
+    for _ in 0..100 {
+        if let Ok(resp) = client.get(format!("http://{addr}/metrics")).send().await {
+            assert_eq!(resp.headers()["content-type"], "text/plain; version=0.0.4");
+            exported = Some(resp.text().await.unwrap());
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let exported = exported.expect("metrics endpoint not listening");
+    let lines: Vec<&str> = exported.lines().collect();
+    let route = r#"service="users",route="list-users""#;
+    for expected in [
+        "# TYPE requests_total counter".to_string(),
+        format!("requests_total{{{route},code=\"201\"}} 1"),
+        r#"requests_total{service="",route="",code="404"} 1"#.to_string(),
+        "# TYPE requests_in_flight gauge".to_string(),
+        format!("requests_in_flight{{{route}}} 0"),
+        "# TYPE upstream_latency_seconds histogram".to_string(),
+        format!("upstream_latency_seconds_bucket{{{route},le=\"+Inf\"}} 1"),
+        format!("upstream_latency_seconds_count{{{route}}} 1"),
+    ] {
+        assert!(lines.contains(&expected.as_str()), "no `{expected}` in\n{exported}");
+    }
+    let sum = lines.iter().find_map(|l| l.strip_prefix(&format!("upstream_latency_seconds_sum{{{route}}} "))).unwrap();
+    assert!(sum.parse::<f64>().unwrap() > 0.0);
+    // Buckets are cumulative, the 10s one holds the request
+    assert!(lines.contains(&format!("upstream_latency_seconds_bucket{{{route},le=\"10\"}} 1").as_str()));
+    // Requests answered before routing have no latency or in flight series
+    assert!(!exported.contains(r#"upstream_latency_seconds_count{service="",route=""}"#));
+    assert!(!exported.contains(r#"requests_in_flight{service="",route=""}"#));
+}
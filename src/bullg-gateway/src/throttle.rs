@@ -0,0 +1,179 @@
+use bullg_utils::{de_duration, ser_duration};
+use dashmap::DashMap;
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::concurrency::{LimitScope, Overflow};
+use crate::policy::PolicyError;
+
+/// Request rate cap towards upstreams (`type: upstream_rate_limit` on a
+/// service or global policy), whichever client the requests come from.
+///
+/// A token bucket refilled with `requests_per_second` and holding up to
+/// `burst` tokens. Without a token the request is either shed at once or
+/// queued until its turn, when that is within `queue_timeout`, before being
+/// rejected with `error`.
+///
+/// ```yaml
+/// - id: svc-upstream-rate
+///   type: upstream_rate_limit
+///   enabled: true
+///   config:
+///     requests_per_second: 50
+///     burst: 100
+///     per: upstream # or service
+///     overflow: queue # or shed
+///     queue_timeout: 1s
+///     error:
+///       status_code: 503
+///       message: "Upstream rate limit exceeded"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpstreamRatePolicy {
+    pub requests_per_second: f64,
+    /// Defaults to one second worth of requests
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub per: LimitScope,
+    #[serde(default)]
+    pub overflow: Overflow,
+    #[serde(
+        default = "def_queue_timeout",
+        deserialize_with = "de_duration",
+        serialize_with = "ser_duration"
+    )]
+    pub queue_timeout: Duration,
+    #[serde(default = "def_error")]
+    pub error: PolicyError,
+}
+
+fn def_queue_timeout() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn def_error() -> PolicyError {
+    PolicyError::new(StatusCode::SERVICE_UNAVAILABLE, "Upstream rate limit exceeded")
+}
+
+impl UpstreamRatePolicy {
+    pub const KIND: &'static str = "upstream_rate_limit";
+
+    /// Throttle key for a request to `upstream` of `service`
+    pub fn key(&self, service: &str, upstream: &str) -> String {
+        match self.per {
+            LimitScope::Upstream => format!("{}/{}", service, upstream),
+            LimitScope::Service => service.to_string(),
+        }
+    }
+
+    fn capacity(&self) -> f64 {
+        self.burst.map_or(self.requests_per_second.ceil(), f64::from).max(1.0)
+    }
+}
+
+struct Bucket {
+    rate: f64,
+    capacity: f64,
+    // Negative while queued requests wait for their token
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Token buckets for every throttled service/upstream
+#[derive(Default)]
+pub struct Throttle {
+    buckets: DashMap<String, Bucket>,
+}
+
+impl Throttle {
+    /// Take a token, waiting for it when the policy queues. False when the
+    /// request is rejected.
+    pub async fn acquire(&self, key: String, policy: &UpstreamRatePolicy) -> bool {
+        if policy.requests_per_second <= 0.0 {
+            return false;
+        }
+        let wait = {
+            let (rate, capacity) = (policy.requests_per_second, policy.capacity());
+            let now = Instant::now();
+            let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+                rate,
+                capacity,
+                tokens: capacity,
+                refilled: now,
+            });
+            // Limits changed with a new policy, start from a full bucket
+            if bucket.rate != rate || bucket.capacity != capacity {
+                *bucket = Bucket { rate, capacity, tokens: capacity, refilled: now };
+            }
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+            bucket.refilled = now;
+            if bucket.tokens >= 1.0 {
+                bucket.tokens -= 1.0;
+                return true;
+            }
+            let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+            if policy.overflow == Overflow::Shed || wait > policy.queue_timeout {
+                return false;
+            }
+            // Reserve the next token, later requests queue behind this one
+            bucket.tokens -= 1.0;
+            wait
+        };
+        tokio::time::sleep(wait).await;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn policy(config: serde_json::Value) -> UpstreamRatePolicy {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_burst_passes_and_the_rest_is_shed_until_refilled() {
+        let throttle = Throttle::default();
+        let rate = policy(json!({"requests_per_second": 20, "burst": 2, "overflow": "shed"}));
+        let mut passed = 0;
+        for _ in 0..4 {
+            passed += throttle.acquire("a".into(), &rate).await as usize;
+        }
+        assert_eq!(passed, 2);
+        // Other keys have their own bucket
+        assert!(throttle.acquire("b".into(), &rate).await);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(throttle.acquire("a".into(), &rate).await);
+    }
+
+    #[tokio::test]
+    async fn queued_requests_wait_for_their_token_within_the_timeout() {
+        let throttle = Throttle::default();
+        let rate = policy(json!({"requests_per_second": 20, "burst": 1, "overflow": "queue", "queue_timeout": "120ms"}));
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert!(throttle.acquire("a".into(), &rate).await);
+        }
+        // Two tokens waited for, 50ms each
+        assert!(started.elapsed() >= Duration::from_millis(90), "{:?}", started.elapsed());
+        // The queue is full past the timeout
+        let ahead = Throttle::default();
+        for _ in 0..3 {
+            let _ = tokio::time::timeout(Duration::ZERO, ahead.acquire("a".into(), &rate)).await;
+        }
+        assert!(!ahead.acquire("a".into(), &rate).await);
+    }
+
+    #[test]
+    fn keys_follow_the_scope() {
+        let rate = policy(json!({"requests_per_second": 1}));
+        assert_eq!(rate.key("svc", "up"), "svc/up");
+        assert_eq!(policy(json!({"requests_per_second": 1, "per": "service"})).key("svc", "up"), "svc");
+        assert_eq!(rate.capacity(), 1.0);
+    }
+}
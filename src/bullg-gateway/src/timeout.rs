@@ -0,0 +1,86 @@
+use bullg_core::UpstreamCfg;
+use bullg_utils::{de_duration, de_duration_opt, ser_duration, ser_duration_opt};
+use http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::policy::PolicyError;
+
+/// Upstream deadline of a service (`type: timeout` on a service or global
+/// policy), replacing the gateway wide `upstream.timeout_ms`.
+///
+/// The deadline covers every retry attempt and, for buffered responses, the
+/// upstream body. Streamed bodies are not cut once the headers arrived. An
+/// upstream missing it is answered with `error`, 504 by default, `0s`
+/// disables the deadline.
+///
+/// `response_timeout` bounds each attempt until the response headers, also
+/// answered with `error`. `read_timeout` bounds the wait between two body
+/// chunks: a buffered body stalling that long is answered with `error`, a
+/// streamed one is ended as an interrupted stream. Both default to the
+/// gateway wide `upstream.response_timeout_ms` and `upstream.read_timeout_ms`.
+///
+/// ```yaml
+/// - id: svc-timeout
+///   type: timeout
+///   enabled: true
+///   config:
+///     timeout: 10s
+///     response_timeout: 3s
+///     read_timeout: 30s
+///     error:
+///       status_code: 504
+///       message: "upstream timeout"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimeoutPolicy {
+    #[serde(deserialize_with = "de_duration", serialize_with = "ser_duration")]
+    pub timeout: Duration,
+    #[serde(default, deserialize_with = "de_duration_opt", serialize_with = "ser_duration_opt")]
+    pub response_timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "de_duration_opt", serialize_with = "ser_duration_opt")]
+    pub read_timeout: Option<Duration>,
+    #[serde(default = "def_error")]
+    pub error: PolicyError,
+}
+
+fn def_error() -> PolicyError {
+    PolicyError::new(StatusCode::GATEWAY_TIMEOUT, "upstream timeout")
+}
+
+impl TimeoutPolicy {
+    pub const KIND: &'static str = "timeout";
+
+    /// The gateway wide deadline
+    pub fn from_config(cfg: &UpstreamCfg) -> Self {
+        Self {
+            timeout: Duration::from_millis(cfg.timeout_ms),
+            response_timeout: None,
+            read_timeout: None,
+            error: def_error(),
+        }
+        .inherit(cfg)
+    }
+
+    /// Take the gateway wide response and read timeouts the policy leaves out
+    pub fn inherit(mut self, cfg: &UpstreamCfg) -> Self {
+        self.response_timeout = self.response_timeout.or(Some(Duration::from_millis(cfg.response_timeout_ms)));
+        self.read_timeout = self.read_timeout.or(Some(Duration::from_millis(cfg.read_timeout_ms)));
+        self
+    }
+
+    /// None when the upstream may take as long as it wants
+    pub fn deadline(&self) -> Option<Duration> {
+        Some(self.timeout).filter(|t| !t.is_zero())
+    }
+
+    /// Longest wait for the response headers of one attempt
+    pub fn response_timeout(&self) -> Option<Duration> {
+        self.response_timeout.filter(|t| !t.is_zero())
+    }
+
+    /// Longest wait between two upstream body chunks
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout.filter(|t| !t.is_zero())
+    }
+}
@@ -0,0 +1,119 @@
+use bytes::Bytes;
+use http::{HeaderMap, HeaderValue, Method, Response, StatusCode, header};
+use hyper::upgrade::OnUpgrade;
+use hyper_util::rt::tokio::TokioIo;
+use std::time::Instant;
+use tracing::{debug, error, info};
+use url::Url;
+
+use crate::{Gateway, GatewayBody, full, headers, simple, strip_hop_by_hop};
+
+/// Whether the request asks for a protocol upgrade (`Connection: Upgrade`)
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.contains_key(header::UPGRADE)
+        && headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+impl Gateway {
+    /// Relay an upgrade request to the upstream. On `101 Switching Protocols`
+    /// the client and upstream connections are tunnelled until either side
+    /// closes, any other answer is returned as a regular response.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn proxy_upgrade<H: Send + 'static>(
+        &self,
+        protocol: HeaderValue,
+        method: &Method,
+        url: Url,
+        mut headers: HeaderMap,
+        inbound: OnUpgrade,
+        hold: H,
+        request_id: &str,
+        start: Instant,
+    ) -> Response<GatewayBody> {
+        // Hop-by-hop headers were stripped, put back the ones the upgrade needs
+        headers.insert(header::UPGRADE, protocol);
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+
+        debug!("upstream upgrade request: {} {} {:?}", method, url, headers);
+        let resp = match self
+            .upgrade_client
+            .request(method.clone(), url.as_str())
+            .headers(headers)
+            .send()
+            .await
+        {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("upstream upgrade error: {e}");
+                return self.default_headers(
+                    simple(StatusCode::BAD_GATEWAY, Bytes::from_static(b"upstream error")),
+                    request_id,
+                    start,
+                );
+            }
+        };
+
+        let status = resp.status();
+        let mut resp_headers = resp.headers().clone();
+        headers::strip(&self.config.headers, &mut resp_headers);
+        if status != StatusCode::SWITCHING_PROTOCOLS {
+            strip_hop_by_hop(&mut resp_headers);
+            let bytes = resp.bytes().await.unwrap_or_default();
+            let mut out = simple(status, bytes);
+            out.headers_mut().extend(resp_headers);
+            return self.default_headers(out, request_id, start);
+        }
+
+        let upstream = match resp.upgrade().await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                error!("upstream upgrade failed: {e}");
+                return self.default_headers(
+                    simple(StatusCode::BAD_GATEWAY, Bytes::from_static(b"upstream error")),
+                    request_id,
+                    start,
+                );
+            }
+        };
+
+        let id = request_id.to_string();
+        // A tunnel has no way to close gracefully, it runs until the drain timeout
+        let mut guard = self.drain.guard();
+        let metrics = self.metrics.clone();
+        tokio::spawn(async move {
+            let _hold = hold;
+            let client = match inbound.await {
+                Ok(client) => client,
+                Err(e) => {
+                    error!("client upgrade failed for {}: {e}", id);
+                    return;
+                }
+            };
+            let mut client = TokioIo::new(client);
+            let mut upstream = upstream;
+            tokio::select! {
+                copied = tokio::io::copy_bidirectional(&mut client, &mut upstream) => match copied {
+                    Ok((up, down)) => info!("tunnel {} closed: {} bytes up, {} bytes down", id, up, down),
+                    Err(e) => debug!("tunnel {} closed: {e}", id),
+                },
+                _ = guard.forced() => {
+                    metrics.connection_force_closed();
+                    info!("tunnel {} closed on shutdown", id);
+                }
+            }
+        });
+
+        // Upgrade and Connection are kept, the tunnel starts once this is sent
+        let mut out = Response::new(full(Bytes::new()));
+        *out.status_mut() = status;
+        resp_headers.remove(header::CONTENT_LENGTH);
+        resp_headers.remove(header::TRANSFER_ENCODING);
+        *out.headers_mut() = resp_headers;
+        self.default_headers(out, request_id, start)
+    }
+}
@@ -0,0 +1,314 @@
+use anyhow::{Result, bail};
+use opentelemetry::logs::{AnyValue, LogRecord as _, Logger as _, LoggerProvider as _, Severity};
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::logs::{SdkLogger, SdkLoggerProvider};
+use serde::Serialize;
+use std::io::{BufWriter, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{SyncSender, TrySendError};
+
+use crate::rolling::{RollingFile, Rotation};
+
+// Lines waiting for the writer thread, further ones are dropped
+const QUEUE_LINES: usize = 8192;
+
+/// One served request, the same fields in every format
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessRecord {
+    pub time: String,
+    pub request_id: String,
+    pub method: String,
+    pub path: String,
+    /// `HTTP/1.1`
+    pub version: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    /// Time to the upstream response headers, None when not proxied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_ip: Option<String>,
+    /// Response body size when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub referer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service_name: Option<String>,
+}
+
+/// How access records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessFormat {
+    /// `time request_id "METHOD path" status duration_ms upstream_ms client_ip`,
+    /// `-` for a missing field
+    Text,
+    /// Common Log Format followed by the request id and upstream latency
+    Common,
+    /// Combined Log Format, the common one with referer and user agent,
+    /// followed by the request id and upstream latency
+    Combined,
+    /// One JSON object per line (NDJSON)
+    JsonLines,
+    /// OTLP log records sent to the OTLP endpoint
+    Otlp,
+}
+
+impl AccessFormat {
+    /// `text`, `common`, `combined`, `json` or `jsonlines`, `otlp`
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(AccessFormat::Text),
+            "common" => Ok(AccessFormat::Common),
+            "combined" => Ok(AccessFormat::Combined),
+            "json" | "jsonlines" | "ndjson" => Ok(AccessFormat::JsonLines),
+            "otlp" => Ok(AccessFormat::Otlp),
+            other => bail!("unknown access log format {:?}", other),
+        }
+    }
+}
+
+enum Sink {
+    /// Written by a thread of its own, requests never wait on the disk
+    Lines { queue: SyncSender<String>, dropped: Arc<AtomicU64> },
+    Otlp { logger: SdkLogger, _provider: SdkLoggerProvider },
+}
+
+/// Access log sink, writing to stdout or a file rotated by size, or
+/// exporting over OTLP. Lines are dropped, and the count logged, while the
+/// writer is behind by more than a few thousand lines.
+pub struct AccessLogger {
+    format: AccessFormat,
+    sink: Sink,
+}
+
+impl AccessLogger {
+    /// `path` is `console` for stdout or a file appended to and rotated as
+    /// `rotation` says, `otlp_endpoint` is only used by the OTLP format
+    pub fn new(
+        format: AccessFormat,
+        path: &str,
+        rotation: Rotation,
+        otlp_endpoint: Option<&str>,
+        service_name: &str,
+    ) -> Result<Self> {
+        let sink = match format {
+            AccessFormat::Otlp => {
+                let Some(endpoint) = otlp_endpoint.filter(|e| !e.is_empty()) else {
+                    bail!("the otlp access log format needs an otlp endpoint");
+                };
+                let exporter = opentelemetry_otlp::LogExporter::builder()
+                    .with_http()
+                    .with_protocol(Protocol::HttpBinary)
+                    .with_endpoint(endpoint)
+                    .build()?;
+                let provider = SdkLoggerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(Resource::builder().with_service_name(service_name.to_string()).build())
+                    .build();
+                Sink::Otlp { logger: provider.logger("access"), _provider: provider }
+            }
+            _ if path.is_empty() || path == "console" => lines(Box::new(std::io::stdout()))?,
+            _ => lines(Box::new(RollingFile::open(path, rotation)?))?,
+        };
+        Ok(Self { format, sink })
+    }
+
+    pub fn format(&self) -> AccessFormat {
+        self.format
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        match &self.sink {
+            Sink::Lines { queue, dropped } => match queue.try_send(self.line(record)) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    dropped.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Disconnected(_)) => tracing::warn!("access log writer stopped"),
+            },
+            Sink::Otlp { logger, .. } => {
+                let mut rec = logger.create_log_record();
+                rec.set_severity_number(Severity::Info);
+                rec.set_severity_text("INFO");
+                rec.set_event_name("access");
+                rec.set_body(AnyValue::from(format!("{} {} {}", record.method, record.path, record.status)));
+                rec.add_attribute("time", record.time.clone());
+                rec.add_attribute("request_id", record.request_id.clone());
+                rec.add_attribute("method", record.method.clone());
+                rec.add_attribute("path", record.path.clone());
+                rec.add_attribute("status", i64::from(record.status));
+                rec.add_attribute("duration_ms", record.duration_ms);
+                if let Some(ms) = record.upstream_ms {
+                    rec.add_attribute("upstream_ms", ms);
+                }
+                if let Some(ip) = &record.client_ip {
+                    rec.add_attribute("client_ip", ip.clone());
+                }
+                if let Some(name) = &record.service_name {
+                    rec.add_attribute("service_name", name.clone());
+                }
+                logger.emit(rec);
+            }
+        }
+    }
+
+    fn line(&self, record: &AccessRecord) -> String {
+        let dash = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        let upstream = record.upstream_ms.map(|ms| format!("{ms:.3}"));
+        let client = || dash(record.client_ip.clone());
+        match self.format {
+            AccessFormat::Text => format!(
+                "{} {} \"{} {}\" {} {:.3}ms {} {}",
+                record.time,
+                record.request_id,
+                record.method,
+                record.path,
+                record.status,
+                record.duration_ms,
+                dash(upstream.map(|ms| ms + "ms")),
+                client()
+            ),
+            AccessFormat::Common | AccessFormat::Combined => {
+                let time = chrono::DateTime::parse_from_rfc3339(&record.time)
+                    .map(|t| t.format("%d/%b/%Y:%H:%M:%S %z").to_string())
+                    .unwrap_or_else(|_| record.time.clone());
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    client(),
+                    time,
+                    record.method,
+                    record.path,
+                    record.version,
+                    record.status,
+                    dash(record.bytes.map(|b| b.to_string()))
+                );
+                if self.format == AccessFormat::Combined {
+                    let quoted = |v: &Option<String>| v.as_deref().unwrap_or("-").replace('"', "\\\"");
+                    line += &format!(" \"{}\" \"{}\"", quoted(&record.referer), quoted(&record.user_agent));
+                }
+                line + &format!(" {} {}", record.request_id, dash(upstream))
+            }
+            _ => serde_json::to_string(record).unwrap_or_default(),
+        }
+    }
+}
+
+/// Queue of a writer thread, it flushes whenever the queue runs empty
+fn lines(out: Box<dyn Write + Send>) -> Result<Sink> {
+    let (queue, pending) = std::sync::mpsc::sync_channel::<String>(QUEUE_LINES);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let lost = dropped.clone();
+    std::thread::Builder::new().name("access-log".into()).spawn(move || {
+        let mut out = BufWriter::new(out);
+        while let Ok(line) = pending.recv() {
+            for line in std::iter::once(line).chain(pending.try_iter()) {
+                if let Err(e) = writeln!(out, "{line}") {
+                    tracing::warn!("failed to write access log: {e}");
+                }
+            }
+            if let Err(e) = out.flush() {
+                tracing::warn!("failed to write access log: {e}");
+            }
+            let lost = lost.swap(0, Ordering::Relaxed);
+            if lost > 0 {
+                tracing::warn!("access log behind, {lost} lines dropped");
+            }
+        }
+    })?;
+    Ok(Sink::Lines { queue, dropped })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEVER: Rotation = Rotation { max_size: 0, max_backups: 0, max_age: None, compress: false };
+
+    fn record(status: u16) -> AccessRecord {
+        AccessRecord {
+            time: "2024-05-01T10:00:00+00:00".into(),
+            request_id: format!("req-{status}"),
+            method: "GET".into(),
+            path: "/api/users".into(),
+            version: "HTTP/1.1".into(),
+            status,
+            duration_ms: 1.5,
+            upstream_ms: Some(1.25),
+            client_ip: Some("10.0.0.1".into()),
+            bytes: None,
+            referer: None,
+            user_agent: Some("curl/8".into()),
+            service_name: Some("users".into()),
+        }
+    }
+
+    #[test]
+    fn json_lines_are_one_object_per_line_without_missing_fields() {
+        let path = std::env::temp_dir().join(format!("bullg-access-{}.log", std::process::id()));
+        let logger = AccessLogger::new(AccessFormat::JsonLines, path.to_str().unwrap(), NEVER, None, "bullg").unwrap();
+        logger.log(&record(200));
+        logger.log(&record(502));
+        drop(logger);
+
+        let mut written = String::new();
+        for _ in 0..200 {
+            written = std::fs::read_to_string(&path).unwrap();
+            if written.lines().count() == 2 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = written.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 2, "{written}");
+        assert_eq!(
+            lines[0],
+            serde_json::json!({
+                "time": "2024-05-01T10:00:00+00:00",
+                "request_id": "req-200",
+                "method": "GET",
+                "path": "/api/users",
+                "version": "HTTP/1.1",
+                "status": 200,
+                "duration_ms": 1.5,
+                "upstream_ms": 1.25,
+                "client_ip": "10.0.0.1",
+                "user_agent": "curl/8",
+                "service_name": "users",
+            })
+        );
+        assert_eq!(lines[1]["status"], 502);
+    }
+
+    #[test]
+    fn text_formats_dash_missing_fields() {
+        let logger = |format| AccessLogger::new(format, "console", NEVER, None, "bullg").unwrap();
+        let mut rec = record(200);
+        rec.upstream_ms = None;
+        assert_eq!(
+            logger(AccessFormat::Text).line(&rec),
+            "2024-05-01T10:00:00+00:00 req-200 \"GET /api/users\" 200 1.500ms - 10.0.0.1"
+        );
+        assert_eq!(
+            logger(AccessFormat::Combined).line(&rec),
+            "10.0.0.1 - - [01/May/2024:10:00:00 +0000] \"GET /api/users HTTP/1.1\" 200 - \"-\" \"curl/8\" req-200 -"
+        );
+    }
+
+    #[tokio::test]
+    async fn otlp_needs_an_endpoint_and_starts_the_exporter() {
+        assert!(AccessLogger::new(AccessFormat::Otlp, "", NEVER, None, "bullg").is_err());
+        let logger =
+            AccessLogger::new(AccessFormat::Otlp, "", NEVER, Some("http://127.0.0.1:4318/v1/logs"), "bullg").unwrap();
+        assert!(matches!(logger.sink, Sink::Otlp { .. }));
+        // Records are batched, an unreachable collector never fails a request
+        logger.log(&record(200));
+        assert_eq!(AccessFormat::parse("NDJSON").unwrap(), AccessFormat::JsonLines);
+        assert!(AccessFormat::parse("xml").is_err());
+    }
+}
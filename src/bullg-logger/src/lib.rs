@@ -1,3 +1,5 @@
+pub mod access;
+pub mod rolling;
 
 use reqwest::Client;
 use tracing::info;
@@ -0,0 +1,198 @@
+use anyhow::Result;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// When a log file is rotated and how long its backups are kept
+#[derive(Debug, Clone, Copy)]
+pub struct Rotation {
+    /// Size in bytes above which the file is rotated, 0 never rotates
+    pub max_size: u64,
+    /// Backups kept, 0 keeps all
+    pub max_backups: u32,
+    /// Age past which backups are removed, None keeps them
+    pub max_age: Option<Duration>,
+    /// Gzip backups
+    pub compress: bool,
+}
+
+/// File appended to and rotated by size. A full file is renamed
+/// `<stem>-<utc time>.<ext>`, gzipped after that when `compress` is set, and
+/// a new one started. Backups above `max_backups` or older than `max_age`
+/// are removed at each rotation.
+pub struct RollingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+}
+
+impl RollingFile {
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation) -> Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, rotation, file, size })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%S%.3f");
+        let (stem, ext) = self.name_parts();
+        let backup = self.path.with_file_name(format!("{stem}-{stamp}{ext}"));
+        fs::rename(&self.path, &backup)?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        if self.rotation.compress
+            && let Err(e) = gzip(&backup)
+        {
+            tracing::warn!("failed to compress access log backup {}: {e}", backup.display());
+        }
+        self.prune();
+        Ok(())
+    }
+
+    // File name without and with its extension, `access` and `.log`
+    fn name_parts(&self) -> (String, String) {
+        let stem = self.path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let ext = self.path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+        (stem, ext)
+    }
+
+    fn prune(&self) {
+        let (stem, ext) = self.name_parts();
+        let prefix = format!("{stem}-");
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        let mut backups: Vec<(String, PathBuf)> = entries
+            .flatten()
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let stamp = name.strip_prefix(&prefix)?;
+                let stamp = stamp.strip_suffix(".gz").unwrap_or(stamp).strip_suffix(ext.as_str())?;
+                // A sibling file sharing the stem is not a backup
+                stamp.starts_with(|c: char| c.is_ascii_digit()).then(|| (name.clone(), e.path()))
+            })
+            .collect();
+        // Newest first, the time stamps sort by name
+        backups.sort_by(|a, b| b.0.cmp(&a.0));
+        let now = SystemTime::now();
+        for (i, (_, path)) in backups.iter().enumerate() {
+            let surplus = self.rotation.max_backups > 0 && i >= self.rotation.max_backups as usize;
+            let expired = self.rotation.max_age.is_some_and(|max| {
+                fs::metadata(path)
+                    .and_then(|m| m.modified())
+                    .is_ok_and(|at| now.duration_since(at).is_ok_and(|age| age > max))
+            });
+            if (surplus || expired)
+                && let Err(e) = fs::remove_file(path)
+            {
+                tracing::warn!("failed to remove access log backup {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.rotation.max_size > 0 && self.size > 0 && self.size + buf.len() as u64 > self.rotation.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Replace `path` by `path.gz`
+fn gzip(path: &Path) -> io::Result<()> {
+    let mut gz_name = path.as_os_str().to_owned();
+    gz_name.push(".gz");
+    let mut input = File::open(path)?;
+    let mut encoder = GzEncoder::new(File::create(&gz_name)?, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn backups(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .filter(|name| name != "access.log")
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn full_files_rotate_into_gzipped_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let rotation = Rotation { max_size: 32, max_backups: 2, max_age: None, compress: true };
+        let mut file = RollingFile::open(&path, rotation).unwrap();
+        for line in ["first line of twenty\n", "second line of twent\n", "third line of twenty\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+            // Backups are named after the time to the millisecond
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        file.write_all(b"fourth line of twent\n").unwrap();
+        file.flush().unwrap();
+
+        // Three rotations, the oldest backup is pruned
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line of twent\n");
+        let names = backups(dir.path());
+        assert_eq!(names.len(), 2, "{names:?}");
+        let mut lines = Vec::new();
+        for name in &names {
+            assert!(name.starts_with("access-") && name.ends_with(".log.gz"), "{name}");
+            let mut line = String::new();
+            GzDecoder::new(File::open(dir.path().join(name)).unwrap()).read_to_string(&mut line).unwrap();
+            lines.push(line);
+        }
+        assert_eq!(lines, ["second line of twent\n", "third line of twenty\n"]);
+    }
+
+    #[test]
+    fn backups_stay_plain_without_compress_and_unpruned_without_a_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        fs::write(dir.path().join("access-notes.log"), "not a backup").unwrap();
+        let rotation = Rotation { max_size: 10, max_backups: 0, max_age: None, compress: false };
+        let mut file = RollingFile::open(&path, rotation).unwrap();
+        for i in 0..4 {
+            file.write_all(format!("line {i} ....\n").as_bytes()).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        let names = backups(dir.path());
+        assert_eq!(names.len(), 4, "{names:?}");
+        assert!(names.contains(&"access-notes.log".to_string()));
+        let backups: Vec<String> = names
+            .iter()
+            .filter(|n| *n != "access-notes.log")
+            .map(|n| fs::read_to_string(dir.path().join(n)).unwrap())
+            .collect();
+        assert_eq!(backups, ["line 0 ....\n", "line 1 ....\n", "line 2 ....\n"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line 3 ....\n");
+    }
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use bytes::Bytes;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+/// Request body the gateway spilled to a temp file because it was above the
+/// in memory buffering threshold. The file is removed once the last clone is
+/// dropped, at the latest when the request is done.
+#[derive(Clone)]
+pub struct SpilledBody {
+    file: Arc<NamedTempFile>,
+    len: u64,
+}
+
+impl SpilledBody {
+    pub fn new(file: NamedTempFile, len: u64) -> Self {
+        Self { file: Arc::new(file), len }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// New handle reading the body from the start
+    pub fn open(&self) -> Result<File> {
+        Ok(self.file.reopen()?)
+    }
+}
+
+/// Reader over the request body, whether buffered in memory or on disk
+pub enum BodyReader {
+    Memory(Cursor<Bytes>),
+    Disk(File),
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            BodyReader::Memory(r) => r.read(buf),
+            BodyReader::Disk(r) => r.read(buf),
+        }
+    }
+}
@@ -1,22 +1,43 @@
+pub mod body;
 
-use anyhow::Result;
+pub use body::*;
+pub use async_trait::async_trait;
+
+use anyhow::{bail, Context, Result};
+use bullg_core::{AsyncMemory, InvalidUtf8, LoadStats};
 use bytes::Bytes;
 use http::{HeaderMap, Method, StatusCode, Uri};
 use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::net::SocketAddr;
+use std::str::FromStr;
 use std::sync::Arc;
+use tracing::warn;
 use uuid::Uuid;
 use http::header::HeaderName;
 
 #[derive(Clone)]
 pub struct BullGTools {
     pub client: reqwest::Client,
+    /// Gateway store, None for contexts created outside the gateway
+    pub store: Option<AsyncMemory>,
+    /// Live load of the gateway node, None outside the gateway
+    pub load: Option<Arc<LoadStats>>,
 }
 
 impl BullGTools {
     pub fn new() -> Self {
         let client = reqwest::Client::new();
-        Self { client }
+        Self { client, store: None, load: None }
+    }
+    pub fn with_store(store: AsyncMemory) -> Self {
+        Self { client: reqwest::Client::new(), store: Some(store), load: None }
+    }
+    pub fn with_load(mut self, load: Arc<LoadStats>) -> Self {
+        self.load = Some(load);
+        self
     }
     pub async fn httpx_get(&self, url: &str) -> Result<String> {
         let resp = self.client.get(url).send().await?;
@@ -52,65 +73,399 @@ impl BullGTools {
     }
 }
 
+impl Default for BullGTools {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct UserVars(serde_json::Value);
 
+impl UserVars {
+    pub fn get(&self, k: &str) -> Option<&serde_json::Value> {
+        self.0.get(k)
+    }
+    pub fn set(&mut self, k: &str, v: serde_json::Value) {
+        if !self.0.is_object() {
+            self.0 = serde_json::Value::Object(Default::default());
+        }
+        if let Some(map) = self.0.as_object_mut() {
+            map.insert(k.to_string(), v);
+        }
+    }
+}
+
+/// Verified client certificate of an mTLS connection
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientCert {
+    /// Subject distinguished name, e.g. `CN=orders,O=acme`
+    pub subject: String,
+    /// DNS, URI and IP subject alternative names
+    pub sans: Vec<String>,
+    /// Lowercase hex SHA-256 of the subject public key info
+    pub spki_sha256: String,
+}
+
+/// Service version and route a request was routed to
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatchedRoute {
+    pub service: String,
+    /// Version of the service the matched context path serves, empty when
+    /// the service has none
+    pub version: String,
+    pub route: String,
+    /// Whether the gateway rejects the request without an authenticated
+    /// consumer once the service and route plugins ran
+    pub require_auth: bool,
+}
+
+/// Var where auth plugins put the id of the consumer they identified
+pub const CONSUMER_ID_VAR: &str = "consumer_id";
+
 #[derive(Clone)]
 pub struct BullGContext {
     pub id: Uuid,
     pub method: Method,
     pub uri: Uri,
     pub headers: Arc<RwLock<HeaderMap>>,
+    // Query sent upstream, starts as the one of `uri`
+    query: Arc<RwLock<Option<String>>>,
+    // Route parameters, set once the request is routed
+    params: Arc<RwLock<HashMap<String, String>>>,
+    // Set once the request is routed
+    matched: Arc<RwLock<Option<Arc<MatchedRoute>>>>,
+    // Upstream URL of the request, set for the Intermediate phase
+    upstream_url: Arc<RwLock<Option<String>>>,
+    // Request headers once `headers` hold the upstream response ones
+    request_headers: Arc<RwLock<Option<HeaderMap>>>,
     pub body: Arc<RwLock<Bytes>>,
     pub status: Arc<RwLock<Option<StatusCode>>>,
     pub vars: Arc<RwLock<UserVars>>,
     pub tools: Arc<BullGTools>,
+    /// Headers added to the client response, whatever phase sets them
+    pub response_headers: Arc<RwLock<HeaderMap>>,
+    request_id: Arc<RwLock<String>>,
+    // Request body on disk, `body` is empty while it is set
+    spilled: Arc<RwLock<Option<SpilledBody>>>,
+    // Post phase of a response streamed to the client
+    streamed: Arc<RwLock<bool>>,
+    invalid_utf8: InvalidUtf8,
+    invalid_header: Arc<RwLock<Option<String>>>,
+    client_cert: Option<Arc<ClientCert>>,
+    client_addr: Option<SocketAddr>,
 }
 
 impl BullGContext {
     pub fn new(method: Method, uri: Uri, headers: HeaderMap, body: Bytes) -> Self {
+        Self::with_tools(method, uri, headers, body, Arc::new(BullGTools::new()))
+    }
+
+    /// Context sharing the gateway tools (http client, store) across requests
+    pub fn with_tools(method: Method, uri: Uri, headers: HeaderMap, body: Bytes, tools: Arc<BullGTools>) -> Self {
+        let id = Uuid::new_v4();
         Self {
-            id: Uuid::new_v4(),
+            id,
+            request_id: Arc::new(RwLock::new(id.to_string())),
+            response_headers: Arc::new(RwLock::new(HeaderMap::new())),
+            spilled: Arc::new(RwLock::new(None)),
+            streamed: Arc::new(RwLock::new(false)),
+            invalid_utf8: InvalidUtf8::default(),
+            invalid_header: Arc::new(RwLock::new(None)),
+            client_cert: None,
+            client_addr: None,
+            query: Arc::new(RwLock::new(uri.query().map(str::to_string))),
+            request_headers: Arc::new(RwLock::new(None)),
+            params: Arc::new(RwLock::new(HashMap::new())),
+            matched: Arc::new(RwLock::new(None)),
+            upstream_url: Arc::new(RwLock::new(None)),
             method,
             uri,
             headers: Arc::new(RwLock::new(headers)),
             body: Arc::new(RwLock::new(body)),
             status: Arc::new(RwLock::new(None)),
             vars: Arc::new(RwLock::new(UserVars::default())),
-            tools: Arc::new(BullGTools::new()),
+            tools,
         }
     }
 
+    /// How `header_get` treats values that are not valid UTF-8
+    pub fn with_invalid_utf8(mut self, mode: InvalidUtf8) -> Self {
+        self.invalid_utf8 = mode;
+        self
+    }
+
+    /// Client certificate the TLS listener verified for this connection
+    pub fn with_client_cert(mut self, cert: ClientCert) -> Self {
+        self.client_cert = Some(Arc::new(cert));
+        self
+    }
+    /// None on plain connections and when the client sent no certificate
+    pub fn client_cert(&self) -> Option<&ClientCert> {
+        self.client_cert.as_deref()
+    }
+
+    /// Peer address of the client connection
+    pub fn with_client_addr(mut self, addr: SocketAddr) -> Self {
+        self.client_addr = Some(addr);
+        self
+    }
+    /// None for contexts created outside a served connection
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
     pub fn get_id(&self) -> Uuid {
         self.id
     }
 
+    /// Request id shown to the client and the upstream, `id` unless the
+    /// gateway reused an inbound one
+    pub fn request_id(&self) -> String {
+        self.request_id.read().clone()
+    }
+    pub fn set_request_id(&self, id: &str) {
+        *self.request_id.write() = id.to_string();
+    }
+
+    pub fn response_header_put(&self, k: &str, v: &str) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(k.as_bytes()), v.parse()) {
+            self.response_headers.write().insert(name, value);
+        }
+    }
+
+    /// Header value as UTF-8. A value that is not valid UTF-8 reads as None,
+    /// and fails the request with 400 once the plugin returns when the
+    /// gateway rejects those
     pub fn header_get(&self, k: &str) -> Option<String> {
-        self.headers.read().get(k).map(|v| v.to_str().ok()).flatten().map(|s| s.to_string())
+        let raw = self.header_get_bytes(k)?;
+        match std::str::from_utf8(&raw) {
+            Ok(v) => Some(v.to_string()),
+            Err(_) => {
+                warn!("request {}: header {} is not valid UTF-8: {:?}", self.request_id(), k, raw);
+                if self.invalid_utf8 == InvalidUtf8::Reject {
+                    *self.invalid_header.write() = Some(k.to_string());
+                }
+                None
+            }
+        }
+    }
+    /// Header a plugin read that was not valid UTF-8, under `InvalidUtf8::Reject`
+    pub fn invalid_header(&self) -> Option<String> {
+        self.invalid_header.read().clone()
+    }
+    /// Raw header value, whatever its encoding
+    pub fn header_get_bytes(&self, k: &str) -> Option<Bytes> {
+        self.headers.read().get(k).map(|v| Bytes::copy_from_slice(v.as_bytes()))
     }
-    pub fn header_put(&self, k: &str, v: &str) {
-        self.headers.write().insert(HeaderName::from_bytes(k.as_bytes()).unwrap(), v.parse().unwrap());
+    /// Set a request header, failing for a name or value a header cannot hold
+    pub fn header_put(&self, k: &str, v: &str) -> Result<()> {
+        let name = HeaderName::from_bytes(k.as_bytes()).with_context(|| format!("invalid header name {k:?}"))?;
+        let value = v.parse().with_context(|| format!("invalid value for header {k}"))?;
+        self.headers.write().insert(name, value);
+        Ok(())
     }
 
     pub fn set_headers(&self, headers: HeaderMap) {
         *self.headers.write() = headers;
     }
 
+    /// Swap in the upstream response headers, the request ones stay
+    /// readable through `request_header_get`
+    pub fn set_response_headers(&self, headers: HeaderMap) {
+        let request = std::mem::replace(&mut *self.headers.write(), headers);
+        *self.request_headers.write() = Some(request);
+    }
+    /// Request header value, in the post phase as well where `headers` hold
+    /// the response ones. None when it is not valid UTF-8.
+    pub fn request_header_get(&self, k: &str) -> Option<String> {
+        match &*self.request_headers.read() {
+            Some(headers) => headers.get(k).and_then(|v| v.to_str().ok()).map(str::to_string),
+            None => self.headers.read().get(k).and_then(|v| v.to_str().ok()).map(str::to_string),
+        }
+    }
+
     pub fn header_remove(&self, k: &str) {
         self.headers.write().remove(k);
     }
+    /// Query string sent upstream, the one of `uri` unless a plugin set another
+    pub fn query(&self) -> Option<String> {
+        self.query.read().clone()
+    }
+    /// Replace the query string sent upstream, an empty one drops it
+    pub fn set_query(&self, query: &str) {
+        *self.query.write() = Some(query).filter(|q| !q.is_empty()).map(str::to_string);
+    }
+    /// Value of a `{name}` segment of the matched route path, e.g. `id` of
+    /// `/users/{id}`. Global plugins running before routing see none.
+    pub fn param_get(&self, name: &str) -> Option<String> {
+        self.params.read().get(name).cloned()
+    }
+    pub fn params(&self) -> HashMap<String, String> {
+        self.params.read().clone()
+    }
+    pub fn set_params(&self, params: HashMap<String, String>) {
+        *self.params.write() = params;
+    }
+    /// Route the request matched, None for global plugins running before
+    /// routing
+    pub fn matched_route(&self) -> Option<Arc<MatchedRoute>> {
+        self.matched.read().clone()
+    }
+    pub fn set_matched_route(&self, route: MatchedRoute) {
+        *self.matched.write() = Some(Arc::new(route));
+    }
+    /// URL the request is sent to, query included. Only set from the
+    /// Intermediate phase on, where plugins may point it elsewhere.
+    pub fn upstream_url(&self) -> Option<String> {
+        self.upstream_url.read().clone()
+    }
+    pub fn set_upstream_url(&self, url: &str) {
+        *self.upstream_url.write() = Some(url.to_string());
+    }
     pub fn set_status(&self, code: StatusCode) {
         *self.status.write() = Some(code);
     }
-    pub fn get_body(&self) -> Bytes { self.body.read().clone() }
-    pub fn set_body(&self, b: Bytes) { *self.body.write() = b; }
+    pub fn var_get(&self, k: &str) -> Option<serde_json::Value> {
+        self.vars.read().get(k).cloned()
+    }
+    pub fn var_set(&self, k: &str, v: serde_json::Value) {
+        self.vars.write().set(k, v);
+    }
+    /// Consumer an auth plugin identified, see `CONSUMER_ID_VAR`
+    pub fn consumer_id(&self) -> Option<String> {
+        self.var_get(CONSUMER_ID_VAR)
+            .and_then(|v| v.as_str().map(str::to_string))
+            .filter(|id| !id.is_empty())
+    }
+    /// Body bytes, a spilled request body is read into memory, prefer
+    /// `body_reader` for bodies that may be large
+    pub fn get_body(&self) -> Bytes {
+        if let Some(spilled) = self.spilled() {
+            let mut buf = Vec::with_capacity(spilled.len() as usize);
+            if let Err(e) = spilled.open().and_then(|mut f| Ok(f.read_to_end(&mut buf)?)) {
+                warn!("failed to read spilled body {}: {e}", spilled.path().display());
+            }
+            return Bytes::from(buf);
+        }
+        self.body.read().clone()
+    }
+    /// Replace the body, a spilled request body is dropped
+    pub fn set_body(&self, b: Bytes) {
+        *self.spilled.write() = None;
+        *self.body.write() = b;
+    }
+
+    /// Reader over the body from the start, whether in memory or on disk
+    pub fn body_reader(&self) -> Result<BodyReader> {
+        match self.spilled() {
+            Some(spilled) => Ok(BodyReader::Disk(spilled.open()?)),
+            None => Ok(BodyReader::Memory(Cursor::new(self.body.read().clone()))),
+        }
+    }
+    pub fn body_len(&self) -> u64 {
+        self.spilled().map_or(self.body.read().len() as u64, |s| s.len())
+    }
+    /// Request body the gateway spilled to disk, None once the body is replaced
+    pub fn spilled(&self) -> Option<SpilledBody> {
+        self.spilled.read().clone()
+    }
+    pub fn set_spilled(&self, body: SpilledBody) {
+        *self.body.write() = Bytes::new();
+        *self.spilled.write() = Some(body);
+    }
+    /// Whether the post phase runs on a streamed response: status and
+    /// headers are the upstream ones but the body goes straight to the
+    /// client, `body` is empty
+    pub fn streamed(&self) -> bool {
+        *self.streamed.read()
+    }
+    pub fn set_streamed(&self) {
+        self.set_body(Bytes::new());
+        *self.streamed.write() = true;
+    }
 }
 
+/// When a plugin runs in the request lifecycle
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum Phase { Pre, Post, Intermediate }
+pub enum Phase {
+    /// Before the upstream is picked, global plugins even before routing.
+    /// Setting a status answers the request with the context.
+    Pre,
+    /// Upstream response received, `headers` and `body` are the response
+    Post,
+    /// Route matched and upstream picked, right before the request is sent:
+    /// `headers` are the outbound ones with the forwarding headers set and
+    /// `upstream_url` can be rewritten. Setting a status answers the request
+    /// as in Pre.
+    Intermediate,
+}
+
+impl FromStr for Phase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "pre" => Ok(Phase::Pre),
+            "post" => Ok(Phase::Post),
+            "intermediate" => Ok(Phase::Intermediate),
+            _ => bail!("unknown plugin phase: {}", s),
+        }
+    }
+}
 
+/// Gateway plugin. `apply` may await, e.g. a call to an auth server or a
+/// store lookup, before the request goes on or is answered; implementations
+/// are written with `#[async_trait]`.
+#[async_trait]
 pub trait Plugin: Send + Sync {
     fn name(&self) -> &'static str;
-    fn phase(&self) -> Phase;
-    fn apply(&self, ctx: &BullGContext, config: &serde_json::Value) -> Result<()>;
+    /// Phases the plugin runs in, `apply` is called once for each of them
+    fn supported_phases(&self) -> &'static [Phase];
+    async fn apply(&self, ctx: &BullGContext, phase: Phase, config: &serde_json::Value) -> Result<()>;
+    /// Whether the plugin needs the matched route, such plugins cannot be
+    /// applied globally where they run before routing and authentication
+    fn needs_route(&self) -> bool {
+        false
+    }
+    /// Check a config before it is swapped in, plugins accept any config by default
+    fn validate(&self, _config: &serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use http::HeaderValue;
+
+    fn latin1(mode: InvalidUtf8) -> BullGContext {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-name", HeaderValue::from_bytes(b"Jos\xe9").unwrap());
+        headers.insert("x-plain", HeaderValue::from_static("ok"));
+        BullGContext::new(Method::GET, Uri::from_static("/"), headers, Bytes::new()).with_invalid_utf8(mode)
+    }
+
+    #[test]
+    fn invalid_utf8_headers_read_as_absent_or_reject_the_request() {
+        let ctx = latin1(InvalidUtf8::Ignore);
+        assert_eq!(ctx.header_get("x-name"), None);
+        assert_eq!(ctx.invalid_header(), None);
+
+        let ctx = latin1(InvalidUtf8::Reject);
+        assert_eq!(ctx.header_get("x-plain").as_deref(), Some("ok"));
+        assert_eq!(ctx.invalid_header(), None);
+        assert_eq!(ctx.header_get("x-name"), None);
+        assert_eq!(ctx.invalid_header().as_deref(), Some("x-name"));
+    }
+
+    #[test]
+    fn raw_header_bytes_stay_readable() {
+        for mode in [InvalidUtf8::Ignore, InvalidUtf8::Reject] {
+            let ctx = latin1(mode);
+            assert_eq!(ctx.header_get_bytes("x-name").unwrap(), &b"Jos\xe9"[..]);
+            assert_eq!(ctx.invalid_header(), None);
+        }
+    }
 }
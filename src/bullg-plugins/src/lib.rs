@@ -1,38 +1,162 @@
-use anyhow::{ Result };
-use bullg_plugin_api::{ BullGContext, Phase, Plugin };
+use anyhow::{ anyhow, bail, Result };
+use bullg_plugin_api::{ BullGContext, CONSUMER_ID_VAR, Phase, Plugin, async_trait };
 use bytes::Bytes;
 use http::StatusCode;
 //use tracing::info;
+use tracing::{debug, error};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
+use chrono::{DateTime, Datelike, Months, NaiveTime, TimeDelta, Timelike, Utc};
+use bullg_core::{
+    CONSUMER_INDEX_DB, CONSUMERS_DB, Cache, Consumer, CustomPluginSpec, Lang, PluginsCatalog, Runner, api_key_entry,
+    issuer_entry,
+};
+use bullg_crypto::BullGCrypto;
+use http::header::{HeaderName, HeaderValue};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Once};
+use std::time::{ Duration, Instant, SystemTime, UNIX_EPOCH };
 
+/// Cross-origin resource sharing. Responses to an allowed `Origin` get
+/// `Access-Control-Allow-Origin`, preflights (OPTIONS with
+/// `Access-Control-Request-Method`) are answered with 204 and the allowed
+/// methods, headers, max age and credentials, a preflight from an origin not
+/// allowed gets 403.
+///
+/// With only `allow_origin` set every response gets that value, as before.
+/// `allow_origins` restricts the origins and echoes the matching one, so
+/// does `allow_credentials` since browsers refuse `*` with credentials.
+/// Without `allow_headers` a preflight gets the headers it asked for. Lists
+/// can also be given as one comma separated string.
+///
+/// ```yaml
+/// type: cors
+/// config:
+///   allow_origins: ["https://app.example.com"]
+///   allow_methods: [GET, POST, PUT, DELETE]
+///   allow_headers: [authorization, content-type]
+///   expose_headers: [x-request-id]
+///   allow_credentials: true
+///   max_age: 600
+/// ```
 pub struct Cors;
+
+const CORS_DEFAULT_METHODS: &str = "GET, HEAD, PUT, PATCH, POST, DELETE";
+
+// A list of strings or one comma separated string
+fn cors_list<'a>(cfg: &'a serde_json::Value, key: &str) -> Vec<&'a str> {
+    match cfg.get(key) {
+        Some(serde_json::Value::String(s)) => s.split(',').map(str::trim).filter(|s| !s.is_empty()).collect(),
+        _ => str_list(cfg, key).collect(),
+    }
+}
+
+#[async_trait]
 impl Plugin for Cors {
     fn name(&self) -> &'static str {
         "cors"
     }
-    fn phase(&self) -> Phase {
-        Phase::Pre
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
     }
-    fn apply(&self, ctx: &BullGContext, cfg: &serde_json::Value) -> Result<()> {
-        let allow_origin = cfg
-            .get("allow_origin")
-            .and_then(|v| v.as_str())
-            .unwrap_or("*");
-        ctx.header_put("access-control-allow-origin", allow_origin);
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let credentials = cfg.get("allow_credentials").and_then(|v| v.as_bool()).unwrap_or(false);
+        let origin = ctx.header_get("origin");
+        let preflight = ctx.method == http::Method::OPTIONS
+            && origin.is_some()
+            && ctx.header_get("access-control-request-method").is_some();
+        let allowed = match (cfg.get("allow_origins"), origin.as_deref()) {
+            (Some(_), Some(origin)) => cors_list(cfg, "allow_origins").iter().any(|o| *o == "*" || *o == origin),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        if !allowed {
+            if preflight {
+                ctx.set_status(StatusCode::FORBIDDEN);
+                ctx.set_body(Bytes::from_static(b"CORS origin not allowed"));
+            }
+            return Ok(());
+        }
+        let allow_origin = match origin.as_deref() {
+            Some(origin) if credentials || cfg.get("allow_origins").is_some() => {
+                ctx.response_header_put("vary", "origin");
+                origin.to_string()
+            }
+            _ => cfg.get("allow_origin").and_then(|v| v.as_str()).unwrap_or("*").to_string(),
+        };
+        ctx.response_header_put("access-control-allow-origin", &allow_origin);
+        if credentials {
+            ctx.response_header_put("access-control-allow-credentials", "true");
+        }
+        if !preflight {
+            let expose = cors_list(cfg, "expose_headers").join(", ");
+            if !expose.is_empty() {
+                ctx.response_header_put("access-control-expose-headers", &expose);
+            }
+            return Ok(());
+        }
+        let methods = match cfg.get("allow_methods") {
+            Some(_) => cors_list(cfg, "allow_methods").join(", "),
+            None => CORS_DEFAULT_METHODS.to_string(),
+        };
+        ctx.response_header_put("access-control-allow-methods", &methods);
+        let headers = match cfg.get("allow_headers") {
+            Some(_) => cors_list(cfg, "allow_headers").join(", "),
+            None => ctx.header_get("access-control-request-headers").unwrap_or_default(),
+        };
+        if !headers.is_empty() {
+            ctx.response_header_put("access-control-allow-headers", &headers);
+        }
+        if let Some(max_age) = cfg.get("max_age").and_then(|v| v.as_u64()) {
+            ctx.response_header_put("access-control-max-age", &max_age.to_string());
+        }
+        ctx.set_status(StatusCode::NO_CONTENT);
+        ctx.set_body(Bytes::new());
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for key in ["allow_origins", "allow_methods", "allow_headers", "expose_headers"] {
+            if let Some(v) = cfg.get(key)
+                && !v.is_string()
+                && !v.as_array().is_some_and(|a| a.iter().all(|e| e.is_string()))
+            {
+                bail!("{} must be a list of strings", key);
+            }
+        }
+        if let Some(method) = cors_list(cfg, "allow_methods").into_iter().find(|m| http::Method::from_bytes(m.as_bytes()).is_err()) {
+            bail!("invalid method in allow_methods: {}", method);
+        }
+        if let Some(v) = cfg.get("allow_origin")
+            && !v.is_string()
+        {
+            bail!("allow_origin must be a string");
+        }
+        if let Some(v) = cfg.get("allow_credentials")
+            && !v.is_boolean()
+        {
+            bail!("allow_credentials must be true or false");
+        }
+        if let Some(v) = cfg.get("max_age")
+            && !v.is_u64()
+        {
+            bail!("max_age must be a number of seconds");
+        }
         Ok(())
     }
 }
 
 pub struct RequestTermination;
+#[async_trait]
 impl Plugin for RequestTermination {
     fn name(&self) -> &'static str {
         "request_termination"
     }
-    fn phase(&self) -> Phase {
-        Phase::Pre
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
     }
-    fn apply(&self, ctx: &BullGContext, cfg: &serde_json::Value) -> Result<()> {
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
         if
             cfg
                 .get("enabled")
@@ -52,33 +176,49 @@ impl Plugin for RequestTermination {
         }
         Ok(())
     }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        if let Some(status) = cfg.get("status") {
+            let code = status.as_u64().and_then(|s| u16::try_from(s).ok());
+            if code.and_then(|c| StatusCode::from_u16(c).ok()).is_none() {
+                bail!("invalid status: {}", status);
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct HttpLog;
 
+const HTTP_LOG_DEFAULT_TIMEOUT_SEC: u64 = 5;
+
+#[async_trait]
 impl Plugin for HttpLog {
     fn name(&self) -> &'static str {
         "http_log"
     }
-    fn phase(&self) -> Phase {
-        Phase::Post
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Post]
     }
-    fn apply(&self, ctx: &BullGContext, cfg: &serde_json::Value) -> Result<()> {
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
         if let Some(endpoint) = cfg.get("endpoint").and_then(|v| v.as_str()) {
-            // Own it as String so it can live inside tokio::spawn
+            // Sent in the background, a slow log endpoint never holds the response
             let endpoint = endpoint.to_string();
-
-            let client = ctx.tools.client.clone();
-
+            let timeout = cfg.get("timeout_sec").and_then(|v| v.as_u64()).unwrap_or(HTTP_LOG_DEFAULT_TIMEOUT_SEC);
+            let sent = ctx
+                .tools
+                .client
+                .post(&endpoint)
+                .timeout(Duration::from_secs(timeout.max(1)))
+                .json(
+                    &serde_json::json!({
+                "message": "Hello from plugin!"
+            })
+                )
+                .send();
             tokio::spawn(async move {
-                let _ = client
-                    .post(endpoint)
-                    .json(
-                        &serde_json::json!({
-                    "message": "Hello from plugin!"
-                })
-                    )
-                    .send().await;
+                if let Err(e) = sent.await {
+                    error!("http_log: {} unreachable: {e}", endpoint);
+                }
             });
         }
 
@@ -94,17 +234,26 @@ impl Plugin for HttpLog {
 
         Ok(())
     }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        if let Some(v) = cfg.get("timeout_sec")
+            && !v.is_u64()
+        {
+            bail!("timeout_sec must be a number of seconds");
+        }
+        Ok(())
+    }
 }
 
 pub struct BasicAuth;
+#[async_trait]
 impl Plugin for BasicAuth {
     fn name(&self) -> &'static str {
         "basic_auth"
     }
-    fn phase(&self) -> Phase {
-        Phase::Pre
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
     }
-    fn apply(&self, ctx: &BullGContext, cfg: &serde_json::Value) -> Result<()> {
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
         let expected_user = cfg
             .get("user")
             .and_then(|v| v.as_str())
@@ -116,46 +265,115 @@ impl Plugin for BasicAuth {
         if expected_user.is_empty() {
             return Ok(());
         }
-        if let Some(auth) = ctx.header_get("authorization") {
-            if let Some(b64) = auth.strip_prefix("Basic ") {
-                if let Ok(bytes) = STANDARD.decode(b64) {
-                    if let Ok(s) = String::from_utf8(bytes) {
-                        let mut parts = s.splitn(2, ':');
-                        let u = parts.next().unwrap_or("");
-                        let p = parts.next().unwrap_or("");
-                        if u == expected_user && p == expected_pass {
-                            return Ok(());
-                        }
-                    }
-                }
+        if let Some(auth) = ctx.header_get("authorization")
+            && let Some(b64) = auth.strip_prefix("Basic ")
+            && let Ok(bytes) = STANDARD.decode(b64)
+            && let Ok(s) = String::from_utf8(bytes)
+        {
+            let mut parts = s.splitn(2, ':');
+            let u = parts.next().unwrap_or("");
+            let p = parts.next().unwrap_or("");
+            // Both compared in full, how far either matched is not timed
+            let user_ok = constant_eq(u.as_bytes(), expected_user.as_bytes());
+            if user_ok & constant_eq(p.as_bytes(), expected_pass.as_bytes()) {
+                // A client sent id must never pass for the verified one
+                ctx.header_remove("x-consumer-id");
+                ctx.var_set(CONSUMER_ID_VAR, serde_json::Value::String(u.to_string()));
+                ctx.header_put("x-consumer-id", u)?;
+                return Ok(());
             }
         }
-        ctx.set_status(StatusCode::UNAUTHORIZED);
-        ctx.set_body(
-            Bytes::from(
-                cfg
-                    .get("message")
-                    .and_then(|v| v.as_str())
-                    .unwrap_or("Unauthorized request: Failed")
-                    .as_bytes()
-                    .to_vec()
-            )
-        );
+        reject(ctx, StatusCode::UNAUTHORIZED, cfg, "Unauthorized request: Failed");
         Ok(())
     }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// Body format of an auth rejection, `error_format` in the plugin config.
+/// `auto` answers JSON to clients accepting it and plain text otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Auto,
+    Json,
+    Text,
+}
+
+impl ErrorFormat {
+    pub fn from_config(cfg: &serde_json::Value) -> Result<Self> {
+        match cfg.get("error_format").and_then(|v| v.as_str()).unwrap_or("auto") {
+            "auto" => Ok(ErrorFormat::Auto),
+            "json" => Ok(ErrorFormat::Json),
+            "text" => Ok(ErrorFormat::Text),
+            other => bail!("invalid error_format: {} (auto, json or text)", other),
+        }
+    }
+
+    fn json(self, ctx: &BullGContext) -> bool {
+        match self {
+            ErrorFormat::Json => true,
+            ErrorFormat::Text => false,
+            ErrorFormat::Auto =>
+                ctx
+                    .header_get("accept")
+                    .is_some_and(|accept| {
+                        accept
+                            .split(',')
+                            .map(|t| t.split(';').next().unwrap_or_default().trim())
+                            .any(|t| t == "application/json" || t.ends_with("+json"))
+                    }),
+        }
+    }
+}
+
+/// Terminate the request with an auth error, shared by the auth plugins so
+/// every rejection has the same shape. The plugin config `message` replaces
+/// `default_message`.
+///
+/// ```json
+/// {"error":"unauthorized","code":401,"message":"...","request_id":"..."}
+/// ```
+pub fn reject(ctx: &BullGContext, status: StatusCode, cfg: &serde_json::Value, default_message: &str) {
+    let message = cfg
+        .get("message")
+        .and_then(|v| v.as_str())
+        .unwrap_or(default_message);
+    let format = ErrorFormat::from_config(cfg).unwrap_or(ErrorFormat::Auto);
+    let (content_type, body) = if format.json(ctx) {
+        let error = status
+            .canonical_reason()
+            .unwrap_or("error")
+            .to_ascii_lowercase()
+            .replace(' ', "_");
+        let body =
+            serde_json::json!({
+            "error": error,
+            "code": status.as_u16(),
+            "message": message,
+            "request_id": ctx.request_id(),
+        });
+        ("application/json", body.to_string())
+    } else {
+        ("text/plain; charset=utf-8", message.to_string())
+    };
+    ctx.set_status(status);
+    ctx.response_header_put("content-type", content_type);
+    ctx.set_body(Bytes::from(body));
 }
 
 pub struct SecurityHeadersPlugin;
 
+#[async_trait]
 impl Plugin for SecurityHeadersPlugin {
     fn name(&self) -> &'static str {
         "security_headers"
     }
-    fn phase(&self) -> Phase {
-        Phase::Post
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Post]
     }
 
-    fn apply(&self, ctx: &BullGContext, _config: &serde_json::Value) -> Result<()> {
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, _config: &serde_json::Value) -> Result<()> {
         ctx.headers.write().insert("x-content-type-options", "nosniff".parse().unwrap());
         ctx.headers.write().insert("x-frame-options", "DENY".parse().unwrap());
         ctx.headers.write().insert(
@@ -166,6 +384,56 @@ impl Plugin for SecurityHeadersPlugin {
     }
 }
 
+/// Measures the time spent between the pre and post phases of a request
+pub struct Timing;
+
+const TIMING_START: &str = "timing.start_us";
+
+#[async_trait]
+impl Plugin for Timing {
+    fn name(&self) -> &'static str {
+        "timing"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre, Phase::Post]
+    }
+    async fn apply(&self, ctx: &BullGContext, phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        match phase {
+            Phase::Pre => ctx.var_set(TIMING_START, serde_json::json!(now_us())),
+            Phase::Post => {
+                let Some(start) = ctx.var_get(TIMING_START).and_then(|v| v.as_u64()) else {
+                    return Ok(());
+                };
+                let header = cfg
+                    .get("header")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("x-response-time");
+                let elapsed = now_us().saturating_sub(start);
+                ctx.var_set("timing.duration_us", serde_json::json!(elapsed));
+                ctx.header_put(header, &format!("{:.3}ms", (elapsed as f64) / 1000.0))?;
+            }
+            Phase::Intermediate => {}
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        if let Some(header) = cfg.get("header") {
+            let name = header.as_str().unwrap_or_default();
+            if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                bail!("invalid header name: {}", header);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or_default()
+}
+
 // impl Plugin for LoggingPlugin {
 //     fn name(&self) -> &str {
 //         "logging"
@@ -182,13 +450,3031 @@ impl Plugin for SecurityHeadersPlugin {
 //     }
 // }
 
-pub fn builtin() -> Vec<Box<dyn Plugin>> {
-    vec![
-        Box::new(Cors),
-        Box::new(RequestTermination),
-        Box::new(HttpLog),
-        Box::new(BasicAuth),
-        Box::new(SecurityHeadersPlugin),
-       // Box::new(LoggingPlugin),
-    ]
+/// Allows only clients whose verified mTLS certificate is listed, by subject,
+/// subject alternative name or SPKI SHA-256 pin. Requests without a client
+/// certificate are rejected as well.
+///
+/// ```yaml
+/// type: mtls_acl
+/// config:
+///   subjects: ["CN=orders,O=acme"]
+///   sans: ["spiffe://acme/orders"]
+///   spki_sha256: ["9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"]
+/// ```
+pub struct MtlsAcl;
+
+fn str_list<'a>(cfg: &'a serde_json::Value, key: &str) -> impl Iterator<Item = &'a str> {
+    cfg.get(key)
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str())
+}
+
+#[async_trait]
+impl Plugin for MtlsAcl {
+    fn name(&self) -> &'static str {
+        "mtls_acl"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let Some(cert) = ctx.client_cert() else {
+            reject(ctx, StatusCode::FORBIDDEN, cfg, "Client certificate required");
+            return Ok(());
+        };
+        let allowed = str_list(cfg, "subjects").any(|s| s == cert.subject)
+            || str_list(cfg, "sans").any(|s| cert.sans.iter().any(|san| san == s))
+            || str_list(cfg, "spki_sha256").any(|s| s.eq_ignore_ascii_case(&cert.spki_sha256));
+        if !allowed {
+            reject(ctx, StatusCode::FORBIDDEN, cfg, "Client certificate not allowed");
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for key in ["subjects", "sans", "spki_sha256"] {
+            if let Some(v) = cfg.get(key)
+                && !v.as_array().is_some_and(|a| a.iter().all(|e| e.is_string()))
+            {
+                bail!("{} must be a list of strings", key);
+            }
+        }
+        if let Some(pin) = str_list(cfg, "spki_sha256").find(|p| p.len() != 64 || !p.bytes().all(|b| b.is_ascii_hexdigit())) {
+            bail!("invalid spki_sha256 pin: {}", pin);
+        }
+        let entries = ["subjects", "sans", "spki_sha256"].iter().map(|k| str_list(cfg, k).count()).sum::<usize>();
+        if entries == 0 {
+            bail!("mtls_acl needs at least one allowed subject, san or spki_sha256");
+        }
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// Fixed window rate limit, `limit` requests per `window_sec` for each key.
+/// Requests past the limit get 429 with `Retry-After` set to the seconds
+/// left in the window, every response carries `X-RateLimit-Limit` and
+/// `X-RateLimit-Remaining`.
+///
+/// `key` is `ip` (the client connection address, the default), `consumer`
+/// (the consumer an auth plugin identified, run the plugin after the auth
+/// one, requests without a consumer are counted by client address) or
+/// `header:<name>`. Requests without a value share one counter. Counters are
+/// kept by the gateway node for as long as it runs and are shared by
+/// rate_limit plugins with the same limit, window and key, add a distinct
+/// `scope` to keep two of them apart.
+///
+/// ```yaml
+/// type: rate_limit
+/// config:
+///   limit: 100
+///   window_sec: 60
+///   key: header:x-api-key
+///   scope: orders
+/// ```
+#[derive(Default)]
+pub struct RateLimit {
+    counters: Mutex<RateCounters>,
+}
+
+#[derive(Default)]
+struct RateCounters {
+    // bucket and key value -> (window index, requests counted in it)
+    windows: HashMap<String, (u64, u64)>,
+    // Size at which counters of ended windows are dropped next
+    sweep_at: usize,
+}
+
+/// Counters kept before windows that ended are dropped
+const RATE_LIMIT_SWEEP: usize = 10_000;
+
+enum RateKey<'a> {
+    Ip,
+    Consumer,
+    Header(&'a str),
+}
+
+impl<'a> RateKey<'a> {
+    fn from_config(cfg: &'a serde_json::Value) -> Result<Self> {
+        Self::parse(cfg, "ip")
+    }
+
+    fn parse(cfg: &'a serde_json::Value, default: &'a str) -> Result<Self> {
+        match cfg.get("key").and_then(|v| v.as_str()).unwrap_or(default) {
+            "ip" => Ok(RateKey::Ip),
+            "consumer" => Ok(RateKey::Consumer),
+            key => match key.strip_prefix("header:") {
+                Some(name) if HeaderName::from_bytes(name.as_bytes()).is_ok() => Ok(RateKey::Header(name)),
+                _ => bail!("invalid key: {} (ip, consumer or header:<name>)", key),
+            },
+        }
+    }
+
+    fn value(&self, ctx: &BullGContext) -> RateValue {
+        let addr = || ctx.client_addr().map(|a| a.ip().to_string()).unwrap_or_default();
+        match self {
+            RateKey::Ip => RateValue::Key(addr()),
+            // Only the authenticated consumer, clients pick their own headers
+            RateKey::Consumer => ctx.consumer_id().map_or_else(|| RateValue::Addr(addr()), RateValue::Key),
+            RateKey::Header(name) => RateValue::Key(ctx.header_get(name).unwrap_or_default()),
+        }
+    }
+}
+
+/// What a request is counted by: the value of the configured key, or the
+/// client address for a `consumer` key without a consumer
+enum RateValue {
+    Key(String),
+    Addr(String),
+}
+
+impl RateValue {
+    fn counter(&self) -> String {
+        match self {
+            RateValue::Key(value) => value.clone(),
+            RateValue::Addr(addr) => format!("ip:{addr}"),
+        }
+    }
+}
+
+fn rate_limit_config(cfg: &serde_json::Value) -> Result<(u64, u64)> {
+    let limit = match cfg.get("limit") {
+        Some(v) => v.as_u64().filter(|l| *l > 0),
+        None => bail!("rate_limit needs a limit"),
+    };
+    let Some(limit) = limit else {
+        bail!("limit must be a positive integer");
+    };
+    let window = match cfg.get("window_sec") {
+        Some(v) => v.as_u64().filter(|w| *w > 0),
+        None => Some(60),
+    };
+    let Some(window) = window else {
+        bail!("window_sec must be a positive integer");
+    };
+    Ok((limit, window))
+}
+
+#[async_trait]
+impl Plugin for RateLimit {
+    fn name(&self) -> &'static str {
+        "rate_limit"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let (limit, window) = rate_limit_config(cfg)?;
+        let key = RateKey::from_config(cfg)?;
+        let counter = format!(
+            "{}\n{}\n{}\n{}\n{}",
+            cfg_str(cfg, "scope"),
+            limit,
+            window,
+            cfg_str(cfg, "key"),
+            key.value(ctx).counter()
+        );
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let (index, reset_ms) = (now / (window * 1000), window * 1000 - now % (window * 1000));
+        let (allowed, remaining) = {
+            let mut counters = self.counters.lock().unwrap_or_else(|e| e.into_inner());
+            let RateCounters { windows, sweep_at } = &mut *counters;
+            // Sweeps are spaced by the number of counters kept, amortized O(1)
+            if windows.len() >= (*sweep_at).max(RATE_LIMIT_SWEEP) && !windows.contains_key(&counter) {
+                windows.retain(|_, (i, _)| *i == index);
+                *sweep_at = windows.len() * 2;
+            }
+            let (i, count) = windows.entry(counter).or_insert((index, 0));
+            if *i != index {
+                (*i, *count) = (index, 0);
+            }
+            // Rejected requests are not counted
+            let allowed = *count < limit;
+            if allowed {
+                *count += 1;
+            }
+            (allowed, limit - *count)
+        };
+        ctx.response_header_put("x-ratelimit-limit", &limit.to_string());
+        ctx.response_header_put("x-ratelimit-remaining", &remaining.to_string());
+        if !allowed {
+            ctx.response_header_put("retry-after", &reset_ms.div_ceil(1000).to_string());
+            reject(ctx, StatusCode::TOO_MANY_REQUESTS, cfg, "Rate limit exceeded");
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        rate_limit_config(cfg)?;
+        RateKey::from_config(cfg)?;
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// Store database of the quota counters
+pub const QUOTAS_DB: &str = "quotas";
+
+/// Long horizon request quota, `limit` requests per calendar `period`
+/// (`hour`, `day` or `month`, in UTC) for each key. Requests past the quota
+/// get 429 with `Retry-After` set to the seconds until the period ends,
+/// every response carries `X-Quota-Limit`, `X-Quota-Remaining` and
+/// `X-Quota-Reset`.
+///
+/// `key` takes the values of rate_limit and defaults to `consumer`, the
+/// consumer an auth plugin identified: run the plugin after the auth one,
+/// requests without a consumer are counted by client address. `limits`
+/// gives some consumers, or other key values, a quota of their own. Counters live in the gateway store so they
+/// survive restarts, they are shared by quota plugins with the same `scope`.
+/// When the store fails requests are let through.
+///
+/// ```yaml
+/// type: quota
+/// config:
+///   limit: 10000
+///   period: day
+///   key: consumer
+///   scope: orders
+///   limits:
+///     partner-app: 100000
+/// ```
+pub struct Quota;
+
+#[derive(Debug, Clone, Copy)]
+enum QuotaPeriod {
+    Hour,
+    Day,
+    Month,
+}
+
+impl QuotaPeriod {
+    fn from_config(cfg: &serde_json::Value) -> Result<Self> {
+        match cfg.get("period").map(|v| v.as_str()) {
+            None | Some(Some("day")) => Ok(QuotaPeriod::Day),
+            Some(Some("hour")) => Ok(QuotaPeriod::Hour),
+            Some(Some("month")) => Ok(QuotaPeriod::Month),
+            Some(_) => bail!("period must be hour, day or month"),
+        }
+    }
+
+    /// The period `now` falls in, the one before it and the seconds left in it
+    fn window(self, now: DateTime<Utc>) -> (String, String, u64) {
+        let date = now.date_naive();
+        let (start, prev, next) = match self {
+            QuotaPeriod::Hour => {
+                let start = date.and_hms_opt(now.hour(), 0, 0).unwrap_or_default();
+                (start, start - TimeDelta::hours(1), start + TimeDelta::hours(1))
+            }
+            QuotaPeriod::Day => {
+                let start = date.and_time(NaiveTime::MIN);
+                (start, start - TimeDelta::days(1), start + TimeDelta::days(1))
+            }
+            QuotaPeriod::Month => {
+                let start = date.with_day(1).unwrap_or(date).and_time(NaiveTime::MIN);
+                let prev = start.checked_sub_months(Months::new(1)).unwrap_or(start);
+                (start, prev, start.checked_add_months(Months::new(1)).unwrap_or(start))
+            }
+        };
+        let format = match self {
+            QuotaPeriod::Hour => "%Y-%m-%dT%H",
+            QuotaPeriod::Day => "%Y-%m-%d",
+            QuotaPeriod::Month => "%Y-%m",
+        };
+        let reset = (next - now.naive_utc()).num_milliseconds().max(0) as u64;
+        (start.format(format).to_string(), prev.format(format).to_string(), reset.div_ceil(1000))
+    }
+}
+
+impl Quota {
+    async fn count(&self, ctx: &BullGContext, cfg: &serde_json::Value, now: DateTime<Utc>) -> Result<()> {
+        let period = QuotaPeriod::from_config(cfg)?;
+        let value = RateKey::parse(cfg, "consumer")?.value(ctx);
+        let own = match &value {
+            RateValue::Key(key) => cfg.get("limits").and_then(|l| l.get(key)),
+            RateValue::Addr(_) => None,
+        };
+        let limit = match own {
+            Some(limit) => limit.as_u64(),
+            None => cfg.get("limit").and_then(|v| v.as_u64()),
+        };
+        let Some(limit) = limit.filter(|l| *l > 0) else {
+            bail!("quota needs a positive limit");
+        };
+        let Some(store) = ctx.tools.store.as_ref() else {
+            return Ok(());
+        };
+        let (current, previous, reset) = period.window(now);
+        let value = value.counter();
+        let counter = |window: &str| format!("{}\n{}\n{}", cfg_str(cfg, "scope"), window, value);
+        let count = match store.incr(QUOTAS_DB, &counter(&current), 1).await {
+            Ok(count) => count.max(0) as u64,
+            Err(e) => {
+                error!("quota: counter unavailable: {e}");
+                return Ok(());
+            }
+        };
+        // The first request of a period drops the counter of the last one
+        if count == 1
+            && let Err(e) = store.delete(QUOTAS_DB, &counter(&previous)).await
+        {
+            debug!("quota: could not drop the counter of {}: {e}", previous);
+        }
+        ctx.response_header_put("x-quota-limit", &limit.to_string());
+        ctx.response_header_put("x-quota-remaining", &limit.saturating_sub(count).to_string());
+        ctx.response_header_put("x-quota-reset", &reset.to_string());
+        if count > limit {
+            ctx.response_header_put("retry-after", &reset.to_string());
+            reject(ctx, StatusCode::TOO_MANY_REQUESTS, cfg, "Quota exceeded");
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Plugin for Quota {
+    fn name(&self) -> &'static str {
+        "quota"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        self.count(ctx, cfg, Utc::now()).await
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        if cfg.get("limit").and_then(|v| v.as_u64()).is_none_or(|l| l == 0) {
+            bail!("quota needs a positive integer limit");
+        }
+        if let Some(limits) = cfg.get("limits") {
+            let Some(limits) = limits.as_object() else {
+                bail!("limits must map keys to limits");
+            };
+            if let Some((key, _)) = limits.iter().find(|(_, l)| l.as_u64().is_none_or(|l| l == 0)) {
+                bail!("limits: {} must be a positive integer", key);
+            }
+        }
+        QuotaPeriod::from_config(cfg)?;
+        RateKey::parse(cfg, "consumer")?;
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// Lets requests through by client IP, `deny` wins over `allow` and with an
+/// `allow` list only listed clients get through. Entries are IPv4 or IPv6
+/// addresses or CIDR ranges, IPv4-mapped IPv6 clients match IPv4 entries.
+///
+/// The client is the connection peer. Only when the peer is one of
+/// `trusted_proxies` is `X-Forwarded-For` read, right to left up to the first
+/// address that is not a trusted proxy, or else `X-Real-IP`, so clients
+/// cannot pick their own address. Rejected requests get 403 with `message`.
+///
+/// ```yaml
+/// type: ip_restriction
+/// config:
+///   allow: ["10.0.0.0/8", "2001:db8::/32"]
+///   deny: ["10.0.13.0/24"]
+///   trusted_proxies: ["127.0.0.1", "::1"]
+///   message: "Your IP is not allowed"
+/// ```
+pub struct IpRestriction;
+
+#[derive(Debug, Clone, Copy)]
+struct Cidr {
+    net: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Result<Self> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let Ok(net) = addr.trim().parse::<IpAddr>() else {
+            bail!("invalid address: {}", s);
+        };
+        let net = canonical_ip(net);
+        let max = if net.is_ipv4() { 32 } else { 128 };
+        let len = match len.map(|l| l.trim().parse::<u8>()) {
+            None => max,
+            Some(Ok(len)) if len <= max => len,
+            Some(_) => bail!("invalid prefix length: {}", s),
+        };
+        Ok(Self { net, len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.net, canonical_ip(ip)) {
+            (IpAddr::V4(n), IpAddr::V4(i)) => (u128::from(n.to_bits()), u128::from(i.to_bits()), 32),
+            (IpAddr::V6(n), IpAddr::V6(i)) => (n.to_bits(), i.to_bits(), 128),
+            _ => return false,
+        };
+        let shift = bits - u32::from(self.len);
+        shift == bits || net >> shift == ip >> shift
+    }
+}
+
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        v4 => v4,
+    }
+}
+
+fn cidr_list(cfg: &serde_json::Value, key: &str) -> Result<Vec<Cidr>> {
+    if let Some(v) = cfg.get(key)
+        && !v.as_array().is_some_and(|a| a.iter().all(|e| e.is_string()))
+    {
+        bail!("{} must be a list of addresses or CIDR ranges", key);
+    }
+    str_list(cfg, key).map(Cidr::parse).collect()
+}
+
+impl IpRestriction {
+    /// Client address, from the forwarding headers only behind a trusted proxy
+    fn client_ip(ctx: &BullGContext, trusted: &[Cidr]) -> Option<IpAddr> {
+        let peer = ctx.client_addr()?.ip();
+        let is_trusted = |ip: IpAddr| trusted.iter().any(|c| c.contains(ip));
+        if !is_trusted(peer) {
+            return Some(peer);
+        }
+        if let Some(forwarded) = ctx.header_get("x-forwarded-for") {
+            let mut last = peer;
+            for hop in forwarded.rsplit(',').map(str::trim) {
+                // An unparsable hop cannot be trusted, the last trusted one made it up
+                let Ok(ip) = hop.parse::<IpAddr>() else {
+                    return Some(last);
+                };
+                if !is_trusted(ip) {
+                    return Some(ip);
+                }
+                last = ip;
+            }
+            return Some(last);
+        }
+        match ctx.header_get("x-real-ip").and_then(|v| v.trim().parse().ok()) {
+            Some(ip) => Some(ip),
+            None => Some(peer),
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for IpRestriction {
+    fn name(&self) -> &'static str {
+        "ip_restriction"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let (allow, deny) = (cidr_list(cfg, "allow")?, cidr_list(cfg, "deny")?);
+        let trusted = cidr_list(cfg, "trusted_proxies")?;
+        let permitted = match Self::client_ip(ctx, &trusted) {
+            Some(ip) => !deny.iter().any(|c| c.contains(ip)) && (allow.is_empty() || allow.iter().any(|c| c.contains(ip))),
+            // Only a context built outside a connection has no client
+            None => allow.is_empty(),
+        };
+        if !permitted {
+            reject(ctx, StatusCode::FORBIDDEN, cfg, "Your IP address is not allowed");
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        let (allow, deny) = (cidr_list(cfg, "allow")?, cidr_list(cfg, "deny")?);
+        cidr_list(cfg, "trusted_proxies")?;
+        if allow.is_empty() && deny.is_empty() {
+            bail!("ip_restriction needs an allow or deny list");
+        }
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// Bearer JWT authentication. The token must be signed with HS256 or RS256,
+/// not be expired and carry the expected issuer and audience, else the
+/// request gets 401.
+///
+/// Keys come from the plugin config (`secret` for HS256, a PEM `public_key`
+/// for RS256, the consumer is then the `consumer_claim` claim, `sub` by
+/// default) or, when the config has none, from the `jwt` credentials of the
+/// enabled consumers, with their own issuer and audience. The consumer id is
+/// put in the `consumer_id` var and the `x-consumer-id` request header for
+/// the plugins after it and the upstream.
+///
+/// ```yaml
+/// type: jwt_auth
+/// config:
+///   secret: "hs256-secret"
+///   issuer: "https://auth.example.com"
+///   audience: "orders"
+///   leeway_sec: 30
+/// ```
+#[derive(Default)]
+pub struct JwtAuth {
+    // "hs:<secret>" or "rs:<pem>" -> parsed key
+    keys: Mutex<HashMap<String, DecodingKey>>,
+}
+
+/// One key a token may be signed with
+struct JwtKey<'a> {
+    consumer: Option<&'a str>,
+    secret: &'a str,
+    public_key: &'a str,
+    issuer: &'a str,
+    audience: &'a str,
+}
+
+fn cfg_str<'a>(cfg: &'a serde_json::Value, key: &str) -> &'a str {
+    cfg.get(key).and_then(|v| v.as_str()).unwrap_or_default()
+}
+
+impl JwtAuth {
+    /// `iss` claim of a token not verified yet, only to pick the keys to
+    /// verify it with
+    fn unverified_issuer(token: &str) -> Option<String> {
+        let payload = token.split('.').nth(1)?;
+        let claims: serde_json::Value =
+            serde_json::from_slice(&base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(payload).ok()?).ok()?;
+        claims.get("iss")?.as_str().map(str::to_string)
+    }
+
+    fn key(&self, alg: Algorithm, material: &str) -> Result<DecodingKey> {
+        let id = match alg {
+            Algorithm::HS256 => format!("hs:{material}"),
+            _ => format!("rs:{material}"),
+        };
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(key) = keys.get(&id) {
+            return Ok(key.clone());
+        }
+        let key = match alg {
+            Algorithm::HS256 => DecodingKey::from_secret(material.as_bytes()),
+            _ => DecodingKey::from_rsa_pem(material.as_bytes())?,
+        };
+        keys.insert(id, key.clone());
+        Ok(key)
+    }
+
+    /// Claims of a token verified with `key`
+    fn verify(&self, token: &str, alg: Algorithm, key: &JwtKey, leeway: u64) -> Result<serde_json::Value> {
+        // Each key only verifies its own algorithm, an RS256 public key must
+        // not be usable as an HS256 secret
+        let material = match alg {
+            Algorithm::HS256 if !key.secret.is_empty() => key.secret,
+            Algorithm::RS256 if !key.public_key.is_empty() => key.public_key,
+            _ => bail!("no {:?} key", alg),
+        };
+        let mut validation = Validation::new(alg);
+        validation.leeway = leeway;
+        if !key.issuer.is_empty() {
+            validation.set_issuer(&[key.issuer]);
+        }
+        if key.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&[key.audience]);
+        }
+        let data = jsonwebtoken::decode::<serde_json::Value>(token, &self.key(alg, material)?, &validation)?;
+        Ok(data.claims)
+    }
+}
+
+#[async_trait]
+impl Plugin for JwtAuth {
+    fn name(&self) -> &'static str {
+        "jwt_auth"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let token = ctx.header_get("authorization").and_then(|auth| {
+            let (scheme, token) = auth.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("bearer").then(|| token.trim().to_string())
+        });
+        let Some(token) = token else {
+            ctx.response_header_put("www-authenticate", "Bearer");
+            reject(ctx, StatusCode::UNAUTHORIZED, cfg, "Missing bearer token");
+            return Ok(());
+        };
+        let alg = match jsonwebtoken::decode_header(&token) {
+            Ok(header) if matches!(header.alg, Algorithm::HS256 | Algorithm::RS256) => Some(header.alg),
+            Ok(header) => {
+                debug!("jwt_auth: unsupported algorithm {:?}", header.alg);
+                None
+            }
+            Err(e) => {
+                debug!("jwt_auth: malformed token: {e}");
+                None
+            }
+        };
+        let leeway = cfg.get("leeway_sec").and_then(|v| v.as_u64()).unwrap_or(0);
+        let (secret, public_key) = (cfg_str(cfg, "secret"), cfg_str(cfg, "public_key"));
+        let (issuer, audience) = (cfg_str(cfg, "issuer"), cfg_str(cfg, "audience"));
+        // Consumer credentials are only read without keys in the config, those
+        // of the token issuer and those without an issuer of their own
+        let consumers = if secret.is_empty() && public_key.is_empty() {
+            let mut entries = vec![issuer_entry("")];
+            if let Some(iss) = Self::unverified_issuer(&token).filter(|iss| !iss.is_empty()) {
+                entries.push(issuer_entry(&iss));
+            }
+            indexed_consumers(ctx, entries, "jwt_auth").await
+        } else {
+            Vec::new()
+        };
+        let keys: Vec<JwtKey> = if secret.is_empty() && public_key.is_empty() {
+            consumers
+                .iter()
+                .filter(|c| c.enabled)
+                .filter_map(|c| Some((c, c.authentication.as_ref().filter(|a| a.enabled)?)))
+                .flat_map(|(c, auth)| auth.jwt.iter().filter(|j| j.enabled).map(move |j| (c, j)))
+                .map(|(c, j)| JwtKey {
+                    consumer: Some(&c.id),
+                    secret: &j.secret,
+                    public_key: &j.public_key,
+                    issuer: if j.issuer.is_empty() { issuer } else { &j.issuer },
+                    audience: if j.audience.is_empty() { audience } else { &j.audience },
+                })
+                .collect()
+        } else {
+            vec![JwtKey { consumer: None, secret, public_key, issuer, audience }]
+        };
+        let verified = alg.and_then(|alg| {
+            keys.iter().find_map(|key| match self.verify(&token, alg, key, leeway) {
+                Ok(claims) => Some((key, claims)),
+                Err(e) => {
+                    debug!("jwt_auth: token rejected by key of {}: {e}", key.consumer.unwrap_or("config"));
+                    None
+                }
+            })
+        });
+        let Some((key, claims)) = verified else {
+            ctx.response_header_put("www-authenticate", "Bearer error=\"invalid_token\"");
+            reject(ctx, StatusCode::UNAUTHORIZED, cfg, "Invalid token");
+            return Ok(());
+        };
+        let claim = cfg.get("consumer_claim").and_then(|v| v.as_str()).unwrap_or("sub");
+        let consumer = key
+            .consumer
+            .map(str::to_string)
+            .or_else(|| claims.get(claim).and_then(|v| v.as_str()).map(str::to_string));
+        // A client sent id must never pass for the verified one
+        ctx.header_remove("x-consumer-id");
+        if let Some(id) = consumer {
+            ctx.vars.write().set(CONSUMER_ID_VAR, serde_json::Value::String(id.clone()));
+            ctx.header_put("x-consumer-id", &id)?;
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for key in ["secret", "public_key", "issuer", "audience", "consumer_claim"] {
+            if let Some(v) = cfg.get(key)
+                && !v.is_string()
+            {
+                bail!("{} must be a string", key);
+            }
+        }
+        if let Some(v) = cfg.get("leeway_sec")
+            && !v.is_u64()
+        {
+            bail!("leeway_sec must be a number of seconds");
+        }
+        let public_key = cfg_str(cfg, "public_key");
+        if !public_key.is_empty() {
+            DecodingKey::from_rsa_pem(public_key.as_bytes()).map_err(|e| anyhow::anyhow!("invalid public_key: {e}"))?;
+        }
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// API key authentication. The key is read from the `key_names` headers,
+/// then from the query parameters of the same names, and must be one of the
+/// `api_key` credentials or app `keys` of an enabled consumer, else the
+/// request gets 401. Keys are compared in constant time.
+///
+/// The consumer id is put in the `consumer_id` var and the `x-consumer-id`
+/// request header, the app id in the `app_id` var when the key is an app
+/// one. `hide_credentials` removes the key from the headers and query sent
+/// upstream.
+///
+/// ```yaml
+/// type: key_auth
+/// config:
+///   key_names: [apikey, x-api-key] # default apikey
+///   key_in_header: true
+///   key_in_query: true
+///   hide_credentials: true
+/// ```
+pub struct KeyAuth;
+
+const KEY_AUTH_DEFAULT_NAME: &str = "apikey";
+
+/// Enabled consumers listed under the `CONSUMER_INDEX_DB` entries, in index
+/// order without duplicates. A failing store leaves no consumer to match, the
+/// auth plugins must not fail open.
+async fn indexed_consumers(ctx: &BullGContext, entries: Vec<String>, plugin: &str) -> Vec<Consumer> {
+    let Some(store) = ctx.tools.store.as_ref() else {
+        return Vec::new();
+    };
+    let found = store
+        .run(move |m| {
+            let mut ids: Vec<String> = Vec::new();
+            for entry in &entries {
+                for id in m.get::<Vec<String>>(CONSUMER_INDEX_DB, entry)?.unwrap_or_default() {
+                    if !ids.contains(&id) {
+                        ids.push(id);
+                    }
+                }
+            }
+            let mut consumers = Vec::with_capacity(ids.len());
+            for id in &ids {
+                consumers.extend(m.get::<Consumer>(CONSUMERS_DB, id)?.filter(|c| c.enabled));
+            }
+            Ok(consumers)
+        })
+        .await;
+    found.unwrap_or_else(|e| {
+        error!("{plugin}: consumers unavailable: {e}");
+        Vec::new()
+    })
+}
+
+// Compares every byte so the time taken does not tell how much of the key matched
+fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+impl KeyAuth {
+    fn key_names(cfg: &serde_json::Value) -> Vec<&str> {
+        let names = cors_list(cfg, "key_names");
+        if names.is_empty() { vec![KEY_AUTH_DEFAULT_NAME] } else { names }
+    }
+
+    /// Request query without the `names` parameters, the others are kept as sent
+    fn strip_query(query: &str, names: &[&str]) -> String {
+        query
+            .split('&')
+            .filter(|pair| {
+                let (name, _) = form_urlencoded::parse(pair.as_bytes()).next().unwrap_or_default();
+                !pair.is_empty() && !names.contains(&name.as_ref())
+            })
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+}
+
+#[async_trait]
+impl Plugin for KeyAuth {
+    fn name(&self) -> &'static str {
+        "key_auth"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let names = Self::key_names(cfg);
+        let in_header = cfg.get("key_in_header").and_then(|v| v.as_bool()).unwrap_or(true);
+        let in_query = cfg.get("key_in_query").and_then(|v| v.as_bool()).unwrap_or(true);
+        let query = ctx.query().unwrap_or_default();
+        let key = in_header
+            .then(|| names.iter().find_map(|name| ctx.header_get(name)))
+            .flatten()
+            .or_else(|| {
+                in_query
+                    .then(|| {
+                        form_urlencoded::parse(query.as_bytes())
+                            .find(|(name, _)| names.contains(&name.as_ref()))
+                            .map(|(_, value)| value.into_owned())
+                    })
+                    .flatten()
+            })
+            .filter(|key| !key.is_empty());
+        let Some(key) = key else {
+            reject(ctx, StatusCode::UNAUTHORIZED, cfg, "No API key found in request");
+            return Ok(());
+        };
+
+        let consumers = indexed_consumers(ctx, vec![api_key_entry(&key)], "key_auth").await;
+        let matched = consumers.iter().filter(|c| c.enabled).find_map(|c| {
+            let credential = c
+                .authentication
+                .as_ref()
+                .filter(|a| a.enabled)
+                .is_some_and(|a| a.api_key.iter().any(|k| k.enabled && constant_eq(k.key.as_bytes(), key.as_bytes())));
+            if credential {
+                return Some((c, None));
+            }
+            c.apps.iter().flatten().find_map(|app| {
+                app.keys
+                    .iter()
+                    .flatten()
+                    .any(|k| constant_eq(k.as_bytes(), key.as_bytes()))
+                    .then_some((c, Some(&app.id)))
+            })
+        });
+        let Some((consumer, app)) = matched else {
+            debug!("key_auth: no consumer has the key");
+            reject(ctx, StatusCode::UNAUTHORIZED, cfg, "Invalid authentication credentials");
+            return Ok(());
+        };
+
+        if cfg.get("hide_credentials").and_then(|v| v.as_bool()).unwrap_or(false) {
+            for name in &names {
+                ctx.header_remove(name);
+            }
+            ctx.set_query(&Self::strip_query(&query, &names));
+        }
+        // A client sent id must never pass for the verified one
+        ctx.header_remove("x-consumer-id");
+        ctx.var_set(CONSUMER_ID_VAR, serde_json::Value::String(consumer.id.clone()));
+        if let Some(app) = app {
+            ctx.var_set("app_id", serde_json::Value::String(app.clone()));
+        }
+        ctx.header_put("x-consumer-id", &consumer.id)?;
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for name in Self::key_names(cfg) {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| anyhow::anyhow!("invalid key name: {}", name))?;
+        }
+        for key in ["key_in_header", "key_in_query", "hide_credentials"] {
+            if let Some(v) = cfg.get(key)
+                && !v.is_boolean()
+            {
+                bail!("{} must be true or false", key);
+            }
+        }
+        if cfg.get("key_in_header").and_then(|v| v.as_bool()) == Some(false)
+            && cfg.get("key_in_query").and_then(|v| v.as_bool()) == Some(false)
+        {
+            bail!("key_auth needs key_in_header or key_in_query");
+        }
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// HMAC-SHA256 request signatures, as webhook senders use. The signature
+/// header carries the hex (or base64) HMAC of the canonical request under
+/// `secret`, optionally after a `sha256=` prefix:
+///
+/// ```text
+/// METHOD\nPATH?QUERY\nDATE\nhex(sha256(body))
+/// ```
+///
+/// where PATH?QUERY is the request target as sent (no `?` without a query),
+/// DATE is the date header as sent and the body hash is lowercase hex, that of
+/// the empty string for requests without a body. Requests without a signature, with
+/// a wrong one, or dated more than `clock_skew_sec` away from the gateway
+/// clock (an HTTP date or RFC 3339) get 401. `consumer` names the consumer the
+/// verified requests are from, for `require_auth` and consumer rate limits.
+///
+/// ```yaml
+/// type: hmac_auth
+/// config:
+///   secret: shared-webhook-secret
+///   signature_header: x-signature # default
+///   date_header: date # default
+///   clock_skew_sec: 300 # default
+///   consumer: github-webhooks
+/// ```
+pub struct HmacAuth;
+
+const HMAC_DEFAULT_SKEW_SEC: i64 = 300;
+
+impl HmacAuth {
+    fn canonical(ctx: &BullGContext, date: &str) -> String {
+        let target = ctx.uri.path_and_query().map_or_else(|| ctx.uri.path(), |pq| pq.as_str());
+        let body = BullGCrypto::sha256_hex(&ctx.get_body());
+        format!("{}\n{}\n{}\n{}", ctx.method, target, date, body)
+    }
+
+    fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+        DateTime::parse_from_rfc2822(date)
+            .or_else(|_| DateTime::parse_from_rfc3339(date))
+            .ok()
+            .map(|d| d.with_timezone(&Utc))
+    }
+}
+
+#[async_trait]
+impl Plugin for HmacAuth {
+    fn name(&self) -> &'static str {
+        "hmac_auth"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let secret = cfg_str(cfg, "secret");
+        let signature_header = cfg.get("signature_header").and_then(|v| v.as_str()).unwrap_or("x-signature");
+        let date_header = cfg.get("date_header").and_then(|v| v.as_str()).unwrap_or("date");
+        let skew = cfg.get("clock_skew_sec").and_then(|v| v.as_i64()).unwrap_or(HMAC_DEFAULT_SKEW_SEC);
+
+        let Some(signature) = ctx.header_get(signature_header).filter(|s| !s.is_empty()) else {
+            reject(ctx, StatusCode::UNAUTHORIZED, cfg, "No request signature found");
+            return Ok(());
+        };
+        let date = ctx.header_get(date_header).unwrap_or_default();
+        let fresh = Self::parse_date(&date).is_some_and(|at| (Utc::now() - at).num_seconds().abs() <= skew);
+        if !fresh {
+            debug!("hmac_auth: {} header `{}` missing or outside the clock skew", date_header, date);
+            reject(ctx, StatusCode::UNAUTHORIZED, cfg, "Request date missing or out of range");
+            return Ok(());
+        }
+        let signature = signature.strip_prefix("sha256=").unwrap_or(&signature);
+        if !BullGCrypto::hmac_verify(Self::canonical(ctx, &date).as_bytes(), secret.as_bytes(), signature) {
+            reject(ctx, StatusCode::UNAUTHORIZED, cfg, "Invalid request signature");
+            return Ok(());
+        }
+
+        // A client sent id must never pass for the verified one
+        ctx.header_remove("x-consumer-id");
+        let consumer = cfg_str(cfg, "consumer");
+        if !consumer.is_empty() {
+            ctx.var_set(CONSUMER_ID_VAR, serde_json::Value::String(consumer.to_string()));
+            ctx.header_put("x-consumer-id", consumer)?;
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        if cfg_str(cfg, "secret").is_empty() {
+            bail!("hmac_auth needs a secret");
+        }
+        for key in ["signature_header", "date_header"] {
+            if let Some(name) = cfg.get(key).and_then(|v| v.as_str()) {
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| anyhow::anyhow!("invalid {}: {}", key, name))?;
+            }
+        }
+        if let Some(v) = cfg.get("clock_skew_sec")
+            && v.as_u64().is_none()
+        {
+            bail!("clock_skew_sec must be a number of seconds");
+        }
+        ErrorFormat::from_config(cfg).map(|_| ())
+    }
+}
+
+/// Add, set, remove and rename headers, the `request` section on the
+/// request before it is proxied and the `response` one on the response.
+/// Operations run in that order: `remove`, `rename`, `set`, `add`.
+///
+/// Names are matched without case. `set` replaces any value, `add` appends
+/// to an existing header with `mode: append` and replaces it with the
+/// default `mode: override`. Values interpolate `${request_id}`,
+/// `${method}`, `${path}`, `${query}`, `${client_ip}`, the route parameter
+/// `${param.<name>}` and, in the response, `${status}`. Any other `${name}`
+/// is the `name` var set by an earlier plugin, empty when there is none. A header whose value ends up invalid
+/// is skipped.
+///
+/// ```yaml
+/// type: header_transform
+/// config:
+///   request:
+///     remove: [x-internal-token]
+///     rename: ["x-user:x-forwarded-user"]
+///     set: ["x-request-id:${request_id}"]
+///     add: ["x-consumer:${consumer_id}"]
+///     mode: append
+///   response:
+///     remove: [server]
+///     set: ["x-served-by:bullg"]
+/// ```
+pub struct HeaderTransform;
+
+// "name:value" entries of a list
+fn header_pairs<'a>(section: &'a serde_json::Value, key: &str) -> impl Iterator<Item = (&'a str, &'a str)> {
+    str_list(section, key).filter_map(|s| s.split_once(':')).map(|(k, v)| (k.trim(), v.trim()))
+}
+
+impl HeaderTransform {
+    fn interpolate(ctx: &BullGContext, phase: Phase, template: &str) -> String {
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(at) = rest.find("${") {
+            let Some(len) = rest[at + 2..].find('}') else {
+                break;
+            };
+            out.push_str(&rest[..at]);
+            let name = &rest[at + 2..at + 2 + len];
+            match name {
+                "request_id" => out.push_str(&ctx.request_id()),
+                "method" => out.push_str(ctx.method.as_str()),
+                "path" => out.push_str(ctx.uri.path()),
+                "query" => out.push_str(ctx.uri.query().unwrap_or_default()),
+                "client_ip" => out.push_str(&ctx.client_addr().map(|a| a.ip().to_string()).unwrap_or_default()),
+                "status" if phase == Phase::Post => {
+                    out.push_str(ctx.status.read().map(|s| s.as_u16().to_string()).unwrap_or_default().as_str())
+                }
+                _ if name.starts_with("param.") => {
+                    out.push_str(&ctx.param_get(&name["param.".len()..]).unwrap_or_default())
+                }
+                _ => match ctx.var_get(name) {
+                    Some(serde_json::Value::String(v)) => out.push_str(&v),
+                    Some(serde_json::Value::Null) | None => {}
+                    Some(v) => out.push_str(&v.to_string()),
+                },
+            }
+            rest = &rest[at + 3 + len..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    fn header(ctx: &BullGContext, phase: Phase, name: &str, value: &str) -> Option<(HeaderName, HeaderValue)> {
+        let value = Self::interpolate(ctx, phase, value);
+        match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+            (Ok(name), Ok(value)) => Some((name, value)),
+            _ => {
+                debug!("header_transform: skipping invalid header {name}: {value:?}");
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for HeaderTransform {
+    fn name(&self) -> &'static str {
+        "header_transform"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre, Phase::Post]
+    }
+    async fn apply(&self, ctx: &BullGContext, phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let section = match phase {
+            Phase::Pre => cfg.get("request"),
+            Phase::Post => cfg.get("response"),
+            Phase::Intermediate => None,
+        };
+        let Some(section) = section else {
+            return Ok(());
+        };
+        let append = section.get("mode").and_then(|v| v.as_str()) == Some("append");
+        // Values are built before taking the header lock, they may read it
+        let set: Vec<_> = header_pairs(section, "set").filter_map(|(k, v)| Self::header(ctx, phase, k, v)).collect();
+        let add: Vec<_> = header_pairs(section, "add").filter_map(|(k, v)| Self::header(ctx, phase, k, v)).collect();
+
+        let mut headers = ctx.headers.write();
+        if phase == Phase::Post {
+            // Headers set by earlier plugins for the client win over the
+            // upstream ones, fold them in so they are transformed too
+            let extra = std::mem::take(&mut *ctx.response_headers.write());
+            headers.extend(extra);
+        }
+        for name in str_list(section, "remove") {
+            headers.remove(name);
+        }
+        for (from, to) in header_pairs(section, "rename") {
+            let (Ok(from), Ok(to)) = (HeaderName::from_bytes(from.as_bytes()), HeaderName::from_bytes(to.as_bytes())) else {
+                continue;
+            };
+            let values: Vec<HeaderValue> = headers.get_all(&from).iter().cloned().collect();
+            headers.remove(&from);
+            for value in values {
+                headers.append(to.clone(), value);
+            }
+        }
+        for (name, value) in set {
+            headers.insert(name, value);
+        }
+        for (name, value) in add {
+            if append {
+                headers.append(name, value);
+            } else {
+                headers.insert(name, value);
+            }
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for side in ["request", "response"] {
+            let Some(section) = cfg.get(side) else {
+                continue;
+            };
+            for key in ["add", "set", "rename"] {
+                for entry in str_list(section, key) {
+                    let Some((name, value)) = entry.split_once(':') else {
+                        bail!("{}.{}: `{}` is not name:value", side, key, entry);
+                    };
+                    HeaderName::from_bytes(name.trim().as_bytes())
+                        .map_err(|_| anyhow::anyhow!("{}.{}: invalid header name `{}`", side, key, name.trim()))?;
+                    if key == "rename" {
+                        HeaderName::from_bytes(value.trim().as_bytes())
+                            .map_err(|_| anyhow::anyhow!("{}.rename: invalid header name `{}`", side, value.trim()))?;
+                    }
+                }
+            }
+            for name in str_list(section, "remove") {
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("{}.remove: invalid header name `{}`", side, name))?;
+            }
+            match section.get("mode").map(|v| v.as_str()) {
+                None | Some(Some("append" | "override")) => {}
+                Some(_) => bail!("{}.mode must be append or override", side),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rewrite JSON response bodies: `remove` fields, `rename` keys, wrap the
+/// payload in an `envelope` object and `add` static fields, in that order.
+/// Responses that are not JSON, by content type or by body, are left as is.
+///
+/// Paths are dot separated keys, a number picks an array element and `*`
+/// every element or value. `rename` entries are `path:new_key`, the key
+/// stays in its object. `add` applies to the final document, with an
+/// envelope its paths start at the envelope, missing objects are created.
+///
+/// ```yaml
+/// type: response_transform
+/// config:
+///   remove: [internal_id, "items.*.cost"]
+///   rename: ["items.*.qty:quantity"]
+///   envelope: data
+///   add:
+///     status: ok
+///     meta.version: 2
+/// ```
+pub struct ResponseTransform;
+
+fn json_children<'a>(v: &'a mut serde_json::Value, segment: &str) -> Vec<&'a mut serde_json::Value> {
+    let all = segment == "*";
+    match v {
+        serde_json::Value::Object(m) => {
+            if all {
+                m.values_mut().collect()
+            } else {
+                m.get_mut(segment).into_iter().collect()
+            }
+        }
+        serde_json::Value::Array(a) => {
+            if all {
+                a.iter_mut().collect()
+            } else {
+                segment.parse().ok().and_then(|i: usize| a.get_mut(i)).into_iter().collect()
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Values at `path`, `*` fans out
+fn json_resolve<'a>(v: &'a mut serde_json::Value, path: &[&str]) -> Vec<&'a mut serde_json::Value> {
+    match path.split_first() {
+        None => vec![v],
+        Some((head, rest)) => json_children(v, head).into_iter().flat_map(|c| json_resolve(c, rest)).collect(),
+    }
+}
+
+impl ResponseTransform {
+    fn remove(doc: &mut serde_json::Value, path: &[&str]) {
+        let Some((last, parent)) = path.split_last() else {
+            return;
+        };
+        for v in json_resolve(doc, parent) {
+            match v {
+                serde_json::Value::Object(m) if *last == "*" => m.clear(),
+                serde_json::Value::Object(m) => {
+                    m.remove(*last);
+                }
+                serde_json::Value::Array(a) if *last == "*" => a.clear(),
+                serde_json::Value::Array(a) => {
+                    if let Ok(i) = last.parse::<usize>()
+                        && i < a.len()
+                    {
+                        a.remove(i);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn rename(doc: &mut serde_json::Value, path: &[&str], to: &str) {
+        let Some((last, parent)) = path.split_last() else {
+            return;
+        };
+        for v in json_resolve(doc, parent) {
+            if let serde_json::Value::Object(m) = v
+                && let Some(value) = m.remove(*last)
+            {
+                m.insert(to.to_string(), value);
+            }
+        }
+    }
+
+    fn add(doc: &mut serde_json::Value, path: &[&str], value: &serde_json::Value) {
+        let Some((last, parent)) = path.split_last() else {
+            return;
+        };
+        let mut at = doc;
+        for segment in parent {
+            let serde_json::Value::Object(m) = at else {
+                return;
+            };
+            at = m
+                .entry(segment.to_string())
+                .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        }
+        if let serde_json::Value::Object(m) = at {
+            m.insert(last.to_string(), value.clone());
+        }
+    }
+
+    fn is_json(content_type: &str) -> bool {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        mime.eq_ignore_ascii_case("application/json") || mime.to_ascii_lowercase().ends_with("+json")
+    }
+}
+
+#[async_trait]
+impl Plugin for ResponseTransform {
+    fn name(&self) -> &'static str {
+        "response_transform"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Post]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        // Bodyless answers such as 204 and 304 are left alone
+        if ctx.body_len() == 0 || !ctx.header_get("content-type").is_some_and(|ct| Self::is_json(&ct)) {
+            return Ok(());
+        }
+        let mut doc: serde_json::Value = match serde_json::from_slice(&ctx.get_body()) {
+            Ok(doc) => doc,
+            Err(e) => {
+                debug!("response_transform: body is not JSON: {e}");
+                return Ok(());
+            }
+        };
+        for path in str_list(cfg, "remove") {
+            Self::remove(&mut doc, &path.split('.').collect::<Vec<_>>());
+        }
+        for (path, to) in str_list(cfg, "rename").filter_map(|r| r.split_once(':')) {
+            Self::rename(&mut doc, &path.trim().split('.').collect::<Vec<_>>(), to.trim());
+        }
+        let envelope = cfg_str(cfg, "envelope");
+        if !envelope.is_empty() {
+            doc = serde_json::json!({ envelope: doc });
+        }
+        if let Some(add) = cfg.get("add").and_then(|v| v.as_object()) {
+            for (path, value) in add {
+                Self::add(&mut doc, &path.split('.').collect::<Vec<_>>(), value);
+            }
+        }
+        ctx.set_body(Bytes::from(serde_json::to_vec(&doc)?));
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for key in ["remove", "rename"] {
+            if let Some(v) = cfg.get(key)
+                && !v.as_array().is_some_and(|a| a.iter().all(|e| e.is_string()))
+            {
+                bail!("{} must be a list of strings", key);
+            }
+        }
+        for entry in str_list(cfg, "rename") {
+            if !entry.split_once(':').is_some_and(|(path, to)| !path.trim().is_empty() && !to.trim().is_empty()) {
+                bail!("rename: `{}` is not path:new_key", entry);
+            }
+        }
+        if let Some(v) = cfg.get("envelope")
+            && !v.is_string()
+        {
+            bail!("envelope must be a string");
+        }
+        if let Some(v) = cfg.get("add")
+            && !v.is_object()
+        {
+            bail!("add must map paths to values");
+        }
+        Ok(())
+    }
+}
+
+/// Compresses response bodies with brotli or gzip, whichever the client
+/// accepts first in `encodings` (post phase).
+///
+/// Only bodies of at least `min_size` bytes are compressed, streamed
+/// responses never are. Responses that already carry a `Content-Encoding`,
+/// say `Cache-Control: no-transform`, are partial or empty, and content
+/// types starting with an entry of `skip_types` (already compressed images,
+/// video, archives and fonts by default) are left untouched. `level` goes from 0 (fastest) to 9 for gzip
+/// and to 11 for brotli, it is capped per encoding.
+///
+/// ```yaml
+/// type: compression
+/// config:
+///   encodings: [br, gzip]
+///   min_size: 1024
+///   level: 6
+///   skip_types: [image/png, image/jpeg, video/, application/zip]
+/// ```
+pub struct Compression;
+
+const COMPRESSED_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "video/",
+    "audio/",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/x-brotli",
+    "application/zstd",
+    "font/woff",
+    "font/woff2",
+];
+
+impl Compression {
+    fn def_encodings() -> Vec<String> {
+        vec!["br".into(), "gzip".into()]
+    }
+
+    // First of `encodings` the client accepts, `q=0` refuses an encoding
+    fn negotiate(accept: &str, encodings: &[String]) -> Option<String> {
+        let offers: Vec<(String, bool)> = accept
+            .split(',')
+            .filter_map(|offer| {
+                let mut parts = offer.split(';');
+                let coding = parts.next()?.trim().to_ascii_lowercase();
+                let refused = parts.any(|p| {
+                    p.trim()
+                        .strip_prefix("q=")
+                        .and_then(|q| q.trim().parse::<f32>().ok())
+                        .is_some_and(|q| q <= 0.0)
+                });
+                (!coding.is_empty()).then_some((coding, !refused))
+            })
+            .collect();
+        encodings
+            .iter()
+            .find(|enc| {
+                match offers.iter().find(|(coding, _)| coding == *enc) {
+                    Some((_, accepted)) => *accepted,
+                    None => offers.iter().any(|(coding, accepted)| coding == "*" && *accepted),
+                }
+            })
+            .cloned()
+    }
+
+    fn compress(encoding: &str, level: u32, body: &[u8]) -> Result<Vec<u8>> {
+        use std::io::Write;
+        match encoding {
+            "gzip" => {
+                let mut enc = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::new(level.min(9)));
+                enc.write_all(body)?;
+                Ok(enc.finish()?)
+            }
+            "br" => {
+                let mut out = Vec::new();
+                {
+                    let mut enc = brotli::CompressorWriter::new(&mut out, 4096, level.min(11), 22);
+                    enc.write_all(body)?;
+                }
+                Ok(out)
+            }
+            other => bail!("unsupported encoding {}", other),
+        }
+    }
+}
+
+#[async_trait]
+impl Plugin for Compression {
+    fn name(&self) -> &'static str {
+        "compression"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Post]
+    }
+    async fn apply(&self, ctx: &BullGContext, _phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let min_size = cfg.get("min_size").and_then(|v| v.as_u64()).unwrap_or(1024);
+        let status = *ctx.status.read();
+        if ctx.method == http::Method::HEAD
+            || ctx.streamed()
+            || status.is_some_and(|s| s == StatusCode::NO_CONTENT || s == StatusCode::PARTIAL_CONTENT || s == StatusCode::NOT_MODIFIED)
+            || ctx.body_len() < min_size.max(1)
+        {
+            return Ok(());
+        }
+        let headers = ctx.headers.read().clone();
+        if headers.contains_key(http::header::CONTENT_ENCODING) || headers.contains_key(http::header::CONTENT_RANGE) {
+            return Ok(());
+        }
+        let no_transform = headers
+            .get_all(http::header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .any(|d| d.trim().eq_ignore_ascii_case("no-transform"));
+        if no_transform {
+            return Ok(());
+        }
+        let content_type = headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let skipped = match cfg.get("skip_types") {
+            Some(_) => str_list(cfg, "skip_types").any(|t| content_type.starts_with(&t.to_ascii_lowercase())),
+            None => COMPRESSED_TYPES.iter().any(|t| content_type.starts_with(t)),
+        };
+        if skipped {
+            return Ok(());
+        }
+        let encodings = match cfg.get("encodings") {
+            Some(_) => str_list(cfg, "encodings").map(|e| e.trim().to_ascii_lowercase()).collect(),
+            None => Self::def_encodings(),
+        };
+        let Some(accept) = ctx.request_header_get("accept-encoding") else {
+            return Ok(());
+        };
+        let Some(encoding) = Self::negotiate(&accept, &encodings) else {
+            return Ok(());
+        };
+        let level = cfg.get("level").and_then(|v| v.as_u64()).unwrap_or(6) as u32;
+        let body = ctx.get_body();
+        let compressed = Self::compress(&encoding, level, &body)?;
+        if compressed.len() >= body.len() {
+            debug!("compression: {} would not shrink a {} byte body", encoding, body.len());
+            return Ok(());
+        }
+        {
+            let mut headers = ctx.headers.write();
+            headers.insert(http::header::CONTENT_ENCODING, HeaderValue::from_str(&encoding)?);
+            headers.insert(http::header::CONTENT_LENGTH, HeaderValue::from(compressed.len()));
+            let varies = headers
+                .get_all(http::header::VARY)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("accept-encoding"));
+            if !varies {
+                headers.append(http::header::VARY, HeaderValue::from_static("accept-encoding"));
+            }
+            // The compressed body is not byte for byte the one a strong ETag names
+            if let Some(etag) = headers.get(http::header::ETAG).and_then(|v| v.to_str().ok())
+                && !etag.starts_with("W/")
+                && let Ok(weak) = HeaderValue::from_str(&format!("W/{etag}"))
+            {
+                headers.insert(http::header::ETAG, weak);
+            }
+        }
+        ctx.set_body(Bytes::from(compressed));
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for key in ["encodings", "skip_types"] {
+            if let Some(v) = cfg.get(key)
+                && !v.as_array().is_some_and(|a| a.iter().all(|e| e.is_string()))
+            {
+                bail!("{} must be a list of strings", key);
+            }
+        }
+        for encoding in str_list(cfg, "encodings") {
+            if !matches!(encoding.trim().to_ascii_lowercase().as_str(), "br" | "gzip") {
+                bail!("unsupported encoding `{}`, expected br or gzip", encoding);
+            }
+        }
+        for key in ["min_size", "level"] {
+            if let Some(v) = cfg.get(key)
+                && !v.is_u64()
+            {
+                bail!("{} must be a non negative integer", key);
+            }
+        }
+        if let Some(level) = cfg.get("level").and_then(|v| v.as_u64())
+            && level > 11
+        {
+            bail!("level must be between 0 and 11");
+        }
+        Ok(())
+    }
+}
+
+/// Caches upstream responses in the gateway node. A hit in the pre phase
+/// answers the request from the cache without contacting the upstream, a
+/// miss stores the response in the post phase for `ttl_sec` seconds, less
+/// when the response `Cache-Control` gives a smaller `s-maxage` or
+/// `max-age`. Responses get `X-Cache: HIT` and `Age`, or `X-Cache: MISS`.
+///
+/// Entries are keyed by the matched service, version and route, the
+/// authenticated consumer, method, path, query and the request headers
+/// listed in `vary_headers`. Run it after the auth plugins: a response is
+/// only stored for the consumer its lookup saw, and on routes requiring
+/// authentication nothing is served or stored without a consumer. It needs
+/// the matched route and cannot be applied globally. Only `methods` (GET
+/// and HEAD by default) and `statuses` (200 by default) are cached, with
+/// bodies from `min_body_size` (0 by default) to `max_body_size` (1 MiB by
+/// default) bytes, larger ones would fill the node memory and smaller ones
+/// may not be worth an entry.
+/// `Cache-Control: no-store` on the request or the response keeps it out of
+/// the cache, as do `private`, `no-cache` and `max-age=0` responses and
+/// responses setting cookies. A request with `no-cache` skips the lookup
+/// and refreshes the entry. Requests with `Authorization` bypass the cache
+/// unless `cache_authorized` is set, streamed responses are never stored.
+/// A node keeps at most `PROXY_CACHE_ENTRIES` responses, evicting the least
+/// recently used when full.
+///
+/// ```yaml
+/// type: proxy_cache
+/// config:
+///   ttl_sec: 60
+///   methods: [GET]
+///   statuses: [200, 404]
+///   vary_headers: [accept, accept-language]
+///   min_body_size: 256
+///   max_body_size: 1048576
+/// ```
+pub struct ProxyCache {
+    cache: Arc<Cache<String, CachedResponse>>,
+    // Started by the first store, the plugin is built outside the runtime
+    janitor: Once,
+}
+
+#[derive(Clone)]
+struct CachedResponse {
+    status: StatusCode,
+    headers: http::HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+}
+
+/// Time between two sweeps of the expired cache entries
+const PROXY_CACHE_SWEEP: Duration = Duration::from_secs(60);
+
+/// Responses cached by a node
+pub const PROXY_CACHE_ENTRIES: usize = 10_000;
+
+impl Default for ProxyCache {
+    fn default() -> Self {
+        Self { cache: Cache::with_capacity(None, PROXY_CACHE_ENTRIES), janitor: Once::new() }
+    }
+}
+
+// Lowercase directives of Cache-Control values, with their argument
+fn cache_directives<'a>(values: impl Iterator<Item = &'a str>) -> Vec<(String, Option<String>)> {
+    values
+        .flat_map(|v| v.split(','))
+        .filter_map(|d| {
+            let (name, arg) = match d.split_once('=') {
+                Some((name, arg)) => (name, Some(arg.trim().trim_matches('"').to_string())),
+                None => (d, None),
+            };
+            let name = name.trim().to_ascii_lowercase();
+            (!name.is_empty()).then_some((name, arg))
+        })
+        .collect()
+}
+
+/// Var carrying the cache key and consumer of a lookup to the post phase
+const PROXY_CACHE_VAR: &str = "proxy_cache";
+
+impl ProxyCache {
+    // None when the request may not use the cache: not routed, or on a
+    // route requiring authentication without a consumer
+    fn key(ctx: &BullGContext, cfg: &serde_json::Value) -> Option<String> {
+        let route = ctx.matched_route()?;
+        let consumer = ctx.consumer_id();
+        if route.require_auth && consumer.is_none() {
+            return None;
+        }
+        let mut key = format!(
+            "{}\n{}\n{}\n{:?}\n{}\n{}?{}",
+            route.service,
+            route.version,
+            route.route,
+            consumer,
+            ctx.method,
+            ctx.uri.path(),
+            ctx.query().unwrap_or_default()
+        );
+        for name in str_list(cfg, "vary_headers") {
+            key.push_str(&format!("\n{}={}", name.to_ascii_lowercase(), ctx.header_get(name).unwrap_or_default()));
+        }
+        Some(key)
+    }
+
+    // Cacheable body sizes, both inclusive
+    fn body_sizes(cfg: &serde_json::Value) -> (u64, u64) {
+        let size = |key, default| cfg.get(key).and_then(|v| v.as_u64()).unwrap_or(default);
+        (size("min_body_size", 0), size("max_body_size", 1024 * 1024))
+    }
+
+    // Whether the plugin handles requests with this method at all
+    fn caches(ctx: &BullGContext, cfg: &serde_json::Value) -> bool {
+        let method = ctx.method.as_str();
+        let allowed = match cfg.get("methods") {
+            Some(_) => str_list(cfg, "methods").any(|m| m.eq_ignore_ascii_case(method)),
+            None => method == "GET" || method == "HEAD",
+        };
+        let authorized = cfg.get("cache_authorized").and_then(|v| v.as_bool()).unwrap_or(false);
+        allowed && (authorized || ctx.header_get("authorization").is_none())
+    }
+
+    async fn lookup(&self, ctx: &BullGContext, cfg: &serde_json::Value) {
+        if !Self::caches(ctx, cfg) {
+            return;
+        }
+        let request = ctx.header_get("cache-control");
+        let directives = cache_directives(request.iter().map(String::as_str));
+        if directives.iter().any(|(d, _)| d == "no-store") {
+            return;
+        }
+        let Some(key) = Self::key(ctx, cfg) else {
+            return;
+        };
+        let hit = if directives.iter().any(|(d, _)| d == "no-cache") {
+            None
+        } else {
+            self.cache.get(&key).await
+        };
+        match hit {
+            Some(hit) => {
+                let mut headers = hit.headers;
+                headers.insert("age", HeaderValue::from(hit.stored_at.elapsed().as_secs()));
+                headers.insert("x-cache", HeaderValue::from_static("HIT"));
+                ctx.response_headers.write().extend(headers);
+                ctx.set_body(hit.body);
+                ctx.set_status(hit.status);
+            }
+            None => {
+                ctx.response_header_put("x-cache", "MISS");
+                ctx.var_set(PROXY_CACHE_VAR, serde_json::json!({"key": key, "consumer": ctx.consumer_id()}));
+            }
+        }
+    }
+
+    async fn store(&self, ctx: &BullGContext, cfg: &serde_json::Value) {
+        let Some(lookup) = ctx.var_get(PROXY_CACHE_VAR) else {
+            return;
+        };
+        // A consumer identified after the lookup would get an entry under another key
+        let Some(key) = lookup["key"].as_str().filter(|_| lookup["consumer"].as_str() == ctx.consumer_id().as_deref()) else {
+            return;
+        };
+        let Some(status) = *ctx.status.read() else {
+            return;
+        };
+        let cached = match cfg.get("statuses") {
+            Some(statuses) => statuses
+                .as_array()
+                .is_some_and(|s| s.iter().any(|c| c.as_u64() == Some(u64::from(status.as_u16())))),
+            None => status == StatusCode::OK,
+        };
+        let (min_body, max_body) = Self::body_sizes(cfg);
+        if !cached || ctx.streamed() || !(min_body..=max_body).contains(&ctx.body_len()) {
+            return;
+        }
+        let headers = ctx.headers.read().clone();
+        if headers.contains_key(http::header::SET_COOKIE)
+            || headers.get_all(http::header::VARY).iter().any(|v| v.as_bytes().trim_ascii() == b"*")
+        {
+            return;
+        }
+        let directives = cache_directives(headers.get_all(http::header::CACHE_CONTROL).iter().filter_map(|v| v.to_str().ok()));
+        if directives.iter().any(|(d, _)| matches!(d.as_str(), "no-store" | "private" | "no-cache")) {
+            return;
+        }
+        let max_age = ["s-maxage", "max-age"]
+            .iter()
+            .find_map(|name| directives.iter().find(|(d, _)| d == name))
+            .and_then(|(_, arg)| arg.as_deref()?.parse::<u64>().ok());
+        let ttl = cfg.get("ttl_sec").and_then(|v| v.as_u64()).unwrap_or(300).min(max_age.unwrap_or(u64::MAX));
+        if ttl == 0 {
+            return;
+        }
+        let entry = CachedResponse { status, headers, body: ctx.get_body(), stored_at: Instant::now() };
+        self.janitor.call_once(|| {
+            self.cache.start_janitor(PROXY_CACHE_SWEEP);
+        });
+        self.cache.insert_for(key.to_string(), entry, Duration::from_secs(ttl)).await;
+    }
+}
+
+#[async_trait]
+impl Plugin for ProxyCache {
+    fn name(&self) -> &'static str {
+        "proxy_cache"
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        &[Phase::Pre, Phase::Post]
+    }
+    fn needs_route(&self) -> bool {
+        true
+    }
+    async fn apply(&self, ctx: &BullGContext, phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        match phase {
+            Phase::Pre => self.lookup(ctx, cfg).await,
+            Phase::Post => self.store(ctx, cfg).await,
+            Phase::Intermediate => {}
+        }
+        Ok(())
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        for key in ["methods", "vary_headers"] {
+            if let Some(v) = cfg.get(key)
+                && !v.as_array().is_some_and(|a| a.iter().all(|e| e.is_string()))
+            {
+                bail!("{} must be a list of strings", key);
+            }
+        }
+        if let Some(name) = str_list(cfg, "vary_headers").find(|h| HeaderName::from_bytes(h.as_bytes()).is_err()) {
+            bail!("vary_headers: invalid header name {}", name);
+        }
+        if let Some(v) = cfg.get("statuses")
+            && !v.as_array().is_some_and(|a| a.iter().all(|c| c.as_u64().is_some_and(|c| (100..=599).contains(&c))))
+        {
+            bail!("statuses must be a list of status codes");
+        }
+        for key in ["ttl_sec", "min_body_size", "max_body_size"] {
+            if let Some(v) = cfg.get(key)
+                && !v.is_u64()
+            {
+                bail!("{} must be a non negative integer", key);
+            }
+        }
+        let (min_body, max_body) = Self::body_sizes(cfg);
+        if min_body > max_body {
+            bail!("min_body_size {} is above max_body_size {}", min_body, max_body);
+        }
+        if let Some(v) = cfg.get("cache_authorized")
+            && !v.is_boolean()
+        {
+            bail!("cache_authorized must be a boolean");
+        }
+        Ok(())
+    }
+}
+
+/// Custom plugin of the catalog, its handler script runs on the `Runner`
+/// with the limits `PluginsCatalog::runner_limits` gives the plugin.
+///
+/// Applied plugins refer to it by the catalog id as their `type`. The
+/// handler gets one argument and may return an object to change the
+/// request, or nothing:
+///
+/// ```python
+/// def custom_auth_handler(ctx):
+///     # ctx: phase, config, method, path, query, headers, body, params,
+///     # consumer_id, and status in the post phase
+///     if ctx["headers"].get("x-auth-token") != ctx["config"]["token_secret"]:
+///         return {"status": 401, "body": "invalid token"}
+///     return {"headers": {"x-auth-type": ctx["config"]["auth_type"]}, "vars": {"consumer_id": "partner"}}
+/// ```
+///
+/// `status` answers the request in the pre and intermediate phases,
+/// `headers` are set on the request (the response in the post phase),
+/// `response_headers` on the client response, `body` replaces the body and
+/// `vars` are set for later plugins. Scripts run on a blocking thread.
+pub struct Script {
+    name: &'static str,
+    phases: &'static [Phase],
+    lang: Lang,
+    code: Arc<str>,
+    handler: String,
+    required: Vec<String>,
+    runner: Runner,
+}
+
+impl Script {
+    /// Script plugin of a catalog entry. Its name and phases are leaked to give
+    /// them the `'static` lifetime `Plugin` wants, plugins are built once at start.
+    pub fn new(spec: &CustomPluginSpec, runner: Runner) -> Result<Self> {
+        let lang: Lang = spec.handler.language.parse().map_err(|e| anyhow!("plugin {}: {e}", spec.id))?;
+        if !lang.enabled() {
+            bail!("plugin {}: {:?} handlers need a build with the `{}` feature", spec.id, lang, lang.feature());
+        }
+        if spec.handler.code.trim().is_empty() || spec.handler.name.is_empty() {
+            bail!("plugin {}: handler needs a name and code", spec.id);
+        }
+        let phases = spec
+            .phases
+            .iter()
+            .map(|p| p.parse::<Phase>())
+            .collect::<Result<Vec<_>>>()
+            .map_err(|e| anyhow!("plugin {}: {e}", spec.id))?;
+        let phases = if phases.is_empty() { vec![Phase::Pre] } else { phases };
+        Ok(Self {
+            name: Box::leak(spec.id.clone().into_boxed_str()),
+            phases: Box::leak(phases.into_boxed_slice()),
+            lang,
+            code: spec.handler.code.as_str().into(),
+            handler: spec.handler.name.clone(),
+            required: spec.schema.required.clone().unwrap_or_default(),
+            runner,
+        })
+    }
+
+    fn args(ctx: &BullGContext, phase: Phase, cfg: &serde_json::Value) -> serde_json::Value {
+        let headers: serde_json::Map<String, serde_json::Value> = ctx
+            .headers
+            .read()
+            .iter()
+            .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.into())))
+            .collect();
+        serde_json::json!({
+            "phase": format!("{phase:?}").to_ascii_lowercase(),
+            "config": cfg,
+            "method": ctx.method.as_str(),
+            "path": ctx.uri.path(),
+            "query": ctx.query(),
+            "headers": headers,
+            "body": String::from_utf8_lossy(&ctx.get_body()),
+            "params": ctx.params(),
+            "consumer_id": ctx.consumer_id(),
+            "status": ctx.status.read().map(|s| s.as_u16()),
+        })
+    }
+
+    fn apply_result(ctx: &BullGContext, out: serde_json::Value) -> Result<()> {
+        let out = match out {
+            serde_json::Value::Null => return Ok(()),
+            serde_json::Value::Object(out) => out,
+            other => bail!("handler must return an object or nothing, got {}", other),
+        };
+        for (key, target) in [("headers", &ctx.headers), ("response_headers", &ctx.response_headers)] {
+            let Some(headers) = out.get(key) else { continue };
+            let headers = headers.as_object().ok_or_else(|| anyhow!("{key} must be an object"))?;
+            for (name, value) in headers {
+                let value = value.as_str().ok_or_else(|| anyhow!("header {name} must be a string"))?;
+                let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| anyhow!("header {name}: {e}"))?;
+                let value = HeaderValue::from_str(value).map_err(|e| anyhow!("header {name}: {e}"))?;
+                target.write().insert(name, value);
+            }
+        }
+        if let Some(vars) = out.get("vars") {
+            for (k, v) in vars.as_object().ok_or_else(|| anyhow!("vars must be an object"))? {
+                ctx.var_set(k, v.clone());
+            }
+        }
+        if let Some(body) = out.get("body") {
+            let body = body.as_str().ok_or_else(|| anyhow!("body must be a string"))?;
+            ctx.set_body(Bytes::from(body.to_string()));
+        }
+        if let Some(status) = out.get("status") {
+            let code = status.as_u64().and_then(|s| u16::try_from(s).ok());
+            let code = code.and_then(|c| StatusCode::from_u16(c).ok()).ok_or_else(|| anyhow!("invalid status: {status}"))?;
+            ctx.set_status(code);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Plugin for Script {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn supported_phases(&self) -> &'static [Phase] {
+        self.phases
+    }
+    async fn apply(&self, ctx: &BullGContext, phase: Phase, cfg: &serde_json::Value) -> Result<()> {
+        let args = Self::args(ctx, phase, cfg);
+        let (mut runner, lang, code, handler) = (self.runner.clone(), self.lang, self.code.clone(), self.handler.clone());
+        let out = tokio::task::spawn_blocking(move || runner.invoke(lang, &code, &handler, &args)).await??;
+        Self::apply_result(ctx, out)
+    }
+    fn validate(&self, cfg: &serde_json::Value) -> Result<()> {
+        if let Some(missing) = self.required.iter().find(|k| cfg.get(k.as_str()).is_none()) {
+            bail!("{} is required", missing);
+        }
+        Ok(())
+    }
+}
+
+/// Script plugins of the enabled custom plugins of `catalog`
+pub fn custom(catalog: &PluginsCatalog) -> Result<Vec<Box<dyn Plugin>>> {
+    let builtin = builtin();
+    let mut plugins: Vec<Box<dyn Plugin>> = Vec::new();
+    for spec in catalog.plugins.custom.iter().flatten().filter(|p| p.enabled) {
+        if builtin.iter().any(|p| p.name() == spec.id) {
+            bail!("custom plugin {} has the name of a builtin plugin", spec.id);
+        }
+        plugins.push(Box::new(Script::new(spec, catalog.runner(&spec.id))?));
+    }
+    Ok(plugins)
+}
+
+pub fn builtin() -> Vec<Box<dyn Plugin>> {
+    vec![
+        Box::new(Cors),
+        Box::new(RequestTermination),
+        Box::new(HttpLog),
+        Box::new(BasicAuth),
+        Box::new(SecurityHeadersPlugin),
+        Box::new(Timing),
+        Box::new(MtlsAcl),
+        Box::new(RateLimit::default()),
+        Box::new(Quota),
+        Box::new(IpRestriction),
+        Box::new(JwtAuth::default()),
+        Box::new(KeyAuth),
+        Box::new(HmacAuth),
+        Box::new(HeaderTransform),
+        Box::new(ResponseTransform),
+        Box::new(Compression),
+        Box::new(ProxyCache::default()),
+       // Box::new(LoggingPlugin),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bullg_core::{CatalogPlugins, HandlerDecl, RunnerLimitsCfg, SchemaDecl};
+    use bullg_plugin_api::MatchedRoute;
+    use http::{HeaderMap, Method};
+    use serde_json::json;
+
+    fn ctx(method: Method, uri: &str, headers: &[(&'static str, &'static str)]) -> BullGContext {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v)))
+            .collect();
+        // One client for all, building one per context is slow
+        static TOOLS: std::sync::OnceLock<Arc<bullg_plugin_api::BullGTools>> = std::sync::OnceLock::new();
+        let tools = TOOLS.get_or_init(|| Arc::new(bullg_plugin_api::BullGTools::new())).clone();
+        BullGContext::with_tools(method, uri.parse().unwrap(), headers, Bytes::new(), tools)
+    }
+
+    fn script_spec(id: &str, code: &str) -> CustomPluginSpec {
+        CustomPluginSpec {
+            id: id.into(),
+            enabled: true,
+            phases: vec!["pre".into()],
+            schema: SchemaDecl { required: Some(vec!["token".into()]), ..Default::default() },
+            handler: HandlerDecl { name: "handle".into(), language: "rhai".into(), code: code.into(), ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    fn catalog(specs: Vec<CustomPluginSpec>, runner: RunnerLimitsCfg) -> PluginsCatalog {
+        PluginsCatalog { plugins: CatalogPlugins { custom: Some(specs), ..Default::default() }, runner, ..Default::default() }
+    }
+
+    const AUTH: &str = r#"
+        fn handle(ctx) {
+            if ctx.headers["x-token"] != ctx.config.token {
+                return #{ status: 401, body: "denied " + ctx.method + " " + ctx.path };
+            }
+            #{ headers: #{ "x-checked": "yes" }, response_headers: #{ "x-auth": "script" }, vars: #{ consumer_id: "partner" } }
+        }
+    "#;
+
+    #[tokio::test]
+    async fn script_plugins_change_the_request_with_their_result() {
+        let plugins = custom(&catalog(vec![script_spec("script-auth", AUTH)], Default::default())).unwrap();
+        let plugin = &plugins[0];
+        assert_eq!(plugin.name(), "script-auth");
+        assert_eq!(plugin.supported_phases(), &[Phase::Pre]);
+        let cfg = json!({"token": "s3cret"});
+
+        let denied = ctx(Method::GET, "/users?x=1", &[("x-token", "wrong")]);
+        plugin.apply(&denied, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(*denied.status.read(), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(denied.get_body(), "denied GET /users");
+
+        let allowed = ctx(Method::GET, "/users", &[("x-token", "s3cret")]);
+        plugin.apply(&allowed, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(*allowed.status.read(), None);
+        assert_eq!(allowed.header_get("x-checked").as_deref(), Some("yes"));
+        assert_eq!(allowed.response_headers.read()["x-auth"], "script");
+        assert_eq!(allowed.consumer_id().as_deref(), Some("partner"));
+    }
+
+    #[tokio::test]
+    async fn script_plugins_validate_required_config_and_results() {
+        let bad = r#"fn handle(ctx) { #{ headers: #{ "x-bad": "line\nbreak" } } }"#;
+        let plugins = custom(&catalog(vec![script_spec("bad", bad)], Default::default())).unwrap();
+        assert!(plugins[0].validate(&json!({})).unwrap_err().to_string().contains("token is required"));
+        assert!(plugins[0].validate(&json!({"token": "t"})).is_ok());
+        // An invalid header value fails the plugin instead of panicking
+        let err = plugins[0].apply(&ctx(Method::GET, "/", &[]), Phase::Pre, &json!({})).await.unwrap_err();
+        assert!(err.to_string().contains("x-bad"));
+    }
+
+    #[tokio::test]
+    async fn script_plugins_run_with_the_catalog_limits() {
+        let spin = "fn handle(ctx) { loop {} }";
+        let mut spec = script_spec("spin", spin);
+        spec.limits = Some(RunnerLimitsCfg { max_time: Some(Duration::from_millis(20)), ..Default::default() });
+        let runner = RunnerLimitsCfg { max_code_bytes: Some(AUTH.len() - 1), rhai_max_ops: Some(u64::MAX), ..Default::default() };
+        let plugins = custom(&catalog(vec![spec, script_spec("big", AUTH)], runner)).unwrap();
+
+        let started = Instant::now();
+        assert!(plugins[0].apply(&ctx(Method::GET, "/", &[]), Phase::Pre, &json!({})).await.is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let err = plugins[1].apply(&ctx(Method::GET, "/", &[]), Phase::Pre, &json!({"token": "t"})).await.unwrap_err();
+        assert!(err.to_string().contains("code too large"));
+    }
+
+    fn routed(ctx: &BullGContext, service: &str, route: &str, require_auth: bool) {
+        ctx.set_matched_route(MatchedRoute {
+            service: service.into(),
+            version: "v1".into(),
+            route: route.into(),
+            require_auth,
+        });
+    }
+
+    fn consumer<'a>(ctx: &'a BullGContext, id: &str) -> &'a BullGContext {
+        ctx.var_set(CONSUMER_ID_VAR, json!(id));
+        ctx
+    }
+
+    // Pre phase lookup, then on a miss the upstream answers `body` and the post phase stores it
+    async fn cached(cache: &ProxyCache, ctx: &BullGContext, body: &'static str) -> Option<Bytes> {
+        let cfg = json!({"ttl_sec": 60});
+        cache.apply(ctx, Phase::Pre, &cfg).await.unwrap();
+        if ctx.status.read().is_some() {
+            return Some(ctx.get_body());
+        }
+        ctx.set_status(StatusCode::OK);
+        ctx.set_response_headers(HeaderMap::new());
+        ctx.set_body(Bytes::from_static(body.as_bytes()));
+        cache.apply(ctx, Phase::Post, &cfg).await.unwrap();
+        None
+    }
+
+    fn get(path: &str) -> BullGContext {
+        ctx(Method::GET, path, &[])
+    }
+
+    #[tokio::test]
+    async fn proxy_cache_serves_each_consumer_its_own_responses() {
+        let cache = ProxyCache::default();
+        let alice = get("/users");
+        routed(consumer(&alice, "alice"), "svc", "users", false);
+        assert_eq!(cached(&cache, &alice, "alice's").await, None);
+
+        let again = get("/users");
+        routed(consumer(&again, "alice"), "svc", "users", false);
+        assert_eq!(cached(&cache, &again, "x").await.as_deref(), Some(&b"alice's"[..]));
+        assert_eq!(again.response_headers.read()["x-cache"], "HIT");
+
+        let bob = get("/users");
+        routed(consumer(&bob, "bob"), "svc", "users", false);
+        assert_eq!(cached(&cache, &bob, "bob's").await, None);
+
+        // A spoofed consumer header is not the authenticated consumer
+        let anonymous = ctx(Method::GET, "/users", &[("x-consumer-id", "alice")]);
+        routed(&anonymous, "svc", "users", false);
+        assert_eq!(cached(&cache, &anonymous, "public").await, None);
+    }
+
+    #[tokio::test]
+    async fn proxy_cache_keys_include_the_matched_route() {
+        let cache = ProxyCache::default();
+        let first = get("/users");
+        routed(&first, "svc", "users", false);
+        assert_eq!(cached(&cache, &first, "svc").await, None);
+
+        for (service, route) in [("other", "users"), ("svc", "admins")] {
+            let ctx = get("/users");
+            routed(&ctx, service, route, false);
+            assert_eq!(cached(&cache, &ctx, "x").await, None, "{service} {route}");
+        }
+    }
+
+    #[tokio::test]
+    async fn proxy_cache_needs_a_consumer_where_authentication_is_required() {
+        let cache = ProxyCache::default();
+        for _ in 0..2 {
+            let anonymous = get("/users");
+            routed(&anonymous, "svc", "users", true);
+            assert_eq!(cached(&cache, &anonymous, "x").await, None);
+            assert!(anonymous.response_headers.read().get("x-cache").is_none());
+        }
+
+        // Not routed, as for a global plugin
+        for _ in 0..2 {
+            assert_eq!(cached(&cache, &get("/users"), "x").await, None);
+        }
+        assert!(cache.needs_route());
+    }
+
+    #[tokio::test]
+    async fn proxy_cache_only_stores_bodies_within_the_size_range() {
+        let cache = ProxyCache::default();
+        let cfg = json!({"min_body_size": 4, "max_body_size": 8});
+        for (path, body, stored) in [("/small", "abc", false), ("/large", "123456789", false), ("/min", "abcd", true), ("/max", "12345678", true)] {
+            let miss = get(path);
+            routed(&miss, "svc", "users", false);
+            cache.apply(&miss, Phase::Pre, &cfg).await.unwrap();
+            miss.set_status(StatusCode::OK);
+            miss.set_response_headers(HeaderMap::new());
+            miss.set_body(Bytes::from_static(body.as_bytes()));
+            cache.apply(&miss, Phase::Post, &cfg).await.unwrap();
+
+            let again = get(path);
+            routed(&again, "svc", "users", false);
+            cache.apply(&again, Phase::Pre, &cfg).await.unwrap();
+            assert_eq!(again.status.read().is_some(), stored, "{path}");
+        }
+    }
+
+    #[test]
+    fn proxy_cache_validates_the_body_sizes() {
+        let cache = ProxyCache::default();
+        assert!(cache.validate(&json!({"min_body_size": 10, "max_body_size": 10})).is_ok());
+        assert!(cache.validate(&json!({"min_body_size": 2 << 20})).unwrap_err().to_string().contains("above max_body_size"));
+        assert!(cache.validate(&json!({"min_body_size": -1})).is_err());
+        assert!(cache.validate(&json!({"max_body_size": "1MB"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_cache_does_not_store_for_a_consumer_identified_after_the_lookup() {
+        let cache = ProxyCache::default();
+        let late = get("/users");
+        routed(&late, "svc", "users", false);
+        let cfg = json!({});
+        cache.apply(&late, Phase::Pre, &cfg).await.unwrap();
+        consumer(&late, "alice");
+        late.set_status(StatusCode::OK);
+        late.set_response_headers(HeaderMap::new());
+        late.set_body(Bytes::from_static(b"alice's"));
+        cache.apply(&late, Phase::Post, &cfg).await.unwrap();
+
+        let anonymous = get("/users");
+        routed(&anonymous, "svc", "users", false);
+        assert_eq!(cached(&cache, &anonymous, "public").await, None);
+    }
+
+    fn from(ip: &str, ctx: BullGContext) -> BullGContext {
+        ctx.with_client_addr(std::net::SocketAddr::new(ip.parse().unwrap(), 40000))
+    }
+
+    async fn limited(plugin: &dyn Plugin, ctx: &BullGContext, cfg: &serde_json::Value) -> bool {
+        plugin.apply(ctx, Phase::Pre, cfg).await.unwrap();
+        *ctx.status.read() == Some(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    #[tokio::test]
+    async fn rate_limit_rejects_requests_past_the_limit() {
+        let limit = RateLimit::default();
+        let cfg = json!({"limit": 2, "window_sec": 60});
+        for _ in 0..2 {
+            assert!(!limited(&limit, &from("10.0.0.1", get("/")), &cfg).await);
+        }
+        let rejected = from("10.0.0.1", get("/"));
+        assert!(limited(&limit, &rejected, &cfg).await);
+        let headers = rejected.response_headers.read().clone();
+        assert_eq!(headers["x-ratelimit-limit"], "2");
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        assert!(headers["retry-after"].to_str().unwrap().parse::<u64>().unwrap() <= 60);
+
+        // Other clients and scopes have counters of their own
+        assert!(!limited(&limit, &from("10.0.0.2", get("/")), &cfg).await);
+        assert!(!limited(&limit, &from("10.0.0.1", get("/")), &json!({"limit": 2, "scope": "other"})).await);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_counts_the_authenticated_consumer_only() {
+        let limit = RateLimit::default();
+        let cfg = json!({"limit": 1, "key": "consumer"});
+        let alice = from("10.0.0.1", get("/"));
+        assert!(!limited(&limit, consumer(&alice, "alice"), &cfg).await);
+        let again = from("10.0.0.9", get("/"));
+        assert!(limited(&limit, consumer(&again, "alice"), &cfg).await);
+
+        // Claiming to be alice neither uses up nor is refused her limit
+        let spoofed = from("10.0.0.2", ctx(Method::GET, "/", &[("x-consumer-id", "alice")]));
+        assert!(!limited(&limit, &spoofed, &cfg).await);
+        let spoofed = from("10.0.0.2", ctx(Method::GET, "/", &[("x-consumer-id", "bob")]));
+        assert!(limited(&limit, &spoofed, &cfg).await);
+        assert!(!limited(&limit, &from("10.0.0.3", get("/")), &cfg).await);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_sweeps_are_spaced_out() {
+        let limit = RateLimit::default();
+        let cfg = json!({"limit": 1, "window_sec": 86400, "key": "header:x-key"});
+        for i in 0..RATE_LIMIT_SWEEP + 1 {
+            let key = HeaderValue::from_str(&i.to_string()).unwrap();
+            let ctx = get("/");
+            ctx.headers.write().insert("x-key", key);
+            limit.apply(&ctx, Phase::Pre, &cfg).await.unwrap();
+        }
+        let counters = limit.counters.lock().unwrap();
+        // Current counters are kept, the next sweep waits for twice as many
+        assert_eq!(counters.windows.len(), RATE_LIMIT_SWEEP + 1);
+        assert_eq!(counters.sweep_at, RATE_LIMIT_SWEEP * 2);
+    }
+
+    #[tokio::test]
+    async fn http_log_does_not_wait_for_the_log_endpoint() {
+        // Accepts the log request but never answers it
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let cfg = json!({"endpoint": format!("http://{}/logs", listener.local_addr().unwrap()), "timeout_sec": 1});
+        let started = Instant::now();
+        HttpLog.apply(&get("/"), Phase::Post, &cfg).await.unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+        let (_conn, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept()).await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn http_log_timeouts_are_seconds() {
+        assert!(HttpLog.validate(&json!({"timeout_sec": 2})).is_ok());
+        assert!(HttpLog.validate(&json!({"timeout_sec": "2s"})).is_err());
+    }
+
+    #[tokio::test]
+    async fn basic_auth_identifies_the_consumer_with_the_right_password() {
+        let cfg = json!({"user": "alice", "pass": "s3cret"});
+        let ok = ctx(Method::GET, "/", &[("authorization", "Basic YWxpY2U6czNjcmV0"), ("x-consumer-id", "bob")]);
+        BasicAuth.apply(&ok, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(*ok.status.read(), None);
+        assert_eq!(ok.consumer_id().as_deref(), Some("alice"));
+        assert_eq!(ok.header_get("x-consumer-id").as_deref(), Some("alice"));
+
+        let wrong = ctx(Method::GET, "/", &[("authorization", "Basic YWxpY2U6czNjcmVU")]);
+        BasicAuth.apply(&wrong, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(*wrong.status.read(), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(wrong.consumer_id(), None);
+    }
+
+    fn consumer_tools(consumers: Vec<serde_json::Value>) -> Arc<bullg_plugin_api::BullGTools> {
+        let template: bullg_core::ConsumersTemplate = serde_json::from_value(json!({"consumers": consumers})).unwrap();
+        let memory = bullg_core::Memory::memory();
+        memory.insert_many(CONSUMERS_DB, template.consumers.iter().map(|c| (c.id.clone(), c))).unwrap();
+        memory.insert_many(CONSUMER_INDEX_DB, template.index()).unwrap();
+        Arc::new(bullg_plugin_api::BullGTools::with_store(bullg_core::AsyncMemory::new(Arc::new(memory))))
+    }
+
+    fn with_header(tools: &Arc<bullg_plugin_api::BullGTools>, name: &'static str, value: &str) -> BullGContext {
+        let headers = [(HeaderName::from_static(name), HeaderValue::from_str(value).unwrap())].into_iter().collect();
+        BullGContext::with_tools(Method::GET, "/".parse().unwrap(), headers, Bytes::new(), tools.clone())
+    }
+
+    #[tokio::test]
+    async fn key_auth_finds_the_consumer_of_a_key_or_app_key() {
+        let tools = consumer_tools(vec![
+            json!({"id": "alice", "authentication": {"api_key": [{"key": "alice-key"}]}}),
+            json!({"id": "bob", "apps": [{"id": "mobile", "keys": ["bob-app-key"]}]}),
+            json!({"id": "carol", "enabled": false, "authentication": {"api_key": [{"key": "carol-key"}]}}),
+        ]);
+        let cfg = json!({});
+        let alice = with_header(&tools, "apikey", "alice-key");
+        KeyAuth.apply(&alice, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(alice.consumer_id().as_deref(), Some("alice"));
+
+        let bob = with_header(&tools, "apikey", "bob-app-key");
+        KeyAuth.apply(&bob, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(bob.consumer_id().as_deref(), Some("bob"));
+        assert_eq!(bob.var_get("app_id"), Some(json!("mobile")));
+
+        for key in ["carol-key", "alice-ke"] {
+            let denied = with_header(&tools, "apikey", key);
+            KeyAuth.apply(&denied, Phase::Pre, &cfg).await.unwrap();
+            assert_eq!(*denied.status.read(), Some(StatusCode::UNAUTHORIZED));
+        }
+    }
+
+    fn bearer(claims: serde_json::Value, secret: &str) -> String {
+        let key = jsonwebtoken::EncodingKey::from_secret(secret.as_bytes());
+        format!("Bearer {}", jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap())
+    }
+
+    #[tokio::test]
+    async fn jwt_auth_verifies_with_the_keys_of_the_token_issuer() {
+        let tools = consumer_tools(vec![
+            json!({"id": "partner", "authentication": {"jwt": [{"secret": "partner-secret", "issuer": "idp"}]}}),
+            json!({"id": "internal", "authentication": {"jwt": [{"secret": "internal-secret"}]}}),
+        ]);
+        let cfg = json!({});
+        let exp = Utc::now().timestamp() + 60;
+        let cases = [
+            (json!({"iss": "idp", "exp": exp}), "partner-secret", Some("partner")),
+            (json!({"iss": "other", "exp": exp}), "internal-secret", Some("internal")),
+            (json!({"exp": exp}), "internal-secret", Some("internal")),
+            // The issuer picks the key, a partner token claiming another one is not accepted
+            (json!({"iss": "other", "exp": exp}), "partner-secret", None),
+        ];
+        for (claims, secret, expected) in cases {
+            let ctx = with_header(&tools, "authorization", &bearer(claims, secret));
+            JwtAuth::default().apply(&ctx, Phase::Pre, &cfg).await.unwrap();
+            assert_eq!(ctx.consumer_id().as_deref(), expected);
+        }
+    }
+
+    async fn jwt(authorization: &str, cfg: &serde_json::Value) -> BullGContext {
+        let ctx = with_header(&quota_tools(), "authorization", authorization);
+        JwtAuth::default().apply(&ctx, Phase::Pre, cfg).await.unwrap();
+        ctx
+    }
+
+    #[tokio::test]
+    async fn jwt_auth_rejects_expired_misaddressed_and_malformed_tokens() {
+        let cfg = json!({"secret": "s3cret", "issuer": "idp", "audience": "orders", "leeway_sec": 30});
+        let now = Utc::now().timestamp();
+        let claims = |exp: i64, aud: &str| json!({"sub": "alice", "iss": "idp", "aud": aud, "exp": exp});
+
+        let ok = jwt(&bearer(claims(now + 60, "orders"), "s3cret"), &cfg).await;
+        assert_eq!(*ok.status.read(), None);
+        assert_eq!(ok.consumer_id().as_deref(), Some("alice"));
+        assert_eq!(ok.header_get("x-consumer-id").as_deref(), Some("alice"));
+        // Within the leeway
+        assert_eq!(*jwt(&bearer(claims(now - 10, "orders"), "s3cret"), &cfg).await.status.read(), None);
+
+        let hs384 = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::HS384),
+            &claims(now + 60, "orders"),
+            &jsonwebtoken::EncodingKey::from_secret(b"s3cret"),
+        )
+        .unwrap();
+        for authorization in [
+            bearer(claims(now - 120, "orders"), "s3cret"),
+            bearer(claims(now + 60, "billing"), "s3cret"),
+            bearer(json!({"sub": "alice", "iss": "other", "aud": "orders", "exp": now + 60}), "s3cret"),
+            bearer(claims(now + 60, "orders"), "guessed"),
+            format!("Bearer {hs384}"),
+            "Bearer not.a.jwt".into(),
+            "Bearer e30".into(),
+        ] {
+            let denied = jwt(&authorization, &cfg).await;
+            assert_eq!(*denied.status.read(), Some(StatusCode::UNAUTHORIZED), "{authorization}");
+            assert_eq!(denied.response_headers.read()["www-authenticate"], "Bearer error=\"invalid_token\"");
+            assert_eq!(denied.consumer_id(), None);
+        }
+
+        for authorization in ["Basic YWxpY2U6czNjcmV0", "Bearer"] {
+            let missing = jwt(authorization, &cfg).await;
+            assert_eq!(*missing.status.read(), Some(StatusCode::UNAUTHORIZED));
+            assert_eq!(missing.response_headers.read()["www-authenticate"], "Bearer");
+        }
+    }
+
+    #[test]
+    fn invalid_header_values_are_errors() {
+        let ctx = get("/");
+        assert!(ctx.header_put("x-consumer-id", "bad\nvalue").is_err());
+        assert!(ctx.header_put("bad header", "value").is_err());
+        ctx.header_put("x-consumer-id", "alice").unwrap();
+        assert_eq!(ctx.header_get("x-consumer-id").as_deref(), Some("alice"));
+    }
+
+    fn quota_tools() -> Arc<bullg_plugin_api::BullGTools> {
+        let store = bullg_core::AsyncMemory::new(Arc::new(bullg_core::Memory::memory()));
+        Arc::new(bullg_plugin_api::BullGTools::with_store(store))
+    }
+
+    fn quota_ctx(tools: &Arc<bullg_plugin_api::BullGTools>, ip: &str, headers: &[(&'static str, &'static str)]) -> BullGContext {
+        let headers = headers.iter().map(|(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v))).collect();
+        from(ip, BullGContext::with_tools(Method::GET, "/".parse().unwrap(), headers, Bytes::new(), tools.clone()))
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    async fn over_quota(ctx: &BullGContext, cfg: &serde_json::Value, now: &str) -> bool {
+        Quota.count(ctx, cfg, utc(now)).await.unwrap();
+        *ctx.status.read() == Some(StatusCode::TOO_MANY_REQUESTS)
+    }
+
+    #[tokio::test]
+    async fn quota_blocks_after_the_daily_limit_and_resets_the_next_day() {
+        let tools = quota_tools();
+        let cfg = json!({"limit": 2, "period": "day"});
+        let alice = || {
+            let ctx = quota_ctx(&tools, "10.0.0.1", &[]);
+            consumer(&ctx, "alice");
+            ctx
+        };
+        assert!(!over_quota(&alice(), &cfg, "2026-03-01T08:00:00Z").await);
+        assert!(!over_quota(&alice(), &cfg, "2026-03-01T12:00:00Z").await);
+        let blocked = alice();
+        assert!(over_quota(&blocked, &cfg, "2026-03-01T23:59:00Z").await);
+        let headers = blocked.response_headers.read().clone();
+        assert_eq!(headers["x-quota-remaining"], "0");
+        assert_eq!(headers["retry-after"], "60");
+
+        let next_day = alice();
+        assert!(!over_quota(&next_day, &cfg, "2026-03-02T00:00:01Z").await);
+        assert_eq!(next_day.response_headers.read()["x-quota-remaining"], "1");
+    }
+
+    #[tokio::test]
+    async fn quota_counts_the_authenticated_consumer_only() {
+        let tools = quota_tools();
+        let cfg = json!({"limit": 1, "limits": {"partner": 3}});
+        let now = "2026-03-01T08:00:00Z";
+        let partner = quota_ctx(&tools, "10.0.0.1", &[]);
+        consumer(&partner, "partner");
+        assert!(!over_quota(&partner, &cfg, now).await);
+        assert_eq!(partner.response_headers.read()["x-quota-limit"], "3");
+
+        // The header does not give the partner quota, nor charge the partner
+        let spoofed = quota_ctx(&tools, "10.0.0.2", &[("x-consumer-id", "partner")]);
+        assert!(!over_quota(&spoofed, &cfg, now).await);
+        assert_eq!(spoofed.response_headers.read()["x-quota-limit"], "1");
+        assert!(over_quota(&quota_ctx(&tools, "10.0.0.2", &[("x-consumer-id", "partner")]), &cfg, now).await);
+        let partner = quota_ctx(&tools, "10.0.0.1", &[]);
+        consumer(&partner, "partner");
+        assert!(!over_quota(&partner, &cfg, now).await);
+        assert_eq!(partner.response_headers.read()["x-quota-remaining"], "1");
+    }
+
+    #[test]
+    fn quota_periods_are_calendar_periods() {
+        let now = utc("2026-01-31T10:30:00Z");
+        assert_eq!(QuotaPeriod::Hour.window(now), ("2026-01-31T10".into(), "2026-01-31T09".into(), 1800));
+        assert_eq!(QuotaPeriod::Day.window(now), ("2026-01-31".into(), "2026-01-30".into(), 48600));
+        assert_eq!(QuotaPeriod::Month.window(now).0, "2026-01");
+        assert_eq!(QuotaPeriod::Month.window(now).1, "2025-12");
+    }
+
+    #[test]
+    fn custom_plugins_are_checked_when_loaded() {
+        let named = |id: &str, f: fn(&mut CustomPluginSpec)| {
+            let mut spec = script_spec(id, AUTH);
+            f(&mut spec);
+            custom(&catalog(vec![spec], Default::default())).err().map(|e| e.to_string())
+        };
+        assert!(named("cors", |_| {}).unwrap().contains("builtin"));
+        assert!(named("x", |s| s.handler.language = "cobol".into()).unwrap().contains("unknown script language"));
+        assert!(named("x", |s| s.phases = vec!["later".into()]).unwrap().contains("unknown plugin phase"));
+        assert!(named("x", |s| s.handler.code.clear()).unwrap().contains("needs a name and code"));
+        assert!(named("x", |s| s.enabled = false).is_none());
+    }
+
+    #[tokio::test]
+    async fn auth_rejections_answer_json_to_json_clients() {
+        let cfg = json!({"user": "alice", "pass": "s3cret"});
+        let c = ctx(Method::GET, "/", &[("accept", "text/html, application/problem+json;q=0.9")]);
+        c.set_request_id("req-1");
+        BasicAuth.apply(&c, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(*c.status.read(), Some(StatusCode::UNAUTHORIZED));
+        assert_eq!(c.response_headers.read()["content-type"], "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&c.get_body()).unwrap();
+        assert_eq!(
+            body,
+            json!({"error": "unauthorized", "code": 401, "message": "Unauthorized request: Failed", "request_id": "req-1"})
+        );
+    }
+
+    #[tokio::test]
+    async fn auth_rejections_answer_text_when_configured_or_not_accepted() {
+        let json_client = [("accept", "application/json")];
+        let cfg = json!({"user": "alice", "pass": "s3cret", "error_format": "text", "message": "log in first"});
+        let c = ctx(Method::GET, "/", &json_client);
+        BasicAuth.apply(&c, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(c.response_headers.read()["content-type"], "text/plain; charset=utf-8");
+        assert_eq!(c.get_body(), "log in first");
+
+        let c = ctx(Method::GET, "/", &[]);
+        BasicAuth.apply(&c, Phase::Pre, &json!({"user": "alice", "pass": "s3cret"})).await.unwrap();
+        assert_eq!(c.get_body(), "Unauthorized request: Failed");
+
+        // Forced JSON without an Accept header
+        let c = ctx(Method::GET, "/", &[]);
+        BasicAuth.apply(&c, Phase::Pre, &json!({"user": "alice", "pass": "s3cret", "error_format": "json"})).await.unwrap();
+        assert_eq!(c.response_headers.read()["content-type"], "application/json");
+
+        let err = BasicAuth.validate(&json!({"user": "a", "pass": "b", "error_format": "xml"})).unwrap_err();
+        assert!(err.to_string().contains("invalid error_format"));
+    }
+
+    const PIN: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn with_cert(subject: &str, sans: &[&str], pin: &str) -> BullGContext {
+        get("/").with_client_cert(bullg_plugin_api::ClientCert {
+            subject: subject.into(),
+            sans: sans.iter().map(|s| s.to_string()).collect(),
+            spki_sha256: pin.into(),
+        })
+    }
+
+    #[tokio::test]
+    async fn mtls_acl_allows_listed_identities_only() {
+        let cfg = json!({"subjects": ["CN=orders,O=acme"], "sans": ["spiffe://acme/billing"], "spki_sha256": [PIN.to_uppercase()]});
+        MtlsAcl.validate(&cfg).unwrap();
+        for allowed in [
+            with_cert("CN=orders,O=acme", &[], ""),
+            with_cert("CN=billing", &["billing.internal", "spiffe://acme/billing"], ""),
+            with_cert("CN=pinned", &[], PIN),
+        ] {
+            MtlsAcl.apply(&allowed, Phase::Pre, &cfg).await.unwrap();
+            assert_eq!(*allowed.status.read(), None);
+        }
+
+        let other = with_cert("CN=orders,O=evil", &["spiffe://evil/orders"], &"0".repeat(64));
+        MtlsAcl.apply(&other, Phase::Pre, &cfg).await.unwrap();
+        assert_eq!(*other.status.read(), Some(StatusCode::FORBIDDEN));
+        assert_eq!(other.get_body(), "Client certificate not allowed");
+    }
+
+    #[tokio::test]
+    async fn mtls_acl_fails_closed_without_a_client_certificate() {
+        let anonymous = get("/");
+        MtlsAcl.apply(&anonymous, Phase::Pre, &json!({"subjects": ["CN=orders"]})).await.unwrap();
+        assert_eq!(*anonymous.status.read(), Some(StatusCode::FORBIDDEN));
+        assert_eq!(anonymous.get_body(), "Client certificate required");
+
+        assert!(MtlsAcl.validate(&json!({})).is_err());
+        assert!(MtlsAcl.validate(&json!({"spki_sha256": ["abc"]})).is_err());
+        assert!(MtlsAcl.validate(&json!({"subjects": "CN=orders"})).is_err());
+    }
+
+    async fn restricted(ctx: BullGContext, cfg: &serde_json::Value) -> bool {
+        IpRestriction.apply(&ctx, Phase::Pre, cfg).await.unwrap();
+        *ctx.status.read() == Some(StatusCode::FORBIDDEN)
+    }
+
+    #[tokio::test]
+    async fn ip_restriction_matches_ipv4_and_ipv6_ranges_deny_first() {
+        let cfg = json!({"allow": ["10.0.0.0/8", "2001:db8::/32"], "deny": ["10.0.13.0/24", "2001:db8:bad::/48"]});
+        for (ip, denied) in [
+            ("10.1.2.3", false),
+            ("10.0.13.7", true),
+            ("192.168.0.1", true),
+            ("2001:db8::1", false),
+            ("2001:db8:bad::1", true),
+            ("2001:db9::1", true),
+            // IPv4-mapped clients match IPv4 entries
+            ("::ffff:10.1.2.3", false),
+            ("::ffff:10.0.13.7", true),
+        ] {
+            assert_eq!(restricted(from(ip, get("/")), &cfg).await, denied, "{ip}");
+        }
+
+        let rejected = from("192.168.0.1", get("/"));
+        IpRestriction.apply(&rejected, Phase::Pre, &json!({"deny": ["0.0.0.0/0"], "message": "go away"})).await.unwrap();
+        assert_eq!(*rejected.status.read(), Some(StatusCode::FORBIDDEN));
+        assert_eq!(rejected.get_body(), "go away");
+    }
+
+    #[tokio::test]
+    async fn ip_restriction_ignores_forwarding_headers_from_untrusted_peers() {
+        let cfg = json!({"allow": ["10.0.0.0/8"], "trusted_proxies": ["127.0.0.1", "::1"]});
+        // A client claiming to be allowed
+        let spoofed = ctx(Method::GET, "/", &[("x-forwarded-for", "10.1.2.3"), ("x-real-ip", "10.1.2.3")]);
+        assert!(restricted(from("203.0.113.9", spoofed), &cfg).await);
+
+        // Behind a trusted proxy the first untrusted hop from the right is the client
+        let proxied = ctx(Method::GET, "/", &[("x-forwarded-for", "203.0.113.9, 10.1.2.3")]);
+        assert!(!restricted(from("::1", proxied), &cfg).await);
+        let prepended = ctx(Method::GET, "/", &[("x-forwarded-for", "10.1.2.3, 203.0.113.9, 127.0.0.1")]);
+        assert!(restricted(from("127.0.0.1", prepended), &cfg).await);
+        let garbage = ctx(Method::GET, "/", &[("x-forwarded-for", "10.1.2.3, not-an-ip")]);
+        assert!(restricted(from("127.0.0.1", garbage), &cfg).await);
+        let real_ip = ctx(Method::GET, "/", &[("x-real-ip", "10.9.9.9")]);
+        assert!(!restricted(from("127.0.0.1", real_ip), &cfg).await);
+    }
+
+    #[test]
+    fn ip_restriction_validates_its_ranges() {
+        assert!(IpRestriction.validate(&json!({"allow": ["10.0.0.0/8", "::1"]})).is_ok());
+        assert!(IpRestriction.validate(&json!({})).is_err());
+        assert!(IpRestriction.validate(&json!({"allow": ["10.0.0.0/33"]})).is_err());
+        assert!(IpRestriction.validate(&json!({"deny": ["2001:db8::/129"]})).is_err());
+        assert!(IpRestriction.validate(&json!({"deny": ["example.com"]})).is_err());
+        assert!(IpRestriction.validate(&json!({"deny": "10.0.0.1"})).is_err());
+    }
+
+    async fn transformed(content_type: &'static str, body: &str, cfg: &serde_json::Value) -> Bytes {
+        let ctx = ctx(Method::GET, "/", &[("content-type", content_type)]);
+        ctx.set_body(Bytes::from(body.to_string()));
+        ResponseTransform.apply(&ctx, Phase::Post, cfg).await.unwrap();
+        ctx.get_body()
+    }
+
+    #[tokio::test]
+    async fn response_transform_removes_nested_fields_and_renames_keys() {
+        let body = json!({
+            "internal_id": 7,
+            "order": {"id": "o-1", "audit": {"by": "svc", "at": 1}},
+            "items": [{"sku": "a", "qty": 1, "cost": 3}, {"sku": "b", "qty": 2, "cost": 4}],
+        });
+        let cfg = json!({"remove": ["internal_id", "order.audit.by", "items.*.cost", "items.5"], "rename": ["items.*.qty:quantity"]});
+        let out = transformed("application/json; charset=utf-8", &body.to_string(), &cfg).await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&out).unwrap(),
+            json!({
+                "order": {"id": "o-1", "audit": {"at": 1}},
+                "items": [{"sku": "a", "quantity": 1}, {"sku": "b", "quantity": 2}],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn response_transform_wraps_in_an_envelope_then_adds_fields() {
+        let cfg = json!({"envelope": "data", "add": {"status": "ok", "meta.version": 2}});
+        let out = transformed("application/problem+json", "[1, 2]", &cfg).await;
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&out).unwrap(),
+            json!({"data": [1, 2], "status": "ok", "meta": {"version": 2}})
+        );
+        // Not JSON by type or by body, left as is
+        assert_eq!(transformed("text/plain", "[1, 2]", &cfg).await, "[1, 2]");
+        assert_eq!(transformed("application/json", "{oops", &cfg).await, "{oops");
+        // Bodyless answers such as a 204 stay empty
+        assert_eq!(transformed("application/json", "", &cfg).await, "");
+    }
+
+    #[test]
+    fn response_transform_validates_its_lists() {
+        assert!(ResponseTransform.validate(&json!({"remove": ["a.b"], "rename": ["a:b"], "envelope": "data"})).is_ok());
+        assert!(ResponseTransform.validate(&json!({"remove": "a"})).is_err());
+        assert!(ResponseTransform.validate(&json!({"rename": ["a"]})).is_err());
+        assert!(ResponseTransform.validate(&json!({"envelope": 1})).is_err());
+    }
+
+    async fn cors(ctx: BullGContext, cfg: serde_json::Value) -> BullGContext {
+        Cors.validate(&cfg).unwrap();
+        Cors.apply(&ctx, Phase::Pre, &cfg).await.unwrap();
+        ctx
+    }
+
+    fn preflight(origin: &'static str) -> BullGContext {
+        ctx(
+            Method::OPTIONS,
+            "/users",
+            &[
+                ("origin", origin),
+                ("access-control-request-method", "PUT"),
+                ("access-control-request-headers", "x-trace, content-type"),
+            ],
+        )
+    }
+
+    #[tokio::test]
+    async fn cors_preflights_are_answered_with_the_allowed_methods_and_headers() {
+        let cfg = json!({"allow_origins": ["https://app.example.com"], "allow_methods": "GET, PUT", "max_age": 600});
+        let answered = cors(preflight("https://app.example.com"), cfg).await;
+        assert_eq!(*answered.status.read(), Some(StatusCode::NO_CONTENT));
+        assert!(answered.get_body().is_empty());
+        let headers = answered.response_headers.read().clone();
+        assert_eq!(headers["access-control-allow-origin"], "https://app.example.com");
+        assert_eq!(headers["access-control-allow-methods"], "GET, PUT");
+        // Without allow_headers the requested ones are allowed
+        assert_eq!(headers["access-control-allow-headers"], "x-trace, content-type");
+        assert_eq!(headers["access-control-max-age"], "600");
+
+        let listed = cors(preflight("https://app.example.com"), json!({"allow_headers": ["authorization"]})).await;
+        let headers = listed.response_headers.read().clone();
+        assert_eq!(headers["access-control-allow-methods"], CORS_DEFAULT_METHODS);
+        assert_eq!(headers["access-control-allow-headers"], "authorization");
+        assert!(!headers.contains_key("access-control-max-age"));
+    }
+
+    #[tokio::test]
+    async fn cors_disallowed_origins_get_no_allow_origin() {
+        let cfg = json!({"allow_origins": ["https://app.example.com"]});
+        let simple = cors(ctx(Method::GET, "/users", &[("origin", "https://evil.example")]), cfg.clone()).await;
+        assert_eq!(*simple.status.read(), None);
+        assert!(simple.response_headers.read().get("access-control-allow-origin").is_none());
+
+        let refused = cors(preflight("https://evil.example"), cfg.clone()).await;
+        assert_eq!(*refused.status.read(), Some(StatusCode::FORBIDDEN));
+        assert!(refused.response_headers.read().is_empty());
+        // An allow list needs an origin to match
+        let no_origin = cors(ctx(Method::GET, "/users", &[]), cfg).await;
+        assert!(no_origin.response_headers.read().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cors_with_credentials_echoes_the_origin() {
+        let cfg = json!({"allow_origins": "*", "allow_credentials": true, "expose_headers": ["x-request-id", "x-cache"]});
+        let answered = cors(ctx(Method::GET, "/users", &[("origin", "https://app.example.com")]), cfg).await;
+        assert_eq!(*answered.status.read(), None);
+        let headers = answered.response_headers.read().clone();
+        assert_eq!(headers["access-control-allow-origin"], "https://app.example.com");
+        assert_eq!(headers["vary"], "origin");
+        assert_eq!(headers["access-control-allow-credentials"], "true");
+        assert_eq!(headers["access-control-expose-headers"], "x-request-id, x-cache");
+    }
+
+    #[tokio::test]
+    async fn cors_legacy_allow_origin_is_still_sent() {
+        let cfg = json!({"allow_origin": "https://app.example.com"});
+        let answered = cors(ctx(Method::GET, "/users", &[("origin", "https://app.example.com")]), cfg).await;
+        let headers = answered.response_headers.read().clone();
+        assert_eq!(headers["access-control-allow-origin"], "https://app.example.com");
+        assert!(!headers.contains_key("vary"));
+        let any = cors(ctx(Method::GET, "/users", &[]), json!({})).await;
+        assert_eq!(any.response_headers.read()["access-control-allow-origin"], "*");
+    }
+
+    #[test]
+    fn cors_validates_its_config() {
+        assert!(Cors.validate(&json!({"allow_origins": [1]})).is_err());
+        assert!(Cors.validate(&json!({"allow_methods": ["GET", "NOT A METHOD"]})).is_err());
+        assert!(Cors.validate(&json!({"allow_origin": true})).is_err());
+        assert!(Cors.validate(&json!({"allow_credentials": "yes"})).is_err());
+        assert!(Cors.validate(&json!({"max_age": -1})).is_err());
+    }
+
+    fn values(headers: &HeaderMap, name: &str) -> Vec<String> {
+        headers.get_all(name).iter().map(|v| v.to_str().unwrap().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn header_transform_removes_and_renames_without_case() {
+        let req = ctx(
+            Method::GET,
+            "/users",
+            &[("x-internal-token", "secret"), ("x-user", "alice"), ("x-user", "bob"), ("x-keep", "1")],
+        );
+        let cfg = json!({"request": {"remove": ["X-Internal-Token"], "rename": ["X-User:x-forwarded-user"]}});
+        HeaderTransform.validate(&cfg).unwrap();
+        HeaderTransform.apply(&req, Phase::Pre, &cfg).await.unwrap();
+        let headers = req.headers.read().clone();
+        assert!(!headers.contains_key("x-internal-token") && !headers.contains_key("x-user"));
+        assert_eq!(values(&headers, "x-forwarded-user"), ["alice", "bob"]);
+        assert_eq!(headers["x-keep"], "1");
+    }
+
+    #[tokio::test]
+    async fn header_transform_sets_overrides_or_appends() {
+        let headers_with = |mode: &str| {
+            let cfg = json!({"request": {"set": ["x-env:prod"], "add": ["x-tag:b"], "mode": mode}});
+            async move {
+                let req = ctx(Method::GET, "/users", &[("x-env", "dev"), ("x-env", "test"), ("x-tag", "a")]);
+                HeaderTransform.apply(&req, Phase::Pre, &cfg).await.unwrap();
+                let headers = req.headers.read().clone();
+                (values(&headers, "x-env"), values(&headers, "x-tag"))
+            }
+        };
+        assert_eq!(headers_with("override").await, (vec!["prod".to_string()], vec!["b".to_string()]));
+        assert_eq!(headers_with("append").await, (vec!["prod".to_string()], vec!["a".to_string(), "b".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn header_transform_interpolates_request_values() {
+        let req = ctx(Method::POST, "/users/7?expand=true", &[]).with_client_addr("10.0.0.9:4000".parse().unwrap());
+        req.set_request_id("req-1");
+        req.set_params(HashMap::from([("id".to_string(), "7".to_string())]));
+        req.var_set("consumer_id", json!("partner"));
+        req.var_set("tier", json!(2));
+        let cfg = json!({"request": {"set": [
+            "x-trace:${request_id} ${method} ${path}?${query}",
+            "x-caller:${consumer_id}/${tier}@${client_ip}",
+            "x-user:${param.id}${missing}",
+        ]}});
+        HeaderTransform.apply(&req, Phase::Pre, &cfg).await.unwrap();
+        let headers = req.headers.read().clone();
+        assert_eq!(headers["x-trace"], "req-1 POST /users/7?expand=true");
+        assert_eq!(headers["x-caller"], "partner/2@10.0.0.9");
+        assert_eq!(headers["x-user"], "7");
+
+        // The response side sees the status and the headers set for the client
+        req.set_status(StatusCode::CREATED);
+        req.response_header_put("x-auth", "key");
+        let cfg = json!({"response": {"set": ["x-status:${status}"], "rename": ["x-auth:x-authenticated-by"]}});
+        HeaderTransform.apply(&req, Phase::Post, &cfg).await.unwrap();
+        let headers = req.headers.read().clone();
+        assert_eq!(headers["x-status"], "201");
+        assert_eq!(headers["x-authenticated-by"], "key");
+    }
+
+    #[test]
+    fn header_transform_rejects_invalid_names() {
+        let cfg = json!({"request": {"set": ["x-a:1"], "remove": ["x-b"], "mode": "append"}});
+        assert!(HeaderTransform.validate(&cfg).is_ok());
+        for (cfg, message) in [
+            (json!({"request": {"set": ["bad name:1"]}}), "request.set: invalid header name `bad name`"),
+            (json!({"response": {"add": ["no-value"]}}), "response.add: `no-value` is not name:value"),
+            (json!({"request": {"rename": ["x-a:bad name"]}}), "request.rename: invalid header name `bad name`"),
+            (json!({"response": {"remove": ["bad name"]}}), "response.remove: invalid header name `bad name`"),
+            (json!({"request": {"mode": "merge"}}), "request.mode must be append or override"),
+        ] {
+            assert_eq!(HeaderTransform.validate(&cfg).unwrap_err().to_string(), message);
+        }
+    }
+
+    // Runs compression on a response `body` to a request sending `accept`
+    async fn compressed(
+        accept: &'static str,
+        response: &[(&'static str, &'static str)],
+        body: &str,
+        cfg: serde_json::Value,
+    ) -> BullGContext {
+        let req = ctx(Method::GET, "/docs", &[("accept-encoding", accept)]);
+        let mut headers = HeaderMap::new();
+        for (name, value) in response {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        req.set_response_headers(headers);
+        req.set_status(StatusCode::OK);
+        req.set_body(Bytes::from(body.to_string()));
+        Compression.validate(&cfg).unwrap();
+        Compression.apply(&req, Phase::Post, &cfg).await.unwrap();
+        req
+    }
+
+    fn encoding(ctx: &BullGContext) -> Option<String> {
+        ctx.headers.read().get("content-encoding").map(|v| v.to_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn compression_negotiates_the_encoding() {
+        use std::io::Read;
+        let text = "compress me please ".repeat(200);
+        let body = |ctx: &BullGContext| ctx.get_body().to_vec();
+
+        let req = compressed("gzip, deflate, br", &[("content-type", "text/html")], &text, json!({})).await;
+        assert_eq!(encoding(&req).as_deref(), Some("br"));
+        let mut plain = String::new();
+        brotli::Decompressor::new(&body(&req)[..], 4096).read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, text);
+
+        let req = compressed("br;q=0, gzip;q=0.5", &[("content-type", "text/html")], &text, json!({})).await;
+        assert_eq!(encoding(&req).as_deref(), Some("gzip"));
+        let mut plain = String::new();
+        flate2::read::GzDecoder::new(&body(&req)[..]).read_to_string(&mut plain).unwrap();
+        assert_eq!(plain, text);
+
+        // The order of `encodings` wins over the one of the client
+        let cfg = json!({"encodings": ["gzip", "br"]});
+        let req = compressed("br, gzip", &[("content-type", "text/html")], &text, cfg).await;
+        assert_eq!(encoding(&req).as_deref(), Some("gzip"));
+        let req = compressed("*", &[("content-type", "text/html")], &text, json!({})).await;
+        assert_eq!(encoding(&req).as_deref(), Some("br"));
+        let req = compressed("identity, gzip;q=0", &[("content-type", "text/html")], &text, json!({})).await;
+        assert_eq!(encoding(&req), None);
+        assert_eq!(body(&req), text.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn compression_fixes_up_length_vary_and_etag() {
+        let text = "compress me please ".repeat(200);
+        let response = [
+            ("content-type", "application/json"),
+            ("content-length", "3800"),
+            ("vary", "origin"),
+            ("etag", "\"v1\""),
+        ];
+        let req = compressed("gzip", &response, &text, json!({})).await;
+        let headers = req.headers.read().clone();
+        assert_eq!(headers["content-encoding"], "gzip");
+        assert_eq!(headers["content-length"], req.get_body().len().to_string().as_str());
+        assert_eq!(values(&headers, "vary"), ["origin", "accept-encoding"]);
+        assert_eq!(headers["etag"], "W/\"v1\"");
+
+        // A response that already varies on it gets no second Vary
+        let req = compressed("gzip", &[("vary", "Accept-Encoding")], &text, json!({})).await;
+        assert_eq!(values(&req.headers.read(), "vary"), ["Accept-Encoding"]);
+    }
+
+    #[tokio::test]
+    async fn compression_leaves_some_responses_alone() {
+        let text = "compress me please ".repeat(200);
+        let html = [("content-type", "text/html")];
+
+        // Under `min_size`
+        let req = compressed("gzip", &html, "short body", json!({})).await;
+        assert_eq!((encoding(&req), req.get_body().len()), (None, 10));
+        let req = compressed("gzip", &html, &text, json!({"min_size": 8192})).await;
+        assert_eq!(encoding(&req), None);
+
+        // Already compressed types, by default or per `skip_types`
+        let req = compressed("gzip", &[("content-type", "image/png")], &text, json!({})).await;
+        assert_eq!(encoding(&req), None);
+        let req = compressed("gzip", &[("content-type", "video/mp4")], &text, json!({})).await;
+        assert_eq!(encoding(&req), None);
+        let cfg = json!({"skip_types": ["text/"]});
+        let req = compressed("gzip", &[("content-type", "Text/HTML; charset=utf-8")], &text, cfg).await;
+        assert_eq!(encoding(&req), None);
+
+        // An encoding set upstream, or one the client should not see changed
+        let req = compressed("gzip", &[("content-encoding", "br")], &text, json!({})).await;
+        assert_eq!(encoding(&req).as_deref(), Some("br"));
+        assert_eq!(req.get_body(), text.as_bytes());
+        let req = compressed("gzip", &[("cache-control", "public, no-transform")], &text, json!({})).await;
+        assert_eq!(encoding(&req), None);
+
+        // Streamed responses, even with a body large enough to compress
+        let req = ctx(Method::GET, "/docs", &[("accept-encoding", "gzip")]);
+        req.set_response_headers(HeaderMap::new());
+        req.set_streamed();
+        req.set_body(Bytes::from(text.clone()));
+        Compression.apply(&req, Phase::Post, &json!({})).await.unwrap();
+        assert_eq!(encoding(&req), None);
+        assert_eq!(req.get_body(), text.as_bytes());
+    }
+
+    #[test]
+    fn compression_validates_its_config() {
+        assert!(Compression.validate(&json!({"encodings": ["BR", "gzip"], "level": 11, "min_size": 0})).is_ok());
+        for (cfg, message) in [
+            (json!({"encodings": ["zstd"]}), "unsupported encoding `zstd`, expected br or gzip"),
+            (json!({"skip_types": "image/"}), "skip_types must be a list of strings"),
+            (json!({"min_size": -1}), "min_size must be a non negative integer"),
+            (json!({"level": 12}), "level must be between 0 and 11"),
+        ] {
+            assert_eq!(Compression.validate(&cfg).unwrap_err().to_string(), message);
+        }
+    }
+
+    const HMAC_SECRET: &str = "webhook-secret";
+
+    // Request carrying `signature` of its canonical form, `date` in the date header
+    fn hmac_request(uri: &str, body: &str, date: &str, signature: impl Fn(&str) -> String) -> BullGContext {
+        let req = ctx(Method::POST, uri, &[("x-consumer-id", "spoofed")]);
+        req.set_body(Bytes::from(body.to_string()));
+        let canonical = format!("POST\n{uri}\n{date}\n{}", BullGCrypto::sha256_hex(body.as_bytes()));
+        let mut headers = req.headers.write();
+        headers.insert("date", HeaderValue::from_str(date).unwrap());
+        headers.insert("x-signature", HeaderValue::from_str(&signature(&canonical)).unwrap());
+        drop(headers);
+        req
+    }
+
+    async fn hmac_auth(req: &BullGContext, cfg: serde_json::Value) -> Option<StatusCode> {
+        HmacAuth.validate(&cfg).unwrap();
+        HmacAuth.apply(req, Phase::Pre, &cfg).await.unwrap();
+        *req.status.read()
+    }
+
+    #[tokio::test]
+    async fn hmac_auth_accepts_signed_requests() {
+        let cfg = json!({"secret": HMAC_SECRET, "consumer": "github-webhooks"});
+        let now = Utc::now().to_rfc2822();
+        let hex = |c: &str| BullGCrypto::hmac_sign(c.as_bytes(), HMAC_SECRET.as_bytes());
+        let req = hmac_request("/hooks?delivery=42", r#"{"action":"opened"}"#, &now, hex);
+        assert_eq!(hmac_auth(&req, cfg.clone()).await, None);
+        assert_eq!(req.var_get(CONSUMER_ID_VAR), Some(json!("github-webhooks")));
+        assert_eq!(values(&req.headers.read(), "x-consumer-id"), ["github-webhooks"]);
+
+        // A `sha256=` prefix, base64 signatures and RFC 3339 dates
+        let prefixed = |c: &str| format!("sha256={}", hex(c));
+        let req = hmac_request("/hooks", "", &now, prefixed);
+        assert_eq!(hmac_auth(&req, cfg.clone()).await, None);
+        let base64 = |c: &str| STANDARD.encode(BullGCrypto::sign_hmac_sha256(c.as_bytes(), HMAC_SECRET.as_bytes()));
+        let req = hmac_request("/hooks", "payload", &Utc::now().to_rfc3339(), base64);
+        assert_eq!(hmac_auth(&req, cfg).await, None);
+
+        // Without a consumer the client sent id is still dropped
+        let req = hmac_request("/hooks", "payload", &now, hex);
+        assert_eq!(hmac_auth(&req, json!({"secret": HMAC_SECRET})).await, None);
+        assert!(!req.headers.read().contains_key("x-consumer-id"));
+        assert_eq!(req.var_get(CONSUMER_ID_VAR), None);
+    }
+
+    #[tokio::test]
+    async fn hmac_auth_rejects_tampered_or_stale_requests() {
+        let cfg = json!({"secret": HMAC_SECRET, "consumer": "github-webhooks", "clock_skew_sec": 60});
+        let now = Utc::now().to_rfc2822();
+        let hex = |c: &str| BullGCrypto::hmac_sign(c.as_bytes(), HMAC_SECRET.as_bytes());
+        let rejected = |req: &BullGContext| {
+            assert!(req.var_get(CONSUMER_ID_VAR).is_none());
+            assert_eq!(values(&req.headers.read(), "x-consumer-id"), ["spoofed"]);
+        };
+
+        let req = hmac_request("/hooks?delivery=42", "payload", &now, hex);
+        req.set_body(Bytes::from_static(b"tampered"));
+        assert_eq!(hmac_auth(&req, cfg.clone()).await, Some(StatusCode::UNAUTHORIZED));
+        rejected(&req);
+
+        // Signed for another query
+        let req = hmac_request("/hooks?delivery=42", "payload", &now, |c| hex(&c.replace("=42", "=43")));
+        assert_eq!(hmac_auth(&req, cfg.clone()).await, Some(StatusCode::UNAUTHORIZED));
+
+        let other_key = |c: &str| BullGCrypto::hmac_sign(c.as_bytes(), b"other-secret");
+        let req = hmac_request("/hooks", "payload", &now, other_key);
+        assert_eq!(hmac_auth(&req, cfg.clone()).await, Some(StatusCode::UNAUTHORIZED));
+
+        for date in [
+            (Utc::now() - chrono::Duration::seconds(120)).to_rfc2822(),
+            (Utc::now() + chrono::Duration::seconds(120)).to_rfc2822(),
+            "yesterday".to_string(),
+        ] {
+            let req = hmac_request("/hooks", "payload", &date, hex);
+            assert_eq!(hmac_auth(&req, cfg.clone()).await, Some(StatusCode::UNAUTHORIZED), "{date}");
+            rejected(&req);
+        }
+
+        let req = hmac_request("/hooks", "payload", &now, hex);
+        req.header_remove("x-signature");
+        assert_eq!(hmac_auth(&req, cfg.clone()).await, Some(StatusCode::UNAUTHORIZED));
+        rejected(&req);
+        let req = hmac_request("/hooks", "payload", &now, hex);
+        req.header_remove("date");
+        assert_eq!(hmac_auth(&req, cfg).await, Some(StatusCode::UNAUTHORIZED));
+    }
+
+    #[tokio::test]
+    async fn hmac_auth_reads_the_configured_headers() {
+        let cfg = json!({"secret": HMAC_SECRET, "signature_header": "x-hub-signature-256", "date_header": "x-date"});
+        let now = Utc::now().to_rfc2822();
+        let hex = |c: &str| BullGCrypto::hmac_sign(c.as_bytes(), HMAC_SECRET.as_bytes());
+        let req = hmac_request("/hooks", "payload", &now, hex);
+        {
+            let mut headers = req.headers.write();
+            let signature = headers.remove("x-signature").unwrap();
+            headers.insert("x-hub-signature-256", signature);
+            let date = headers.remove("date").unwrap();
+            headers.insert("x-date", date);
+        }
+        assert_eq!(hmac_auth(&req, cfg).await, None);
+
+        assert_eq!(HmacAuth.validate(&json!({})).unwrap_err().to_string(), "hmac_auth needs a secret");
+        let cfg = json!({"secret": HMAC_SECRET, "clock_skew_sec": -1});
+        assert_eq!(HmacAuth.validate(&cfg).unwrap_err().to_string(), "clock_skew_sec must be a number of seconds");
+        assert!(HmacAuth.validate(&json!({"secret": HMAC_SECRET, "signature_header": "bad name"})).is_err());
+    }
 }
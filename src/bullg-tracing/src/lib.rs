@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{ bail, Result };
 use tracing_subscriber::{ prelude::*, Registry };
 use tracing_subscriber::filter::EnvFilter;
 use tracing_subscriber::fmt;
@@ -11,27 +11,70 @@ use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_otlp::Protocol;
 use std::borrow::Cow;
 
-/// Initialize tracing + OpenTelemetry tracer provider.
-pub fn init(service_name: &str, otlp_endpoint: Option<&str>, logging_mode: &str) -> Result<()> {
-    // Build resource
-    let resource = Resource::builder()
-        .with_service_name(Cow::Owned(service_name.to_string()))
+/// OTLP transport of the span exporter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OtlpProtocol {
+    /// Protobuf over HTTP, usually port 4318
+    #[default]
+    Http,
+    /// gRPC, usually port 4317
+    Grpc,
+}
+
+impl OtlpProtocol {
+    /// `http` or `grpc`
+    pub fn parse(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "" | "http" | "http/protobuf" => Ok(OtlpProtocol::Http),
+            "grpc" => Ok(OtlpProtocol::Grpc),
+            other => bail!("unknown otlp protocol {:?}", other),
+        }
+    }
+}
+
+/// Where spans go and the resource attributes they carry
+pub struct TraceSettings<'a> {
+    pub service_name: &'a str,
+    pub service_version: &'a str,
+    pub environment: &'a str,
+    /// None prints spans to stdout
+    pub otlp_endpoint: Option<&'a str>,
+    pub protocol: OtlpProtocol,
+}
+
+// Resource attributes of every span
+fn resource(settings: &TraceSettings) -> Resource {
+    Resource::builder()
+        .with_service_name(Cow::Owned(settings.service_name.to_string()))
         .with_attributes(
             vec![
-                KeyValue::new("service.version", "0.1.0"),
-                KeyValue::new("deployment.environment", "development")
+                KeyValue::new("service.version", settings.service_version.to_string()),
+                KeyValue::new("deployment.environment", settings.environment.to_string())
             ]
         )
-        .build();
+        .build()
+}
+
+/// Initialize tracing + OpenTelemetry tracer provider.
+pub fn init(settings: &TraceSettings, logging_mode: &str) -> Result<()> {
+    let service_name = settings.service_name;
+    let resource = resource(settings);
 
     // Choose exporter
-    let tracer_provider = if let Some(endpoint) = otlp_endpoint {
-        let exporter = opentelemetry_otlp::SpanExporter
-            ::builder()
-            .with_http()
-            .with_protocol(Protocol::HttpBinary)
-            .with_endpoint(endpoint)
-            .build()?;
+    let tracer_provider = if let Some(endpoint) = settings.otlp_endpoint {
+        let exporter = match settings.protocol {
+            OtlpProtocol::Http => opentelemetry_otlp::SpanExporter
+                ::builder()
+                .with_http()
+                .with_protocol(Protocol::HttpBinary)
+                .with_endpoint(endpoint)
+                .build()?,
+            OtlpProtocol::Grpc => opentelemetry_otlp::SpanExporter
+                ::builder()
+                .with_tonic()
+                .with_endpoint(endpoint)
+                .build()?,
+        };
 
         sdktrace::SdkTracerProvider
             ::builder()
@@ -79,3 +122,42 @@ pub fn init(service_name: &str, otlp_endpoint: Option<&str>, logging_mode: &str)
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::{ Key, Value };
+
+    #[test]
+    fn otlp_protocols_parse() {
+        for (name, protocol) in [
+            ("grpc", OtlpProtocol::Grpc),
+            ("GRPC", OtlpProtocol::Grpc),
+            ("http", OtlpProtocol::Http),
+            ("http/protobuf", OtlpProtocol::Http),
+            ("", OtlpProtocol::Http),
+        ] {
+            assert_eq!(OtlpProtocol::parse(name).unwrap(), protocol, "{name:?}");
+        }
+        for name in ["http/json", "udp", " grpc"] {
+            assert!(OtlpProtocol::parse(name).is_err(), "{name:?}");
+        }
+        assert_eq!(OtlpProtocol::parse("zipkin").unwrap_err().to_string(), "unknown otlp protocol \"zipkin\"");
+    }
+
+    #[test]
+    fn resources_carry_the_service_attributes() {
+        let settings = TraceSettings {
+            service_name: "bullg-edge",
+            service_version: "1.0.1",
+            environment: "staging",
+            otlp_endpoint: None,
+            protocol: OtlpProtocol::default(),
+        };
+        let resource = resource(&settings);
+        let attribute = |key: &'static str| resource.get(&Key::from_static_str(key));
+        assert_eq!(attribute("service.name"), Some(Value::from("bullg-edge")));
+        assert_eq!(attribute("service.version"), Some(Value::from("1.0.1")));
+        assert_eq!(attribute("deployment.environment"), Some(Value::from("staging")));
+    }
+}
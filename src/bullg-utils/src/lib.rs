@@ -1,10 +1,142 @@
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Deserializer, Serializer};
+use std::time::Duration;
 use base64::{engine::general_purpose, Engine as _};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::digest::{digest, SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
 
-pub fn custom_encrypt(data: &[u8]) -> Vec<u8> {
-    general_purpose::STANDARD.encode(data).into_bytes()
+// AES-256-GCM key of a state encryption key such as a `BullGCrypto` map key
+fn aead_key(key: &str) -> LessSafeKey {
+    let hashed = digest(&SHA256, key.as_bytes());
+    let unbound = UnboundKey::new(&AES_256_GCM, hashed.as_ref()).expect("SHA-256 output is an AES-256 key");
+    LessSafeKey::new(unbound)
+}
+
+/// AES-256-GCM encryption of `data` under `key`, the random nonce prepended
+/// to the ciphertext and tag, base64 encoded for transport
+pub fn custom_encrypt(data: &[u8], key: &str) -> Vec<u8> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).expect("system random source unavailable");
+    let mut sealed = data.to_vec();
+    aead_key(key)
+        .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed)
+        .expect("AES-256-GCM input too large");
+    let mut out = nonce.to_vec();
+    out.extend_from_slice(&sealed);
+    general_purpose::STANDARD.encode(out).into_bytes()
+}
+
+/// Plaintext of a `custom_encrypt` message, failing when it was encrypted
+/// under another key or altered
+pub fn custom_decrypt(data: &[u8], key: &str) -> Result<Vec<u8>> {
+    let mut sealed = general_purpose::STANDARD.decode(data.trim_ascii())?;
+    if sealed.len() < NONCE_LEN + AES_256_GCM.tag_len() {
+        bail!("encrypted message too short");
+    }
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&sealed[..NONCE_LEN]);
+    let plain = aead_key(key)
+        .open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed[NONCE_LEN..])
+        .map_err(|_| anyhow!("decryption failed: wrong key or tampered message"))?;
+    Ok(plain.to_vec())
+}
+/// Parse a duration such as `250ms`, `30s`, `1m`, `1h` or `1d`, a bare number is seconds
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| anyhow!("invalid duration: {:?}", s))?;
+    let d = match unit.trim() {
+        "ms" => Duration::from_millis(n),
+        "" | "s" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n * 60),
+        "h" => Duration::from_secs(n * 3600),
+        "d" => Duration::from_secs(n * 86400),
+        _ => bail!("invalid duration unit: {:?}", s),
+    };
+    Ok(d)
+}
+
+/// Serde helper for duration fields written as `30s` (see `parse_duration`)
+pub fn de_duration<'de, D>(d: D) -> std::result::Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(d)?;
+    parse_duration(&s).map_err(serde::de::Error::custom)
+}
+
+/// Serde helper writing a duration back as milliseconds, e.g. `1500ms`
+pub fn ser_duration<S>(d: &Duration, s: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(&format!("{}ms", d.as_millis()))
+}
+
+/// `de_duration` for optional fields
+pub fn de_duration_opt<'de, D>(d: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(d)? {
+        Some(s) => parse_duration(&s).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// `ser_duration` for optional fields
+pub fn ser_duration_opt<S>(d: &Option<Duration>, s: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match d {
+        Some(d) => ser_duration(d, s),
+        None => s.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "state-key";
+
+    #[test]
+    fn encrypted_state_round_trips() {
+        for plain in [&b"{\"services\":[]}"[..], b"", &[0xff; 4096]] {
+            let sealed = custom_encrypt(plain, KEY);
+            assert_eq!(custom_decrypt(&sealed, KEY).unwrap(), plain);
+        }
+        // Fresh nonce every time, the plaintext does not show through
+        let (a, b) = (custom_encrypt(b"services", KEY), custom_encrypt(b"services", KEY));
+        assert_ne!(a, b);
+        assert!(!String::from_utf8(a.clone()).unwrap().contains("services"));
+        // Transports may add a trailing newline
+        let mut line = a;
+        line.extend_from_slice(b"\n");
+        assert_eq!(custom_decrypt(&line, KEY).unwrap(), b"services");
+    }
+
+    #[test]
+    fn tampered_or_foreign_messages_are_refused() {
+        let sealed = general_purpose::STANDARD.decode(custom_encrypt(b"services", KEY)).unwrap();
+        for at in [0, NONCE_LEN, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            let tampered = general_purpose::STANDARD.encode(tampered);
+            assert!(custom_decrypt(tampered.as_bytes(), KEY).is_err(), "byte {at}");
+        }
+        let encoded = general_purpose::STANDARD.encode(&sealed);
+        let err = custom_decrypt(encoded.as_bytes(), "other-key").unwrap_err();
+        assert_eq!(err.to_string(), "decryption failed: wrong key or tampered message");
+
+        let short = general_purpose::STANDARD.encode(&sealed[..NONCE_LEN + 4]);
+        assert_eq!(custom_decrypt(short.as_bytes(), KEY).unwrap_err().to_string(), "encrypted message too short");
+        assert!(custom_decrypt(b"not base64!", KEY).is_err());
+        // Plain base64, what the state used to be sent as, is no longer accepted
+        assert!(custom_decrypt(general_purpose::STANDARD.encode(b"{\"services\":[]} padding").as_bytes(), KEY).is_err());
+    }
 }
-pub fn custom_decrypt(data: &[u8]) -> Result<Vec<u8>> {
-    Ok(general_purpose::STANDARD.decode(data)?)
-}
\ No newline at end of file
@@ -1,9 +1,22 @@
 use anyhow::Result;
-use bullg_core::{load_all, Memory, ToServicesMapperVec, BullGService};
+use bullg_control_sync::SyncClient;
+use bullg_control_sync::protocol::StateUpdate;
+use bullg_core::{load_all, try_read_file, ConsumersTemplate, Memory, ServicesTemplate};
+use bullg_gateway::Gateway;
 use clap::Parser;
-use tokio::time::Instant;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{error, info, warn};
 
-#[derive(Parser, Debug)]
+// Quiet time after a change of a watched file before reloading, editors and
+// deploy tools often write a file in several steps
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+#[derive(Parser, Debug, Clone)]
 #[command(version, about = "BullG — 10x Faster API & AI Gateway")]
 struct Args {
     /// Path to config file (yaml/json/toml)
@@ -15,6 +28,9 @@ struct Args {
     plugins: String,
     #[arg(long, default_value = "")]
     consumers: String,
+    /// Reload the services and consumers when one of their files changes
+    #[arg(long)]
+    watch: bool,
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -22,32 +38,262 @@ async fn main() -> Result<()> {
     let args = Args::parse();
     let config = load_all(&args.config, &args.plugins, &args.consumers, &args.services);
     //println!("{:#?}", config);
-    let _memory = if config.config.gateway.memory.engine == "lmdb" {
-        Memory::open_lmdb(&config.config.gateway.memory.path)?
+    let node = config.config.gateway.clone();
+
+    let tracing = bullg_tracing::TraceSettings {
+        service_name: node.service_name(),
+        service_version: node.service_version(),
+        environment: &node.tracing.environment,
+        otlp_endpoint: node
+            .tracing
+            .enabled
+            .then_some(node.tracing.otlp_endpoint.as_str()),
+        protocol: bullg_tracing::OtlpProtocol::parse(&node.tracing.protocol)?,
+    };
+    bullg_tracing::init(&tracing, &node.logging_mode)?;
+
+    let memory = if node.memory.engine == "lmdb" && node.memory.read_only {
+        Memory::open_lmdb_ro(&node.memory.path)?
+    } else if node.memory.engine == "lmdb" {
+        Memory::open_lmdb(&node.memory.path)?
     } else {
         Memory::memory()
     };
 
-    let mut start = Instant::now();
-    let servicemaps = config.services.get_services_map_vec().services;
-    println!("Service Map Length: {}", servicemaps.len());
-    let mut router = BullGService::new();
-    let _ = router.add_service_mapper(servicemaps);
-
-    println!("Service mappers loaded in {}ms", start.elapsed().as_millis());
-    start = Instant::now();
-    let mut r_path = "/dummy/test/users";
-    if let Some((svc, params)) = router.find_service(r_path) {
-        println!("Services: {:?}", svc.context_paths);
-        let mut sub_path = r_path.trim_start_matches(&svc.context_paths.paths[0].path);
-        if !sub_path.starts_with("/") { sub_path = format!("/{}", sub_path).leak(); }
-        if let Some(route) = svc.router.find_route(sub_path){
-            println!("Route: {:?}", route.config);
+    let gw = Arc::new(Gateway::new(node.clone(), memory).with_catalog(&config.plugins_catalog)?);
+    gw.update_state(config.services).await?;
+    gw.update_consumers(config.consumers).await?;
+
+    if node.control_plane.enabled {
+        let sync = SyncClient::new(&node.control_plane)?;
+        gw.track_control_plane(sync.connection());
+        // Deltas build on each other, updates are applied one at a time in order
+        let gw = gw.clone();
+        tokio::spawn(async move {
+            sync.run(move |update| {
+                let gw = gw.clone();
+                async move {
+                    match update {
+                        StateUpdate::Snapshot(state) => gw.update_state(state).await?,
+                        StateUpdate::Delta(delta) => gw.apply_delta(delta).await?,
+                    }
+                    info!("control plane state applied");
+                    Ok(())
+                }
+            })
+            .await
+        });
+    }
+
+    if node.admin.enabled {
+        let addr: SocketAddr = node.admin.get_address().parse()?;
+        let gw = gw.clone();
+        tokio::spawn(async move {
+            if let Err(e) = gw.serve_admin(addr).await {
+                error!("admin API stopped: {e}");
+            }
+        });
+    }
+
+    if node.metrics.enabled {
+        let addr: SocketAddr = node.get_metrics_address().parse()?;
+        let gw = gw.clone();
+        tokio::spawn(async move {
+            if let Err(e) = gw.serve_metrics(addr).await {
+                error!("metrics endpoint stopped: {e}");
+            }
+        });
+    }
+
+    let health = if node.health_check.enabled {
+        let addr: SocketAddr = node.get_health_address().parse()?;
+        let gw = gw.clone();
+        Some(tokio::spawn(async move {
+            if let Err(e) = gw.serve_health(addr).await {
+                error!("health endpoint stopped: {e}");
+            }
+        }))
+    } else {
+        None
+    };
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let mut hangup = signal(SignalKind::hangup())?;
+        let (gw, args) = (gw.clone(), args.clone());
+        let control_plane = node.control_plane.enabled;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                info!("SIGHUP received, reloading configuration");
+                reload(&gw, &args, control_plane).await;
+            }
+        });
+    }
+
+    if args.watch {
+        if node.control_plane.enabled {
+            warn!("--watch ignored, services are synced from the control plane");
+        } else {
+            watch(gw.clone(), args.clone())?;
         }
-        println!("Parameters: {:?}", params);
     }
 
-    println!("Service mappers loaded in {}ms", start.elapsed().as_millis());
+    let addr: SocketAddr = node.get_address().parse()?;
+    gw.clone().serve_with_shutdown(addr, shutdown_signal()).await?;
+    // Answered 503 while connections drained, closed only now
+    if let Some(health) = health {
+        health.abort();
+    }
+    info!("shutdown complete");
 
     Ok(())
 }
+
+/// Re-read the services and consumers files given on the command line and
+/// apply them.
+/// Gateway settings of the config file only change on restart, and with a
+/// control plane the services come from it instead. A file that cannot be
+/// read or parsed rejects the reload.
+async fn reload(gw: &Gateway, args: &Args, control_plane: bool) {
+    if control_plane {
+        warn!("services are synced from the control plane, local files are not reloaded");
+        return;
+    }
+    let args = args.clone();
+    let loaded = tokio::task::spawn_blocking(move || -> Result<(ServicesTemplate, ConsumersTemplate)> {
+        Ok((try_read_file(&args.services)?, try_read_file(&args.consumers)?))
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|loaded| loaded);
+    match loaded {
+        Ok((services, consumers)) => {
+            match gw.update_state(services).await {
+                Ok(()) => info!("configuration reloaded"),
+                Err(e) => error!("reloaded services rejected, keeping the running state: {e}"),
+            }
+            if let Err(e) = gw.update_consumers(consumers).await {
+                error!("reloaded consumers not stored: {e}");
+            }
+        }
+        Err(e) => error!("configuration reload failed, keeping the running state: {e:#}"),
+    }
+}
+
+/// Reload once writes to the services or consumers file settle. Their
+/// directories are watched, as editors often replace a file rather than
+/// write it in place.
+fn watch(gw: Arc<Gateway>, args: Args) -> Result<()> {
+    let files = [&args.services, &args.consumers]
+        .into_iter()
+        .filter(|path| !path.is_empty())
+        .map(std::path::absolute)
+        .collect::<std::io::Result<Vec<PathBuf>>>()?;
+    if files.is_empty() {
+        warn!("--watch ignored, no services or consumers file given");
+        return Ok(());
+    }
+    let (changed, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let watched = files.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event)
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                && event.paths.iter().any(|path| watched.contains(path)) =>
+        {
+            let _ = changed.send(());
+        }
+        Ok(_) => {}
+        Err(e) => warn!("configuration watcher: {e}"),
+    })?;
+    let dirs: HashSet<&Path> = files.iter().filter_map(|file| file.parent()).collect();
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    info!("watching {} configuration files for changes", files.len());
+
+    tokio::spawn(async move {
+        // Dropping the watcher stops it
+        let _watcher = watcher;
+        while changes.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(WATCH_DEBOUNCE, changes.recv()).await {}
+            info!("configuration files changed, reloading");
+            reload(&gw, &args, false).await;
+        }
+    });
+    Ok(())
+}
+
+/// Resolves on SIGTERM or SIGINT, on other platforms on ctrl-c
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => tokio::select! {
+                _ = term.recv() => info!("SIGTERM received, shutting down"),
+                _ = tokio::signal::ctrl_c() => info!("SIGINT received, shutting down"),
+            },
+            Err(e) => {
+                warn!("SIGTERM handler unavailable: {e}");
+                let _ = tokio::signal::ctrl_c().await;
+                info!("SIGINT received, shutting down");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        info!("shutdown signal received");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bullg_gateway::mock::MockUpstream;
+
+    fn args(services: &Path) -> Args {
+        Args {
+            config: String::new(),
+            services: services.to_string_lossy().into_owned(),
+            plugins: String::new(),
+            consumers: String::new(),
+            watch: false,
+        }
+    }
+
+    fn paths(gw: &Gateway) -> Vec<String> {
+        gw.openapi()["paths"].as_object().unwrap().keys().cloned().collect()
+    }
+
+    #[tokio::test]
+    async fn reloads_apply_the_services_file_and_keep_the_state_when_it_is_broken() {
+        let up = MockUpstream::start(|_| Default::default()).await.unwrap();
+        let file = std::env::temp_dir().join(format!("bullg-reload-{}.json", std::process::id()));
+        let gw = Gateway::new(Default::default(), Memory::memory());
+        let write = |route: &str| {
+            let services = ServicesTemplate { services: vec![up.service("/api/", route)], ..Default::default() };
+            std::fs::write(&file, serde_json::to_string(&services).unwrap()).unwrap();
+        };
+
+        write("/users");
+        reload(&gw, &args(&file), false).await;
+        let users = paths(&gw);
+        assert_eq!(users.len(), 1);
+
+        write("/orders");
+        reload(&gw, &args(&file), false).await;
+        assert_ne!(paths(&gw), users);
+        let orders = paths(&gw);
+
+        // A broken file or a control plane leaves the running state alone
+        std::fs::write(&file, "{\"services\": [").unwrap();
+        reload(&gw, &args(&file), false).await;
+        assert_eq!(paths(&gw), orders);
+        write("/users");
+        reload(&gw, &args(&file), true).await;
+        assert_eq!(paths(&gw), orders);
+        std::fs::remove_file(&file).unwrap();
+    }
+}